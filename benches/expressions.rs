@@ -0,0 +1,169 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+//! Benchmark evaluating expressions by walking their trees and as compiled bytecode.
+//!
+//! Run with `cargo bench --bench expressions`; an argument restricts the run to the expressions
+//! whose names contain it.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use bulletml::data::{Expression, ExpressionContext, Value};
+
+/// The expressions to evaluate, by name.
+///
+/// These are typical of the expressions found in patterns.
+const EXPRESSIONS: &[(&str, &str)] = &[
+    ("constant", "180"),
+    ("variable", "$rank"),
+    ("aim-spread", "$1 - 45 + $rand * 90"),
+    ("rank-scaled", "(1.5 + $rank * 2) * $speed"),
+    ("spiral", "$1 * 10 + sin($loop * 6) * 30 - $rand % 5"),
+    (
+        "functions",
+        "max(sin($rank * 360), $speed) + floor($rand * 3) * abs(-$1)",
+    ),
+    (
+        "nested",
+        "((($1 + 1) * ($2 - 1)) / (($rank + 2) * ($rand + 3))) - ((($1 - $2) * 4) % 7)",
+    ),
+];
+/// The number of times each expression is evaluated.
+const EVALS: u32 = 1_000_000;
+
+/// An allocator which counts allocations.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocations() -> usize {
+    ALLOCATIONS.load(Ordering::Relaxed)
+}
+
+/// A context which varies its values with a counter so evaluations are not hoisted.
+struct BenchContext {
+    counter: Value,
+}
+
+impl ExpressionContext for BenchContext {
+    fn get(&self, name: &str) -> Option<Value> {
+        match name {
+            "speed" => Some(1.5),
+            "loop" => Some(self.counter),
+            _ => None,
+        }
+    }
+
+    fn get_param(&self, idx: usize) -> Option<Value> {
+        match idx {
+            1 => Some(self.counter),
+            2 => Some(3.),
+            _ => None,
+        }
+    }
+
+    fn rand(&self) -> Value {
+        0.375
+    }
+
+    fn rank(&self) -> Value {
+        0.5
+    }
+}
+
+/// Measurements for evaluating an expression one way.
+#[derive(Debug)]
+struct Measurement {
+    eval: Duration,
+    allocations: usize,
+    sum: Value,
+}
+
+fn measure(expr: &Expression) -> Measurement {
+    let mut ctx = BenchContext {
+        counter: 0.,
+    };
+    let mut sum = 0.;
+
+    let allocs = allocations();
+    let start = Instant::now();
+    for idx in 0..EVALS {
+        ctx.counter = (idx % 64) as Value;
+        sum += expr.eval(&ctx).unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    Measurement {
+        eval: elapsed / EVALS,
+        allocations: allocations() - allocs,
+        sum,
+    }
+}
+
+fn main() {
+    // Ignore the flags passed by `cargo bench`.
+    let filter = env::args().skip(1).find(|arg| !arg.starts_with('-'));
+
+    println!(
+        "{} evaluations each; times are per evaluation, allocations are in total",
+        EVALS,
+    );
+    println!(
+        "  {:<16} {:>12} {:>10} {:>12} {:>10} {:>8}",
+        "expression", "tree", "allocs", "bytecode", "allocs", "speedup",
+    );
+
+    EXPRESSIONS
+        .iter()
+        .filter(|(name, _)| {
+            filter
+                .as_ref()
+                .map_or(true, |filter| name.contains(filter.as_str()))
+        })
+        .for_each(|&(name, source)| {
+            let tree = Expression::parse(source)
+                .unwrap_or_else(|err| panic!("failed to parse {}: {}", name, err));
+            let mut bytecode = tree.clone();
+            bytecode.compile();
+
+            let tree = measure(&tree);
+            let bytecode = measure(&bytecode);
+            assert_eq!(
+                tree.sum, bytecode.sum,
+                "{}: bytecode evaluates differently",
+                name,
+            );
+
+            println!(
+                "  {:<16} {:>12} {:>10} {:>12} {:>10} {:>7.2}x",
+                name,
+                format!("{:?}", tree.eval),
+                tree.allocations,
+                format!("{:?}", bytecode.eval),
+                bytecode.allocations,
+                tree.eval.as_secs_f64() / bytecode.eval.as_secs_f64().max(1e-12),
+            );
+        });
+}
@@ -0,0 +1,278 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+//! Benchmark compiling and running the pattern corpus by density tier.
+//!
+//! Patterns live in `benches/patterns/<tier>/*.xml`. Run with `cargo bench --bench patterns`;
+//! an argument restricts the run to the tiers whose names contain it.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use bulletml::data::{self, ExpressionContext, Value};
+use bulletml::run::{BulletManager, CompiledBulletML, Rng, Runner, RunnerOptions};
+
+/// The density tiers, from least to most dense.
+const TIERS: &[&str] = &["sparse", "medium", "bullet-hell"];
+/// The number of times each pattern is compiled.
+const COMPILES: u32 = 100;
+/// The number of runners updated together, as for a screen full of enemies.
+const RUNNERS: usize = 100;
+/// The number of frames to run.
+const FRAMES: u32 = 600;
+
+/// An allocator which counts allocations.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocations() -> usize {
+    ALLOCATIONS.load(Ordering::Relaxed)
+}
+
+/// A manager which only counts the bullets it is asked to create.
+struct BenchManager {
+    rng: Rng,
+    turn: u32,
+    aim: f32,
+    fired: usize,
+}
+
+impl BenchManager {
+    fn new(seed: u64) -> Self {
+        BenchManager {
+            rng: Rng::new(seed),
+            turn: 0,
+            aim: (seed % 360) as f32,
+            fired: 0,
+        }
+    }
+}
+
+impl ExpressionContext for BenchManager {
+    fn get(&self, _: &str) -> Option<Value> {
+        None
+    }
+
+    fn get_param(&self, _: usize) -> Option<Value> {
+        None
+    }
+
+    fn rand(&self) -> Value {
+        self.rng.next_value()
+    }
+
+    fn rank(&self) -> Value {
+        0.5
+    }
+}
+
+impl BulletManager for BenchManager {
+    fn new_simple(&mut self, _: f32, _: f32) {
+        self.fired += 1;
+    }
+
+    fn new_bullet(&mut self, _: f32, _: f32) {
+        self.fired += 1;
+    }
+
+    fn turn(&self) -> u32 {
+        self.turn
+    }
+
+    fn direction(&self) -> f32 {
+        0.
+    }
+
+    fn aim_direction(&self) -> f32 {
+        self.aim
+    }
+
+    fn speed(&self) -> f32 {
+        1.
+    }
+
+    fn speed_x(&self) -> f32 {
+        0.
+    }
+
+    fn speed_y(&self) -> f32 {
+        0.
+    }
+
+    fn default_speed(&self) -> f32 {
+        1.
+    }
+
+    fn vanish(&mut self) {}
+
+    fn change_direction(&mut self, _: f32) {}
+
+    fn change_speed(&mut self, _: f32) {}
+
+    fn accel_x(&mut self, _: f32) {}
+
+    fn accel_y(&mut self, _: f32) {}
+}
+
+/// Measurements for a single pattern.
+#[derive(Debug, Default)]
+struct Measurement {
+    compile: Duration,
+    compile_allocations: usize,
+    frame: Duration,
+    slowest_frame: Duration,
+    frame_allocations: usize,
+    fired: usize,
+}
+
+impl Measurement {
+    fn add(&mut self, other: &Self) {
+        self.compile += other.compile;
+        self.compile_allocations += other.compile_allocations;
+        self.frame += other.frame;
+        self.slowest_frame = self.slowest_frame.max(other.slowest_frame);
+        self.frame_allocations += other.frame_allocations;
+        self.fired += other.fired;
+    }
+}
+
+fn patterns(tier: &str) -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("benches/patterns")
+        .join(tier);
+    let mut paths = fs::read_dir(&dir)
+        .unwrap_or_else(|err| panic!("failed to read {}: {}", dir.display(), err))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "xml"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths
+}
+
+fn measure(path: &Path) -> Measurement {
+    let source = fs::read_to_string(path)
+        .unwrap_or_else(|err| panic!("failed to read {}: {}", path.display(), err));
+    let bulletml: data::BulletML = serde_xml_rs::from_str(&source)
+        .unwrap_or_else(|err| panic!("failed to parse {}: {}", path.display(), err));
+    let mut measurement = Measurement::default();
+
+    let allocs = allocations();
+    let start = Instant::now();
+    for _ in 0..COMPILES {
+        CompiledBulletML::new(bulletml.clone()).unwrap();
+    }
+    measurement.compile = start.elapsed() / COMPILES;
+    measurement.compile_allocations = (allocations() - allocs) / (COMPILES as usize);
+
+    let compiled = CompiledBulletML::new(bulletml).unwrap();
+    let mut runners = (0..RUNNERS)
+        .map(|seed| {
+            let manager = BenchManager::new(seed as u64);
+            Runner::from_compiled(manager, &compiled, RunnerOptions::default())
+        })
+        .collect::<Vec<_>>();
+
+    let allocs = allocations();
+    let start = Instant::now();
+    for turn in 0..FRAMES {
+        let frame_start = Instant::now();
+        for runner in &mut runners {
+            runner.manager_mut().turn = turn;
+            runner.update().unwrap();
+        }
+        measurement.slowest_frame = measurement.slowest_frame.max(frame_start.elapsed());
+    }
+    measurement.frame = start.elapsed() / FRAMES;
+    measurement.frame_allocations = (allocations() - allocs) / (FRAMES as usize);
+    measurement.fired = runners
+        .iter()
+        .map(|runner| runner.manager().fired)
+        .sum::<usize>()
+        / (FRAMES as usize);
+
+    measurement
+}
+
+fn report(name: &str, measurement: &Measurement) {
+    println!(
+        "  {:<24} {:>12} {:>10} {:>12} {:>12} {:>10} {:>10}",
+        name,
+        format!("{:?}", measurement.compile),
+        measurement.compile_allocations,
+        format!("{:?}", measurement.frame),
+        format!("{:?}", measurement.slowest_frame),
+        measurement.frame_allocations,
+        measurement.fired,
+    );
+}
+
+fn main() {
+    // Ignore the flags passed by `cargo bench`.
+    let filter = env::args().skip(1).find(|arg| !arg.starts_with('-'));
+
+    println!(
+        "{} runners for {} frames; compile figures are per compile, frame figures per frame",
+        RUNNERS, FRAMES,
+    );
+    println!(
+        "  {:<24} {:>12} {:>10} {:>12} {:>12} {:>10} {:>10}",
+        "pattern", "compile", "allocs", "frame", "slowest", "allocs", "bullets",
+    );
+
+    TIERS
+        .iter()
+        .filter(|tier| {
+            filter
+                .as_ref()
+                .map_or(true, |filter| tier.contains(filter.as_str()))
+        })
+        .for_each(|tier| {
+            println!("{}:", tier);
+
+            let paths = patterns(tier);
+            let mut total = Measurement::default();
+            for path in &paths {
+                let measurement = measure(path);
+                let name = path.file_stem().unwrap().to_string_lossy();
+                report(&name, &measurement);
+                total.add(&measurement);
+            }
+
+            if !paths.is_empty() {
+                let count = paths.len() as u32;
+                let mean = Measurement {
+                    compile: total.compile / count,
+                    compile_allocations: total.compile_allocations / paths.len(),
+                    frame: total.frame / count,
+                    slowest_frame: total.slowest_frame,
+                    frame_allocations: total.frame_allocations / paths.len(),
+                    fired: total.fired / paths.len(),
+                };
+                report("(mean)", &mean);
+            }
+        });
+}
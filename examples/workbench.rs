@@ -0,0 +1,445 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+//! A workbench for tweaking patterns live.
+//!
+//! Run with `cargo run --example workbench --features workbench -- <pattern.xml>`. The pattern is
+//! reloaded whenever the file changes. The rank, seed, and speed of time may be adjusted from the
+//! side panel and the emitter aims at the pointer.
+
+use std::collections::VecDeque;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use bulletml::data::{ExpressionContext, Value};
+use bulletml::run::{BulletManager, BulletScript, Event, Rng, Runner};
+use bulletml::Pattern;
+use eframe::egui::{self, Color32, Pos2, Sense};
+use eframe::epi;
+
+/// The size of the playing field.
+const FIELD_WIDTH: f32 = 480.;
+const FIELD_HEIGHT: f32 = 640.;
+/// The frame rate of the simulation at a time scale of `1`.
+const FRAMES_PER_SECOND: f32 = 60.;
+/// The most frames to simulate per repaint so that slow repaints do not snowball.
+const MAX_FRAMES_PER_REPAINT: u32 = 10;
+/// The most bullets to keep on the field.
+const MAX_BULLETS: usize = 4096;
+/// The number of events shown in the event log.
+const EVENT_LOG_SIZE: usize = 20;
+
+#[derive(Debug, Clone, Copy)]
+struct Body {
+    x: f32,
+    y: f32,
+    direction: f32,
+    speed: f32,
+}
+
+impl Body {
+    fn new(x: f32, y: f32, direction: f32, speed: f32) -> Self {
+        Body {
+            x,
+            y,
+            direction,
+            speed,
+        }
+    }
+
+    fn speed_x(&self) -> f32 {
+        self.speed * self.direction.to_radians().sin()
+    }
+
+    fn speed_y(&self) -> f32 {
+        -self.speed * self.direction.to_radians().cos()
+    }
+
+    fn set_velocity(&mut self, speed_x: f32, speed_y: f32) {
+        self.speed = speed_x.hypot(speed_y);
+        // Keep the previous direction when stopped.
+        if self.speed > 0. {
+            self.direction = speed_x.atan2(-speed_y).to_degrees();
+        }
+    }
+
+    fn advance(&mut self) {
+        self.x += self.speed_x();
+        self.y += self.speed_y();
+    }
+
+    fn is_on_field(&self) -> bool {
+        (0. ..=FIELD_WIDTH).contains(&self.x) && (0. ..=FIELD_HEIGHT).contains(&self.y)
+    }
+}
+
+/// A bullet fired by a runner.
+enum Spawned {
+    Simple(Body),
+    Scripted(Body, BulletScript),
+}
+
+/// A manager for the emitter or a bullet running actions.
+struct BodyManager {
+    body: Body,
+    target: Pos2,
+    rank: Value,
+    turn: u32,
+    spawned: Vec<Spawned>,
+    vanished: bool,
+}
+
+impl BodyManager {
+    fn new(body: Body, target: Pos2, rank: Value) -> Self {
+        BodyManager {
+            body,
+            target,
+            rank,
+            turn: 0,
+            spawned: Vec::new(),
+            vanished: false,
+        }
+    }
+}
+
+impl ExpressionContext for BodyManager {
+    fn get(&self, _: &str) -> Option<Value> {
+        None
+    }
+
+    fn get_param(&self, _: usize) -> Option<Value> {
+        None
+    }
+
+    fn rand(&self) -> Value {
+        // Runners are always given a random number generator.
+        0.5
+    }
+
+    fn rank(&self) -> Value {
+        self.rank
+    }
+}
+
+impl BulletManager for BodyManager {
+    fn new_simple(&mut self, direction: f32, speed: f32) {
+        let body = Body::new(self.body.x, self.body.y, direction, speed);
+        self.spawned.push(Spawned::Simple(body));
+    }
+
+    fn new_bullet(&mut self, direction: f32, speed: f32) {
+        self.new_simple(direction, speed);
+    }
+
+    fn new_bullet_with_script(&mut self, direction: f32, speed: f32, script: BulletScript) {
+        let body = Body::new(self.body.x, self.body.y, direction, speed);
+        self.spawned.push(Spawned::Scripted(body, script));
+    }
+
+    fn turn(&self) -> u32 {
+        self.turn
+    }
+
+    fn direction(&self) -> f32 {
+        self.body.direction
+    }
+
+    fn aim_direction(&self) -> f32 {
+        (self.target.x - self.body.x)
+            .atan2(self.body.y - self.target.y)
+            .to_degrees()
+    }
+
+    fn speed(&self) -> f32 {
+        self.body.speed
+    }
+
+    fn speed_x(&self) -> f32 {
+        self.body.speed_x()
+    }
+
+    fn speed_y(&self) -> f32 {
+        self.body.speed_y()
+    }
+
+    fn default_speed(&self) -> f32 {
+        1.
+    }
+
+    fn vanish(&mut self) {
+        self.vanished = true;
+    }
+
+    fn change_direction(&mut self, degrees: f32) {
+        self.body.direction = degrees;
+    }
+
+    fn change_speed(&mut self, speed: f32) {
+        self.body.speed = speed;
+    }
+
+    fn accel_x(&mut self, amount: f32) {
+        let speed_y = self.body.speed_y();
+        self.body.set_velocity(amount, speed_y);
+    }
+
+    fn accel_y(&mut self, amount: f32) {
+        let speed_x = self.body.speed_x();
+        self.body.set_velocity(speed_x, amount);
+    }
+}
+
+struct Workbench {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    pattern: Option<Pattern>,
+    error: Option<String>,
+
+    rank: Value,
+    seed: u64,
+    time_scale: f32,
+
+    target: Pos2,
+    pending_frames: f32,
+    turn: u32,
+    runners: Vec<Runner<BodyManager>>,
+    bullets: Vec<Body>,
+    events: Arc<Mutex<VecDeque<String>>>,
+}
+
+impl Workbench {
+    fn new(path: PathBuf) -> Self {
+        let mut workbench = Workbench {
+            path,
+            modified: None,
+            pattern: None,
+            error: None,
+
+            rank: 0.5,
+            seed: 0,
+            time_scale: 1.,
+
+            target: Pos2::new(FIELD_WIDTH / 2., FIELD_HEIGHT * 7. / 8.),
+            pending_frames: 0.,
+            turn: 0,
+            runners: Vec::new(),
+            bullets: Vec::new(),
+            events: Arc::new(Mutex::new(VecDeque::with_capacity(EVENT_LOG_SIZE))),
+        };
+        workbench.reload();
+        workbench
+    }
+
+    /// Reload the pattern if its file has changed.
+    fn poll_file(&mut self) {
+        let modified = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if modified != self.modified {
+            self.reload();
+        }
+    }
+
+    fn reload(&mut self) {
+        self.modified = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+
+        // Compile right away so that errors are shown as soon as the file changes.
+        let pattern = Pattern::from_file(&self.path)
+            .and_then(|pattern| pattern.compiled().map(|_| pattern));
+        match pattern {
+            Ok(pattern) => {
+                self.pattern = Some(pattern);
+                self.error = None;
+                self.restart();
+            },
+            // Keep running the last good pattern while the file is being edited.
+            Err(err) => self.error = Some(err.to_string()),
+        }
+    }
+
+    fn restart(&mut self) {
+        self.turn = 0;
+        self.pending_frames = 0.;
+        self.bullets.clear();
+        self.events.lock().unwrap().clear();
+
+        self.runners = self
+            .pattern
+            .iter()
+            .filter_map(|pattern| {
+                let emitter = Body::new(FIELD_WIDTH / 2., FIELD_HEIGHT / 4., 180., 0.);
+                let manager = BodyManager::new(emitter, self.target, self.rank);
+                // The pattern was compiled when it was loaded.
+                let mut runner = pattern.runner(manager).ok()?;
+                runner.set_rng(Rng::new(self.seed));
+                self.observe(&mut runner);
+                Some(runner)
+            })
+            .collect();
+    }
+
+    /// Log the events of a runner.
+    fn observe(&self, runner: &mut Runner<BodyManager>) {
+        let events = Arc::clone(&self.events);
+        runner.set_observer(move |event: &Event| {
+            let mut events = events.lock().unwrap();
+            if events.len() == EVENT_LOG_SIZE {
+                events.pop_front();
+            }
+            events.push_back(format!("{:?}", event));
+        });
+    }
+
+    /// Simulate a single frame.
+    fn step(&mut self) {
+        let mut spawned = Vec::new();
+        for runner in &mut self.runners {
+            let manager = runner.manager_mut();
+            manager.turn = self.turn;
+            manager.target = self.target;
+            manager.rank = self.rank;
+
+            if let Err(err) = runner.update() {
+                self.error = Some(err.to_string());
+            }
+
+            let manager = runner.manager_mut();
+            manager.body.advance();
+            spawned.append(&mut manager.spawned);
+        }
+        self.turn += 1;
+
+        // The emitter stays until the pattern is restarted.
+        let mut index = 0;
+        self.runners.retain(|runner| {
+            let manager = runner.manager();
+            let keep = index == 0 || (!manager.vanished && manager.body.is_on_field());
+            index += 1;
+            keep
+        });
+
+        for bullet in &mut self.bullets {
+            bullet.advance();
+        }
+        self.bullets.retain(Body::is_on_field);
+
+        for spawn in spawned {
+            if self.bullet_count() >= MAX_BULLETS {
+                break;
+            }
+
+            match spawn {
+                Spawned::Simple(body) => self.bullets.push(body),
+                Spawned::Scripted(body, script) => {
+                    let manager = BodyManager::new(body, self.target, self.rank);
+                    let mut runner = script.runner(manager);
+                    self.observe(&mut runner);
+                    self.runners.push(runner);
+                },
+            }
+        }
+    }
+
+    /// The number of bullets on the field, excluding the emitter.
+    fn bullet_count(&self) -> usize {
+        self.bullets.len() + self.runners.len().saturating_sub(1)
+    }
+
+    fn controls(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Pattern");
+        ui.label(self.path.display().to_string());
+        if let Some(error) = self.error.as_ref() {
+            ui.colored_label(Color32::RED, error);
+        }
+
+        ui.separator();
+        // The rank is read every frame, so changing it does not need a restart.
+        ui.add(egui::Slider::new(&mut self.rank, 0. ..=1.).text("rank"));
+        let reseeded = ui
+            .add(egui::Slider::new(&mut self.seed, 0..=1000).text("seed"))
+            .changed();
+        ui.add(egui::Slider::new(&mut self.time_scale, 0. ..=4.).text("time scale"));
+        if reseeded || ui.button("Restart").clicked() {
+            self.restart();
+        }
+
+        ui.separator();
+        ui.label(format!("frame: {}", self.turn));
+        ui.label(format!("bullets: {}", self.bullet_count()));
+
+        ui.separator();
+        ui.heading("Events");
+        for event in self.events.lock().unwrap().iter().rev() {
+            ui.monospace(event);
+        }
+    }
+
+    fn field(&mut self, ui: &mut egui::Ui) {
+        let elapsed = ui.input().unstable_dt * FRAMES_PER_SECOND * self.time_scale;
+        let frames = self.pending_frames + elapsed;
+        let whole = frames.floor();
+        self.pending_frames = frames - whole;
+        for _ in 0..(whole as u32).min(MAX_FRAMES_PER_REPAINT) {
+            self.step();
+        }
+
+        let (response, painter) =
+            ui.allocate_painter(egui::vec2(FIELD_WIDTH, FIELD_HEIGHT), Sense::hover());
+        let origin = response.rect.min;
+        if let Some(pointer) = response.hover_pos() {
+            self.target = Pos2::new(pointer.x - origin.x, pointer.y - origin.y);
+        }
+        let at = |x: f32, y: f32| Pos2::new(origin.x + x, origin.y + y);
+
+        painter.rect_filled(response.rect, 0., Color32::from_gray(16));
+        painter.circle_filled(at(self.target.x, self.target.y), 4., Color32::LIGHT_BLUE);
+        for bullet in &self.bullets {
+            painter.circle_filled(at(bullet.x, bullet.y), 3., Color32::WHITE);
+        }
+        for (index, runner) in self.runners.iter().enumerate() {
+            let body = runner.manager().body;
+            let (radius, color) = if index == 0 {
+                (6., Color32::RED)
+            } else {
+                (3., Color32::YELLOW)
+            };
+            painter.circle_filled(at(body.x, body.y), radius, color);
+        }
+    }
+}
+
+impl epi::App for Workbench {
+    fn name(&self) -> &str {
+        "BulletML workbench"
+    }
+
+    fn update(&mut self, ctx: &egui::CtxRef, _: &mut epi::Frame<'_>) {
+        self.poll_file();
+
+        egui::SidePanel::left("controls").show(ctx, |ui| self.controls(ui));
+        egui::CentralPanel::default().show(ctx, |ui| self.field(ui));
+
+        // The simulation runs continuously.
+        ctx.request_repaint();
+    }
+}
+
+fn main() {
+    let path = if let Some(path) = env::args_os().nth(1) {
+        PathBuf::from(path)
+    } else {
+        eprintln!("usage: workbench <pattern.xml>");
+        process::exit(1);
+    };
+
+    eframe::run_native(
+        Box::new(Workbench::new(path)),
+        eframe::NativeOptions::default(),
+    );
+}
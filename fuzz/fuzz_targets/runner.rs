@@ -0,0 +1,382 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+//! Run randomly generated patterns against a manager which answers with arbitrary values.
+//!
+//! The runner must never panic: evaluation problems are errors, not crashes, no matter what
+//! the pattern or the manager does.
+
+#![no_main]
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use arbitrary::{Result, Unstructured};
+use bulletml::data::{self, Expression, ExpressionContext, Value};
+use bulletml::run::{
+    BulletManager, BulletScript, CompiledBulletML, NegativeSpeed, NoTargetPolicy,
+    RepeatEvaluation, Runner, RunnerOptions, UnknownVariablePolicy,
+};
+use libfuzzer_sys::fuzz_target;
+
+/// How deeply actions, bullets and expressions may nest.
+const MAX_DEPTH: u32 = 4;
+/// How many steps an action may have.
+const MAX_STEPS: usize = 6;
+/// How many frames to run.
+const FRAMES: u32 = 300;
+/// How many fired bullets to run alongside the top-level runner.
+const MAX_CHILDREN: usize = 32;
+/// How many steps a runner may take per frame.
+///
+/// Patterns may loop without waiting; the budget keeps each frame bounded.
+const MAX_STEP_BUDGET: usize = 4096;
+
+/// Values which tend to break arithmetic.
+const EXTREMES: &[f32] = &[
+    f32::NAN,
+    f32::INFINITY,
+    f32::NEG_INFINITY,
+    f32::MAX,
+    f32::MIN,
+    f32::MIN_POSITIVE,
+    f32::EPSILON,
+    0.,
+    -0.,
+    -1.,
+    1e30,
+    -1e30,
+];
+
+fn value(u: &mut Unstructured) -> Result<f32> {
+    if u.ratio(1, 3)? {
+        Ok(*u.choose(EXTREMES)?)
+    } else {
+        u.arbitrary()
+    }
+}
+
+fn expression(u: &mut Unstructured, depth: u32) -> Result<Expression> {
+    let leaf = depth == 0 || u.is_empty();
+    let choice = u.int_in_range(0..=if leaf { 4 } else { 10 })?;
+
+    Ok(match choice {
+        0 => value(u)?.into(),
+        1 => Expression::rand(),
+        2 => Expression::rank(),
+        3 => Expression::param(u.int_in_range(0..=4)?),
+        4 => Expression::var("var"),
+        5 => -expression(u, depth - 1)?,
+        6 => expression(u, depth - 1)? + expression(u, depth - 1)?,
+        7 => expression(u, depth - 1)? - expression(u, depth - 1)?,
+        8 => expression(u, depth - 1)? * expression(u, depth - 1)?,
+        9 => expression(u, depth - 1)? / expression(u, depth - 1)?,
+        _ => expression(u, depth - 1)? % expression(u, depth - 1)?,
+    })
+}
+
+fn change(u: &mut Unstructured) -> Result<data::Change> {
+    Ok(*u.choose(&[
+        data::Change::Absolute,
+        data::Change::Relative,
+        data::Change::Sequence,
+    ])?)
+}
+
+fn direction(u: &mut Unstructured, depth: u32) -> Result<data::Direction> {
+    let kind = *u.choose(&[
+        data::DirectionKind::Aim,
+        data::DirectionKind::Absolute,
+        data::DirectionKind::Relative,
+        data::DirectionKind::Sequence,
+    ])?;
+    Ok(data::Direction::new(kind, expression(u, depth)?))
+}
+
+fn speed(u: &mut Unstructured, depth: u32) -> Result<data::Speed> {
+    Ok(data::Speed::new(change(u)?, expression(u, depth)?))
+}
+
+fn bullet(u: &mut Unstructured, depth: u32) -> Result<data::Bullet> {
+    let mut bullet = data::Bullet::default();
+    if u.arbitrary()? {
+        bullet.direction = Some(direction(u, depth)?);
+    }
+    if u.arbitrary()? {
+        bullet.speed = Some(speed(u, depth)?);
+    }
+    if depth > 0 && u.arbitrary()? {
+        bullet
+            .actions
+            .push(data::EntityRef::Real(Rc::new(action(u, depth - 1)?)));
+    }
+    Ok(bullet)
+}
+
+fn fire(u: &mut Unstructured, depth: u32) -> Result<data::Fire> {
+    let mut fire = data::Fire::new(data::EntityRef::Real(Rc::new(bullet(u, depth)?)));
+    if u.arbitrary()? {
+        fire.direction = Some(direction(u, depth)?);
+    }
+    if u.arbitrary()? {
+        fire.speed = Some(speed(u, depth)?);
+    }
+    Ok(fire)
+}
+
+fn step(u: &mut Unstructured, depth: u32) -> Result<data::Step> {
+    let nested = depth > 0 && !u.is_empty();
+    let choice = u.int_in_range(0..=if nested { 7 } else { 5 })?;
+
+    Ok(match choice {
+        0 => {
+            data::Step::ChangeSpeed(data::ChangeSpeed::new(
+                speed(u, depth)?,
+                data::Term::new(expression(u, depth)?),
+            ))
+        },
+        1 => {
+            data::Step::ChangeDirection(data::ChangeDirection::new(
+                direction(u, depth)?,
+                data::Term::new(expression(u, depth)?),
+            ))
+        },
+        2 => {
+            let horizontal = if u.arbitrary()? {
+                Some(data::Horizontal::new(change(u)?, expression(u, depth)?))
+            } else {
+                None
+            };
+            let vertical = if u.arbitrary()? {
+                Some(data::Vertical::new(change(u)?, expression(u, depth)?))
+            } else {
+                None
+            };
+            data::Step::Accel(data::Accel::new(
+                horizontal,
+                vertical,
+                data::Term::new(expression(u, depth)?),
+            ))
+        },
+        3 => data::Step::Wait(data::Wait::new(expression(u, depth)?)),
+        4 => data::Step::Vanish(data::Vanish::default()),
+        5 => data::Step::Fire(data::EntityRef::Real(Rc::new(fire(u, depth)?))),
+        6 => {
+            data::Step::Repeat(data::Repeat::new(
+                data::Times::new(expression(u, depth)?),
+                vec![data::EntityRef::Real(Rc::new(action(u, depth - 1)?))],
+            ))
+        },
+        _ => data::Step::Action(data::EntityRef::Real(Rc::new(action(u, depth - 1)?))),
+    })
+}
+
+fn action(u: &mut Unstructured, depth: u32) -> Result<data::Action> {
+    let count = u.int_in_range(0..=MAX_STEPS)?;
+    let steps = (0..count)
+        .map(|_| step(u, depth))
+        .collect::<Result<Vec<_>>>()?;
+    let mut action = data::Action::new(steps);
+    if u.ratio(1, 8)? {
+        action.ttl = Some(u.arbitrary()?);
+    }
+    Ok(action)
+}
+
+fn bulletml(u: &mut Unstructured) -> Result<data::BulletML> {
+    let mut top = action(u, MAX_DEPTH)?;
+    top.label = Some("top".into());
+
+    Ok(data::BulletML {
+        orientation: *u.choose(&[
+            data::Orientation::None,
+            data::Orientation::Vertical,
+            data::Orientation::Horizontal,
+        ])?,
+        elements: vec![data::Element::Action(Rc::new(top))],
+    })
+}
+
+fn options(u: &mut Unstructured) -> Result<RunnerOptions> {
+    let mut builder = RunnerOptions::builder()
+        .step_budget(u.int_in_range(1..=MAX_STEP_BUDGET)?)
+        .accumulate_wait(u.arbitrary()?)
+        .inherit_velocity(u.arbitrary()?)
+        .no_target(*u.choose(&[
+            NoTargetPolicy::KeepLast,
+            NoTargetPolicy::Up,
+            NoTargetPolicy::Down,
+        ])?)
+        .unknown_variable(*u.choose(&[
+            UnknownVariablePolicy::Error,
+            UnknownVariablePolicy::Zero,
+        ])?)
+        .repeat_evaluation(*u.choose(&[
+            RepeatEvaluation::Once,
+            RepeatEvaluation::EachIteration,
+        ])?)
+        .negative_speed(*u.choose(&[
+            NegativeSpeed::Allow,
+            NegativeSpeed::ReflectDirection,
+            NegativeSpeed::ClampZero,
+        ])?);
+    if u.arbitrary()? {
+        builder = builder.default_ttl(u.arbitrary()?);
+    }
+    if u.arbitrary()? {
+        builder = builder.direction_steps(u.arbitrary()?);
+    }
+    if u.arbitrary()? {
+        builder = builder.speed_fraction_bits(u.arbitrary()?);
+    }
+    Ok(builder.build())
+}
+
+/// A manager which answers every query with the next of a set of arbitrary values.
+struct FuzzManager {
+    values: Rc<[f32]>,
+    next: Cell<usize>,
+    turn: u32,
+    scripts: Vec<BulletScript>,
+}
+
+impl FuzzManager {
+    fn new(values: Rc<[f32]>) -> Self {
+        FuzzManager {
+            values,
+            next: Cell::new(0),
+            turn: 0,
+            scripts: Vec::new(),
+        }
+    }
+
+    fn value(&self) -> f32 {
+        let next = self.next.get();
+        self.next.set(next.wrapping_add(1));
+        if self.values.is_empty() {
+            0.
+        } else {
+            self.values[next % self.values.len()]
+        }
+    }
+}
+
+impl ExpressionContext for FuzzManager {
+    fn get(&self, _: &str) -> Option<Value> {
+        Some(self.value())
+    }
+
+    fn get_param(&self, _: usize) -> Option<Value> {
+        Some(self.value())
+    }
+
+    fn rand(&self) -> Value {
+        self.value()
+    }
+
+    fn rank(&self) -> Value {
+        self.value()
+    }
+}
+
+impl BulletManager for FuzzManager {
+    fn new_simple(&mut self, _: f32, _: f32) {}
+
+    fn new_bullet(&mut self, _: f32, _: f32) {}
+
+    fn new_bullet_with_script(&mut self, _: f32, _: f32, script: BulletScript) {
+        self.scripts.push(script);
+    }
+
+    fn turn(&self) -> u32 {
+        self.turn
+    }
+
+    fn direction(&self) -> f32 {
+        self.value()
+    }
+
+    fn aim_direction(&self) -> f32 {
+        self.value()
+    }
+
+    fn try_aim_direction(&self) -> Option<f32> {
+        Some(self.value()).filter(|value| !value.is_nan())
+    }
+
+    fn speed(&self) -> f32 {
+        self.value()
+    }
+
+    fn speed_x(&self) -> f32 {
+        self.value()
+    }
+
+    fn speed_y(&self) -> f32 {
+        self.value()
+    }
+
+    fn default_speed(&self) -> f32 {
+        self.value()
+    }
+
+    fn owner_velocity(&self) -> (f32, f32) {
+        (self.value(), self.value())
+    }
+
+    fn vanish(&mut self) {}
+
+    fn change_direction(&mut self, _: f32) {}
+
+    fn change_speed(&mut self, _: f32) {}
+
+    fn accel_x(&mut self, _: f32) {}
+
+    fn accel_y(&mut self, _: f32) {}
+}
+
+fn run(u: &mut Unstructured) -> Result<()> {
+    let bulletml = bulletml(u)?;
+    let options = options(u)?;
+    let values = (0..u.int_in_range(0..=64)?)
+        .map(|_| value(u))
+        .collect::<Result<Vec<_>>>()?;
+    let values: Rc<[f32]> = values.into();
+
+    // Generated patterns only reference entities inline, but compilation may still reject
+    // them; that is fine as long as it does not panic.
+    let compiled = if let Ok(compiled) = CompiledBulletML::new(bulletml) {
+        compiled
+    } else {
+        return Ok(());
+    };
+    let mut runners = vec![Runner::from_compiled(
+        FuzzManager::new(Rc::clone(&values)),
+        &compiled,
+        options,
+    )];
+
+    for turn in 0..FRAMES {
+        let mut scripts = Vec::new();
+        for runner in &mut runners {
+            runner.manager_mut().turn = turn;
+            // Errors are fine; panics are not.
+            let _ = runner.update();
+            scripts.append(&mut runner.manager_mut().scripts);
+        }
+
+        let room = MAX_CHILDREN.saturating_sub(runners.len() - 1);
+        runners.extend(
+            scripts
+                .into_iter()
+                .take(room)
+                .map(|script| script.runner(FuzzManager::new(Rc::clone(&values)))),
+        );
+    }
+
+    Ok(())
+}
+
+fuzz_target!(|data: &[u8]| {
+    let _ = run(&mut Unstructured::new(data));
+});
@@ -0,0 +1,335 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+//! Collections of patterns which are loaded as they are needed.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+#[cfg(feature = "zip-archive")]
+use std::fs::File;
+use std::io;
+#[cfg(feature = "zip-archive")]
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::{Pattern, PatternError};
+
+/// The documents of an archive by the names of their patterns.
+type Documents = BTreeMap<String, String>;
+
+/// Where the documents of an archive are stored.
+enum Storage {
+    /// Files within a directory.
+    Directory,
+    /// Entries of a zip file.
+    #[cfg(feature = "zip-archive")]
+    Zip(zip::ZipArchive<BufReader<File>>),
+}
+
+impl fmt::Debug for Storage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Storage::Directory => f.write_str("Directory"),
+            #[cfg(feature = "zip-archive")]
+            Storage::Zip(_) => f.write_str("Zip"),
+        }
+    }
+}
+
+/// The name of the pattern for a document in an archive.
+fn pattern_name(document: &str) -> Option<&str> {
+    document
+        .strip_suffix(".xml")
+        .filter(|name| !name.is_empty() && !name.ends_with('/'))
+}
+
+/// Find the documents within a directory.
+fn scan_directory(dir: &Path, prefix: &str, documents: &mut Documents) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        // Patterns are named by their paths, so they must be valid UTF-8.
+        let file_name = if let Some(file_name) = file_name.to_str() {
+            file_name
+        } else {
+            continue;
+        };
+
+        let document = format!("{}{}", prefix, file_name);
+        if fs::metadata(entry.path())?.is_dir() {
+            scan_directory(&entry.path(), &format!("{}/", document), documents)?;
+        } else if let Some(name) = pattern_name(&document) {
+            documents.insert(name.into(), document);
+        }
+    }
+
+    Ok(())
+}
+
+/// Open an archive which is not a directory.
+#[cfg(feature = "zip-archive")]
+fn open_file(path: &Path) -> Result<(Documents, Storage), PatternError> {
+    use zip::result::ZipError;
+
+    let open_err = |source| {
+        PatternError::Open {
+            path: path.into(),
+            source,
+        }
+    };
+
+    let fin = File::open(path).map_err(open_err)?;
+    let mut zip = zip::ZipArchive::new(BufReader::new(fin)).map_err(|err| {
+        match err {
+            ZipError::InvalidArchive(_) | ZipError::UnsupportedArchive(_) => {
+                PatternError::NotAnArchive {
+                    path: path.into(),
+                }
+            },
+            err => open_err(err.into()),
+        }
+    })?;
+
+    let mut documents = Documents::new();
+    for idx in 0..zip.len() {
+        let entry = zip.by_index(idx).map_err(|err| open_err(err.into()))?;
+        if let Some(name) = pattern_name(entry.name()) {
+            documents.insert(name.into(), entry.name().into());
+        }
+    }
+
+    Ok((documents, Storage::Zip(zip)))
+}
+
+/// Open an archive which is not a directory.
+#[cfg(not(feature = "zip-archive"))]
+fn open_file(path: &Path) -> Result<(Documents, Storage), PatternError> {
+    Err(PatternError::NotAnArchive {
+        path: path.into(),
+    })
+}
+
+/// A collection of patterns.
+///
+/// An archive is a directory of XML documents or, with the `zip-archive` feature, a zip file of
+/// them. Patterns are named by the paths to their documents within the archive without the `.xml`
+/// extension (e.g., `boss/spiral` for `boss/spiral.xml`).
+///
+/// Documents are only read and parsed when their pattern is first requested. Patterns are then
+/// cached, so games shipping many patterns only pay for the ones they use. Documents are parsed
+/// directly from the bytes which are read; see `Pattern::from_bytes` to parse documents from
+/// other sources, such as memory-mapped files.
+#[derive(Debug)]
+pub struct PatternArchive {
+    path: PathBuf,
+    documents: Documents,
+    storage: RefCell<Storage>,
+    patterns: RefCell<HashMap<String, Rc<Pattern>>>,
+}
+
+impl PatternArchive {
+    /// Open a directory or zip file of patterns.
+    ///
+    /// The documents in the archive are found when it is opened, but are not read.
+    pub fn open<P>(path: P) -> Result<Self, PatternError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let open_err = |source| {
+            PatternError::Open {
+                path: path.into(),
+                source,
+            }
+        };
+
+        let (documents, storage) = if fs::metadata(path).map_err(open_err)?.is_dir() {
+            let mut documents = Documents::new();
+            scan_directory(path, "", &mut documents).map_err(open_err)?;
+            (documents, Storage::Directory)
+        } else {
+            open_file(path)?
+        };
+
+        Ok(PatternArchive {
+            path: path.into(),
+            documents,
+            storage: RefCell::new(storage),
+            patterns: RefCell::new(HashMap::new()),
+        })
+    }
+
+    /// The path to the archive.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The names of the patterns in the archive in sorted order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.documents.keys().map(AsRef::as_ref)
+    }
+
+    /// Whether the archive contains a pattern.
+    pub fn contains(&self, name: &str) -> bool {
+        self.documents.contains_key(name)
+    }
+
+    /// Whether a pattern has been loaded.
+    pub fn is_loaded(&self, name: &str) -> bool {
+        self.patterns.borrow().contains_key(name)
+    }
+
+    /// Get a pattern from the archive.
+    ///
+    /// The pattern is loaded on first use and shared afterwards. Errors are not cached, so a
+    /// pattern which fails to load is read again on the next call. The source name of the pattern
+    /// is the path to its document within the archive.
+    pub fn get(&self, name: &str) -> Result<Rc<Pattern>, PatternError> {
+        if let Some(pattern) = self.patterns.borrow().get(name) {
+            return Ok(Rc::clone(pattern));
+        }
+
+        let document = self.documents.get(name).ok_or_else(|| {
+            PatternError::UnknownPattern {
+                name: name.into(),
+            }
+        })?;
+        let xml = self.read(document)?;
+        let source_name = self.path.join(document).display().to_string();
+        let pattern = Rc::new(Pattern::from_bytes(&xml)?.with_source_name(source_name));
+
+        self.patterns
+            .borrow_mut()
+            .insert(name.into(), Rc::clone(&pattern));
+        Ok(pattern)
+    }
+
+    /// Read a document from the archive.
+    fn read(&self, document: &str) -> Result<Vec<u8>, PatternError> {
+        match *self.storage.borrow_mut() {
+            Storage::Directory => {
+                let path = self.path.join(document);
+                fs::read(&path).map_err(|source| {
+                    PatternError::Open {
+                        path,
+                        source,
+                    }
+                })
+            },
+            #[cfg(feature = "zip-archive")]
+            Storage::Zip(ref mut zip) => {
+                let mut read = || -> io::Result<_> {
+                    let mut entry = zip.by_name(document)?;
+                    let mut xml = Vec::with_capacity(entry.size() as usize);
+                    entry.read_to_end(&mut xml)?;
+                    Ok(xml)
+                };
+                read().map_err(|source| {
+                    PatternError::Read {
+                        source,
+                    }
+                })
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use std::rc::Rc;
+
+    use crate::{PatternArchive, PatternError};
+
+    fn patterns() -> PatternArchive {
+        PatternArchive::open(Path::new(env!("CARGO_MANIFEST_DIR")).join("benches/patterns"))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_archive_directory() {
+        let archive = patterns();
+
+        let names = archive.names().collect::<Vec<_>>();
+        assert_eq!(names.len(), 6);
+        assert!(names.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(archive.contains("sparse/aimed-single"));
+        assert!(!archive.contains("sparse/aimed-single.xml"));
+        assert!(!archive.is_loaded("sparse/aimed-single"));
+
+        let pattern = archive.get("sparse/aimed-single").unwrap();
+        assert!(archive.is_loaded("sparse/aimed-single"));
+        assert!(!archive.is_loaded("sparse/slow-spread"));
+        assert!(pattern
+            .source_name()
+            .unwrap()
+            .ends_with("aimed-single.xml"));
+        assert!(pattern.compiled().is_ok());
+
+        // Patterns are shared once loaded.
+        assert!(Rc::ptr_eq(
+            &pattern,
+            &archive.get("sparse/aimed-single").unwrap(),
+        ));
+    }
+
+    #[test]
+    fn test_archive_unknown_pattern() {
+        let err = patterns().get("missing").unwrap_err();
+        if let PatternError::UnknownPattern {
+            ref name,
+        } = err
+        {
+            assert_eq!(name, "missing");
+        } else {
+            panic!("unexpected error: {:?}", err);
+        }
+    }
+
+    #[test]
+    fn test_archive_open_errors() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+
+        let err = PatternArchive::open(root.join("missing")).unwrap_err();
+        assert!(matches!(err, PatternError::Open { .. }));
+
+        let err = PatternArchive::open(root.join("Cargo.toml")).unwrap_err();
+        assert!(matches!(err, PatternError::NotAnArchive { .. }));
+    }
+
+    #[cfg(feature = "zip-archive")]
+    #[test]
+    fn test_archive_zip() {
+        use std::env;
+        use std::fs::{self, File};
+        use std::io::Write;
+        use std::process;
+
+        use zip::write::FileOptions;
+        use zip::ZipWriter;
+
+        let doc = r#"<bulletml>
+            <action label="top"><fire><bullet/></fire></action>
+        </bulletml>"#;
+
+        let path = env::temp_dir().join(format!("bulletml-archive-{}.zip", process::id()));
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        zip.add_directory("boss/", FileOptions::default()).unwrap();
+        zip.start_file("boss/spiral.xml", FileOptions::default())
+            .unwrap();
+        zip.write_all(doc.as_bytes()).unwrap();
+        zip.start_file("README", FileOptions::default()).unwrap();
+        zip.write_all(b"not a pattern").unwrap();
+        zip.finish().unwrap();
+
+        let archive = PatternArchive::open(&path).unwrap();
+        assert_eq!(archive.names().collect::<Vec<_>>(), ["boss/spiral"]);
+        let pattern = archive.get("boss/spiral").unwrap();
+        assert_eq!(pattern.metadata().actions, ["top"]);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
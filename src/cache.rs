@@ -0,0 +1,397 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+//! Caches of compiled patterns on disk.
+
+use std::cell::Cell;
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::data;
+use crate::run::{self, binary, CompileOptions, CompiledBulletML};
+use crate::PatternError;
+
+/// The start of a cache file.
+const MAGIC: &[u8; 4] = b"BMLC";
+/// The version of the crate which wrote a cache file.
+///
+/// Cache files written by other versions are ignored since the compiled form may change between
+/// any two versions.
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// The extension added to the name of a source to name its cache file.
+const EXTENSION: &str = "bmlc";
+
+/// The path to the cache file for a source.
+fn cache_file(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path);
+    name.push(".");
+    name.push(EXTENSION);
+    name.into()
+}
+
+/// Split bytes from the start of a cache file.
+fn take<'a>(rest: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if rest.len() < len {
+        return None;
+    }
+    let (taken, remaining) = rest.split_at(len);
+    *rest = remaining;
+    Some(taken)
+}
+
+/// Statistics about the use of a cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The number of patterns loaded from the cache.
+    pub hits: u64,
+    /// The number of patterns which were compiled.
+    pub misses: u64,
+    /// The number of compiled patterns which could not be stored.
+    ///
+    /// Scripts with custom steps are never stored.
+    pub write_failures: u64,
+}
+
+/// A directory of patterns whose compiled forms are cached on disk.
+///
+/// Compiling a large pattern takes time which adds up when a game loads many of them. The
+/// compiled form of each pattern is stored in a file next to its source (with `.bmlc` appended
+/// to its name) and loaded instead of compiling the pattern again. Cache files are keyed by a
+/// hash of the source, the compile options, and the version of this crate; any mismatch, or a
+/// cache file which cannot be read, compiles the pattern again and replaces the file.
+///
+/// Caching is an optimization only: patterns load the same with or without cache files, and
+/// failures to write cache files are counted rather than reported.
+#[derive(Debug)]
+pub struct PatternCacheDir {
+    root: PathBuf,
+    options: CompileOptions,
+    stats: Cell<CacheStats>,
+}
+
+impl PatternCacheDir {
+    /// A cache for the patterns in a directory.
+    pub fn new<P>(root: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        Self::with_options(root, CompileOptions::default())
+    }
+
+    /// A cache for the patterns in a directory compiled with the given options.
+    pub fn with_options<P>(root: P, options: CompileOptions) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        PatternCacheDir {
+            root: root.into(),
+            options,
+            stats: Cell::new(CacheStats::default()),
+        }
+    }
+
+    /// The directory of the patterns.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Statistics about the use of the cache.
+    pub fn stats(&self) -> CacheStats {
+        self.stats.get()
+    }
+
+    /// The path to the cache file for a pattern.
+    ///
+    /// Relative paths are relative to the root of the cache.
+    pub fn cache_path<P>(&self, path: P) -> PathBuf
+    where
+        P: AsRef<Path>,
+    {
+        cache_file(&self.root.join(path))
+    }
+
+    /// Load the compiled form of a pattern, compiling it if it is not cached.
+    ///
+    /// Relative paths are relative to the root of the cache.
+    pub fn get_or_compile<P>(&self, path: P) -> Result<CompiledBulletML, PatternError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = self.root.join(path);
+        let source = fs::read(&path).map_err(|source| {
+            PatternError::Open {
+                path: path.clone(),
+                source,
+            }
+        })?;
+        let source_hash = run::source_hash(&source);
+        let cache_path = cache_file(&path);
+
+        if let Some(compiled) = self.load(&cache_path, source_hash) {
+            self.update_stats(|stats| stats.hits += 1);
+            return Ok(compiled);
+        }
+
+        self.update_stats(|stats| stats.misses += 1);
+        let bulletml = data::BulletML::from_xml_bytes(&source)?;
+        let compiled = CompiledBulletML::with_options(bulletml, self.options.clone())?;
+        if self.store(&cache_path, source_hash, &compiled).is_err() {
+            self.update_stats(|stats| stats.write_failures += 1);
+        }
+
+        Ok(compiled)
+    }
+
+    fn update_stats<F>(&self, update: F)
+    where
+        F: FnOnce(&mut CacheStats),
+    {
+        let mut stats = self.stats.get();
+        update(&mut stats);
+        self.stats.set(stats);
+    }
+
+    /// Load a cached pattern if it is current.
+    fn load(&self, cache_path: &Path, source_hash: u64) -> Option<CompiledBulletML> {
+        let bytes = fs::read(cache_path).ok()?;
+        let mut rest = bytes.as_slice();
+
+        if take(&mut rest, MAGIC.len())? != MAGIC {
+            return None;
+        }
+        let version_len = u32::from_le_bytes(<[u8; 4]>::try_from(take(&mut rest, 4)?).ok()?);
+        if take(&mut rest, version_len as usize)? != VERSION.as_bytes() {
+            return None;
+        }
+        if u64::from_le_bytes(<[u8; 8]>::try_from(take(&mut rest, 8)?).ok()?) != source_hash {
+            return None;
+        }
+        let content_hash = u64::from_le_bytes(<[u8; 8]>::try_from(take(&mut rest, 8)?).ok()?);
+
+        let compiled = binary::decode(rest).ok()?;
+        // Guard against files which decode but do not hold what they claim to.
+        let rank_matches =
+            compiled.fixed_rank().map(f32::to_bits) == self.options.rank.map(f32::to_bits);
+        if compiled.content_hash() != content_hash || !rank_matches {
+            return None;
+        }
+
+        Some(compiled)
+    }
+
+    /// Store a compiled pattern.
+    ///
+    /// The file is written under a temporary name and then renamed so that other processes never
+    /// see partially written files.
+    fn store(
+        &self,
+        cache_path: &Path,
+        source_hash: u64,
+        compiled: &CompiledBulletML,
+    ) -> io::Result<()> {
+        let ir = binary::encode(compiled)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&(VERSION.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(VERSION.as_bytes());
+        bytes.extend_from_slice(&source_hash.to_le_bytes());
+        bytes.extend_from_slice(&compiled.content_hash().to_le_bytes());
+        bytes.extend_from_slice(&ir);
+
+        let mut tmp_path = OsString::from(cache_path);
+        tmp_path.push(format!(".{}.tmp", process::id()));
+        let tmp_path = PathBuf::from(tmp_path);
+        fs::write(&tmp_path, &bytes)
+            .and_then(|()| fs::rename(&tmp_path, cache_path))
+            .map_err(|err| {
+                let _ = fs::remove_file(&tmp_path);
+                err
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    use crate::run::CompileOptions;
+    use crate::{CacheStats, PatternCacheDir, PatternError};
+
+    const DOC: &str = r#"<bulletml>
+        <bullet label="shot">
+            <speed>1 + $rank</speed>
+        </bullet>
+        <action label="top">
+            <repeat>
+                <times>3</times>
+                <action>
+                    <fire>
+                        <direction type="sequence">$rand * 10</direction>
+                        <bulletRef label="shot"/>
+                    </fire>
+                    <wait>2</wait>
+                </action>
+            </repeat>
+        </action>
+    </bulletml>"#;
+
+    fn cache_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("bulletml-cache-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("pattern.xml"), DOC).unwrap();
+        dir
+    }
+
+    fn stats(hits: u64, misses: u64, write_failures: u64) -> CacheStats {
+        CacheStats {
+            hits,
+            misses,
+            write_failures,
+        }
+    }
+
+    #[test]
+    fn test_cache_hit() {
+        let dir = cache_dir("hit");
+        let cache = PatternCacheDir::new(&dir);
+
+        let compiled = cache.get_or_compile("pattern.xml").unwrap();
+        assert_eq!(cache.stats(), stats(0, 1, 0));
+        assert!(dir.join("pattern.xml.bmlc").is_file());
+        assert_eq!(
+            cache.cache_path("pattern.xml"),
+            dir.join("pattern.xml.bmlc")
+        );
+
+        let cached = cache.get_or_compile("pattern.xml").unwrap();
+        assert_eq!(cache.stats(), stats(1, 1, 0));
+        assert_eq!(cached.content_hash(), compiled.content_hash());
+        assert_eq!(cached.variables(), compiled.variables());
+
+        // Other caches of the same directory use the file as well.
+        let other = PatternCacheDir::new(&dir);
+        other.get_or_compile(dir.join("pattern.xml")).unwrap();
+        assert_eq!(other.stats(), stats(1, 0, 0));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache_source_changed() {
+        let dir = cache_dir("source");
+        let cache = PatternCacheDir::new(&dir);
+        let compiled = cache.get_or_compile("pattern.xml").unwrap();
+
+        fs::write(dir.join("pattern.xml"), DOC.replace("<times>3", "<times>4")).unwrap();
+        let changed = cache.get_or_compile("pattern.xml").unwrap();
+        assert_eq!(cache.stats(), stats(0, 2, 0));
+        assert_ne!(changed.content_hash(), compiled.content_hash());
+
+        // Formatting changes are changes to the source as well.
+        fs::write(
+            dir.join("pattern.xml"),
+            DOC.replace("<times>3", "<times> 4"),
+        )
+        .unwrap();
+        let reformatted = cache.get_or_compile("pattern.xml").unwrap();
+        assert_eq!(cache.stats(), stats(0, 3, 0));
+        assert_eq!(reformatted.content_hash(), changed.content_hash());
+
+        cache.get_or_compile("pattern.xml").unwrap();
+        assert_eq!(cache.stats(), stats(1, 3, 0));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache_invalid_files() {
+        let dir = cache_dir("invalid");
+        let cache = PatternCacheDir::new(&dir);
+        let cache_path = cache.cache_path("pattern.xml");
+        cache.get_or_compile("pattern.xml").unwrap();
+        let bytes = fs::read(&cache_path).unwrap();
+
+        // Files written by another version of the crate.
+        let mut other_version = bytes.clone();
+        other_version[8] ^= 0xff;
+        fs::write(&cache_path, other_version).unwrap();
+        cache.get_or_compile("pattern.xml").unwrap();
+        assert_eq!(cache.stats(), stats(0, 2, 0));
+        assert_eq!(fs::read(&cache_path).unwrap(), bytes);
+
+        // Truncated files.
+        fs::write(&cache_path, &bytes[..bytes.len() - 1]).unwrap();
+        cache.get_or_compile("pattern.xml").unwrap();
+        assert_eq!(cache.stats(), stats(0, 3, 0));
+
+        // Other files.
+        fs::write(&cache_path, DOC).unwrap();
+        cache.get_or_compile("pattern.xml").unwrap();
+        assert_eq!(cache.stats(), stats(0, 4, 0));
+
+        cache.get_or_compile("pattern.xml").unwrap();
+        assert_eq!(cache.stats(), stats(1, 4, 0));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache_options() {
+        let dir = cache_dir("options");
+        let cache = PatternCacheDir::new(&dir);
+        cache.get_or_compile("pattern.xml").unwrap();
+
+        let ranked = PatternCacheDir::with_options(
+            &dir,
+            CompileOptions {
+                rank: Some(0.5),
+            },
+        );
+        let compiled = ranked.get_or_compile("pattern.xml").unwrap();
+        assert_eq!(ranked.stats(), stats(0, 1, 0));
+        assert_eq!(compiled.fixed_rank(), Some(0.5));
+
+        let cached = ranked.get_or_compile("pattern.xml").unwrap();
+        assert_eq!(ranked.stats(), stats(1, 1, 0));
+        assert_eq!(cached.fixed_rank(), Some(0.5));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache_errors() {
+        let dir = cache_dir("errors");
+        let cache = PatternCacheDir::new(&dir);
+
+        let err = cache.get_or_compile("missing.xml").unwrap_err();
+        if let PatternError::Open {
+            path: ref missing, ..
+        } = err
+        {
+            assert!(missing.ends_with("missing.xml"));
+        } else {
+            panic!("unexpected error: {:?}", err);
+        }
+
+        fs::write(dir.join("broken.xml"), "<bulletml>").unwrap();
+        let err = cache.get_or_compile("broken.xml").unwrap_err();
+        assert!(matches!(err, PatternError::Parse { .. }));
+        assert!(!cache.cache_path("broken.xml").exists());
+
+        // Failing to store a compiled pattern is not an error.
+        fs::create_dir(cache.cache_path("pattern.xml")).unwrap();
+        cache.get_or_compile("pattern.xml").unwrap();
+        assert_eq!(cache.stats(), stats(0, 2, 1));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
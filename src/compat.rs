@@ -0,0 +1,456 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+//! Compatibility with the API of the `bulletml` crate
+//!
+//! Projects written against the `bulletml` crate may migrate by importing `AppRunner`,
+//! `BulletMLParser`, `Runner`, and `RunnerData` from this module instead. Games implement
+//! `AppRunner` for their bullets as before and the runner drives it through this crate.
+//!
+//! There are some differences:
+//!
+//!   - `Runner` is generic over the data type as well as the application runner.
+//!   - `Runner::run` returns errors from evaluating expressions rather than panicking.
+//!   - The data type must implement `Default`. The data is moved into the runner for the
+//!     duration of `Runner::run` and a default value is left in its place until it returns.
+//!   - The `bml` member of `RunnerData` is not used; the script is captured when the runner is
+//!     created.
+//!
+//! New code should implement `BulletManager` and use `run::Runner` directly.
+
+use std::cell::RefCell;
+use std::fs;
+use std::mem;
+use std::path::Path;
+
+use crate::data::{self, ExpressionError, Value};
+use crate::run::{self, BulletManager, BulletScript, RunnerOptions};
+use crate::PatternError;
+
+/// A compiled BulletML script.
+pub type BulletML = run::CompiledBulletML;
+
+/// The actions of a fired bullet.
+///
+/// Pass this to `Runner::new_from_state` to run the actions on the new bullet.
+pub type State = BulletScript;
+
+/// The implementation of a bullet for a game.
+///
+/// This corresponds to `BulletManager`, but receives the data of the bullet from
+/// `Runner::run` in each call.
+pub trait AppRunner<D> {
+    /// The current direction of the bullet.
+    fn get_bullet_direction(&self, data: &D) -> f64;
+    /// The direction the bullet should aim for.
+    fn get_aim_direction(&self, data: &D) -> f64;
+    /// The current speed of the bullet.
+    fn get_bullet_speed(&self, data: &D) -> f64;
+    /// The default speed of the bullet.
+    fn get_default_speed(&self) -> f64;
+    /// The difficulty of the bullet.
+    fn get_rank(&self, data: &D) -> f64;
+    /// Create a new bullet without actions.
+    fn create_simple_bullet(&mut self, data: &mut D, direction: f64, speed: f64);
+    /// Create a new bullet which runs actions.
+    fn create_bullet(&mut self, data: &mut D, state: State, direction: f64, speed: f64);
+    /// The turn of the simulation.
+    fn get_turn(&self, data: &D) -> u32;
+    /// Destroy the bullet.
+    fn do_vanish(&mut self, data: &mut D);
+    /// Change the direction of the bullet.
+    fn do_change_direction(&mut self, _data: &mut D, _direction: f64) {}
+    /// Change the speed of the bullet.
+    fn do_change_speed(&mut self, _data: &mut D, _speed: f64) {}
+    /// Accelerate the bullet along the `x` axis.
+    fn do_accel_x(&mut self, _amount: f64) {}
+    /// Accelerate the bullet along the `y` axis.
+    fn do_accel_y(&mut self, _amount: f64) {}
+    /// The current `x`-axis speed of the bullet.
+    fn get_bullet_speed_x(&self) -> f64 {
+        0.
+    }
+    /// The current `y`-axis speed of the bullet.
+    fn get_bullet_speed_y(&self) -> f64 {
+        0.
+    }
+    /// Get a random value in the range `[0, 1)`.
+    fn get_rand(&self, data: &mut D) -> f64;
+}
+
+/// The data given to a runner for an update.
+pub struct RunnerData<'a, D> {
+    /// The script being run.
+    ///
+    /// This is unused; runners capture their script when they are created.
+    pub bml: &'a BulletML,
+    /// The data of the bullet.
+    pub data: &'a mut D,
+}
+
+/// A parser for BulletML documents.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BulletMLParser;
+
+impl BulletMLParser {
+    /// Create a new parser.
+    pub fn new() -> Self {
+        BulletMLParser
+    }
+
+    /// Parse and compile an XML document.
+    pub fn parse_str(&self, xml: &str) -> Result<BulletML, PatternError> {
+        Ok(BulletML::new(data::BulletML::from_xml(xml)?)?)
+    }
+
+    /// Parse and compile an XML file.
+    pub fn parse_file<P>(&self, path: P) -> Result<BulletML, PatternError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let xml = fs::read_to_string(path).map_err(|source| {
+            PatternError::Open {
+                path: path.into(),
+                source,
+            }
+        })?;
+        self.parse_str(&xml)
+    }
+}
+
+const OUTSIDE_RUN: &str = "the application runner is only available during `Runner::run`";
+
+/// A `BulletManager` which forwards to an `AppRunner`.
+///
+/// The application runner and the data are lent to the manager while its runner updates.
+struct Bridge<R, D> {
+    app: Option<R>,
+    data: RefCell<Option<D>>,
+}
+
+impl<R, D> Bridge<R, D> {
+    fn new() -> Self {
+        Bridge {
+            app: None,
+            data: RefCell::new(None),
+        }
+    }
+
+    fn lend(&mut self, app: R, data: D) {
+        self.app = Some(app);
+        *self.data.get_mut() = Some(data);
+    }
+
+    fn reclaim(&mut self) -> (R, D) {
+        let app = self.app.take().expect(OUTSIDE_RUN);
+        let data = self.data.get_mut().take().expect(OUTSIDE_RUN);
+        (app, data)
+    }
+
+    fn with<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&R, &D) -> T,
+    {
+        let data = self.data.borrow();
+        f(
+            self.app.as_ref().expect(OUTSIDE_RUN),
+            data.as_ref().expect(OUTSIDE_RUN),
+        )
+    }
+
+    fn with_mut<F, T>(&mut self, f: F) -> T
+    where
+        F: FnOnce(&mut R, &mut D) -> T,
+    {
+        f(
+            self.app.as_mut().expect(OUTSIDE_RUN),
+            self.data.get_mut().as_mut().expect(OUTSIDE_RUN),
+        )
+    }
+}
+
+impl<R, D> data::ExpressionContext for Bridge<R, D>
+where
+    R: AppRunner<D>,
+{
+    fn get(&self, _: &str) -> Option<Value> {
+        None
+    }
+
+    fn get_param(&self, _: usize) -> Option<Value> {
+        None
+    }
+
+    fn rand(&self) -> Value {
+        let mut data = self.data.borrow_mut();
+        let app = self.app.as_ref().expect(OUTSIDE_RUN);
+        app.get_rand(data.as_mut().expect(OUTSIDE_RUN)) as Value
+    }
+
+    fn rank(&self) -> Value {
+        self.with(|app, data| app.get_rank(data)) as Value
+    }
+}
+
+impl<R, D> BulletManager for Bridge<R, D>
+where
+    R: AppRunner<D>,
+{
+    fn new_simple(&mut self, direction: f32, speed: f32) {
+        self.with_mut(|app, data| app.create_simple_bullet(data, direction.into(), speed.into()))
+    }
+
+    fn new_bullet(&mut self, direction: f32, speed: f32) {
+        self.new_simple(direction, speed)
+    }
+
+    fn new_bullet_with_script(&mut self, direction: f32, speed: f32, script: BulletScript) {
+        self.with_mut(|app, data| app.create_bullet(data, script, direction.into(), speed.into()))
+    }
+
+    fn turn(&self) -> u32 {
+        self.with(|app, data| app.get_turn(data))
+    }
+
+    fn direction(&self) -> f32 {
+        self.with(|app, data| app.get_bullet_direction(data)) as f32
+    }
+
+    fn aim_direction(&self) -> f32 {
+        self.with(|app, data| app.get_aim_direction(data)) as f32
+    }
+
+    fn speed(&self) -> f32 {
+        self.with(|app, data| app.get_bullet_speed(data)) as f32
+    }
+
+    fn speed_x(&self) -> f32 {
+        self.with(|app, _| app.get_bullet_speed_x()) as f32
+    }
+
+    fn speed_y(&self) -> f32 {
+        self.with(|app, _| app.get_bullet_speed_y()) as f32
+    }
+
+    fn default_speed(&self) -> f32 {
+        self.with(|app, _| app.get_default_speed()) as f32
+    }
+
+    fn vanish(&mut self) {
+        self.with_mut(|app, data| app.do_vanish(data))
+    }
+
+    fn change_direction(&mut self, degrees: f32) {
+        self.with_mut(|app, data| app.do_change_direction(data, degrees.into()))
+    }
+
+    fn change_speed(&mut self, speed: f32) {
+        self.with_mut(|app, data| app.do_change_speed(data, speed.into()))
+    }
+
+    fn accel_x(&mut self, amount: f32) {
+        self.with_mut(|app, _| app.do_accel_x(amount.into()))
+    }
+
+    fn accel_y(&mut self, amount: f32) {
+        self.with_mut(|app, _| app.do_accel_y(amount.into()))
+    }
+}
+
+/// A runner for a BulletML script driving an `AppRunner`.
+///
+/// Each top-level action of the script runs in parallel.
+pub struct Runner<R, D> {
+    /// The application runner; it is lent to the manager of each runner as it updates.
+    app: Option<R>,
+    runners: Vec<run::Runner<Bridge<R, D>>>,
+}
+
+impl<R, D> Runner<R, D> {
+    /// Create a new runner for the top-level actions of a script.
+    pub fn new(app: R, bml: &BulletML) -> Self {
+        Runner {
+            app: Some(app),
+            runners: run::Runner::for_top_actions(bml, RunnerOptions::default(), |_| Bridge::new()),
+        }
+    }
+
+    /// Create a new runner for the actions of a fired bullet.
+    pub fn new_from_state(app: R, state: State) -> Self {
+        Runner {
+            app: Some(app),
+            runners: vec![state.runner(Bridge::new())],
+        }
+    }
+
+    /// The application runner.
+    pub fn app(&self) -> &R {
+        self.app.as_ref().expect(OUTSIDE_RUN)
+    }
+
+    /// The application runner.
+    pub fn app_mut(&mut self) -> &mut R {
+        self.app.as_mut().expect(OUTSIDE_RUN)
+    }
+}
+
+impl<R, D> Runner<R, D>
+where
+    R: AppRunner<D>,
+    D: Default,
+{
+    /// Update the state for a frame.
+    ///
+    /// Updating stops at the first action which fails to evaluate an expression.
+    pub fn run(&mut self, data: &mut RunnerData<D>) -> Result<(), ExpressionError> {
+        let mut app = self.app.take().expect(OUTSIDE_RUN);
+        let mut value = mem::take(data.data);
+        let mut result = Ok(());
+
+        for runner in &mut self.runners {
+            runner.manager_mut().lend(app, value);
+            let update = runner.update();
+            let (next_app, next_value) = runner.manager_mut().reclaim();
+            app = next_app;
+            value = next_value;
+
+            if let Err(err) = update {
+                result = Err(err);
+                break;
+            }
+        }
+
+        *data.data = value;
+        self.app = Some(app);
+        result
+    }
+
+    /// Whether all of the actions have completed.
+    pub fn is_end(&self) -> bool {
+        self.runners.iter().all(run::Runner::is_done)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::compat::{AppRunner, BulletMLParser, Runner, RunnerData, State};
+
+    #[derive(Default)]
+    struct Data {
+        turn: u32,
+        log: Vec<String>,
+    }
+
+    #[derive(Default)]
+    struct App {
+        states: Vec<State>,
+    }
+
+    impl AppRunner<Data> for App {
+        fn get_bullet_direction(&self, _: &Data) -> f64 {
+            0.
+        }
+
+        fn get_aim_direction(&self, _: &Data) -> f64 {
+            180.
+        }
+
+        fn get_bullet_speed(&self, _: &Data) -> f64 {
+            1.
+        }
+
+        fn get_default_speed(&self) -> f64 {
+            1.
+        }
+
+        fn get_rank(&self, _: &Data) -> f64 {
+            0.5
+        }
+
+        fn create_simple_bullet(&mut self, data: &mut Data, direction: f64, speed: f64) {
+            data.log.push(format!("simple({}, {})", direction, speed));
+        }
+
+        fn create_bullet(&mut self, data: &mut Data, state: State, direction: f64, speed: f64) {
+            data.log.push(format!("bullet({}, {})", direction, speed));
+            self.states.push(state);
+        }
+
+        fn get_turn(&self, data: &Data) -> u32 {
+            data.turn
+        }
+
+        fn do_vanish(&mut self, data: &mut Data) {
+            data.log.push("vanish".into());
+        }
+
+        fn get_rand(&self, _: &mut Data) -> f64 {
+            0.25
+        }
+    }
+
+    const DOC: &str = r#"<bulletml>
+        <action label="top1">
+            <fire>
+                <direction type="absolute">$rank * 180</direction>
+                <bullet/>
+            </fire>
+        </action>
+        <action label="top2">
+            <fire>
+                <direction type="aim">$rand * 4</direction>
+                <bulletRef label="child"/>
+            </fire>
+        </action>
+        <bullet label="child">
+            <action>
+                <vanish/>
+            </action>
+        </bullet>
+    </bulletml>"#;
+
+    #[test]
+    fn test_compat_runner() {
+        let bml = BulletMLParser::new().parse_str(DOC).unwrap();
+        let mut data = Data::default();
+        let mut runner = Runner::new(App::default(), &bml);
+
+        runner
+            .run(&mut RunnerData {
+                bml: &bml,
+                data: &mut data,
+            })
+            .unwrap();
+        assert_eq!(data.log, ["simple(90, 1)", "bullet(181, 1)"]);
+
+        data.turn += 1;
+        runner
+            .run(&mut RunnerData {
+                bml: &bml,
+                data: &mut data,
+            })
+            .unwrap();
+        assert_eq!(data.log.len(), 2);
+        assert!(runner.is_end());
+
+        let state = runner.app_mut().states.pop().unwrap();
+        let mut child_data = Data::default();
+        let mut child = Runner::new_from_state(App::default(), state);
+        child
+            .run(&mut RunnerData {
+                bml: &bml,
+                data: &mut child_data,
+            })
+            .unwrap();
+        assert_eq!(child_data.log, ["vanish"]);
+    }
+
+    #[test]
+    fn test_compat_parse_file() {
+        let err = BulletMLParser::new()
+            .parse_file("does/not/exist.xml")
+            .unwrap_err();
+        assert!(err.to_string().contains("does/not/exist.xml"));
+    }
+}
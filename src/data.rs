@@ -5,8 +5,35 @@
 //!
 //! These are the data structures used to represent a BulletML file.
 
+mod code;
+mod custom;
 mod data;
 mod expression;
+mod lint;
+mod numeric;
+mod options;
+mod prune;
+mod validate;
+mod xml;
 
+pub use self::code::ErrorCode;
+pub use self::custom::{CustomStep, CustomStepFactory, CustomSteps};
 pub use self::data::*;
-pub use self::expression::{Expression, ExpressionContext, ExpressionError, Value};
+pub use self::expression::{
+    Expression, ExpressionContext, ExpressionError, ExpressionLimits, NumberFormat, Value,
+};
+pub use self::lint::{SequenceElement, SequenceWarning};
+#[cfg(feature = "runtime")]
+pub(crate) use self::expression::Variables;
+pub use self::numeric::Numeric;
+pub use self::options::{Dialect, ParseOptions};
+pub use self::validate::{validate, Diagnostic, Severity};
+#[cfg(feature = "runtime")]
+pub(crate) use self::validate::child_path;
+#[cfg(feature = "runtime")]
+pub(crate) use self::xml::{change_name, direction_kind_name, easing_name, orientation_name};
+pub use crate::parse::normalize_xml;
+#[cfg(feature = "xml")]
+pub use crate::parse::ParseError;
+#[cfg(feature = "runtime")]
+pub(crate) use self::prune::is_top_label;
@@ -0,0 +1,82 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use std::fmt;
+
+/// Stable codes for errors and warnings.
+///
+/// Codes are included in the messages of errors and never change meaning so that tools may map
+/// them to documentation. Codes in the `1xxx` range are for document structure, `2xxx` for
+/// expression syntax, `3xxx` for evaluation, and `4xxx` for warnings about constructs which
+/// behave differently between engines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    /// An entity label is used more than once (`BML1001`).
+    DuplicateLabel,
+    /// A reference names an entity which does not exist (`BML1002`).
+    UnknownReference,
+    /// A reference gives a different number of parameters than the entity uses (`BML1003`).
+    ParameterCount,
+    /// An element appears within a parent which does not allow it (`BML1101`).
+    MisplacedElement,
+    /// An element is not part of BulletML (`BML1102`).
+    UnexpectedElement,
+    /// An expression could not be parsed (`BML2001`).
+    ExpressionSyntax,
+    /// An expression uses a character which is not portable (`BML2002`).
+    NonPortableCharacter,
+    /// An expression can never evaluate to a usable value (`BML2003`).
+    InvalidExpression,
+    /// An expression exceeds the limits on its length or nesting (`BML2004`).
+    ExpressionTooComplex,
+    /// An expression references a variable which is not defined (`BML3001`).
+    UndefinedVariable,
+    /// An expression references a parameter which was not given (`BML3002`).
+    MissingParameter,
+    /// A `sequence` direction or speed may be used before any bullet is fired (`BML4001`).
+    SequenceWithoutPrevious,
+}
+
+impl ErrorCode {
+    /// The code as a string.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::DuplicateLabel => "BML1001",
+            ErrorCode::UnknownReference => "BML1002",
+            ErrorCode::ParameterCount => "BML1003",
+            ErrorCode::MisplacedElement => "BML1101",
+            ErrorCode::UnexpectedElement => "BML1102",
+            ErrorCode::ExpressionSyntax => "BML2001",
+            ErrorCode::NonPortableCharacter => "BML2002",
+            ErrorCode::InvalidExpression => "BML2003",
+            ErrorCode::ExpressionTooComplex => "BML2004",
+            ErrorCode::UndefinedVariable => "BML3001",
+            ErrorCode::MissingParameter => "BML3002",
+            ErrorCode::SequenceWithoutPrevious => "BML4001",
+        }
+    }
+
+    /// A short description of the error.
+    pub fn summary(self) -> &'static str {
+        match self {
+            ErrorCode::DuplicateLabel => "duplicate label",
+            ErrorCode::UnknownReference => "unknown reference",
+            ErrorCode::ParameterCount => "parameter count",
+            ErrorCode::MisplacedElement => "misplaced element",
+            ErrorCode::UnexpectedElement => "unexpected element",
+            ErrorCode::ExpressionSyntax => "expression syntax",
+            ErrorCode::NonPortableCharacter => "non-portable character",
+            ErrorCode::InvalidExpression => "invalid expression",
+            ErrorCode::ExpressionTooComplex => "expression too complex",
+            ErrorCode::UndefinedVariable => "undefined variable",
+            ErrorCode::MissingParameter => "missing parameter",
+            ErrorCode::SequenceWithoutPrevious => "sequence without a previous bullet",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
@@ -0,0 +1,64 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use std::collections::hash_map::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// A user-defined step which may appear within an action.
+///
+/// Custom steps allow engine-specific commands (e.g., playing a sound or spawning an effect) to
+/// be embedded inline within BulletML actions. Traversal and timing is handled by the runner
+/// while the actual command is performed by an executor registered with it.
+///
+/// Steps are shared by compiled scripts which may be used from multiple threads.
+pub trait CustomStep: fmt::Debug + Send + Sync {
+    /// The name of the element for the step.
+    fn name(&self) -> &str;
+    /// The text content of the element for the step.
+    ///
+    /// This is used when writing documents and should be accepted by the factory which created
+    /// the step.
+    fn content(&self) -> String {
+        String::new()
+    }
+}
+
+/// A function to create a custom step from the text content of its element.
+pub type CustomStepFactory = fn(&str) -> Result<Arc<dyn CustomStep>, String>;
+
+/// A registry of custom step elements recognized while parsing.
+#[derive(Clone, Default)]
+pub struct CustomSteps {
+    factories: HashMap<String, CustomStepFactory>,
+}
+
+impl CustomSteps {
+    /// Create a new, empty, registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a custom step element.
+    ///
+    /// Any previous registration for the same name is replaced.
+    pub fn register<N>(&mut self, name: N, factory: CustomStepFactory) -> &mut Self
+    where
+        N: Into<String>,
+    {
+        self.factories.insert(name.into(), factory);
+        self
+    }
+
+    pub(crate) fn factory(&self, name: &str) -> Option<CustomStepFactory> {
+        self.factories.get(name).copied()
+    }
+}
+
+impl fmt::Debug for CustomSteps {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CustomSteps")
+            .field("names", &self.factories.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
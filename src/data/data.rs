@@ -4,22 +4,32 @@
 use std::borrow::Cow;
 use std::fmt;
 use std::marker::PhantomData;
-use std::ops::{Add, Mul};
 use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::Arc;
 
-use serde::de::{Deserializer, EnumAccess, Error, MapAccess, VariantAccess, Visitor};
+use serde::de::value::MapAccessDeserializer;
+use serde::de::{
+    Deserializer, EnumAccess, Error, IgnoredAny, MapAccess, VariantAccess, Visitor,
+};
 use serde::Deserialize;
 use serde_with::enum_map::EnumMap;
-use serde_with::serde_as;
+use serde_with::{serde_as, DeserializeAs};
 use thiserror::Error;
 
-use crate::data::expression::{Expression, ExpressionContext, ExpressionError, Value};
+use crate::data::code::ErrorCode;
+use crate::data::custom::CustomStep;
+use crate::data::options::ParseOptions;
+use crate::data::expression::{Expression, Value};
+use crate::data::numeric::Numeric;
+#[cfg(feature = "runtime")]
+use crate::data::expression::{ExpressionContext, ExpressionError};
 
 /// An error related to entity searches.
 #[derive(Debug, Error)]
 pub enum EntityError {
     /// An entity with the given name could not be found.
-    #[error("could not find entity `{}`", label)]
+    #[error("{}: could not find entity `{}`", ErrorCode::UnknownReference, label)]
     CannotFind {
         /// The label for the requested entity.
         label: String,
@@ -32,6 +42,57 @@ impl EntityError {
             label,
         }
     }
+
+    /// The code for the error.
+    pub fn code(&self) -> ErrorCode {
+        match *self {
+            EntityError::CannotFind {
+                ..
+            } => ErrorCode::UnknownReference,
+        }
+    }
+}
+
+/// Where an element is allowed to appear.
+fn allowed_parents(element: &str) -> Option<&'static str> {
+    Some(match element {
+        "speed" => "`<fire>`, `<bullet>`, or `<changeSpeed>`",
+        "direction" => "`<fire>`, `<bullet>`, or `<changeDirection>`",
+        "horizontal" | "vertical" => "`<accel>`",
+        "term" => "`<changeSpeed>`, `<changeDirection>`, or `<accel>`",
+        "times" => "`<repeat>`",
+        "param" => "`<actionRef>`, `<bulletRef>`, or `<fireRef>`",
+        "bullet" | "bulletRef" => "`<bulletml>` or `<fire>`",
+        "action" | "actionRef" => "`<bulletml>`, `<bullet>`, `<action>`, or `<repeat>`",
+        "fire" => "`<bulletml>` or `<action>`",
+        "fireRef" | "changeSpeed" | "changeDirection" | "accel" | "wait" | "vanish" | "repeat" => {
+            "`<action>`"
+        },
+        _ => return None,
+    })
+}
+
+/// An error for an element which is not allowed within its parent.
+fn misplaced<E>(element: &str, parent: &str) -> E
+where
+    E: Error,
+{
+    if let Some(parents) = allowed_parents(element) {
+        E::custom(format_args!(
+            "{}: `<{}>` is not allowed within {}; it belongs within {}",
+            ErrorCode::MisplacedElement,
+            element,
+            parent,
+            parents,
+        ))
+    } else {
+        E::custom(format_args!(
+            "{}: unexpected `<{}>` within {}",
+            ErrorCode::UnexpectedElement,
+            element,
+            parent,
+        ))
+    }
 }
 
 /// Cause acceleration of a bullet for a given about of time.
@@ -44,6 +105,29 @@ pub struct Accel {
     /// The number of frames to accelerate.
     #[serde(rename = "term")]
     pub duration: Term,
+    /// How the speed changes over time (an extension).
+    ///
+    /// Without an easing, the runner's default is used.
+    #[serde(default)]
+    pub easing: Option<Easing>,
+}
+
+impl Accel {
+    /// Create a new acceleration.
+    pub fn new(horizontal: Option<Horizontal>, vertical: Option<Vertical>, duration: Term) -> Self {
+        Accel {
+            horizontal,
+            vertical,
+            duration,
+            easing: None,
+        }
+    }
+
+    /// Change the speed with an easing.
+    pub fn with_easing(mut self, easing: Easing) -> Self {
+        self.easing = Some(easing);
+        self
+    }
 }
 
 /// Entities which may appear within an action.
@@ -65,9 +149,21 @@ pub enum Step {
     Vanish(Vanish),
     /// Chain into another action.
     Action(EntityRef<Action>),
+    /// A user-defined step.
+    Custom(Arc<dyn CustomStep>),
+}
+
+/// The content of a custom step element.
+#[derive(Deserialize)]
+struct CustomContent {
+    #[serde(default, rename = "$value")]
+    content: String,
 }
 
-struct StepVisitor;
+struct StepVisitor {
+    /// Skip misplaced elements unless parsing is strict.
+    skip_misplaced: bool,
+}
 
 impl StepVisitor {
     const FIELDS: &'static [&'static str] = &[
@@ -85,7 +181,7 @@ impl StepVisitor {
 }
 
 impl<'de> Visitor<'de> for StepVisitor {
-    type Value = Step;
+    type Value = Option<Step>;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
         write!(formatter, "one of `{}`", Self::FIELDS.join("`, `"))
@@ -96,31 +192,60 @@ impl<'de> Visitor<'de> for StepVisitor {
         E: EnumAccess<'de>,
     {
         let (name, v): (Cow<str>, _) = access.variant()?;
-        match name.as_ref() {
-            "repeat" => Ok(Step::Repeat(v.newtype_variant()?)),
+        let step = match name.as_ref() {
+            "repeat" => Step::Repeat(v.newtype_variant()?),
             "fire" => {
                 let fire = v.newtype_variant()?;
-                Ok(Step::Fire(EntityRef::Real(Rc::new(fire))))
+                Step::Fire(EntityRef::Real(Rc::new(fire)))
             },
             "fireRef" => {
                 let iref = v.newtype_variant::<Reference>()?;
-                Ok(Step::Fire(EntityRef::Ref(iref)))
+                Step::Fire(EntityRef::Ref(iref))
             },
-            "changeSpeed" => Ok(Step::ChangeSpeed(v.newtype_variant()?)),
-            "changeDirection" => Ok(Step::ChangeDirection(v.newtype_variant()?)),
-            "accel" => Ok(Step::Accel(v.newtype_variant()?)),
-            "wait" => Ok(Step::Wait(v.newtype_variant()?)),
-            "vanish" => Ok(Step::Vanish(v.newtype_variant()?)),
+            "changeSpeed" => Step::ChangeSpeed(v.newtype_variant()?),
+            "changeDirection" => Step::ChangeDirection(v.newtype_variant()?),
+            "accel" => Step::Accel(v.newtype_variant()?),
+            "wait" => Step::Wait(v.newtype_variant()?),
+            "vanish" => Step::Vanish(v.newtype_variant()?),
             "action" => {
                 let action = v.newtype_variant()?;
-                Ok(Step::Action(EntityRef::Real(Rc::new(action))))
+                Step::Action(EntityRef::Real(Rc::new(action)))
             },
             "actionRef" => {
                 let iref = v.newtype_variant::<Reference>()?;
-                Ok(Step::Action(EntityRef::Ref(iref)))
+                Step::Action(EntityRef::Ref(iref))
             },
-            name => Err(E::Error::unknown_variant(name, Self::FIELDS)),
-        }
+            name => {
+                let factory = ParseOptions::with_active(|options| {
+                    options.custom_steps.factory(name)
+                });
+                if let Some(factory) = factory {
+                    let custom = v.newtype_variant::<CustomContent>()?;
+                    factory(&custom.content)
+                        .map(Step::Custom)
+                        .map_err(E::Error::custom)?
+                } else if allowed_parents(name).is_some() {
+                    let strict = ParseOptions::with_active(|options| options.strict);
+                    if self.skip_misplaced && !strict {
+                        v.newtype_variant::<IgnoredAny>()?;
+                        return Ok(None);
+                    }
+                    return Err(misplaced(name, "`<action>`"));
+                } else {
+                    return Err(E::Error::unknown_variant(name, Self::FIELDS));
+                }
+            },
+        };
+
+        Ok(Some(step))
+    }
+
+    fn visit_map<M>(self, access: M) -> Result<Self::Value, M::Error>
+    where
+        M: MapAccess<'de>,
+    {
+        // Formats without enums give a step as a map with a single entry.
+        self.visit_enum(MapAccessDeserializer::new(access))
     }
 }
 
@@ -129,28 +254,122 @@ impl<'de> Deserialize<'de> for Step {
     where
         D: Deserializer<'de>,
     {
-        deserializer.deserialize_enum("Step", StepVisitor::FIELDS, StepVisitor)
+        let visitor = StepVisitor {
+            skip_misplaced: false,
+        };
+        deserializer
+            .deserialize_enum("Step", StepVisitor::FIELDS, visitor)?
+            .ok_or_else(|| D::Error::custom("a step was skipped"))
+    }
+}
+
+/// A step within an action, if it was not skipped.
+struct ActionStep(Option<Step>);
+
+impl<'de> Deserialize<'de> for ActionStep {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let visitor = StepVisitor {
+            skip_misplaced: true,
+        };
+        deserializer
+            .deserialize_enum("Step", StepVisitor::FIELDS, visitor)
+            .map(ActionStep)
+    }
+}
+
+/// Deserialize the steps of an action.
+///
+/// Misplaced elements are skipped unless parsing is strict.
+fn deserialize_steps<'de, D>(deserializer: D) -> Result<Vec<Step>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let steps: Vec<ActionStep> = EnumMap::deserialize_as(deserializer)?;
+    Ok(steps.into_iter().filter_map(|step| step.0).collect())
+}
+
+/// Deserialize a comma-separated list of tags.
+fn deserialize_tags<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let tags = String::deserialize(deserializer)?;
+    Ok(tags
+        .split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(Into::into)
+        .collect())
+}
+
+/// Deserialize an optional value which may be given as text.
+///
+/// Elements with flattened children buffer their attributes, so formats which only have text
+/// (e.g., XML) give the value as a string.
+fn deserialize_parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + FromStr,
+    T::Err: fmt::Display,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Parsed<T> {
+        Value(T),
+        Text(String),
+    }
+
+    match Parsed::<T>::deserialize(deserializer)? {
+        Parsed::Value(value) => Ok(Some(value)),
+        Parsed::Text(text) => text.trim().parse().map(Some).map_err(D::Error::custom),
     }
 }
 
 /// An action that may be performed for a bullet.
-#[serde_as]
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct Action {
     /// The name of the action.
     pub label: Option<String>,
+    /// The number of frames after which the bullet vanishes once the action starts (extension).
+    #[serde(default, deserialize_with = "deserialize_parsed")]
+    pub ttl: Option<u32>,
+    /// The tags of the action (extension).
+    ///
+    /// Tags are given as a comma-separated list. Runners may be restricted to actions with
+    /// certain tags using `RunnerOptions::tags`.
+    #[serde(default, deserialize_with = "deserialize_tags")]
+    pub tags: Vec<String>,
     /// The steps which make up the action.
-    #[serde(flatten)]
-    #[serde_as(as = "EnumMap")]
+    #[serde(flatten, deserialize_with = "deserialize_steps")]
     pub steps: Vec<Step>,
 }
 
+impl Action {
+    /// Create a new, unlabeled, action.
+    pub fn new(steps: Vec<Step>) -> Self {
+        Action {
+            steps,
+            ..Default::default()
+        }
+    }
+}
+
 /// A bullet.
 #[serde_as]
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct Bullet {
     /// The label for the bullet.
     pub label: Option<String>,
+    /// The number of frames after which the bullet vanishes (extension).
+    ///
+    /// Runners for bullets with actions vanish the bullet once this many frames have passed since
+    /// it was fired. Simple bullets have no runner, so managers need to use the `ttl` given by
+    /// `Event::Fired` for them.
+    #[serde(default, deserialize_with = "deserialize_parsed")]
+    pub ttl: Option<u32>,
     /// The direction to fire the bullet.
     pub direction: Option<Direction>,
     /// The initial speed of the bullet.
@@ -209,7 +428,7 @@ pub enum Element {
 
 /// The top-level BulletML entity.
 #[serde_as]
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct BulletML {
     /// The orientation of the game.
     #[serde(default)]
@@ -245,8 +464,7 @@ impl Change {
     /// Change a value.
     pub fn modify<T>(self, value: T, current: T, duration: T) -> T
     where
-        T: Add<Output = T>,
-        T: Mul<Output = T>,
+        T: Numeric,
     {
         match self {
             Change::Absolute => value,
@@ -256,6 +474,35 @@ impl Change {
     }
 }
 
+/// How a change over time moves from its start to its end (an extension).
+///
+/// The BulletML specification changes values at a constant rate. Names in `snake_case` are
+/// accepted as well so that options files may use them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Easing {
+    /// Change at a constant rate.
+    #[serde(rename = "linear")]
+    Linear,
+    /// Start slowly and speed up.
+    #[serde(rename = "easeIn", alias = "ease_in")]
+    EaseIn,
+    /// Start quickly and slow down.
+    #[serde(rename = "easeOut", alias = "ease_out")]
+    EaseOut,
+    /// Start and end slowly.
+    #[serde(rename = "easeInOut", alias = "ease_in_out")]
+    EaseInOut,
+    /// Follow half a period of a cosine wave; gentler than `EaseInOut`.
+    #[serde(rename = "sine")]
+    Sine,
+}
+
+impl Default for Easing {
+    fn default() -> Self {
+        Easing::Linear
+    }
+}
+
 /// A change in direction.
 #[derive(Debug, Clone, Deserialize)]
 pub struct ChangeDirection {
@@ -264,6 +511,28 @@ pub struct ChangeDirection {
     /// How much to change the direction by.
     #[serde(rename = "term")]
     pub value: Term,
+    /// How the direction changes over time (an extension).
+    ///
+    /// Without an easing, the runner's default is used.
+    #[serde(default)]
+    pub easing: Option<Easing>,
+}
+
+impl ChangeDirection {
+    /// Create a new change in direction.
+    pub fn new(direction: Direction, value: Term) -> Self {
+        ChangeDirection {
+            direction,
+            value,
+            easing: None,
+        }
+    }
+
+    /// Change the direction with an easing.
+    pub fn with_easing(mut self, easing: Easing) -> Self {
+        self.easing = Some(easing);
+        self
+    }
 }
 
 /// A change in speed.
@@ -274,6 +543,28 @@ pub struct ChangeSpeed {
     /// How much to change the speed by.
     #[serde(rename = "term")]
     pub value: Term,
+    /// How the speed changes over time (an extension).
+    ///
+    /// Without an easing, the runner's default is used.
+    #[serde(default)]
+    pub easing: Option<Easing>,
+}
+
+impl ChangeSpeed {
+    /// Create a new change in speed.
+    pub fn new(speed: Speed, value: Term) -> Self {
+        ChangeSpeed {
+            speed,
+            value,
+            easing: None,
+        }
+    }
+
+    /// Change the speed with an easing.
+    pub fn with_easing(mut self, easing: Easing) -> Self {
+        self.easing = Some(easing);
+        self
+    }
 }
 
 /// How to interpret a direction.
@@ -308,6 +599,65 @@ pub struct Direction {
     /// The angle against the given direction.
     #[serde(rename = "$value")]
     pub degrees: Expression,
+    /// Whether to lead the target when aiming (an extension).
+    ///
+    /// Aimed directions which lead the target account for the velocity of the target given by
+    /// `BulletManager::target_velocity` and the speed of the bullet so that the bullet intercepts
+    /// the target if it keeps moving. This is ignored for other kinds of directions.
+    #[serde(default)]
+    pub lead: bool,
+}
+
+impl Direction {
+    /// Create a new direction.
+    pub fn new<E>(kind: DirectionKind, degrees: E) -> Self
+    where
+        E: Into<Expression>,
+    {
+        Direction {
+            kind,
+            degrees: degrees.into(),
+            lead: false,
+        }
+    }
+
+    /// Lead the target when aiming.
+    pub fn leading(mut self) -> Self {
+        self.lead = true;
+        self
+    }
+
+    /// A direction relative to the player.
+    pub fn aim<E>(degrees: E) -> Self
+    where
+        E: Into<Expression>,
+    {
+        Self::new(DirectionKind::Aim, degrees)
+    }
+
+    /// An absolute direction.
+    pub fn absolute<E>(degrees: E) -> Self
+    where
+        E: Into<Expression>,
+    {
+        Self::new(DirectionKind::Absolute, degrees)
+    }
+
+    /// A direction relative to the current heading.
+    pub fn relative<E>(degrees: E) -> Self
+    where
+        E: Into<Expression>,
+    {
+        Self::new(DirectionKind::Relative, degrees)
+    }
+
+    /// A direction relative to the previous direction.
+    pub fn sequence<E>(degrees: E) -> Self
+    where
+        E: Into<Expression>,
+    {
+        Self::new(DirectionKind::Sequence, degrees)
+    }
 }
 
 /// A parameter to an entity reference.
@@ -318,6 +668,23 @@ pub struct Param {
     value: Expression,
 }
 
+impl Param {
+    /// Create a new parameter.
+    pub fn new<E>(value: E) -> Self
+    where
+        E: Into<Expression>,
+    {
+        Param {
+            value: value.into(),
+        }
+    }
+
+    /// The expression of the parameter.
+    pub fn value(&self) -> &Expression {
+        &self.value
+    }
+}
+
 /// A reference to another entity.
 #[derive(Debug, Clone)]
 pub struct Reference {
@@ -327,6 +694,38 @@ pub struct Reference {
     params: Vec<Param>,
 }
 
+impl Reference {
+    /// Create a new reference to a labeled entity.
+    pub fn new<L>(label: L) -> Self
+    where
+        L: Into<String>,
+    {
+        Reference {
+            label: label.into(),
+            params: Vec::new(),
+        }
+    }
+
+    /// The name of the referred-to entity.
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// The parameters to forward to the entity.
+    pub fn params(&self) -> &[Param] {
+        &self.params
+    }
+
+    /// Add a parameter to the reference.
+    pub fn param<E>(mut self, value: E) -> Self
+    where
+        E: Into<Expression>,
+    {
+        self.params.push(Param::new(value));
+        self
+    }
+}
+
 struct ReferenceVisitor;
 
 impl ReferenceVisitor {
@@ -337,7 +736,19 @@ impl<'de> Visitor<'de> for ReferenceVisitor {
     type Value = Reference;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        write!(formatter, "one of `{}`", Self::FIELDS.join("`, `"))
+        write!(
+            formatter,
+            "a label or one of `{}`",
+            Self::FIELDS.join("`, `")
+        )
+    }
+
+    fn visit_str<E>(self, label: &str) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        // Formats other than XML may give references without parameters as their label.
+        Ok(Reference::new(label))
     }
 
     fn visit_map<M>(self, mut access: M) -> Result<Self::Value, M::Error>
@@ -359,7 +770,12 @@ impl<'de> Visitor<'de> for ReferenceVisitor {
                     let expr = access.next_value()?;
                     local_param.push(expr);
                 },
-                _ => {},
+                key => {
+                    if ParseOptions::with_active(|options| options.strict) {
+                        return Err(misplaced(key, "a reference"));
+                    }
+                    access.next_value::<IgnoredAny>()?;
+                },
             }
         }
 
@@ -378,7 +794,7 @@ impl<'de> Deserialize<'de> for Reference {
     where
         D: Deserializer<'de>,
     {
-        deserializer.deserialize_map(ReferenceVisitor)
+        deserializer.deserialize_any(ReferenceVisitor)
     }
 }
 
@@ -479,6 +895,13 @@ where
             Err(E::Error::unknown_variant(&name, Self::FIELDS))
         }
     }
+
+    fn visit_map<M>(self, access: M) -> Result<Self::Value, M::Error>
+    where
+        M: MapAccess<'de>,
+    {
+        self.visit_enum(MapAccessDeserializer::new(access))
+    }
 }
 
 impl<'de, T> Deserialize<'de> for EntityRef<T>
@@ -511,6 +934,18 @@ pub struct Fire {
     pub bullet: EntityRef<Bullet>,
 }
 
+impl Fire {
+    /// Create a new fire for a bullet.
+    pub fn new(bullet: EntityRef<Bullet>) -> Self {
+        Fire {
+            label: None,
+            direction: None,
+            speed: None,
+            bullet,
+        }
+    }
+}
+
 struct FireVisitor;
 
 impl FireVisitor {
@@ -567,7 +1002,12 @@ impl<'de> Visitor<'de> for FireVisitor {
                     let iref = access.next_value::<Reference>()?;
                     local_bullet = Some(EntityRef::Ref(iref));
                 },
-                _ => {},
+                key => {
+                    if ParseOptions::with_active(|options| options.strict) {
+                        return Err(misplaced(key, "`<fire>`"));
+                    }
+                    access.next_value::<IgnoredAny>()?;
+                },
             }
         }
 
@@ -605,18 +1045,98 @@ pub struct Horizontal {
     pub change: Expression,
 }
 
+impl Horizontal {
+    /// Create a new horizontal change.
+    pub fn new<E>(kind: Change, change: E) -> Self
+    where
+        E: Into<Expression>,
+    {
+        Horizontal {
+            kind,
+            change: change.into(),
+        }
+    }
+}
+
 /// Repetition action.
-#[serde_as]
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone)]
 pub struct Repeat {
     /// How many times to repeat the actions.
     pub times: Times,
     /// The actions to repeat.
-    #[serde(flatten)]
-    #[serde_as(as = "EnumMap")]
     pub actions: Vec<EntityRef<Action>>,
 }
 
+impl Repeat {
+    /// Create a new repetition.
+    pub fn new(times: Times, actions: Vec<EntityRef<Action>>) -> Self {
+        Repeat {
+            times,
+            actions,
+        }
+    }
+}
+
+struct RepeatVisitor;
+
+impl RepeatVisitor {
+    const FIELDS: &'static [&'static str] = &["times", "action", "actionRef"];
+}
+
+impl<'de> Visitor<'de> for RepeatVisitor {
+    type Value = Repeat;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "one of `{}`", Self::FIELDS.join("`, `"))
+    }
+
+    fn visit_map<M>(self, mut access: M) -> Result<Self::Value, M::Error>
+    where
+        M: MapAccess<'de>,
+    {
+        let mut local_times = None;
+        let mut local_actions = Vec::new();
+
+        // The count may be given before, after, or between the actions.
+        while let Some(key) = access.next_key::<Cow<str>>()? {
+            match key.as_ref() {
+                "times" => {
+                    if local_times.is_some() {
+                        return Err(M::Error::duplicate_field("times"));
+                    }
+                    local_times = Some(access.next_value()?);
+                },
+                "action" => {
+                    let action = access.next_value()?;
+                    local_actions.push(EntityRef::Real(action));
+                },
+                "actionRef" => {
+                    let iref = access.next_value::<Reference>()?;
+                    local_actions.push(EntityRef::Ref(iref));
+                },
+                key => return Err(misplaced(key, "`<repeat>`")),
+            }
+        }
+
+        let times = local_times.ok_or_else(|| M::Error::missing_field("times"))?;
+        let actions = local_actions;
+
+        Ok(Repeat {
+            times,
+            actions,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Repeat {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_map(RepeatVisitor)
+    }
+}
+
 /// A change in speed.
 #[derive(Debug, Clone, Deserialize)]
 pub struct Speed {
@@ -628,6 +1148,43 @@ pub struct Speed {
     pub change: Expression,
 }
 
+impl Speed {
+    /// Create a new speed.
+    pub fn new<E>(kind: Change, change: E) -> Self
+    where
+        E: Into<Expression>,
+    {
+        Speed {
+            kind,
+            change: change.into(),
+        }
+    }
+
+    /// An absolute speed.
+    pub fn absolute<E>(change: E) -> Self
+    where
+        E: Into<Expression>,
+    {
+        Self::new(Change::Absolute, change)
+    }
+
+    /// A speed relative to the current speed.
+    pub fn relative<E>(change: E) -> Self
+    where
+        E: Into<Expression>,
+    {
+        Self::new(Change::Relative, change)
+    }
+
+    /// A speed relative to the previous speed.
+    pub fn sequence<E>(change: E) -> Self
+    where
+        E: Into<Expression>,
+    {
+        Self::new(Change::Sequence, change)
+    }
+}
+
 /// An expression to compute a value for an action.
 #[derive(Debug, Clone, Deserialize)]
 pub struct Term {
@@ -637,7 +1194,23 @@ pub struct Term {
 }
 
 impl Term {
+    /// Create a new term.
+    pub fn new<E>(value: E) -> Self
+    where
+        E: Into<Expression>,
+    {
+        Term {
+            value: value.into(),
+        }
+    }
+
+    /// A term with a constant value.
+    pub fn constant(value: Value) -> Self {
+        Self::new(value)
+    }
+
     /// Evaluate the term in the given context.
+    #[cfg(feature = "runtime")]
     pub fn eval(&self, ctx: &dyn ExpressionContext) -> Result<Value, ExpressionError> {
         self.value.eval(ctx)
     }
@@ -651,8 +1224,20 @@ pub struct Times {
     pub value: Expression,
 }
 
+impl Times {
+    /// Create a new repetition count.
+    pub fn new<E>(value: E) -> Self
+    where
+        E: Into<Expression>,
+    {
+        Times {
+            value: value.into(),
+        }
+    }
+}
+
 /// Cause the bullet to vanish.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
 pub struct Vanish {}
 
 /// Vertical change description.
@@ -666,6 +1251,19 @@ pub struct Vertical {
     pub change: Expression,
 }
 
+impl Vertical {
+    /// Create a new vertical change.
+    pub fn new<E>(kind: Change, change: E) -> Self
+    where
+        E: Into<Expression>,
+    {
+        Vertical {
+            kind,
+            change: change.into(),
+        }
+    }
+}
+
 /// Pause execution for a given number of frames.
 #[derive(Debug, Clone, Deserialize)]
 pub struct Wait {
@@ -673,3 +1271,20 @@ pub struct Wait {
     #[serde(rename = "$value")]
     pub frames: Expression,
 }
+
+impl Wait {
+    /// Create a new wait.
+    pub fn new<E>(frames: E) -> Self
+    where
+        E: Into<Expression>,
+    {
+        Wait {
+            frames: frames.into(),
+        }
+    }
+
+    /// A wait for a constant number of frames.
+    pub fn frames(frames: u32) -> Self {
+        Self::new(frames)
+    }
+}
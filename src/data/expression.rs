@@ -1,39 +1,135 @@
 // Distributed under the OSI-approved BSD 2-Clause License.
 // See accompanying LICENSE file for details.
 
-use serde::de::{Deserializer, Error, Unexpected};
+#[cfg(feature = "runtime")]
+use std::collections::hash_map::{Entry, HashMap};
+use std::fmt;
+use std::ops::{Add, Div, Mul, Neg, Rem, Sub};
+
+use serde::de::{Deserializer, Error, Unexpected, Visitor};
 use serde::Deserialize;
 use thiserror::Error;
 
+use crate::data::code::ErrorCode;
+use crate::data::options::{Dialect, ParseOptions};
+
+#[cfg(feature = "runtime")]
 mod ast;
+#[cfg(feature = "runtime")]
+mod bytecode;
+#[cfg(feature = "runtime")]
 mod grammar;
 
-use self::ast::{Expr, ExprVar};
+#[cfg(feature = "runtime")]
+use self::ast::{BinaryOp, Expr, ExprVar, UnaryOp, MAX_ARGS};
+#[cfg(feature = "runtime")]
+use self::bytecode::Program;
+
+#[cfg(feature = "runtime")]
+type ParseError = peg::error::ParseError<peg::str::LineCol>;
+
+/// Expressions are not parsed without the `runtime` feature.
+#[cfg(not(feature = "runtime"))]
+#[derive(Debug)]
+pub enum ParseError {}
+
+#[cfg(not(feature = "runtime"))]
+impl fmt::Display for ParseError {
+    fn fmt(&self, _: &mut fmt::Formatter) -> fmt::Result {
+        match *self {}
+    }
+}
+
+#[cfg(not(feature = "runtime"))]
+impl std::error::Error for ParseError {}
 
 /// An error when evaluating an expression.
 #[derive(Debug, Error)]
 pub enum ExpressionError {
     /// Failed to parse an expression.
-    #[error("failed to parse expression")]
+    #[error("{}: failed to parse expression", ErrorCode::ExpressionSyntax)]
     ParseFailure {
         /// The parser error.
         #[from]
-        source: peg::error::ParseError<peg::str::LineCol>,
+        source: ParseError,
+    },
+    /// A character which is not portable across BulletML implementations.
+    #[error(
+        "{}: non-portable character `{}` at offset {}",
+        ErrorCode::NonPortableCharacter,
+        character,
+        offset
+    )]
+    NonPortableCharacter {
+        /// The character.
+        character: char,
+        /// The byte offset of the character within the expression.
+        offset: usize,
     },
     /// Reference to an undefined variable.
-    #[error("undefined variable `{}`", name)]
+    #[error("{}: undefined variable `{}`", ErrorCode::UndefinedVariable, name)]
     UndefinedVariable {
         /// The name of the variable.
         name: String,
     },
     /// Reference to a missing parameter.
-    #[error("missing parameter `{}`", idx)]
+    #[error("{}: missing parameter `{}`", ErrorCode::MissingParameter, idx)]
     MissingParameter {
         /// The index
         idx: usize,
     },
+    /// An expression is longer than allowed.
+    #[error(
+        "{}: expression of {} bytes exceeds the limit of {}",
+        ErrorCode::ExpressionTooComplex,
+        length,
+        max
+    )]
+    TooLong {
+        /// The length of the expression in bytes.
+        length: usize,
+        /// The maximum length.
+        max: usize,
+    },
+    /// An expression is nested more deeply than allowed.
+    #[error(
+        "{}: expression nesting exceeds the limit of {}",
+        ErrorCode::ExpressionTooComplex,
+        max
+    )]
+    TooDeep {
+        /// The maximum depth.
+        max: usize,
+    },
 }
 
+impl ExpressionError {
+    /// The code for the error.
+    pub fn code(&self) -> ErrorCode {
+        match *self {
+            ExpressionError::ParseFailure {
+                ..
+            } => ErrorCode::ExpressionSyntax,
+            ExpressionError::NonPortableCharacter {
+                ..
+            } => ErrorCode::NonPortableCharacter,
+            ExpressionError::UndefinedVariable {
+                ..
+            } => ErrorCode::UndefinedVariable,
+            ExpressionError::MissingParameter {
+                ..
+            } => ErrorCode::MissingParameter,
+            ExpressionError::TooLong {
+                ..
+            }
+            | ExpressionError::TooDeep {
+                ..
+            } => ErrorCode::ExpressionTooComplex,
+        }
+    }
+}
+
+#[cfg(feature = "runtime")]
 impl ExpressionError {
     fn undefined_variable<N>(name: N) -> Self
     where
@@ -54,12 +150,128 @@ impl ExpressionError {
 /// The value of an expression.
 pub type Value = f32;
 
+/// Limits on the size of expressions accepted by the parser.
+///
+/// Parsing and evaluation recurse into nested expressions, so pathological input (e.g., thousands
+/// of nested parentheses) could otherwise exhaust the stack. The defaults are far beyond what
+/// scripts use in practice, but games which accept patterns from players may want tighter ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExpressionLimits {
+    /// The maximum length of an expression in bytes.
+    pub max_length: Option<usize>,
+    /// The maximum nesting depth of an expression.
+    ///
+    /// Parentheses, negations, and operands of operators and functions each nest one level
+    /// deeper.
+    pub max_depth: Option<usize>,
+}
+
+impl Default for ExpressionLimits {
+    fn default() -> Self {
+        ExpressionLimits {
+            max_length: Some(4096),
+            max_depth: Some(256),
+        }
+    }
+}
+
+impl ExpressionLimits {
+    /// Limits which accept any expression.
+    pub fn unlimited() -> Self {
+        ExpressionLimits {
+            max_length: None,
+            max_depth: None,
+        }
+    }
+
+    /// Check the source of an expression before it is parsed.
+    fn check_source(&self, expr: &str) -> Result<(), ExpressionError> {
+        if let Some(max) = self.max_length {
+            if expr.len() > max {
+                return Err(ExpressionError::TooLong {
+                    length: expr.len(),
+                    max,
+                });
+            }
+        }
+
+        self.check_depth(source_depth(expr))
+    }
+
+    fn check_depth(&self, depth: usize) -> Result<(), ExpressionError> {
+        match self.max_depth {
+            Some(max) if depth > max => {
+                Err(ExpressionError::TooDeep {
+                    max,
+                })
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The nesting depth of the source of an expression.
+///
+/// This is the depth to which the parser recurses: a group nests everything up to its closing
+/// parenthesis and a negation nests the rest of its group or function argument.
+fn source_depth(expr: &str) -> usize {
+    // The number of negations within each open group.
+    let mut groups = vec![0];
+    let mut depth = 0;
+    let mut max = 0;
+    let mut after_operand = false;
+
+    for character in expr.chars() {
+        match character {
+            '(' => {
+                groups.push(0);
+                depth += 1;
+                after_operand = false;
+            },
+            ')' => {
+                if groups.len() > 1 {
+                    depth -= 1 + groups.pop().unwrap_or(0);
+                }
+                after_operand = true;
+            },
+            ',' => {
+                if let Some(negations) = groups.last_mut() {
+                    depth -= *negations;
+                    *negations = 0;
+                }
+                after_operand = false;
+            },
+            '-' if !after_operand => {
+                if let Some(negations) = groups.last_mut() {
+                    *negations += 1;
+                }
+                depth += 1;
+            },
+            '+' | '-' | '*' | '/' | '%' => after_operand = false,
+            c if c.is_whitespace() => (),
+            _ => after_operand = true,
+        }
+        max = max.max(depth);
+    }
+
+    max
+}
+
 /// The context in which to execute an expression.
 ///
 /// This provides values for variables referenced in expressions.
 pub trait ExpressionContext {
     /// Get the value of a variable.
     fn get(&self, name: &str) -> Option<Value>;
+    /// Get the value of a variable by its index.
+    ///
+    /// Compiled scripts assign indices to the variables they reference (see
+    /// `CompiledBulletML::variables`). Contexts may override this to avoid looking up variables
+    /// by name; by default, the variable is looked up by `name`.
+    fn get_index(&self, idx: usize, name: &str) -> Option<Value> {
+        let _ = idx;
+        self.get(name)
+    }
     /// Get a parameter.
     fn get_param(&self, idx: usize) -> Option<Value>;
     /// Get a random value.
@@ -68,28 +280,204 @@ pub trait ExpressionContext {
     fn rank(&self) -> Value;
 }
 
+/// Indices assigned to the named variables of a script.
+#[cfg(feature = "runtime")]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Variables {
+    names: Vec<String>,
+    indices: HashMap<String, usize>,
+    /// The value of `$rank` if it is fixed for the script.
+    rank: Option<Value>,
+}
+
+#[cfg(feature = "runtime")]
+impl Variables {
+    /// Variables for a script where `$rank` may be fixed.
+    pub(crate) fn with_rank(rank: Option<Value>) -> Self {
+        Variables {
+            rank,
+            ..Self::default()
+        }
+    }
+
+    /// The index of a variable, assigning a new one if needed.
+    pub(crate) fn index(&mut self, name: &str) -> usize {
+        match self.indices.entry(name.into()) {
+            Entry::Occupied(o) => *o.get(),
+            Entry::Vacant(v) => {
+                self.names.push(v.key().clone());
+                *v.insert(self.names.len() - 1)
+            },
+        }
+    }
+
+    /// The names of the variables by index.
+    pub(crate) fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// The value of `$rank` if it is fixed for the script.
+    pub(crate) fn rank(&self) -> Option<Value> {
+        self.rank
+    }
+}
+
 /// An expression which may be evaluated to compute a value.
+///
+/// Besides the arithmetic operators of the BulletML specification, expressions may call the
+/// functions `sin`, `cos`, `tan`, `abs`, `floor`, `ceil`, and `sqrt` with one argument and `min`
+/// and `max` with two arguments (e.g., `max(sin($rank * 90), 0.5)`). Trigonometric functions take
+/// angles in degrees, like directions. Functions are not portable to other BulletML
+/// implementations.
+///
+/// Without the `runtime` feature, expressions only store their source and may not be evaluated.
 #[derive(Debug, Clone)]
 pub struct Expression {
+    #[cfg(feature = "runtime")]
     expr: Expr,
+    /// The expression flattened for faster evaluation.
+    #[cfg(feature = "runtime")]
+    program: Option<Program>,
+    #[cfg(not(feature = "runtime"))]
+    source: String,
 }
 
+#[cfg(feature = "runtime")]
 impl Expression {
     /// Parse an expression from a string.
+    ///
+    /// The default `ExpressionLimits` apply.
     pub fn parse<E>(expr: E) -> Result<Self, ExpressionError>
     where
         E: AsRef<str>,
     {
-        Ok(grammar::expression(expr.as_ref()).map(|expr| {
-            Expression {
-                expr: expr.constant_fold(),
-            }
-        })?)
+        Self::parse_with_limits(expr, &ExpressionLimits::default())
+    }
+
+    /// Parse an expression from a string within the given limits.
+    pub fn parse_with_limits<E>(expr: E, limits: &ExpressionLimits) -> Result<Self, ExpressionError>
+    where
+        E: AsRef<str>,
+    {
+        let source = expr.as_ref().trim();
+        limits.check_source(source)?;
+        let expr = grammar::expression(source)?;
+        // Chains of operators nest without parentheses.
+        limits.check_depth(expr.depth())?;
+
+        Ok(Self::from_expr(expr.constant_fold()))
+    }
+
+    /// An expression for the difficulty of the entity (`$rank`).
+    pub fn rank() -> Self {
+        Self::var_expr(ExprVar::Rank)
+    }
+
+    /// An expression for a random value (`$rand`).
+    pub fn rand() -> Self {
+        Self::var_expr(ExprVar::Rand)
+    }
+
+    /// An expression for a named variable (`$name`).
+    pub fn var<N>(name: N) -> Self
+    where
+        N: Into<String>,
+    {
+        Self::var_expr(ExprVar::Named(name.into()))
+    }
+
+    /// An expression for a parameter (`$idx`).
+    pub fn param(idx: usize) -> Self {
+        Self::var_expr(ExprVar::Param(idx))
+    }
+
+    fn var_expr(var: ExprVar) -> Self {
+        Self::from_expr(Expr::Var(var))
+    }
+
+    fn from_expr(expr: Expr) -> Self {
+        Expression {
+            expr,
+            program: None,
+        }
+    }
+
+    /// The value of the expression if it does not depend on any variables.
+    pub(crate) fn constant_value(&self) -> Option<Value> {
+        if let Expr::Float(value) = self.expr.clone().constant_fold() {
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    /// Assign indices to the named variables of the expression.
+    ///
+    /// If `$rank` is fixed, it is replaced by its value and the expression is folded again. The
+    /// expression is then compiled since scripts evaluate their expressions repeatedly.
+    pub(crate) fn intern(&mut self, variables: &mut Variables) {
+        self.expr.intern(&mut |name| variables.index(name));
+
+        if let Some(rank) = variables.rank {
+            self.expr.bind_rank(rank);
+            let expr = std::mem::replace(&mut self.expr, Expr::Float(0.));
+            self.expr = expr.constant_fold();
+        }
+
+        self.compile();
+    }
+
+    /// Compile the expression into bytecode for faster evaluation.
+    ///
+    /// Evaluating a compiled expression runs a flat list of instructions on a small stack rather
+    /// than walking the parsed expression. This is worth it for expressions which are evaluated
+    /// many times, such as those in a script. Expressions in a `CompiledBulletML` are compiled
+    /// automatically.
+    pub fn compile(&mut self) {
+        self.program = Some(Program::compile(&self.expr));
+    }
+
+    /// Whether the expression has been compiled into bytecode.
+    pub fn is_compiled(&self) -> bool {
+        self.program.is_some()
     }
 
     /// Evaluate the expression with a given context.
     pub fn eval(&self, ctx: &dyn ExpressionContext) -> Result<Value, ExpressionError> {
-        Self::eval_expr(&self.expr, ctx)
+        if let Some(ref program) = self.program {
+            program.eval(|var| Self::eval_var(var, ctx))
+        } else {
+            Self::eval_expr(&self.expr, ctx)
+        }
+    }
+
+    /// Evaluate the expression for many contexts at once.
+    ///
+    /// The expression is traversed once for all of the contexts rather than once per context.
+    pub fn eval_many<C>(&self, ctxs: &[C]) -> Result<Vec<Value>, ExpressionError>
+    where
+        C: ExpressionContext,
+    {
+        Self::eval_lanes(&self.expr, ctxs.len(), &|lane, var| {
+            Self::eval_var(var, &ctxs[lane])
+        })
+    }
+
+    /// Evaluate the expression at many ranks with an otherwise shared context.
+    ///
+    /// This is useful for plotting how a value changes with difficulty.
+    pub fn eval_ranks(
+        &self,
+        ctx: &dyn ExpressionContext,
+        ranks: &[Value],
+    ) -> Result<Vec<Value>, ExpressionError> {
+        Self::eval_lanes(&self.expr, ranks.len(), &|lane, var| {
+            if let ExprVar::Rank = *var {
+                Ok(ranks[lane])
+            } else {
+                Self::eval_var(var, ctx)
+            }
+        })
     }
 
     fn eval_expr(expr: &Expr, ctx: &dyn ExpressionContext) -> Result<Value, ExpressionError> {
@@ -106,33 +494,723 @@ impl Expression {
                 Self::eval_expr(l.as_ref(), ctx)
                     .and_then(|lr| Self::eval_expr(r.as_ref(), ctx).map(|rr| o.eval(lr, rr)))
             },
-            Expr::Float(f) => Ok(f),
-            Expr::Var(ref v) => {
-                match *v {
-                    ExprVar::Rank => Ok(ctx.rank()),
-                    ExprVar::Rand => Ok(ctx.rand()),
-                    ExprVar::Named(ref n) => {
-                        ctx.get(n)
-                            .ok_or_else(|| ExpressionError::undefined_variable(n))
-                    },
-                    ExprVar::Param(n) => {
-                        ctx.get_param(n)
-                            .ok_or_else(|| ExpressionError::missing_parameter(n))
-                    },
+            Expr::Call {
+                func,
+                ref args,
+            } => {
+                let mut values = [0.; MAX_ARGS];
+                for (value, arg) in values.iter_mut().zip(args) {
+                    *value = Self::eval_expr(arg, ctx)?;
                 }
+                Ok(func.eval(&values[..args.len()]))
+            },
+            Expr::Float(f) => Ok(f),
+            Expr::Var(ref v) => Self::eval_var(v, ctx),
+        }
+    }
+
+    fn eval_lanes<F>(expr: &Expr, lanes: usize, var: &F) -> Result<Vec<Value>, ExpressionError>
+    where
+        F: Fn(usize, &ExprVar) -> Result<Value, ExpressionError>,
+    {
+        match *expr {
+            Expr::Unary {
+                op: ref o,
+                expr: ref e,
+            } => {
+                let mut values = Self::eval_lanes(e.as_ref(), lanes, var)?;
+                values.iter_mut().for_each(|value| *value = o.eval(*value));
+                Ok(values)
+            },
+            Expr::Binary {
+                op: ref o,
+                lhs: ref l,
+                rhs: ref r,
+            } => {
+                let mut values = Self::eval_lanes(l.as_ref(), lanes, var)?;
+                let rhs = Self::eval_lanes(r.as_ref(), lanes, var)?;
+                values
+                    .iter_mut()
+                    .zip(rhs)
+                    .for_each(|(value, rhs)| *value = o.eval(*value, rhs));
+                Ok(values)
+            },
+            Expr::Call {
+                func,
+                ref args,
+            } => {
+                let args = args
+                    .iter()
+                    .map(|arg| Self::eval_lanes(arg, lanes, var))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let values = (0..lanes)
+                    .map(|lane| {
+                        let mut values = [0.; MAX_ARGS];
+                        for (value, arg) in values.iter_mut().zip(&args) {
+                            *value = arg[lane];
+                        }
+                        func.eval(&values[..args.len()])
+                    })
+                    .collect();
+                Ok(values)
+            },
+            Expr::Float(f) => Ok(vec![f; lanes]),
+            Expr::Var(ref v) => (0..lanes).map(|lane| var(lane, v)).collect(),
+        }
+    }
+
+    fn eval_var(var: &ExprVar, ctx: &dyn ExpressionContext) -> Result<Value, ExpressionError> {
+        match *var {
+            ExprVar::Rank => Ok(ctx.rank()),
+            ExprVar::Rand => Ok(ctx.rand()),
+            ExprVar::Named(ref n) => {
+                ctx.get(n)
+                    .ok_or_else(|| ExpressionError::undefined_variable(n))
+            },
+            ExprVar::Indexed(idx, ref n) => {
+                ctx.get_index(idx, n)
+                    .ok_or_else(|| ExpressionError::undefined_variable(n))
+            },
+            ExprVar::Param(n) => {
+                ctx.get_param(n)
+                    .ok_or_else(|| ExpressionError::missing_parameter(n))
             },
         }
     }
 }
 
+#[cfg(not(feature = "runtime"))]
+impl Expression {
+    /// Store an expression from a string.
+    ///
+    /// The expression is not parsed; only its length and nesting are checked against the default
+    /// `ExpressionLimits`.
+    pub fn parse<E>(expr: E) -> Result<Self, ExpressionError>
+    where
+        E: AsRef<str>,
+    {
+        Self::parse_with_limits(expr, &ExpressionLimits::default())
+    }
+
+    /// Store an expression within the given limits.
+    ///
+    /// The expression is not parsed; only its length and nesting are checked.
+    pub fn parse_with_limits<E>(expr: E, limits: &ExpressionLimits) -> Result<Self, ExpressionError>
+    where
+        E: AsRef<str>,
+    {
+        let source = expr.as_ref().trim();
+        limits.check_source(source)?;
+
+        Ok(Self::from_source(source))
+    }
+
+    /// An expression for the difficulty of the entity (`$rank`).
+    pub fn rank() -> Self {
+        Self::from_source("$rank")
+    }
+
+    /// An expression for a random value (`$rand`).
+    pub fn rand() -> Self {
+        Self::from_source("$rand")
+    }
+
+    /// An expression for a named variable (`$name`).
+    pub fn var<N>(name: N) -> Self
+    where
+        N: Into<String>,
+    {
+        Self::from_source(format!("${}", name.into()))
+    }
+
+    /// An expression for a parameter (`$idx`).
+    pub fn param(idx: usize) -> Self {
+        Self::from_source(format!("${}", idx))
+    }
+
+    /// The value of the expression if it does not depend on any variables.
+    ///
+    /// Expressions are not parsed, so this is never known.
+    pub(crate) fn constant_value(&self) -> Option<Value> {
+        None
+    }
+
+    fn from_source<S>(source: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Expression {
+            source: source.into(),
+        }
+    }
+}
+
+impl Expression {
+    /// Check that an expression only uses characters defined by the BulletML specification.
+    ///
+    /// This does not parse the expression; it only checks the characters it uses. Variable names
+    /// may only contain lowercase letters and digits.
+    pub fn check_portable(expr: &str) -> Result<(), ExpressionError> {
+        let mut in_variable = false;
+
+        for (offset, character) in expr.char_indices() {
+            let portable = match character {
+                '$' => {
+                    in_variable = true;
+                    true
+                },
+                'a'..='z' if in_variable => true,
+                '0'..='9' => true,
+                '.' | '+' | '-' | '*' | '/' | '%' | '(' | ')' | ' ' | '\t' | '\n' | '\r' => {
+                    in_variable = false;
+                    true
+                },
+                _ => false,
+            };
+
+            if !portable {
+                return Err(ExpressionError::NonPortableCharacter {
+                    character,
+                    offset,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "runtime")]
+impl From<Value> for Expression {
+    fn from(value: Value) -> Self {
+        Self::from_expr(Expr::Float(value))
+    }
+}
+
+#[cfg(not(feature = "runtime"))]
+impl From<Value> for Expression {
+    fn from(value: Value) -> Self {
+        Self::from_source(value.to_string())
+    }
+}
+
+impl From<i32> for Expression {
+    fn from(value: i32) -> Self {
+        Self::from(value as Value)
+    }
+}
+
+impl From<u32> for Expression {
+    fn from(value: u32) -> Self {
+        Self::from(value as Value)
+    }
+}
+
+#[cfg(feature = "runtime")]
+macro_rules! impl_binary_op {
+    ( $trait:ident, $method:ident, $op:expr, $symbol:expr ) => {
+        impl<R> $trait<R> for Expression
+        where
+            R: Into<Expression>,
+        {
+            type Output = Self;
+
+            fn $method(self, rhs: R) -> Self::Output {
+                Expression::from_expr(
+                    Expr::binary($op, self.expr, rhs.into().expr).constant_fold(),
+                )
+            }
+        }
+    };
+}
+
+#[cfg(not(feature = "runtime"))]
+macro_rules! impl_binary_op {
+    ( $trait:ident, $method:ident, $op:expr, $symbol:expr ) => {
+        impl<R> $trait<R> for Expression
+        where
+            R: Into<Expression>,
+        {
+            type Output = Self;
+
+            fn $method(self, rhs: R) -> Self::Output {
+                Self::from_source(format!(
+                    "({}){}({})",
+                    self.source,
+                    $symbol,
+                    rhs.into().source,
+                ))
+            }
+        }
+    };
+}
+
+impl_binary_op!(Add, add, BinaryOp::Add, "+");
+impl_binary_op!(Sub, sub, BinaryOp::Sub, "-");
+impl_binary_op!(Mul, mul, BinaryOp::Mul, "*");
+impl_binary_op!(Div, div, BinaryOp::Div, "/");
+impl_binary_op!(Rem, rem, BinaryOp::Mod, "%");
+
+impl Neg for Expression {
+    type Output = Self;
+
+    #[cfg(feature = "runtime")]
+    fn neg(self) -> Self::Output {
+        Self::from_expr(Expr::unary(UnaryOp::Negate, self.expr).constant_fold())
+    }
+
+    #[cfg(not(feature = "runtime"))]
+    fn neg(self) -> Self::Output {
+        Self::from_source(format!("-({})", self.source))
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.display_with(NumberFormat::default()))
+    }
+}
+
+/// How numbers within expressions are written.
+///
+/// The default writes numbers as Rust does: in the shortest form which reads back as the same
+/// value and without a decimal point for whole numbers. Documents which are reviewed as diffs
+/// against hand-written originals may want fewer digits or a consistent form instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NumberFormat {
+    /// The most digits to write after the decimal point.
+    ///
+    /// Numbers are rounded to this many digits.
+    pub max_decimals: Option<usize>,
+    /// Whether to remove zeros at the end of the fractional part.
+    pub trim_trailing_zeros: bool,
+    /// Whether to write whole numbers without a decimal point.
+    ///
+    /// Otherwise, whole numbers are written with at least `.0`.
+    pub integers_without_point: bool,
+}
+
+impl Default for NumberFormat {
+    fn default() -> Self {
+        NumberFormat {
+            max_decimals: None,
+            trim_trailing_zeros: true,
+            integers_without_point: true,
+        }
+    }
+}
+
+impl NumberFormat {
+    /// Write a number in the format.
+    pub fn format(&self, value: Value) -> String {
+        if !value.is_finite() {
+            return value.to_string();
+        }
+
+        let mut text = if let Some(decimals) = self.max_decimals {
+            format!("{:.*}", decimals, value)
+        } else {
+            value.to_string()
+        };
+
+        if let Some(point) = text.find('.') {
+            let fraction = text[point + 1..].trim_end_matches('0');
+            if fraction.is_empty() && self.integers_without_point {
+                text.truncate(point);
+            } else if self.trim_trailing_zeros {
+                let len = point + 1 + fraction.len().max(1);
+                text.truncate(len);
+            }
+        } else if !self.integers_without_point {
+            text.push_str(".0");
+        }
+
+        // Do not write a sign for numbers which round to zero.
+        let rounded_to_zero = text
+            .trim_start_matches('-')
+            .chars()
+            .all(|c| c == '0' || c == '.');
+        if value != 0. && rounded_to_zero {
+            text.retain(|c| c != '-');
+        }
+
+        text
+    }
+}
+
+struct DisplayWith<'a> {
+    expr: &'a Expression,
+    format: NumberFormat,
+}
+
+impl fmt::Display for DisplayWith<'_> {
+    #[cfg(feature = "runtime")]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.expr.expr.fmt_with(f, &self.format)
+    }
+
+    #[cfg(not(feature = "runtime"))]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.expr.source)
+    }
+}
+
+impl Expression {
+    /// Display the expression with numbers in a given format.
+    ///
+    /// Without the `runtime` feature, expressions are written as they were given.
+    pub fn display_with(&self, format: NumberFormat) -> impl fmt::Display + '_ {
+        DisplayWith {
+            expr: self,
+            format,
+        }
+    }
+}
+
+struct ExpressionVisitor;
+
+impl<'de> Visitor<'de> for ExpressionVisitor {
+    type Value = Expression;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a BulletML expression")
+    }
+
+    fn visit_str<E>(self, text: &str) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        let expr = strip_cdata(text);
+
+        if ParseOptions::with_active(|options| options.dialect == Dialect::Spec) {
+            Expression::check_portable(expr)
+                .map_err(|err| E::custom(format!("in expression `{}`: {}", expr.trim(), err)))?;
+        }
+
+        let limits = ParseOptions::with_active(|options| options.expression_limits);
+        Expression::parse_with_limits(expr, &limits).map_err(|err| {
+            match err {
+                ExpressionError::ParseFailure {
+                    ..
+                } => E::invalid_value(Unexpected::Str(expr), &self),
+                err => E::custom(err),
+            }
+        })
+    }
+
+    // Formats other than XML may give constant expressions as numbers.
+    fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        self.visit_str(&value.to_string())
+    }
+
+    fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        self.visit_str(&value.to_string())
+    }
+
+    fn visit_f64<E>(self, value: f64) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        self.visit_str(&value.to_string())
+    }
+}
+
 impl<'de> Deserialize<'de> for Expression {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
-        let expr = String::deserialize(deserializer)?;
+        deserializer.deserialize_any(ExpressionVisitor)
+    }
+}
+
+/// Remove `<![CDATA[...]]>` markers around the text of an expression.
+///
+/// XML parsers usually unwrap CDATA sections (and decode entities) before text reaches
+/// deserialization, but not all of them do.
+fn strip_cdata(text: &str) -> &str {
+    text.trim()
+        .strip_prefix("<![CDATA[")
+        .and_then(|inner| inner.strip_suffix("]]>"))
+        .unwrap_or(text)
+}
+
+#[cfg(all(test, feature = "runtime"))]
+mod test {
+    use crate::data::expression::{
+        Expression, ExpressionContext, ExpressionError, ExpressionLimits, Value,
+    };
+    use crate::data::ErrorCode;
+
+    struct Context;
+
+    impl ExpressionContext for Context {
+        fn get(&self, name: &str) -> Option<Value> {
+            if name == "var" {
+                Some(2.)
+            } else {
+                None
+            }
+        }
+
+        fn get_param(&self, _: usize) -> Option<Value> {
+            None
+        }
+
+        fn rand(&self) -> Value {
+            0.5
+        }
+
+        fn rank(&self) -> Value {
+            0.25
+        }
+    }
+
+    struct Ranked(Value);
+
+    impl ExpressionContext for Ranked {
+        fn get(&self, name: &str) -> Option<Value> {
+            Context.get(name)
+        }
+
+        fn get_param(&self, idx: usize) -> Option<Value> {
+            Context.get_param(idx)
+        }
+
+        fn rand(&self) -> Value {
+            Context.rand()
+        }
+
+        fn rank(&self) -> Value {
+            self.0
+        }
+    }
+
+    struct Params;
+
+    impl ExpressionContext for Params {
+        fn get(&self, name: &str) -> Option<Value> {
+            Context.get(name)
+        }
+
+        fn get_param(&self, idx: usize) -> Option<Value> {
+            if idx == 1 {
+                Some(4.)
+            } else {
+                None
+            }
+        }
+
+        fn rand(&self) -> Value {
+            Context.rand()
+        }
+
+        fn rank(&self) -> Value {
+            Context.rank()
+        }
+    }
+
+    fn eval(expr: Expression) -> Value {
+        expr.eval(&Context).unwrap()
+    }
+
+    #[test]
+    fn test_expression_ops_constant() {
+        assert_eq!(eval(Expression::from(4.) + 2.), 6.);
+        assert_eq!(eval(Expression::from(4.) - 2.), 2.);
+        assert_eq!(eval(Expression::from(4.) * 2.), 8.);
+        assert_eq!(eval(Expression::from(4.) / 2.), 2.);
+        assert_eq!(eval(Expression::from(4.) % 3.), 1.);
+        assert_eq!(eval(-Expression::from(4.)), -4.);
+    }
+
+    #[test]
+    fn test_expression_ops_variables() {
+        assert_eq!(
+            eval(Expression::from(180.) + Expression::rand() * 30.),
+            195.,
+        );
+        assert_eq!(
+            eval(-(Expression::var("var") - Expression::rank()) * 4.),
+            -7.,
+        );
+    }
+
+    #[test]
+    fn test_expression_variables() {
+        assert_eq!(eval(Expression::rank()), 0.25);
+        assert_eq!(eval(Expression::rand()), 0.5);
+        assert_eq!(eval(Expression::var("var")), 2.);
+        Expression::var("missing").eval(&Context).unwrap_err();
+        Expression::param(1).eval(&Context).unwrap_err();
+    }
+
+    #[test]
+    fn test_expression_params() {
+        let expr = Expression::parse("3+$1*0.5").unwrap();
+        assert_eq!(expr.eval(&Params).unwrap(), 5.);
+
+        let err = Expression::parse("$1 + $2")
+            .unwrap()
+            .eval(&Params)
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::MissingParameter);
+    }
+
+    #[test]
+    fn test_expression_eval_many() {
+        let expr = Expression::parse("$rank * 10 + $var - $rand").unwrap();
+        let ranks = [0., 0.25, 0.5, 1.];
+        let ctxs = ranks.iter().map(|&rank| Ranked(rank)).collect::<Vec<_>>();
+        let expected = ctxs
+            .iter()
+            .map(|ctx| expr.eval(ctx).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(expected, [1.5, 4., 6.5, 11.5]);
+        assert_eq!(expr.eval_many(&ctxs).unwrap(), expected);
+        assert_eq!(expr.eval_ranks(&Context, &ranks).unwrap(), expected);
+        assert_eq!(
+            Expression::from(3.).eval_ranks(&Context, &ranks).unwrap(),
+            [3.; 4],
+        );
+        assert!(expr.eval_many::<Ranked>(&[]).unwrap().is_empty());
+        Expression::var("missing")
+            .eval_ranks(&Context, &ranks)
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_expression_compile() {
+        let exprs = [
+            "3",
+            "$rank * 10 + $var - $rand",
+            "-(1 - $rand) % 0.3",
+            "max(sin($rank * 360), $var) + floor($rand * 3)",
+        ];
+
+        for expr in exprs.iter() {
+            let tree = Expression::parse(expr).unwrap();
+            let mut compiled = tree.clone();
+            assert!(!compiled.is_compiled());
+            compiled.compile();
+            assert!(compiled.is_compiled());
+
+            for rank in [0., 0.25, 0.5, 1.].iter() {
+                let ctx = Ranked(*rank);
+                assert_eq!(compiled.eval(&ctx).unwrap(), tree.eval(&ctx).unwrap());
+            }
+            assert_eq!(compiled.to_string(), tree.to_string());
+        }
+
+        let mut expr = Expression::parse("$1 + $missing").unwrap();
+        expr.compile();
+        let err = expr.eval(&Context).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::MissingParameter);
+        let err = expr.eval(&Params).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::UndefinedVariable);
+
+        // Building on a compiled expression leaves the result uncompiled.
+        assert!(!(expr + 1.).is_compiled());
+    }
+
+    #[test]
+    fn test_expression_functions() {
+        let expr = Expression::parse("max(sin($rank * 360), $var) + floor($rand * 3)").unwrap();
+        assert_eq!(expr.eval(&Context).unwrap(), 3.);
+        assert_eq!(expr.to_string(), "max(sin($rank*360),$var)+floor($rand*3)");
+
+        let ranks = [0., 0.25, 1.];
+        let expected = ranks
+            .iter()
+            .map(|&rank| expr.eval(&Ranked(rank)).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(expr.eval_ranks(&Context, &ranks).unwrap(), expected);
+
+        assert_eq!(
+            Expression::parse("sqrt(9) * 2").unwrap().constant_value(),
+            Some(6.),
+        );
+        Expression::parse("pow(2, 3)").unwrap_err();
+
+        let err = Expression::check_portable("sin($rank)").unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NonPortableCharacter);
+    }
+
+    #[test]
+    fn test_expression_check_portable() {
+        Expression::check_portable("$rank * 2 + $rand - ($1 % .5)").unwrap();
+        Expression::check_portable("$var2").unwrap();
+
+        let err = Expression::check_portable("$my_var").unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NonPortableCharacter);
+        if let ExpressionError::NonPortableCharacter {
+            character,
+            offset,
+        } = err
+        {
+            assert_eq!(character, '_');
+            assert_eq!(offset, 3);
+        } else {
+            panic!("unexpected error: {:?}", err);
+        }
+
+        Expression::check_portable("$Rank").unwrap_err();
+        Expression::check_portable("1 + a").unwrap_err();
+    }
+
+    fn check_too_deep(expr: &str, limits: &ExpressionLimits) {
+        let err = Expression::parse_with_limits(expr, limits).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::ExpressionTooComplex);
+        if let ExpressionError::TooDeep {
+            max,
+        } = err
+        {
+            assert_eq!(Some(max), limits.max_depth);
+        } else {
+            panic!("unexpected error: {:?}", err);
+        }
+    }
+
+    #[test]
+    fn test_expression_limits() {
+        let limits = ExpressionLimits::default();
+
+        // Nesting is rejected before the parser recurses into it.
+        let parens = format!("{}1{}", "(".repeat(1000), ")".repeat(1000));
+        check_too_deep(&parens, &limits);
+        check_too_deep(&format!("{}1", "-".repeat(1000)), &limits);
+        check_too_deep(&format!("{}1", "1+".repeat(1000)), &limits);
+
+        let err = Expression::parse("1+".repeat(3000)).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::ExpressionTooComplex);
+        if let ExpressionError::TooLong {
+            length,
+            max,
+        } = err
+        {
+            assert_eq!(length, 6000);
+            assert_eq!(max, 4096);
+        } else {
+            panic!("unexpected error: {:?}", err);
+        }
+
+        let limits = ExpressionLimits {
+            max_depth: Some(2),
+            ..Default::default()
+        };
+        Expression::parse_with_limits("max(-1, -2)", &limits).unwrap();
+        Expression::parse_with_limits("(-1) * -(2)", &limits).unwrap();
+        check_too_deep("((-1))", &limits);
+        check_too_deep("1 + 2 * (3 - $rank)", &limits);
 
-        Self::parse(&expr)
-            .map_err(|_| D::Error::invalid_value(Unexpected::Str(&expr), &"a BulletML expression"))
+        let limits = ExpressionLimits::unlimited();
+        Expression::parse_with_limits(format!("{}1", "1+".repeat(1000)), &limits).unwrap();
     }
 }
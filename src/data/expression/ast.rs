@@ -1,16 +1,31 @@
 // Distributed under the OSI-approved BSD 2-Clause License.
 // See accompanying LICENSE file for details.
 
-use crate::data::expression::Value;
+use std::fmt;
+
+use crate::data::expression::{NumberFormat, Value};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ExprVar {
     Rank,
     Rand,
     Named(String),
+    /// A named variable which has been assigned an index.
+    Indexed(usize, String),
     Param(usize),
 }
 
+impl fmt::Display for ExprVar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExprVar::Rank => write!(f, "$rank"),
+            ExprVar::Rand => write!(f, "$rand"),
+            ExprVar::Named(ref n) | ExprVar::Indexed(_, ref n) => write!(f, "${}", n),
+            ExprVar::Param(n) => write!(f, "${}", n),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum UnaryOp {
     Negate,
@@ -22,6 +37,12 @@ impl UnaryOp {
             UnaryOp::Negate => -v,
         }
     }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            UnaryOp::Negate => "-",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -43,6 +64,94 @@ impl BinaryOp {
             BinaryOp::Mod => l % r,
         }
     }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Mod => "%",
+        }
+    }
+
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Add | BinaryOp::Sub => 0,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => 1,
+        }
+    }
+}
+
+/// The largest number of arguments taken by a function.
+pub const MAX_ARGS: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Function {
+    Sin,
+    Cos,
+    Tan,
+    Abs,
+    Floor,
+    Ceil,
+    Sqrt,
+    Min,
+    Max,
+}
+
+impl Function {
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "sin" => Function::Sin,
+            "cos" => Function::Cos,
+            "tan" => Function::Tan,
+            "abs" => Function::Abs,
+            "floor" => Function::Floor,
+            "ceil" => Function::Ceil,
+            "sqrt" => Function::Sqrt,
+            "min" => Function::Min,
+            "max" => Function::Max,
+            _ => return None,
+        })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Function::Sin => "sin",
+            Function::Cos => "cos",
+            Function::Tan => "tan",
+            Function::Abs => "abs",
+            Function::Floor => "floor",
+            Function::Ceil => "ceil",
+            Function::Sqrt => "sqrt",
+            Function::Min => "min",
+            Function::Max => "max",
+        }
+    }
+
+    pub fn arity(self) -> usize {
+        match self {
+            Function::Min | Function::Max => 2,
+            _ => 1,
+        }
+    }
+
+    /// Evaluate the function.
+    ///
+    /// Angles are in degrees, as are directions.
+    pub fn eval(self, args: &[Value]) -> Value {
+        match self {
+            Function::Sin => args[0].to_radians().sin(),
+            Function::Cos => args[0].to_radians().cos(),
+            Function::Tan => args[0].to_radians().tan(),
+            Function::Abs => args[0].abs(),
+            Function::Floor => args[0].floor(),
+            Function::Ceil => args[0].ceil(),
+            Function::Sqrt => args[0].sqrt(),
+            Function::Min => args[0].min(args[1]),
+            Function::Max => args[0].max(args[1]),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -56,6 +165,10 @@ pub enum Expr {
         lhs: Box<Expr>,
         rhs: Box<Expr>,
     },
+    Call {
+        func: Function,
+        args: Vec<Expr>,
+    },
     Float(Value),
     Var(ExprVar),
 }
@@ -76,6 +189,44 @@ impl Expr {
         }
     }
 
+    pub fn call(func: Function, args: Vec<Expr>) -> Self {
+        Expr::Call {
+            func,
+            args,
+        }
+    }
+
+    /// The nesting depth of the expression.
+    ///
+    /// This does not recurse so that it is safe to use on arbitrarily deep expressions.
+    pub fn depth(&self) -> usize {
+        let mut max = 0;
+        let mut stack = vec![(self, 0)];
+
+        while let Some((expr, depth)) = stack.pop() {
+            max = max.max(depth);
+            match *expr {
+                Expr::Unary {
+                    ref expr, ..
+                } => stack.push((expr, depth + 1)),
+                Expr::Binary {
+                    ref lhs,
+                    ref rhs,
+                    ..
+                } => {
+                    stack.push((lhs, depth + 1));
+                    stack.push((rhs, depth + 1));
+                },
+                Expr::Call {
+                    ref args, ..
+                } => stack.extend(args.iter().map(|arg| (arg, depth + 1))),
+                Expr::Float(_) | Expr::Var(_) => (),
+            }
+        }
+
+        max
+    }
+
     fn constant_value(&self) -> Option<Value> {
         if let Expr::Float(v) = *self {
             Some(v)
@@ -84,6 +235,58 @@ impl Expr {
         }
     }
 
+    /// Assign indices to named variables.
+    pub fn intern<F>(&mut self, index: &mut F)
+    where
+        F: FnMut(&str) -> usize,
+    {
+        match *self {
+            Expr::Unary {
+                expr: ref mut e, ..
+            } => e.intern(index),
+            Expr::Binary {
+                lhs: ref mut l,
+                rhs: ref mut r,
+                ..
+            } => {
+                l.intern(index);
+                r.intern(index);
+            },
+            Expr::Call {
+                ref mut args, ..
+            } => args.iter_mut().for_each(|arg| arg.intern(index)),
+            Expr::Float(_) => (),
+            Expr::Var(ref mut v) => {
+                if let ExprVar::Named(ref mut n) = *v {
+                    let name = std::mem::replace(n, String::new());
+                    *v = ExprVar::Indexed(index(&name), name);
+                }
+            },
+        }
+    }
+
+    /// Replace `$rank` with a value.
+    pub fn bind_rank(&mut self, rank: Value) {
+        match *self {
+            Expr::Unary {
+                expr: ref mut e, ..
+            } => e.bind_rank(rank),
+            Expr::Binary {
+                lhs: ref mut l,
+                rhs: ref mut r,
+                ..
+            } => {
+                l.bind_rank(rank);
+                r.bind_rank(rank);
+            },
+            Expr::Call {
+                ref mut args, ..
+            } => args.iter_mut().for_each(|arg| arg.bind_rank(rank)),
+            Expr::Var(ExprVar::Rank) => *self = Expr::Float(rank),
+            Expr::Float(_) | Expr::Var(_) => (),
+        }
+    }
+
     pub fn constant_fold(self) -> Self {
         match self {
             Expr::Unary {
@@ -110,9 +313,113 @@ impl Expr {
                     Self::binary(o, nl, nr)
                 }
             },
+            Expr::Call {
+                func,
+                args,
+            } => {
+                let args = args
+                    .into_iter()
+                    .map(Self::constant_fold)
+                    .collect::<Vec<_>>();
+                let values = args
+                    .iter()
+                    .map(Self::constant_value)
+                    .collect::<Option<Vec<_>>>();
+                if let Some(values) = values {
+                    Expr::Float(func.eval(&values))
+                } else {
+                    Self::call(func, args)
+                }
+            },
             e => e,
         }
     }
+
+    // Whether the expression needs parentheses as an operand of a binary operator.
+    fn needs_parens(&self, parent: BinaryOp, is_rhs: bool) -> bool {
+        match *self {
+            // Negation applies to the entire expression following it, so always group it.
+            Expr::Unary {
+                ..
+            } => true,
+            Expr::Float(v) => v.is_sign_negative(),
+            Expr::Binary {
+                op, ..
+            } => {
+                op.precedence() < parent.precedence()
+                    || (is_rhs && op.precedence() == parent.precedence())
+            },
+            Expr::Call {
+                ..
+            }
+            | Expr::Var(_) => false,
+        }
+    }
+
+    fn fmt_operand(
+        &self,
+        f: &mut fmt::Formatter,
+        format: &NumberFormat,
+        parens: bool,
+    ) -> fmt::Result {
+        if parens {
+            write!(f, "(")?;
+            self.fmt_with(f, format)?;
+            write!(f, ")")
+        } else {
+            self.fmt_with(f, format)
+        }
+    }
+
+    pub fn fmt_with(&self, f: &mut fmt::Formatter, format: &NumberFormat) -> fmt::Result {
+        match *self {
+            Expr::Unary {
+                op,
+                ref expr,
+            } => {
+                let parens = match **expr {
+                    Expr::Float(v) => v.is_sign_negative(),
+                    Expr::Call {
+                        ..
+                    }
+                    | Expr::Var(_) => false,
+                    _ => true,
+                };
+                write!(f, "{}", op.symbol())?;
+                expr.fmt_operand(f, format, parens)
+            },
+            Expr::Binary {
+                op,
+                ref lhs,
+                ref rhs,
+            } => {
+                lhs.fmt_operand(f, format, lhs.needs_parens(op, false))?;
+                write!(f, "{}", op.symbol())?;
+                rhs.fmt_operand(f, format, rhs.needs_parens(op, true))
+            },
+            Expr::Call {
+                func,
+                ref args,
+            } => {
+                write!(f, "{}(", func.name())?;
+                for (idx, arg) in args.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ",")?;
+                    }
+                    arg.fmt_with(f, format)?;
+                }
+                write!(f, ")")
+            },
+            Expr::Float(v) => f.write_str(&format.format(v)),
+            Expr::Var(ref v) => write!(f, "{}", v),
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_with(f, &NumberFormat::default())
+    }
 }
 
 #[cfg(test)]
@@ -212,4 +519,48 @@ mod test {
         assert_eq!(eval("1*-1"), -1.);
         assert_eq!(eval("(-1)"), -1.);
     }
+
+    fn display(expr: &str) -> String {
+        parse(expr).to_string()
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(display("1+2*3"), "1+2*3");
+        assert_eq!(display("(1+2)*3"), "(1+2)*3");
+        assert_eq!(display("1-2-3"), "1-2-3");
+        assert_eq!(display("1-(2-3)"), "1-(2-3)");
+        assert_eq!(display("(-1)*2"), "(-1)*2");
+        assert_eq!(display("$rank*(-$rand)"), "$rank*(-$rand)");
+        assert_eq!(display("-($1+$var)"), "-($1+$var)");
+        assert_eq!(display(".5"), "0.5");
+        assert_eq!(display("4."), "4");
+        assert_eq!(display("sin( $rank*90 )"), "sin($rank*90)");
+        assert_eq!(display("max($1, -$rand)*2"), "max($1,-$rand)*2");
+        assert_eq!(display("-abs($rand)"), "-abs($rand)");
+    }
+
+    #[test]
+    fn test_functions() {
+        assert!((eval("sin(30)") - 0.5).abs() < 1e-6);
+        assert!((eval("cos(60)") - 0.5).abs() < 1e-6);
+        assert!((eval("tan(45)") - 1.).abs() < 1e-6);
+        assert_eq!(eval("abs(-2.5)"), 2.5);
+        assert_eq!(eval("floor(2.5)"), 2.);
+        assert_eq!(eval("floor(-2.5)"), -3.);
+        assert_eq!(eval("ceil(2.5)"), 3.);
+        assert_eq!(eval("sqrt(16)"), 4.);
+        assert_eq!(eval("min(1, 2)"), 1.);
+        assert_eq!(eval("max(1, 2)"), 2.);
+        assert_eq!(eval("max(1, min(4, 3)) * 2 + abs(-1)"), 7.);
+    }
+
+    #[test]
+    fn test_constant_folding_functions() {
+        check_literal(parse("sqrt(4)+1").constant_fold(), 3.);
+
+        // Arguments are folded even when the call cannot be.
+        let expr = parse("max($rank, 1+1)").constant_fold();
+        assert_eq!(expr.to_string(), "max($rank,2)");
+    }
 }
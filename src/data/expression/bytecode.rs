@@ -0,0 +1,255 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use crate::data::expression::ast::{BinaryOp, Expr, ExprVar, Function, UnaryOp};
+use crate::data::expression::{ExpressionError, Value};
+
+/// The stack depth which is evaluated without allocating.
+const INLINE_STACK: usize = 16;
+
+/// An instruction of a compiled expression.
+#[derive(Debug, Clone)]
+enum Op {
+    /// Push a value.
+    Push(Value),
+    /// Push the value of a variable.
+    Var(ExprVar),
+    /// Replace the top of the stack.
+    Unary(UnaryOp),
+    /// Replace the top two values of the stack with one.
+    Binary(BinaryOp),
+    /// Replace the arguments at the top of the stack with the result of the call.
+    Call(Function),
+}
+
+impl Op {
+    /// The change in stack depth after the instruction.
+    fn stack_effect(&self) -> isize {
+        match *self {
+            Op::Push(_) | Op::Var(_) => 1,
+            Op::Unary(_) => 0,
+            Op::Binary(_) => -1,
+            Op::Call(func) => 1 - func.arity() as isize,
+        }
+    }
+}
+
+/// A node of an expression tree which is being flattened.
+enum Visit<'a> {
+    Enter(&'a Expr),
+    Exit(Op),
+}
+
+/// An expression flattened into instructions for a stack machine.
+///
+/// Instructions are stored in postfix order in a single allocation so that evaluating does not
+/// chase pointers through the expression tree.
+#[derive(Debug, Clone)]
+pub struct Program {
+    ops: Box<[Op]>,
+    /// The deepest the stack gets while evaluating.
+    stack: usize,
+}
+
+impl Program {
+    /// Flatten an expression.
+    ///
+    /// This does not recurse so that it is safe to use on arbitrarily deep expressions.
+    pub fn compile(expr: &Expr) -> Self {
+        let mut ops = Vec::new();
+        let mut todo = vec![Visit::Enter(expr)];
+
+        while let Some(visit) = todo.pop() {
+            let expr = match visit {
+                Visit::Enter(expr) => expr,
+                Visit::Exit(op) => {
+                    ops.push(op);
+                    continue;
+                },
+            };
+
+            match *expr {
+                Expr::Unary {
+                    op,
+                    ref expr,
+                } => {
+                    todo.push(Visit::Exit(Op::Unary(op)));
+                    todo.push(Visit::Enter(expr));
+                },
+                Expr::Binary {
+                    op,
+                    ref lhs,
+                    ref rhs,
+                } => {
+                    todo.push(Visit::Exit(Op::Binary(op)));
+                    todo.push(Visit::Enter(rhs));
+                    todo.push(Visit::Enter(lhs));
+                },
+                Expr::Call {
+                    func,
+                    ref args,
+                } => {
+                    todo.push(Visit::Exit(Op::Call(func)));
+                    todo.extend(args.iter().rev().map(Visit::Enter));
+                },
+                Expr::Float(v) => ops.push(Op::Push(v)),
+                Expr::Var(ref v) => ops.push(Op::Var(v.clone())),
+            }
+        }
+
+        let mut depth = 0;
+        let mut stack = 0;
+        for op in &ops {
+            depth += op.stack_effect();
+            stack = stack.max(depth as usize);
+        }
+
+        Program {
+            ops: ops.into_boxed_slice(),
+            stack,
+        }
+    }
+
+    /// Evaluate the program, looking up variables with the given function.
+    pub fn eval<F>(&self, var: F) -> Result<Value, ExpressionError>
+    where
+        F: FnMut(&ExprVar) -> Result<Value, ExpressionError>,
+    {
+        if self.stack <= INLINE_STACK {
+            self.eval_on(&mut [0.; INLINE_STACK], var)
+        } else {
+            self.eval_on(&mut vec![0.; self.stack], var)
+        }
+    }
+
+    fn eval_on<F>(&self, stack: &mut [Value], mut var: F) -> Result<Value, ExpressionError>
+    where
+        F: FnMut(&ExprVar) -> Result<Value, ExpressionError>,
+    {
+        let mut top = 0;
+
+        for op in self.ops.iter() {
+            match *op {
+                Op::Push(v) => {
+                    stack[top] = v;
+                    top += 1;
+                },
+                Op::Var(ref v) => {
+                    stack[top] = var(v)?;
+                    top += 1;
+                },
+                Op::Unary(op) => stack[top - 1] = op.eval(stack[top - 1]),
+                Op::Binary(op) => {
+                    top -= 1;
+                    stack[top - 1] = op.eval(stack[top - 1], stack[top]);
+                },
+                Op::Call(func) => {
+                    let base = top - func.arity();
+                    stack[base] = func.eval(&stack[base..top]);
+                    top = base + 1;
+                },
+            }
+        }
+
+        Ok(stack[0])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::data::expression::ast::{Expr, ExprVar};
+    use crate::data::expression::bytecode::{Program, INLINE_STACK};
+    use crate::data::expression::grammar;
+    use crate::data::expression::{ExpressionError, Value};
+    use crate::data::ErrorCode;
+
+    fn var(var: &ExprVar) -> Result<Value, ExpressionError> {
+        match *var {
+            ExprVar::Rank => Ok(0.25),
+            ExprVar::Rand => Ok(0.5),
+            ExprVar::Param(1) => Ok(4.),
+            ExprVar::Named(ref n) if n == "var" => Ok(2.),
+            ExprVar::Param(n) => Err(ExpressionError::missing_parameter(n)),
+            ExprVar::Named(ref n) | ExprVar::Indexed(_, ref n) => {
+                Err(ExpressionError::undefined_variable(n))
+            },
+        }
+    }
+
+    fn eval_tree(expr: &Expr) -> Value {
+        match *expr {
+            Expr::Unary {
+                op,
+                ref expr,
+            } => op.eval(eval_tree(expr)),
+            Expr::Binary {
+                op,
+                ref lhs,
+                ref rhs,
+            } => op.eval(eval_tree(lhs), eval_tree(rhs)),
+            Expr::Call {
+                func,
+                ref args,
+            } => func.eval(&args.iter().map(eval_tree).collect::<Vec<_>>()),
+            Expr::Float(v) => v,
+            Expr::Var(ref v) => var(v).unwrap(),
+        }
+    }
+
+    fn check(expr: &str) {
+        let expr = grammar::expression(expr).unwrap();
+        let program = Program::compile(&expr);
+
+        assert_eq!(program.eval(var).unwrap(), eval_tree(&expr));
+    }
+
+    #[test]
+    fn test_program_matches_tree() {
+        check("1");
+        check("-$rank");
+        check("1-2-3");
+        check("1-(2-3)");
+        check("$rank * 10 + $var - $rand");
+        check("(2*2)*(1+2)-4*$1");
+        check("-(-$rand % 0.3)");
+        check("max(sin($rank * 360), $var) + floor($rand * 3)");
+        check("min(1, max($1, -2)) / sqrt(abs(-$var))");
+    }
+
+    #[test]
+    fn test_program_layout() {
+        let program = Program::compile(&grammar::expression("1").unwrap());
+        assert_eq!(program.ops.len(), 1);
+        assert_eq!(program.stack, 1);
+
+        let program = Program::compile(&grammar::expression("1+2*($rank-$rand)").unwrap());
+        assert_eq!(program.ops.len(), 7);
+        assert_eq!(program.stack, 4);
+
+        let program = Program::compile(&grammar::expression("max($rank, 1) + 2").unwrap());
+        assert_eq!(program.ops.len(), 5);
+        assert_eq!(program.stack, 2);
+    }
+
+    #[test]
+    fn test_program_deep_stack() {
+        let depth = INLINE_STACK * 2;
+        let expr = format!("{}1{}", "1+(".repeat(depth), ")".repeat(depth));
+        let expr = grammar::expression(&expr).unwrap();
+        let program = Program::compile(&expr);
+
+        assert_eq!(program.stack, depth + 1);
+        assert_eq!(program.eval(var).unwrap(), (depth + 1) as Value);
+    }
+
+    #[test]
+    fn test_program_variable_errors() {
+        let program = Program::compile(&grammar::expression("$1 + $2").unwrap());
+        let err = program.eval(var).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::MissingParameter);
+
+        let program = Program::compile(&grammar::expression("1 + $missing").unwrap());
+        let err = program.eval(var).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::UndefinedVariable);
+    }
+}
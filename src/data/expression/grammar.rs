@@ -4,7 +4,7 @@
 peg::parser! {
     grammar expression() for str {
         use crate::data::expression::Value;
-        use crate::data::expression::ast::{BinaryOp, Expr, ExprVar, UnaryOp};
+        use crate::data::expression::ast::{BinaryOp, Expr, ExprVar, Function, UnaryOp};
 
         pub rule expression() -> Expr
             = binary_expression()
@@ -30,11 +30,28 @@ peg::parser! {
         rule simple_expression() -> Expr
             = OP_OPEN_PAREN() e:expression() OP_CLOSE_PAREN() { e }
             / OP_SUB() e:expression() { Expr::unary(UnaryOp::Negate, e) }
+            / call()
             / literal()
             / identifier()
 
         rule OP_OPEN_PAREN() = "(" __
         rule OP_CLOSE_PAREN() = ")" __
+        rule OP_COMMA() = "," __
+
+        rule call() -> Expr
+            = f:function() OP_OPEN_PAREN() args:(expression() ** OP_COMMA()) OP_CLOSE_PAREN() {?
+                if args.len() != f.arity() {
+                    Err(if f.arity() == 1 { "1 argument" } else { "2 arguments" })
+                } else {
+                    Ok(Expr::call(f, args))
+                }
+            }
+
+        rule function() -> Function
+            = quiet!{_function()} / expected!("function")
+
+        rule _function() -> Function
+            = n:$(['a'..='z']+) __ {? Function::from_name(n).ok_or("function") }
 
         rule literal() -> Expr
             = f:float() { Expr::Float(f) }
@@ -61,8 +78,8 @@ peg::parser! {
             / "$" v:varname() { Expr::Var(v) }
 
         rule paramname() -> ExprVar
-            = n:$(['0'..='9']+) __ {
-                ExprVar::Param(n.parse().unwrap())
+            = n:$(['0'..='9']+) __ {?
+                n.parse().map(ExprVar::Param).or(Err("parameter index"))
             }
 
         rule varname() -> ExprVar
@@ -78,7 +95,7 @@ peg::parser! {
 
         rule __ = whitespace()*
 
-        rule whitespace() = quiet!{[' ' | '\t']}
+        rule whitespace() = quiet!{[' ' | '\t' | '\n' | '\r']}
     }
 }
 
@@ -86,7 +103,7 @@ pub use self::expression::expression;
 
 #[cfg(test)]
 mod test {
-    use crate::data::expression::ast::{BinaryOp, Expr, ExprVar, UnaryOp};
+    use crate::data::expression::ast::{BinaryOp, Expr, ExprVar, Function, UnaryOp};
     use crate::data::expression::grammar;
     use crate::data::expression::Value;
 
@@ -252,4 +269,78 @@ mod test {
         let res = grammar::expression("$0").unwrap();
         check_variable(res, ExprVar::Param(0));
     }
+
+    #[test]
+    fn test_parse_param_expression() {
+        let res = grammar::expression("3+$1*0.5").unwrap();
+        assert_eq!(res.to_string(), "3+$1*0.5");
+
+        if let Expr::Binary {
+            op: BinaryOp::Add,
+            lhs,
+            rhs,
+        } = res
+        {
+            check_literal_ref(lhs.as_ref(), 3.);
+            if let Expr::Binary {
+                op: BinaryOp::Mul,
+                lhs,
+                rhs,
+            } = *rhs
+            {
+                check_variable(*lhs, ExprVar::Param(1));
+                check_literal_ref(rhs.as_ref(), 0.5);
+            } else {
+                panic!("did not parse a multiplication: {:?}", rhs);
+            }
+        } else {
+            panic!("did not parse an addition: {:?}", res);
+        }
+    }
+
+    #[test]
+    fn test_parse_param_multiple_digits() {
+        let res = grammar::expression("$12").unwrap();
+        check_variable(res, ExprVar::Param(12));
+    }
+
+    #[test]
+    fn test_parse_function() {
+        let res = grammar::expression("sin($rank)").unwrap();
+        if let Expr::Call {
+            func,
+            args,
+        } = res
+        {
+            assert_eq!(func, Function::Sin);
+            assert_eq!(args.len(), 1);
+        } else {
+            panic!("did not parse a function call: {:?}", res);
+        }
+    }
+
+    #[test]
+    fn test_parse_function_arguments() {
+        let res = grammar::expression("max( 1 , $2*3 )+1").unwrap();
+        assert_eq!(res.to_string(), "max(1,$2*3)+1");
+    }
+
+    #[test]
+    fn test_parse_function_arity_fail() {
+        grammar::expression("sin()").unwrap_err();
+        grammar::expression("sin(1, 2)").unwrap_err();
+        grammar::expression("max(1)").unwrap_err();
+    }
+
+    #[test]
+    fn test_parse_function_unknown_fail() {
+        let err = grammar::expression("log(1)").unwrap_err();
+        assert_eq!(err.location.offset, 0);
+    }
+
+    #[test]
+    fn test_parse_param_overflow_fail() {
+        // Out-of-range indices are an error rather than a panic.
+        grammar::expression("$99999999999999999999999").unwrap_err();
+    }
 }
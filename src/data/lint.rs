@@ -0,0 +1,430 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use std::collections::hash_map::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::data::prune::is_top_label;
+use crate::data::{
+    Action, Bullet, BulletML, Change, DirectionKind, Element, EntityLookup, EntityRef, ErrorCode,
+    Fire, Step,
+};
+
+/// The element of a fire which uses a `sequence` type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceElement {
+    /// The `<direction>` of the bullet.
+    Direction,
+    /// The `<speed>` of the bullet.
+    Speed,
+}
+
+impl SequenceElement {
+    fn name(self) -> &'static str {
+        match self {
+            SequenceElement::Direction => "direction",
+            SequenceElement::Speed => "speed",
+        }
+    }
+}
+
+/// A `sequence` direction or speed which may be used before any bullet has been fired.
+///
+/// Without a previous bullet, there is nothing for the sequence to be relative to. This crate
+/// aims at the target and uses a speed of `1`, but other engines differ.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SequenceWarning {
+    /// The label of the innermost labeled action containing the fire.
+    pub action: Option<String>,
+    /// The label of the fire.
+    pub fire: Option<String>,
+    /// The element using the `sequence` type.
+    pub element: SequenceElement,
+}
+
+impl SequenceWarning {
+    /// The code for the warning.
+    pub fn code(&self) -> ErrorCode {
+        ErrorCode::SequenceWithoutPrevious
+    }
+}
+
+impl fmt::Display for SequenceWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: sequence {} may be used before any bullet is fired",
+            self.code(),
+            self.element.name(),
+        )?;
+        if let Some(ref fire) = self.fire {
+            write!(f, " by fire `{}`", fire)?;
+        }
+        if let Some(ref action) = self.action {
+            write!(f, " in action `{}`", action)?;
+        }
+        Ok(())
+    }
+}
+
+/// Every labeled entity of a document, including nested ones.
+#[derive(Debug, Default)]
+struct Library {
+    actions: HashMap<String, Rc<Action>>,
+    bullets: HashMap<String, Rc<Bullet>>,
+    fires: HashMap<String, Rc<Fire>>,
+}
+
+impl Library {
+    fn new(bulletml: &BulletML) -> Self {
+        let mut library = Self::default();
+
+        bulletml.elements.iter().for_each(|element| {
+            match *element {
+                Element::Action(ref action) => library.action(action),
+                Element::Bullet(ref bullet) => library.bullet(bullet),
+                Element::Fire(ref fire) => library.fire(fire),
+            }
+        });
+
+        library
+    }
+
+    fn define<T>(entities: &mut HashMap<String, Rc<T>>, label: &Option<String>, entity: &Rc<T>) {
+        if let Some(label) = label {
+            entities
+                .entry(label.clone())
+                .or_insert_with(|| Rc::clone(entity));
+        }
+    }
+
+    fn entity<T, F>(&mut self, entity: &EntityRef<T>, f: F)
+    where
+        F: FnOnce(&mut Self, &Rc<T>),
+    {
+        if let EntityRef::Real(ref real) = *entity {
+            f(self, real)
+        }
+    }
+
+    fn action(&mut self, action: &Rc<Action>) {
+        Self::define(&mut self.actions, &action.label, action);
+
+        action.steps.iter().for_each(|step| {
+            match *step {
+                Step::Repeat(ref repeat) => {
+                    repeat
+                        .actions
+                        .iter()
+                        .for_each(|action| self.entity(action, Self::action))
+                },
+                Step::Fire(ref fire) => self.entity(fire, Self::fire),
+                Step::Action(ref action) => self.entity(action, Self::action),
+                _ => (),
+            }
+        })
+    }
+
+    fn bullet(&mut self, bullet: &Rc<Bullet>) {
+        Self::define(&mut self.bullets, &bullet.label, bullet);
+
+        bullet
+            .actions
+            .iter()
+            .for_each(|action| self.entity(action, Self::action))
+    }
+
+    fn fire(&mut self, fire: &Rc<Fire>) {
+        Self::define(&mut self.fires, &fire.label, fire);
+        self.entity(&fire.bullet, Self::bullet)
+    }
+}
+
+impl EntityLookup<Action> for Library {
+    fn find(&self, name: &str) -> Option<Rc<Action>> {
+        self.actions.get(name).cloned()
+    }
+}
+
+impl EntityLookup<Bullet> for Library {
+    fn find(&self, name: &str) -> Option<Rc<Bullet>> {
+        self.bullets.get(name).cloned()
+    }
+}
+
+impl EntityLookup<Fire> for Library {
+    fn find(&self, name: &str) -> Option<Rc<Fire>> {
+        self.fires.get(name).cloned()
+    }
+}
+
+/// A walk over the steps of runners which tracks whether a bullet has been fired.
+struct SequenceCheck<'a> {
+    library: &'a Library,
+    /// The actions being walked, innermost last.
+    stack: Vec<Rc<Action>>,
+    /// Bullets whose actions have been or will be walked.
+    bullets: Vec<Rc<Bullet>>,
+    warnings: Vec<SequenceWarning>,
+}
+
+impl<'a> SequenceCheck<'a> {
+    fn warn(&mut self, fire: &Fire, element: SequenceElement) {
+        let action = self
+            .stack
+            .iter()
+            .rev()
+            .find_map(|action| action.label.clone());
+
+        self.warnings.push(SequenceWarning {
+            action,
+            fire: fire.label.clone(),
+            element,
+        })
+    }
+
+    /// Walk an action.
+    ///
+    /// Returns whether a bullet has been fired once the action completes. Repeats are assumed to
+    /// run at least once.
+    fn action(&mut self, action: &EntityRef<Action>, mut fired: bool) -> bool {
+        // Unknown references are reported when compiling.
+        let action = match action.entity(self.library) {
+            Ok(action) => action,
+            Err(_) => return fired,
+        };
+        // Recursive actions never complete.
+        if self.stack.iter().any(|outer| Rc::ptr_eq(outer, &action)) {
+            return fired;
+        }
+
+        self.stack.push(Rc::clone(&action));
+        for step in &action.steps {
+            fired = match *step {
+                Step::Repeat(ref repeat) => {
+                    repeat
+                        .actions
+                        .iter()
+                        .fold(fired, |fired, action| self.action(action, fired))
+                },
+                Step::Fire(ref fire) => self.fire(fire, fired),
+                Step::Action(ref action) => self.action(action, fired),
+                _ => fired,
+            };
+        }
+        self.stack.pop();
+
+        fired
+    }
+
+    fn fire(&mut self, fire: &EntityRef<Fire>, fired: bool) -> bool {
+        // A fire which may not be resolved is assumed to fire.
+        let fire = match fire.entity(self.library) {
+            Ok(fire) => fire,
+            Err(_) => return true,
+        };
+        let bullet = match fire.bullet.entity(self.library) {
+            Ok(bullet) => bullet,
+            Err(_) => return true,
+        };
+
+        if !fired {
+            // The bullet's elements take precedence over those of the fire.
+            let direction = bullet
+                .direction
+                .as_ref()
+                .or_else(|| fire.direction.as_ref());
+            if let Some(DirectionKind::Sequence) = direction.map(|direction| direction.kind) {
+                self.warn(&fire, SequenceElement::Direction);
+            }
+            let speed = bullet.speed.as_ref().or_else(|| fire.speed.as_ref());
+            if let Some(Change::Sequence) = speed.map(|speed| speed.kind) {
+                self.warn(&fire, SequenceElement::Speed);
+            }
+        }
+
+        if !self.bullets.iter().any(|seen| Rc::ptr_eq(seen, &bullet)) {
+            self.bullets.push(bullet);
+        }
+
+        true
+    }
+}
+
+impl BulletML {
+    /// Find `sequence` directions and speeds which may be used before any bullet is fired.
+    ///
+    /// The top actions share a runner, as do the actions of each fired bullet. Each runner starts
+    /// without a previous bullet, so a `sequence` on its first fire has nothing to be relative to
+    /// and its behavior depends on the engine.
+    pub fn sequence_warnings(&self) -> Vec<SequenceWarning> {
+        let library = Library::new(self);
+        let mut check = SequenceCheck {
+            library: &library,
+            stack: Vec::new(),
+            bullets: Vec::new(),
+            warnings: Vec::new(),
+        };
+
+        self.elements
+            .iter()
+            .filter_map(|element| {
+                match *element {
+                    Element::Action(ref action) => Some(action),
+                    _ => None,
+                }
+            })
+            .filter(|action| action.label.as_deref().map_or(false, is_top_label))
+            .fold(false, |fired, action| {
+                check.action(&EntityRef::Real(Rc::clone(action)), fired)
+            });
+
+        // Walking the actions of a bullet may find more bullets.
+        let mut idx = 0;
+        while let Some(bullet) = check.bullets.get(idx).cloned() {
+            bullet
+                .actions
+                .iter()
+                .fold(false, |fired, action| check.action(action, fired));
+            idx += 1;
+        }
+
+        check.warnings
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::data::{BulletML, ErrorCode, SequenceElement, SequenceWarning};
+
+    fn warnings(doc: &str) -> Vec<SequenceWarning> {
+        let bulletml: BulletML = serde_xml_rs::from_str(doc).unwrap();
+        bulletml.sequence_warnings()
+    }
+
+    #[test]
+    fn test_sequence_first_fire() {
+        let warnings = warnings(
+            r#"<bulletml>
+                <action label="top">
+                    <fire label="first">
+                        <direction type="sequence">10</direction>
+                        <speed type="sequence">1</speed>
+                        <bullet/>
+                    </fire>
+                </action>
+            </bulletml>"#,
+        );
+
+        assert_eq!(
+            warnings,
+            [
+                SequenceWarning {
+                    action: Some("top".into()),
+                    fire: Some("first".into()),
+                    element: SequenceElement::Direction,
+                },
+                SequenceWarning {
+                    action: Some("top".into()),
+                    fire: Some("first".into()),
+                    element: SequenceElement::Speed,
+                },
+            ],
+        );
+        assert_eq!(warnings[0].code(), ErrorCode::SequenceWithoutPrevious);
+        assert_eq!(
+            warnings[0].to_string(),
+            "BML4001: sequence direction may be used before any bullet is fired by fire `first` \
+             in action `top`",
+        );
+    }
+
+    #[test]
+    fn test_sequence_after_fire() {
+        let warnings = warnings(
+            r#"<bulletml>
+                <action label="top">
+                    <fire>
+                        <direction>0</direction>
+                        <bullet/>
+                    </fire>
+                    <repeat>
+                        <times>10</times>
+                        <action>
+                            <fireRef label="ring"/>
+                        </action>
+                    </repeat>
+                </action>
+                <fire label="ring">
+                    <direction type="sequence">36</direction>
+                    <bullet/>
+                </fire>
+            </bulletml>"#,
+        );
+
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_sequence_in_repeat() {
+        let warnings = warnings(
+            r#"<bulletml>
+                <action label="top">
+                    <repeat>
+                        <times>10</times>
+                        <actionRef label="ring"/>
+                    </repeat>
+                </action>
+                <action label="ring">
+                    <fire>
+                        <bulletRef label="shot"/>
+                    </fire>
+                </action>
+                <bullet label="shot">
+                    <speed type="sequence">0.1</speed>
+                </bullet>
+            </bulletml>"#,
+        );
+
+        assert_eq!(
+            warnings,
+            [SequenceWarning {
+                action: Some("ring".into()),
+                fire: None,
+                element: SequenceElement::Speed,
+            }],
+        );
+    }
+
+    #[test]
+    fn test_sequence_bullet_runner() {
+        // Fired bullets start their own sequences.
+        let warnings = warnings(
+            r#"<bulletml>
+                <action label="top">
+                    <fire>
+                        <direction>0</direction>
+                        <bullet>
+                            <action label="split">
+                                <fire>
+                                    <direction type="sequence">90</direction>
+                                    <bullet/>
+                                </fire>
+                            </action>
+                        </bullet>
+                    </fire>
+                </action>
+            </bulletml>"#,
+        );
+
+        assert_eq!(
+            warnings,
+            [SequenceWarning {
+                action: Some("split".into()),
+                fire: None,
+                element: SequenceElement::Direction,
+            }],
+        );
+    }
+}
@@ -0,0 +1,44 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use std::fmt;
+use std::ops::{Add, Div, Mul, Sub};
+
+/// Floating point types which may be used for values which change over time.
+///
+/// This allows code interpolating values to be written once for both `f32` and `f64`.
+pub trait Numeric:
+    Copy
+    + PartialOrd
+    + fmt::Debug
+    + fmt::Display
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+{
+    /// Convert a number of frames into a value.
+    fn from_frames(frames: u32) -> Self;
+    /// Convert a fraction computed in double precision into a value.
+    fn from_fraction(fraction: f64) -> Self;
+}
+
+impl Numeric for f32 {
+    fn from_frames(frames: u32) -> Self {
+        frames as f32
+    }
+
+    fn from_fraction(fraction: f64) -> Self {
+        fraction as f32
+    }
+}
+
+impl Numeric for f64 {
+    fn from_frames(frames: u32) -> Self {
+        frames.into()
+    }
+
+    fn from_fraction(fraction: f64) -> Self {
+        fraction
+    }
+}
@@ -0,0 +1,171 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use std::cell::RefCell;
+
+use crate::data::{CustomSteps, ExpressionLimits};
+
+/// The dialect of BulletML to accept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    /// Accept the extensions supported by this crate.
+    Extended,
+    /// Accept only what the BulletML specification defines.
+    ///
+    /// Variable names in expressions are limited to lowercase letters and digits.
+    Spec,
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Dialect::Extended
+    }
+}
+
+/// The elements defined by the BulletML specification.
+const ELEMENT_NAMES: &[&str] = &[
+    "bulletml",
+    "bullet",
+    "action",
+    "fire",
+    "changeDirection",
+    "changeSpeed",
+    "accel",
+    "wait",
+    "vanish",
+    "repeat",
+    "direction",
+    "speed",
+    "horizontal",
+    "vertical",
+    "term",
+    "times",
+    "bulletRef",
+    "actionRef",
+    "fireRef",
+    "param",
+];
+
+/// The attributes defined by the BulletML specification.
+const SPEC_ATTRIBUTE_NAMES: &[(&str, &str)] = &[
+    ("bulletml", "type"),
+    ("bullet", "label"),
+    ("action", "label"),
+    ("fire", "label"),
+    ("direction", "type"),
+    ("speed", "type"),
+    ("horizontal", "type"),
+    ("vertical", "type"),
+    ("bulletRef", "label"),
+    ("actionRef", "label"),
+    ("fireRef", "label"),
+];
+
+/// The attributes accepted by this crate.
+const EXTENDED_ATTRIBUTE_NAMES: &[(&str, &str)] = &[
+    ("bulletml", "type"),
+    ("bullet", "label"),
+    ("bullet", "ttl"),
+    ("action", "label"),
+    ("action", "ttl"),
+    ("action", "tags"),
+    ("fire", "label"),
+    ("direction", "type"),
+    ("direction", "lead"),
+    ("changeDirection", "easing"),
+    ("changeSpeed", "easing"),
+    ("accel", "easing"),
+    ("speed", "type"),
+    ("horizontal", "type"),
+    ("vertical", "type"),
+    ("bulletRef", "label"),
+    ("actionRef", "label"),
+    ("fireRef", "label"),
+];
+
+impl Dialect {
+    /// The names of the elements recognized in the dialect.
+    ///
+    /// Custom steps registered with `ParseOptions::custom_steps` are not included.
+    pub fn element_names(self) -> &'static [&'static str] {
+        ELEMENT_NAMES
+    }
+
+    /// The attributes recognized in the dialect as `(element, attribute)` pairs.
+    pub fn attribute_names(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Dialect::Extended => EXTENDED_ATTRIBUTE_NAMES,
+            Dialect::Spec => SPEC_ATTRIBUTE_NAMES,
+        }
+    }
+
+    /// Whether an element is recognized in the dialect.
+    pub fn is_element(self, element: &str) -> bool {
+        self.element_names().contains(&element)
+    }
+
+    /// Whether an attribute of an element is recognized in the dialect.
+    pub fn is_attribute(self, element: &str, attribute: &str) -> bool {
+        self.attribute_names()
+            .iter()
+            .any(|&(e, a)| e == element && a == attribute)
+    }
+}
+
+/// Options for parsing BulletML documents.
+///
+/// Since deserialization is driven by `serde`, options take effect for deserialization performed
+/// within `ParseOptions::scope`.
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Reject unexpected elements rather than ignoring them.
+    pub strict: bool,
+    /// The dialect of BulletML to accept.
+    pub dialect: Dialect,
+    /// Custom step elements to recognize within actions.
+    pub custom_steps: CustomSteps,
+    /// Limits on the expressions within the document.
+    pub expression_limits: ExpressionLimits,
+}
+
+thread_local! {
+    static ACTIVE: RefCell<Option<ParseOptions>> = RefCell::new(None);
+}
+
+struct ScopeGuard {
+    previous: Option<ParseOptions>,
+}
+
+impl Drop for ScopeGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        ACTIVE.with(|active| active.replace(previous));
+    }
+}
+
+impl ParseOptions {
+    /// Deserialize within a scope where these options are in effect.
+    pub fn scope<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let _guard = ScopeGuard {
+            previous: ACTIVE.with(|active| active.replace(Some(self.clone()))),
+        };
+
+        f()
+    }
+
+    pub(crate) fn with_active<F, R>(f: F) -> R
+    where
+        F: FnOnce(&Self) -> R,
+    {
+        ACTIVE.with(|active| {
+            if let Some(options) = active.borrow().as_ref() {
+                f(options)
+            } else {
+                f(&Self::default())
+            }
+        })
+    }
+}
@@ -0,0 +1,239 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use std::collections::hash_map::HashMap;
+use std::mem;
+
+use crate::data::{Action, Bullet, BulletML, Element, EntityRef, Fire, Step};
+
+/// Whether an action label marks an action which is run by the script.
+pub(crate) fn is_top_label(label: &str) -> bool {
+    label.starts_with("top")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    Action,
+    Bullet,
+    Fire,
+}
+
+/// The labels defined and referenced within a top-level element.
+#[derive(Debug, Default)]
+struct Labels<'a> {
+    defined: Vec<(Kind, &'a str)>,
+    referenced: Vec<(Kind, &'a str)>,
+}
+
+impl<'a> Labels<'a> {
+    fn new(element: &'a Element) -> Self {
+        let mut labels = Self::default();
+
+        match *element {
+            Element::Action(ref action) => labels.action(action),
+            Element::Bullet(ref bullet) => labels.bullet(bullet),
+            Element::Fire(ref fire) => labels.fire(fire),
+        }
+
+        labels
+    }
+
+    fn define(&mut self, kind: Kind, label: &'a Option<String>) {
+        if let Some(label) = label {
+            self.defined.push((kind, label.as_str()));
+        }
+    }
+
+    fn entity<T, F>(&mut self, kind: Kind, entity: &'a EntityRef<T>, f: F)
+    where
+        F: FnOnce(&mut Self, &'a T),
+    {
+        match *entity {
+            EntityRef::Ref(ref refer) => self.referenced.push((kind, refer.label())),
+            EntityRef::Real(ref real) => f(self, real),
+        }
+    }
+
+    fn action(&mut self, action: &'a Action) {
+        self.define(Kind::Action, &action.label);
+
+        action.steps.iter().for_each(|step| {
+            match *step {
+                Step::Repeat(ref repeat) => {
+                    repeat
+                        .actions
+                        .iter()
+                        .for_each(|action| self.entity(Kind::Action, action, Self::action))
+                },
+                Step::Fire(ref fire) => self.entity(Kind::Fire, fire, Self::fire),
+                Step::Action(ref action) => self.entity(Kind::Action, action, Self::action),
+                _ => (),
+            }
+        })
+    }
+
+    fn bullet(&mut self, bullet: &'a Bullet) {
+        self.define(Kind::Bullet, &bullet.label);
+
+        bullet
+            .actions
+            .iter()
+            .for_each(|action| self.entity(Kind::Action, action, Self::action))
+    }
+
+    fn fire(&mut self, fire: &'a Fire) {
+        self.define(Kind::Fire, &fire.label);
+        self.entity(Kind::Bullet, &fire.bullet, Self::bullet)
+    }
+}
+
+impl BulletML {
+    /// Remove top-level elements which may not be reached from the top actions.
+    ///
+    /// Returns the number of elements which were removed.
+    pub fn prune(&mut self) -> usize {
+        self.prune_with(is_top_label)
+    }
+
+    /// Remove top-level elements which may not be reached from the actions whose labels match a
+    /// predicate.
+    ///
+    /// Elements which define a reachable label are kept as a whole, even if the label is nested
+    /// within them. Returns the number of elements which were removed.
+    pub fn prune_with<F>(&mut self, is_entry: F) -> usize
+    where
+        F: Fn(&str) -> bool,
+    {
+        let reachable = {
+            let labels = self.elements.iter().map(Labels::new).collect::<Vec<_>>();
+
+            let mut definitions = HashMap::<_, Vec<_>>::new();
+            labels.iter().enumerate().for_each(|(idx, element_labels)| {
+                element_labels
+                    .defined
+                    .iter()
+                    .for_each(|&label| definitions.entry(label).or_default().push(idx))
+            });
+
+            let mut reachable = vec![false; self.elements.len()];
+            let mut queue = self
+                .elements
+                .iter()
+                .enumerate()
+                .filter(|&(_, element)| {
+                    match *element {
+                        Element::Action(ref action) => {
+                            action.label.as_ref().map_or(false, |label| is_entry(label))
+                        },
+                        _ => false,
+                    }
+                })
+                .map(|(idx, _)| idx)
+                .collect::<Vec<_>>();
+
+            while let Some(idx) = queue.pop() {
+                if reachable[idx] {
+                    continue;
+                }
+                reachable[idx] = true;
+
+                labels[idx]
+                    .referenced
+                    .iter()
+                    .filter_map(|label| definitions.get(label))
+                    .for_each(|defs| queue.extend(defs))
+            }
+
+            reachable
+        };
+
+        let count = self.elements.len();
+        self.elements = mem::take(&mut self.elements)
+            .into_iter()
+            .zip(reachable)
+            .filter(|&(_, reachable)| reachable)
+            .map(|(element, _)| element)
+            .collect();
+
+        count - self.elements.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::data::{BulletML, Element};
+
+    const LIBRARY: &str = r#"<bulletml>
+        <action label="top">
+            <fire><bulletRef label="used"/></fire>
+        </action>
+        <fire label="container">
+            <bullet>
+                <action label="nested">
+                    <vanish/>
+                </action>
+            </bullet>
+        </fire>
+        <bullet label="used">
+            <actionRef label="nested"/>
+        </bullet>
+        <bullet label="unused">
+            <actionRef label="also_unused"/>
+        </bullet>
+        <action label="also_unused">
+            <fireRef label="container"/>
+        </action>
+        <action>
+            <vanish/>
+        </action>
+    </bulletml>"#;
+
+    fn labels(bulletml: &BulletML) -> Vec<&str> {
+        bulletml
+            .elements
+            .iter()
+            .map(|element| {
+                match *element {
+                    Element::Action(ref action) => action.label.as_ref(),
+                    Element::Bullet(ref bullet) => bullet.label.as_ref(),
+                    Element::Fire(ref fire) => fire.label.as_ref(),
+                }
+                .map_or("", String::as_str)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_prune() {
+        let mut bulletml: BulletML = serde_xml_rs::from_str(LIBRARY).unwrap();
+
+        assert_eq!(bulletml.prune(), 3);
+        assert_eq!(labels(&bulletml), ["top", "container", "used"]);
+        assert_eq!(bulletml.prune(), 0);
+    }
+
+    #[test]
+    fn test_prune_with() {
+        let mut bulletml: BulletML = serde_xml_rs::from_str(LIBRARY).unwrap();
+
+        assert_eq!(bulletml.prune_with(|label| label == "also_unused"), 4);
+        assert_eq!(labels(&bulletml), ["container", "also_unused"]);
+    }
+
+    #[test]
+    fn test_prune_empty() {
+        let mut bulletml: BulletML = serde_xml_rs::from_str(LIBRARY).unwrap();
+
+        assert_eq!(bulletml.prune_with(|_| false), 6);
+        assert!(bulletml.elements.is_empty());
+    }
+
+    #[cfg(feature = "runtime")]
+    #[test]
+    fn test_prune_compile() {
+        let mut bulletml: BulletML = serde_xml_rs::from_str(LIBRARY).unwrap();
+        bulletml.prune();
+
+        crate::run::CompiledBulletML::new(bulletml).unwrap();
+    }
+}
@@ -0,0 +1,615 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use std::collections::hash_map::{Entry, HashMap};
+use std::fmt;
+
+use crate::data::code::ErrorCode;
+use crate::data::data::{Action, Bullet, BulletML, Element, EntityRef, Fire, Reference, Step};
+use crate::data::expression::Expression;
+
+/// How serious a diagnostic is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// The document works, but likely not as intended.
+    Warning,
+    /// The document may not be compiled or run.
+    Error,
+}
+
+/// A problem found while validating a document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// How serious the problem is.
+    pub severity: Severity,
+    /// The code for the problem.
+    pub code: ErrorCode,
+    /// The path to the element with the problem.
+    ///
+    /// Paths are written in XPath syntax. Labeled elements are selected by their label and
+    /// unlabeled elements by their position among siblings of the same name (e.g.,
+    /// `/bulletml/action[@label="top"]/repeat[1]/times`).
+    pub path: String,
+    /// A description of the problem.
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} (at {})", self.code, self.message, self.path)
+    }
+}
+
+/// The kinds of labeled entities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    Action,
+    Bullet,
+    Fire,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Action => "action",
+            Kind::Bullet => "bullet",
+            Kind::Fire => "fire",
+        }
+    }
+
+    fn ref_name(self) -> &'static str {
+        match self {
+            Kind::Action => "actionRef",
+            Kind::Bullet => "bulletRef",
+            Kind::Fire => "fireRef",
+        }
+    }
+}
+
+/// A labeled entity.
+struct Definition {
+    path: String,
+    /// The highest parameter used by the entity.
+    params: usize,
+}
+
+/// A reference to a labeled entity.
+struct Use {
+    kind: Kind,
+    label: String,
+    path: String,
+    /// The number of parameters given by the reference.
+    params: usize,
+}
+
+/// The path to a child element.
+///
+/// Labeled elements are selected by their label and others by their position among siblings of
+/// the same name, starting at 1.
+pub(crate) fn child_path(parent: &str, name: &str, label: Option<&str>, position: usize) -> String {
+    if let Some(label) = label {
+        format!("{}/{}[@label=\"{}\"]", parent, name, label)
+    } else {
+        format!("{}/{}[{}]", parent, name, position)
+    }
+}
+
+/// Paths to the children of an element.
+struct Children<'a> {
+    parent: &'a str,
+    counts: HashMap<&'static str, usize>,
+}
+
+impl<'a> Children<'a> {
+    fn new(parent: &'a str) -> Self {
+        Children {
+            parent,
+            counts: HashMap::new(),
+        }
+    }
+
+    /// The path to the next child with a name.
+    fn next(&mut self, name: &'static str, label: Option<&str>) -> String {
+        let position = self.counts.entry(name).or_insert(0);
+        *position += 1;
+
+        child_path(self.parent, name, label, *position)
+    }
+
+    /// The path to a child which appears at most once.
+    fn only(&self, name: &str) -> String {
+        format!("{}/{}", self.parent, name)
+    }
+}
+
+/// The parameters used by an expression.
+///
+/// Expressions are inspected through their written form so that this works whether or not they
+/// have been parsed.
+fn params(expr: &Expression) -> Vec<usize> {
+    let source = expr.to_string();
+    source
+        .split('$')
+        .skip(1)
+        .filter_map(|rest| {
+            let name = rest
+                .split(|c: char| !c.is_ascii_alphanumeric())
+                .next()
+                .unwrap_or("");
+            if !name.is_empty() && name.bytes().all(|b| b.is_ascii_digit()) {
+                // Indices too large to represent are certainly not given.
+                Some(name.parse().unwrap_or(usize::MAX))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+#[derive(Default)]
+struct Validator {
+    definitions: HashMap<(Kind, String), Definition>,
+    uses: Vec<Use>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Validator {
+    fn report(&mut self, severity: Severity, code: ErrorCode, path: String, message: String) {
+        self.diagnostics.push(Diagnostic {
+            severity,
+            code,
+            path,
+            message,
+        })
+    }
+
+    /// Record a labeled entity.
+    ///
+    /// Returns whether the entity is the first with its label.
+    fn define(&mut self, kind: Kind, label: Option<&String>, path: &str) -> bool {
+        let label = if let Some(label) = label {
+            label
+        } else {
+            return false;
+        };
+
+        match self.definitions.entry((kind, label.clone())) {
+            Entry::Occupied(first) => {
+                let message = format!(
+                    "{} label `{}` is already used at {}",
+                    kind.name(),
+                    label,
+                    first.get().path,
+                );
+                self.report(
+                    Severity::Error,
+                    ErrorCode::DuplicateLabel,
+                    path.into(),
+                    message,
+                );
+                false
+            },
+            Entry::Vacant(entry) => {
+                entry.insert(Definition {
+                    path: path.into(),
+                    params: 0,
+                });
+                true
+            },
+        }
+    }
+
+    /// Record the parameters used by a labeled entity.
+    fn set_params(&mut self, kind: Kind, label: Option<&String>, params: usize) {
+        if let Some(label) = label {
+            if let Some(definition) = self.definitions.get_mut(&(kind, label.clone())) {
+                definition.params = params;
+            }
+        }
+    }
+
+    /// Check an expression.
+    ///
+    /// Returns the highest parameter it uses.
+    fn expression(&mut self, expr: &Expression, path: String) -> usize {
+        let params = params(expr);
+        if params.contains(&0) {
+            self.report(
+                Severity::Error,
+                ErrorCode::InvalidExpression,
+                path.clone(),
+                format!("`{}` uses `$0`, but parameters are numbered from `$1`", expr),
+            );
+        }
+        if let Some(value) = expr.constant_value() {
+            if !value.is_finite() {
+                self.report(
+                    Severity::Error,
+                    ErrorCode::InvalidExpression,
+                    path,
+                    format!("`{}` always evaluates to `{}`", expr, value),
+                );
+            }
+        }
+
+        params.into_iter().max().unwrap_or(0)
+    }
+
+    /// Check a reference or an inline entity.
+    ///
+    /// Returns the highest parameter of the enclosing entity it uses. Inline entities use the
+    /// parameters of the enclosing entity, while references only use them for the parameters
+    /// they give.
+    fn entity<T, F>(
+        &mut self,
+        entity: &EntityRef<T>,
+        kind: Kind,
+        children: &mut Children,
+        check: F,
+    ) -> usize
+    where
+        F: FnOnce(&mut Self, &T, &str) -> usize,
+    {
+        match *entity {
+            EntityRef::Real(ref real) => {
+                let path = children.next(kind.name(), None);
+                check(self, real, &path)
+            },
+            EntityRef::Ref(ref reference) => self.reference(reference, kind, children),
+        }
+    }
+
+    fn reference(&mut self, reference: &Reference, kind: Kind, children: &mut Children) -> usize {
+        let path = children.next(kind.ref_name(), Some(reference.label()));
+        let mut params = Children::new(&path);
+        let used = reference
+            .params()
+            .iter()
+            .map(|param| {
+                let path = params.next("param", None);
+                self.expression(param.value(), path)
+            })
+            .max()
+            .unwrap_or(0);
+
+        self.uses.push(Use {
+            kind,
+            label: reference.label().into(),
+            path,
+            params: reference.params().len(),
+        });
+
+        used
+    }
+
+    fn action(&mut self, action: &Action, path: &str) -> usize {
+        let defined = self.define(Kind::Action, action.label.as_ref(), path);
+        let mut children = Children::new(path);
+        let params = action
+            .steps
+            .iter()
+            .map(|step| self.step(step, &mut children))
+            .max()
+            .unwrap_or(0);
+
+        if defined {
+            self.set_params(Kind::Action, action.label.as_ref(), params);
+        }
+        params
+    }
+
+    fn step(&mut self, step: &Step, children: &mut Children) -> usize {
+        match *step {
+            Step::Repeat(ref repeat) => {
+                let path = children.next("repeat", None);
+                let mut repeat_children = Children::new(&path);
+                let times = self.expression(&repeat.times.value, repeat_children.only("times"));
+                repeat
+                    .actions
+                    .iter()
+                    .map(|action| {
+                        self.entity(action, Kind::Action, &mut repeat_children, Self::action)
+                    })
+                    .fold(times, usize::max)
+            },
+            Step::Fire(ref fire) => self.entity(fire, Kind::Fire, children, Self::fire),
+            Step::ChangeSpeed(ref cs) => {
+                let path = children.next("changeSpeed", None);
+                let inner = Children::new(&path);
+                let speed = self.expression(&cs.speed.change, inner.only("speed"));
+                let term = self.expression(&cs.value.value, inner.only("term"));
+                speed.max(term)
+            },
+            Step::ChangeDirection(ref cd) => {
+                let path = children.next("changeDirection", None);
+                let inner = Children::new(&path);
+                let direction = self.expression(&cd.direction.degrees, inner.only("direction"));
+                let term = self.expression(&cd.value.value, inner.only("term"));
+                direction.max(term)
+            },
+            Step::Accel(ref accel) => {
+                let path = children.next("accel", None);
+                let inner = Children::new(&path);
+                let horizontal = accel.horizontal.as_ref().map_or(0, |horizontal| {
+                    self.expression(&horizontal.change, inner.only("horizontal"))
+                });
+                let vertical = accel.vertical.as_ref().map_or(0, |vertical| {
+                    self.expression(&vertical.change, inner.only("vertical"))
+                });
+                let term = self.expression(&accel.duration.value, inner.only("term"));
+                horizontal.max(vertical).max(term)
+            },
+            Step::Wait(ref wait) => {
+                let path = children.next("wait", None);
+                self.expression(&wait.frames, path)
+            },
+            Step::Vanish(_) => {
+                children.next("vanish", None);
+                0
+            },
+            Step::Action(ref action) => self.entity(action, Kind::Action, children, Self::action),
+            // Custom steps are opaque.
+            Step::Custom(_) => 0,
+        }
+    }
+
+    fn bullet(&mut self, bullet: &Bullet, path: &str) -> usize {
+        let defined = self.define(Kind::Bullet, bullet.label.as_ref(), path);
+        let mut children = Children::new(path);
+        let direction = bullet.direction.as_ref().map_or(0, |direction| {
+            self.expression(&direction.degrees, children.only("direction"))
+        });
+        let speed = bullet
+            .speed
+            .as_ref()
+            .map_or(0, |speed| self.expression(&speed.change, children.only("speed")));
+        let params = bullet
+            .actions
+            .iter()
+            .map(|action| self.entity(action, Kind::Action, &mut children, Self::action))
+            .fold(direction.max(speed), usize::max);
+
+        if defined {
+            self.set_params(Kind::Bullet, bullet.label.as_ref(), params);
+        }
+        params
+    }
+
+    fn fire(&mut self, fire: &Fire, path: &str) -> usize {
+        let defined = self.define(Kind::Fire, fire.label.as_ref(), path);
+        let mut children = Children::new(path);
+        let direction = fire.direction.as_ref().map_or(0, |direction| {
+            self.expression(&direction.degrees, children.only("direction"))
+        });
+        let speed = fire
+            .speed
+            .as_ref()
+            .map_or(0, |speed| self.expression(&speed.change, children.only("speed")));
+        let bullet = self.entity(&fire.bullet, Kind::Bullet, &mut children, Self::bullet);
+        let params = direction.max(speed).max(bullet);
+
+        if defined {
+            self.set_params(Kind::Fire, fire.label.as_ref(), params);
+        }
+        params
+    }
+
+    fn check_uses(&mut self) {
+        for reference in std::mem::replace(&mut self.uses, Vec::new()) {
+            let key = (reference.kind, reference.label);
+            let used = if let Some(definition) = self.definitions.get(&key) {
+                definition.params
+            } else {
+                let message = format!("no {} is labeled `{}`", key.0.name(), key.1);
+                self.report(
+                    Severity::Error,
+                    ErrorCode::UnknownReference,
+                    reference.path,
+                    message,
+                );
+                continue;
+            };
+
+            let given = reference.params;
+            if given < used {
+                let message = format!(
+                    "{} `{}` uses `${}`, but only {} parameters are given",
+                    key.0.name(),
+                    key.1,
+                    used,
+                    given,
+                );
+                self.report(
+                    Severity::Error,
+                    ErrorCode::ParameterCount,
+                    reference.path,
+                    message,
+                );
+            } else if given > used {
+                let message = format!(
+                    "{} `{}` uses {} of the {} parameters given",
+                    key.0.name(),
+                    key.1,
+                    used,
+                    given,
+                );
+                self.report(
+                    Severity::Warning,
+                    ErrorCode::ParameterCount,
+                    reference.path,
+                    message,
+                );
+            }
+        }
+    }
+}
+
+/// Check a document for problems.
+///
+/// Unlike compiling, which stops at the first error, every problem which is found is reported.
+/// The document is checked for:
+///
+///   - labels which are used by more than one entity of the same kind;
+///   - references to labels which do not exist;
+///   - references which give fewer parameters than the entity uses (an error) or more (a
+///     warning); and
+///   - expressions which may never be evaluated successfully, such as those using `$0` or, with
+///     the `runtime` feature, those which always evaluate to an infinite or NaN value.
+///
+/// Diagnostics for references are reported after those for definitions and expressions.
+pub fn validate(bulletml: &BulletML) -> Vec<Diagnostic> {
+    let mut validator = Validator::default();
+    let mut children = Children::new("/bulletml");
+
+    for element in &bulletml.elements {
+        match *element {
+            Element::Action(ref action) => {
+                let path = children.next("action", action.label.as_deref());
+                validator.action(action, &path);
+            },
+            Element::Bullet(ref bullet) => {
+                let path = children.next("bullet", bullet.label.as_deref());
+                validator.bullet(bullet, &path);
+            },
+            Element::Fire(ref fire) => {
+                let path = children.next("fire", fire.label.as_deref());
+                validator.fire(fire, &path);
+            },
+        }
+    }
+
+    validator.check_uses();
+    validator.diagnostics
+}
+
+#[cfg(test)]
+mod test {
+    use crate::data::{self, ErrorCode, Severity};
+
+    fn validate(doc: &str) -> Vec<data::Diagnostic> {
+        let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+        data::validate(&bulletml)
+    }
+
+    #[test]
+    fn test_validate_clean() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <repeat>
+                    <times>3</times>
+                    <actionRef label="volley">
+                        <param>10</param>
+                    </actionRef>
+                </repeat>
+            </action>
+            <action label="volley">
+                <fire>
+                    <direction type="sequence">$1</direction>
+                    <bullet/>
+                </fire>
+            </action>
+        </bulletml>"#;
+
+        assert_eq!(validate(doc), []);
+    }
+
+    #[test]
+    fn test_validate_labels() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <fireRef label="missing"/>
+                <action label="top"/>
+            </action>
+            <bullet label="top"/>
+        </bulletml>"#;
+        let diagnostics = validate(doc);
+
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].code, ErrorCode::DuplicateLabel);
+        assert_eq!(
+            diagnostics[0].path,
+            r#"/bulletml/action[@label="top"]/action[1]"#,
+        );
+        assert_eq!(diagnostics[1].code, ErrorCode::UnknownReference);
+        assert_eq!(
+            diagnostics[1].to_string(),
+            concat!(
+                "BML1002: no fire is labeled `missing` ",
+                r#"(at /bulletml/action[@label="top"]/fireRef[@label="missing"])"#,
+            ),
+        );
+    }
+
+    #[test]
+    fn test_validate_params() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <actionRef label="aim">
+                    <param>1</param>
+                </actionRef>
+                <fireRef label="shot">
+                    <param>1</param>
+                    <param>2</param>
+                </fireRef>
+            </action>
+            <action label="aim">
+                <changeDirection>
+                    <direction>$2</direction>
+                    <term>$1</term>
+                </changeDirection>
+            </action>
+            <fire label="shot">
+                <bullet>
+                    <speed>$1</speed>
+                </bullet>
+            </fire>
+        </bulletml>"#;
+        let diagnostics = validate(doc);
+
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(diagnostics[0].code, ErrorCode::ParameterCount);
+        assert_eq!(
+            diagnostics[0].message,
+            "action `aim` uses `$2`, but only 1 parameters are given",
+        );
+        assert_eq!(diagnostics[1].severity, Severity::Warning);
+        assert_eq!(diagnostics[1].code, ErrorCode::ParameterCount);
+        assert_eq!(
+            diagnostics[1].path,
+            r#"/bulletml/action[@label="top"]/fireRef[@label="shot"]"#,
+        );
+    }
+
+    #[test]
+    fn test_validate_expressions() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <wait>$0</wait>
+                <repeat>
+                    <times>1 / 0</times>
+                    <action>
+                        <vanish/>
+                    </action>
+                </repeat>
+            </action>
+        </bulletml>"#;
+        let diagnostics = validate(doc);
+
+        assert!(diagnostics
+            .iter()
+            .all(|diagnostic| diagnostic.code == ErrorCode::InvalidExpression));
+        assert_eq!(
+            diagnostics[0].path,
+            r#"/bulletml/action[@label="top"]/wait[1]"#,
+        );
+        #[cfg(feature = "runtime")]
+        {
+            assert_eq!(diagnostics.len(), 2);
+            assert_eq!(
+                diagnostics[1].path,
+                r#"/bulletml/action[@label="top"]/repeat[1]/times"#,
+            );
+        }
+    }
+}
@@ -0,0 +1,566 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use std::fmt::{self, Write};
+
+use crate::data::{
+    Accel, Action, Bullet, BulletML, Change, ChangeDirection, ChangeSpeed, Direction,
+    DirectionKind, Easing, Element, EntityRef, Expression, Fire, Horizontal, NumberFormat,
+    Orientation, Reference, Repeat, Speed, Step, Vertical,
+};
+
+/// Escape text for use within XML content or attribute values.
+fn escape(text: &str) -> String {
+    text.chars().fold(String::with_capacity(text.len()), |mut out, c| {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+        out
+    })
+}
+
+pub(crate) fn orientation_name(orientation: Orientation) -> &'static str {
+    match orientation {
+        Orientation::None => "none",
+        Orientation::Vertical => "vertical",
+        Orientation::Horizontal => "horizontal",
+    }
+}
+
+pub(crate) fn direction_kind_name(kind: DirectionKind) -> &'static str {
+    match kind {
+        DirectionKind::Aim => "aim",
+        DirectionKind::Absolute => "absolute",
+        DirectionKind::Relative => "relative",
+        DirectionKind::Sequence => "sequence",
+    }
+}
+
+pub(crate) fn change_name(kind: Change) -> &'static str {
+    match kind {
+        Change::Absolute => "absolute",
+        Change::Relative => "relative",
+        Change::Sequence => "sequence",
+    }
+}
+
+pub(crate) fn easing_name(easing: Easing) -> &'static str {
+    match easing {
+        Easing::Linear => "linear",
+        Easing::EaseIn => "easeIn",
+        Easing::EaseOut => "easeOut",
+        Easing::EaseInOut => "easeInOut",
+        Easing::Sine => "sine",
+    }
+}
+
+/// Attributes of an element.
+type Attributes = Vec<(&'static str, String)>;
+
+fn easing_attributes(easing: Option<Easing>) -> Attributes {
+    easing
+        .map(|easing| ("easing", easing_name(easing).into()))
+        .into_iter()
+        .collect()
+}
+
+fn label_attributes(label: &Option<String>, ttl: Option<u32>, tags: &[String]) -> Attributes {
+    let mut attrs = Attributes::new();
+    if let Some(label) = label {
+        attrs.push(("label", label.clone()));
+    }
+    if let Some(ttl) = ttl {
+        attrs.push(("ttl", ttl.to_string()));
+    }
+    if !tags.is_empty() {
+        attrs.push(("tags", tags.join(",")));
+    }
+    attrs
+}
+
+/// A writer of indented XML.
+struct XmlWriter<'a, W> {
+    out: &'a mut W,
+    depth: usize,
+    format: NumberFormat,
+}
+
+impl<'a, W> XmlWriter<'a, W>
+where
+    W: Write,
+{
+    fn start(&mut self, name: &str, attrs: &[(&'static str, String)]) -> fmt::Result {
+        write!(self.out, "{:indent$}<{}", "", name, indent = 2 * self.depth)?;
+        for (attr, value) in attrs {
+            write!(self.out, " {}=\"{}\"", attr, escape(value))?;
+        }
+        Ok(())
+    }
+
+    fn empty(&mut self, name: &str, attrs: &[(&'static str, String)]) -> fmt::Result {
+        self.start(name, attrs)?;
+        writeln!(self.out, "/>")
+    }
+
+    fn text(&mut self, name: &str, attrs: &[(&'static str, String)], text: &str) -> fmt::Result {
+        self.start(name, attrs)?;
+        writeln!(self.out, ">{}</{}>", escape(text), name)
+    }
+
+    fn expression(
+        &mut self,
+        name: &str,
+        kind: Option<&'static str>,
+        expr: &Expression,
+    ) -> fmt::Result {
+        let attrs = kind
+            .map(|kind| vec![("type", kind.into())])
+            .unwrap_or_default();
+        self.text(name, &attrs, &expr.display_with(self.format).to_string())
+    }
+
+    fn element<F>(&mut self, name: &str, attrs: &[(&'static str, String)], f: F) -> fmt::Result
+    where
+        F: FnOnce(&mut Self) -> fmt::Result,
+    {
+        self.start(name, attrs)?;
+        writeln!(self.out, ">")?;
+        self.depth += 1;
+        f(self)?;
+        self.depth -= 1;
+        writeln!(
+            self.out,
+            "{:indent$}</{}>",
+            "",
+            name,
+            indent = 2 * self.depth
+        )
+    }
+
+    fn reference(&mut self, name: &str, refer: &Reference) -> fmt::Result {
+        let attrs = [("label", refer.label().into())];
+        if refer.params().is_empty() {
+            self.empty(name, &attrs)
+        } else {
+            self.element(name, &attrs, |w| {
+                refer
+                    .params()
+                    .iter()
+                    .try_for_each(|param| w.expression("param", None, param.value()))
+            })
+        }
+    }
+
+    fn entity<T, F>(&mut self, name: &str, entity: &EntityRef<T>, f: F) -> fmt::Result
+    where
+        F: FnOnce(&mut Self, &T) -> fmt::Result,
+    {
+        match *entity {
+            EntityRef::Ref(ref refer) => self.reference(&format!("{}Ref", name), refer),
+            EntityRef::Real(ref real) => f(self, real),
+        }
+    }
+
+    fn direction(&mut self, direction: &Direction) -> fmt::Result {
+        let mut attrs = vec![("type", direction_kind_name(direction.kind).into())];
+        if direction.lead {
+            attrs.push(("lead", "true".into()));
+        }
+        let degrees = direction.degrees.display_with(self.format).to_string();
+        self.text("direction", &attrs, &degrees)
+    }
+
+    fn speed(&mut self, speed: &Speed) -> fmt::Result {
+        self.expression("speed", Some(change_name(speed.kind)), &speed.change)
+    }
+
+    fn horizontal(&mut self, horizontal: &Horizontal) -> fmt::Result {
+        self.expression(
+            "horizontal",
+            Some(change_name(horizontal.kind)),
+            &horizontal.change,
+        )
+    }
+
+    fn vertical(&mut self, vertical: &Vertical) -> fmt::Result {
+        self.expression(
+            "vertical",
+            Some(change_name(vertical.kind)),
+            &vertical.change,
+        )
+    }
+
+    fn action(&mut self, action: &Action) -> fmt::Result {
+        let attrs = label_attributes(&action.label, action.ttl, &action.tags);
+        if action.steps.is_empty() {
+            return self.empty("action", &attrs);
+        }
+
+        self.element("action", &attrs, |w| {
+            action.steps.iter().try_for_each(|step| w.step(step))
+        })
+    }
+
+    fn bullet(&mut self, bullet: &Bullet) -> fmt::Result {
+        let attrs = label_attributes(&bullet.label, bullet.ttl, &[]);
+        if bullet.direction.is_none() && bullet.speed.is_none() && bullet.actions.is_empty() {
+            return self.empty("bullet", &attrs);
+        }
+
+        self.element("bullet", &attrs, |w| {
+            if let Some(ref direction) = bullet.direction {
+                w.direction(direction)?;
+            }
+            if let Some(ref speed) = bullet.speed {
+                w.speed(speed)?;
+            }
+            bullet
+                .actions
+                .iter()
+                .try_for_each(|action| w.entity("action", action, Self::action))
+        })
+    }
+
+    fn fire(&mut self, fire: &Fire) -> fmt::Result {
+        self.element("fire", &label_attributes(&fire.label, None, &[]), |w| {
+            if let Some(ref direction) = fire.direction {
+                w.direction(direction)?;
+            }
+            if let Some(ref speed) = fire.speed {
+                w.speed(speed)?;
+            }
+            w.entity("bullet", &fire.bullet, Self::bullet)
+        })
+    }
+
+    fn repeat(&mut self, repeat: &Repeat) -> fmt::Result {
+        self.element("repeat", &[], |w| {
+            w.expression("times", None, &repeat.times.value)?;
+            repeat
+                .actions
+                .iter()
+                .try_for_each(|action| w.entity("action", action, Self::action))
+        })
+    }
+
+    fn change_direction(&mut self, cd: &ChangeDirection) -> fmt::Result {
+        self.element("changeDirection", &easing_attributes(cd.easing), |w| {
+            w.direction(&cd.direction)?;
+            w.expression("term", None, &cd.value.value)
+        })
+    }
+
+    fn change_speed(&mut self, cs: &ChangeSpeed) -> fmt::Result {
+        self.element("changeSpeed", &easing_attributes(cs.easing), |w| {
+            w.speed(&cs.speed)?;
+            w.expression("term", None, &cs.value.value)
+        })
+    }
+
+    fn accel(&mut self, accel: &Accel) -> fmt::Result {
+        self.element("accel", &easing_attributes(accel.easing), |w| {
+            if let Some(ref horizontal) = accel.horizontal {
+                w.horizontal(horizontal)?;
+            }
+            if let Some(ref vertical) = accel.vertical {
+                w.vertical(vertical)?;
+            }
+            w.expression("term", None, &accel.duration.value)
+        })
+    }
+
+    fn step(&mut self, step: &Step) -> fmt::Result {
+        match *step {
+            Step::Repeat(ref repeat) => self.repeat(repeat),
+            Step::Fire(ref fire) => self.entity("fire", fire, Self::fire),
+            Step::ChangeSpeed(ref cs) => self.change_speed(cs),
+            Step::ChangeDirection(ref cd) => self.change_direction(cd),
+            Step::Accel(ref accel) => self.accel(accel),
+            Step::Wait(ref wait) => self.expression("wait", None, &wait.frames),
+            Step::Vanish(_) => self.empty("vanish", &[]),
+            Step::Action(ref action) => self.entity("action", action, Self::action),
+            Step::Custom(ref custom) => self.text(custom.name(), &[], &custom.content()),
+        }
+    }
+
+    fn bulletml(&mut self, bulletml: &BulletML) -> fmt::Result {
+        writeln!(self.out, r#"<?xml version="1.0" ?>"#)?;
+
+        let attrs = [("type", orientation_name(bulletml.orientation).into())];
+        self.element("bulletml", &attrs, |w| {
+            bulletml.elements.iter().try_for_each(|element| {
+                match *element {
+                    Element::Bullet(ref bullet) => w.bullet(bullet),
+                    Element::Action(ref action) => w.action(action),
+                    Element::Fire(ref fire) => w.fire(fire),
+                }
+            })
+        })
+    }
+}
+
+impl BulletML {
+    /// Write the document as XML.
+    ///
+    /// Expressions are written in a normalized form, so the output may not match the original
+    /// document exactly.
+    pub fn write_xml<W>(&self, out: &mut W) -> fmt::Result
+    where
+        W: Write,
+    {
+        self.write_xml_with(out, NumberFormat::default())
+    }
+
+    /// Write the document as XML with numbers in a given format.
+    ///
+    /// This allows written documents to match the conventions of hand-written documents so that
+    /// they may be compared against them.
+    pub fn write_xml_with<W>(&self, out: &mut W, format: NumberFormat) -> fmt::Result
+    where
+        W: Write,
+    {
+        XmlWriter {
+            out,
+            depth: 0,
+            format,
+        }
+        .bulletml(self)
+    }
+
+    /// The document as XML.
+    pub fn to_xml(&self) -> String {
+        self.to_xml_with(NumberFormat::default())
+    }
+
+    /// The document as XML with numbers in a given format.
+    pub fn to_xml_with(&self, format: NumberFormat) -> String {
+        let mut out = String::new();
+        // Writing to a `String` does not fail.
+        let _ = self.write_xml_with(&mut out, format);
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::data::{
+        Action, Bullet, BulletML, ChangeSpeed, Direction, DirectionKind, Element, EntityRef, Fire,
+        NumberFormat, Reference, Repeat, Speed, Step, Term, Times, Wait,
+    };
+
+    const DOC: &str = r#"<?xml version="1.0" ?>
+<bulletml type="vertical">
+  <bullet label="shot" ttl="60">
+    <speed type="absolute">2</speed>
+    <action>
+      <changeSpeed>
+        <speed type="sequence">0.5</speed>
+        <term>10</term>
+      </changeSpeed>
+      <accel easing="easeOut">
+        <horizontal type="relative">$1</horizontal>
+        <term>5</term>
+      </accel>
+    </action>
+  </bullet>
+  <fire label="aimed">
+    <direction type="aim" lead="true">$rand*10</direction>
+    <bulletRef label="shot"/>
+  </fire>
+  <action label="top">
+    <repeat>
+      <times>4+$rank*4</times>
+      <action>
+        <fireRef label="aimed"/>
+        <changeDirection easing="sine">
+          <direction type="relative">-30</direction>
+          <term>2</term>
+        </changeDirection>
+        <wait>(2+$rank)*3</wait>
+      </action>
+    </repeat>
+    <actionRef label="finish">
+      <param>$rank</param>
+    </actionRef>
+  </action>
+  <action label="finish" ttl="1" tags="hard,boss">
+    <vanish/>
+  </action>
+</bulletml>
+"#;
+
+    #[test]
+    fn test_write_round_trip() {
+        let bulletml: BulletML = serde_xml_rs::from_str(DOC).unwrap();
+        let xml = bulletml.to_xml();
+
+        #[cfg(feature = "runtime")]
+        assert_eq!(xml, DOC);
+
+        let reparsed: BulletML = serde_xml_rs::from_str(&xml).unwrap();
+        assert_eq!(reparsed.to_xml(), xml);
+    }
+
+    #[cfg(feature = "runtime")]
+    #[test]
+    fn test_write_number_format() {
+        let action = Action::new(vec![
+            Step::Wait(Wait::new(2.)),
+            Step::Wait(Wait::new(0.126)),
+            Step::Wait(Wait::new(-0.0001)),
+        ]);
+        let bulletml = BulletML {
+            elements: vec![Element::Action(action.into())],
+            ..Default::default()
+        };
+        let waits = |format| {
+            bulletml
+                .to_xml_with(format)
+                .lines()
+                .filter(|line| line.contains("<wait>"))
+                .map(|line| line.trim().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            waits(NumberFormat::default()),
+            [
+                "<wait>2</wait>",
+                "<wait>0.126</wait>",
+                "<wait>-0.0001</wait>",
+            ],
+        );
+        assert_eq!(
+            waits(NumberFormat {
+                max_decimals: Some(2),
+                ..Default::default()
+            }),
+            ["<wait>2</wait>", "<wait>0.13</wait>", "<wait>0</wait>"],
+        );
+        assert_eq!(
+            waits(NumberFormat {
+                max_decimals: Some(2),
+                trim_trailing_zeros: false,
+                integers_without_point: false,
+            }),
+            [
+                "<wait>2.00</wait>",
+                "<wait>0.13</wait>",
+                "<wait>0.00</wait>",
+            ],
+        );
+        assert_eq!(
+            waits(NumberFormat {
+                integers_without_point: false,
+                ..Default::default()
+            }),
+            [
+                "<wait>2.0</wait>",
+                "<wait>0.126</wait>",
+                "<wait>-0.0001</wait>",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_write_constructed() {
+        let fire = Fire {
+            label: None,
+            direction: Some(Direction::new(DirectionKind::Absolute, 180.)),
+            speed: None,
+            bullet: EntityRef::Ref(Reference::new("a&b")),
+        };
+        let action = Action {
+            label: Some("top".into()),
+            ttl: None,
+            tags: Vec::new(),
+            steps: vec![
+                Step::Fire(EntityRef::Real(fire.into())),
+                Step::Wait(Wait::new(1.)),
+            ],
+        };
+        let bulletml = BulletML {
+            elements: vec![Element::Action(action.into())],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            bulletml.to_xml(),
+            r#"<?xml version="1.0" ?>
+<bulletml type="none">
+  <action label="top">
+    <fire>
+      <direction type="absolute">180</direction>
+      <bulletRef label="a&amp;b"/>
+    </fire>
+    <wait>1</wait>
+  </action>
+</bulletml>
+"#,
+        );
+    }
+
+    #[test]
+    fn test_write_constructors() {
+        let bullet = Bullet {
+            speed: Some(Speed::absolute(2.)),
+            ..Default::default()
+        };
+        let fire = Fire {
+            direction: Some(Direction::aim(0)),
+            ..Fire::new(EntityRef::Real(bullet.into()))
+        };
+        let body = Action::new(vec![
+            Step::Fire(EntityRef::Real(fire.into())),
+            Step::ChangeSpeed(ChangeSpeed::new(Speed::sequence(1), Term::constant(30.))),
+            Step::Wait(Wait::frames(10)),
+        ]);
+        let action = Action {
+            label: Some("top".into()),
+            ..Action::new(vec![Step::Repeat(Repeat::new(
+                Times::new(3),
+                vec![EntityRef::Real(body.into())],
+            ))])
+        };
+        let bulletml = BulletML {
+            elements: vec![Element::Action(action.into())],
+            ..Default::default()
+        };
+        let xml = bulletml.to_xml();
+
+        #[cfg(feature = "runtime")]
+        assert_eq!(
+            xml,
+            r#"<?xml version="1.0" ?>
+<bulletml type="none">
+  <action label="top">
+    <repeat>
+      <times>3</times>
+      <action>
+        <fire>
+          <direction type="aim">0</direction>
+          <bullet>
+            <speed type="absolute">2</speed>
+          </bullet>
+        </fire>
+        <changeSpeed>
+          <speed type="sequence">1</speed>
+          <term>30</term>
+        </changeSpeed>
+        <wait>10</wait>
+      </action>
+    </repeat>
+  </action>
+</bulletml>
+"#,
+        );
+
+        let reparsed: BulletML = serde_xml_rs::from_str(&xml).unwrap();
+        assert_eq!(reparsed.to_xml(), xml);
+    }
+}
@@ -0,0 +1,176 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+//! Conversions between bullet motion and vectors.
+//!
+//! Directions are in degrees where `0` points up and `90` points right. Vectors use screen
+//! coordinates, so `y` increases downwards. Conversions to the vector types of other crates are
+//! provided by the `mint`, `glam`, and `nalgebra` features.
+
+/// The velocity of a bullet as a vector.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Velocity {
+    /// The horizontal component.
+    pub x: f32,
+    /// The vertical component (increasing downwards).
+    pub y: f32,
+}
+
+impl Velocity {
+    /// The velocity of a bullet moving in a direction at a speed.
+    pub fn new(direction: f32, speed: f32) -> Self {
+        let radians = direction.to_radians();
+
+        Velocity {
+            x: speed * radians.sin(),
+            y: -speed * radians.cos(),
+        }
+    }
+
+    /// The direction of the velocity.
+    ///
+    /// A velocity at rest points up.
+    pub fn direction(self) -> f32 {
+        if self.x == 0. && self.y == 0. {
+            0.
+        } else {
+            self.x.atan2(-self.y).to_degrees()
+        }
+    }
+
+    /// The speed of the velocity.
+    pub fn speed(self) -> f32 {
+        self.x.hypot(self.y)
+    }
+}
+
+#[cfg(feature = "mint")]
+impl From<Velocity> for mint::Vector2<f32> {
+    fn from(velocity: Velocity) -> Self {
+        mint::Vector2 {
+            x: velocity.x,
+            y: velocity.y,
+        }
+    }
+}
+
+#[cfg(feature = "mint")]
+impl From<mint::Vector2<f32>> for Velocity {
+    fn from(vector: mint::Vector2<f32>) -> Self {
+        Velocity {
+            x: vector.x,
+            y: vector.y,
+        }
+    }
+}
+
+#[cfg(feature = "glam")]
+impl From<Velocity> for glam::Vec2 {
+    fn from(velocity: Velocity) -> Self {
+        glam::Vec2::from([velocity.x, velocity.y])
+    }
+}
+
+#[cfg(feature = "glam")]
+impl From<glam::Vec2> for Velocity {
+    fn from(vector: glam::Vec2) -> Self {
+        let [x, y]: [f32; 2] = vector.into();
+
+        Velocity {
+            x,
+            y,
+        }
+    }
+}
+
+#[cfg(feature = "nalgebra")]
+impl From<Velocity> for nalgebra::Vector2<f32> {
+    fn from(velocity: Velocity) -> Self {
+        nalgebra::Vector2::new(velocity.x, velocity.y)
+    }
+}
+
+#[cfg(feature = "nalgebra")]
+impl From<nalgebra::Vector2<f32>> for Velocity {
+    fn from(vector: nalgebra::Vector2<f32>) -> Self {
+        Velocity {
+            x: vector.x,
+            y: vector.y,
+        }
+    }
+}
+
+/// The velocity of a bullet as a `mint` vector.
+#[cfg(feature = "mint")]
+pub fn velocity_mint(direction: f32, speed: f32) -> mint::Vector2<f32> {
+    Velocity::new(direction, speed).into()
+}
+
+/// The velocity of a bullet as a `glam` vector.
+#[cfg(feature = "glam")]
+pub fn velocity_glam(direction: f32, speed: f32) -> glam::Vec2 {
+    Velocity::new(direction, speed).into()
+}
+
+/// The velocity of a bullet as a `nalgebra` vector.
+#[cfg(feature = "nalgebra")]
+pub fn velocity_nalgebra(direction: f32, speed: f32) -> nalgebra::Vector2<f32> {
+    Velocity::new(direction, speed).into()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::geom::Velocity;
+
+    fn assert_close(actual: Velocity, x: f32, y: f32) {
+        assert!(
+            (actual.x - x).abs() < 1e-5 && (actual.y - y).abs() < 1e-5,
+            "{:?} is not ({}, {})",
+            actual,
+            x,
+            y,
+        );
+    }
+
+    #[test]
+    fn test_velocity_new() {
+        assert_close(Velocity::new(0., 2.), 0., -2.);
+        assert_close(Velocity::new(90., 2.), 2., 0.);
+        assert_close(Velocity::new(180., 2.), 0., 2.);
+        assert_close(Velocity::new(-90., 2.), -2., 0.);
+    }
+
+    #[test]
+    fn test_velocity_round_trip() {
+        for &direction in &[-135., -45., 0., 30., 90., 179.] {
+            let velocity = Velocity::new(direction, 3.);
+
+            assert!((velocity.direction() - direction).abs() < 1e-3);
+            assert!((velocity.speed() - 3.).abs() < 1e-5);
+        }
+
+        assert_eq!(Velocity::default().direction(), 0.);
+        assert_eq!(Velocity::default().speed(), 0.);
+    }
+
+    #[cfg(feature = "mint")]
+    #[test]
+    fn test_velocity_mint() {
+        let vector = crate::geom::velocity_mint(90., 2.);
+        assert_close(vector.into(), 2., 0.);
+    }
+
+    #[cfg(feature = "glam")]
+    #[test]
+    fn test_velocity_glam() {
+        let vector = crate::geom::velocity_glam(90., 2.);
+        assert_close(vector.into(), 2., 0.);
+    }
+
+    #[cfg(feature = "nalgebra")]
+    #[test]
+    fn test_velocity_nalgebra() {
+        let vector = crate::geom::velocity_nalgebra(90., 2.);
+        assert_close(vector.into(), 2., 0.);
+    }
+}
@@ -0,0 +1,75 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+//! Compatibility with the `failure`-based API
+//!
+//! Earlier releases returned `failure::Fallible` from most functions. The error types of this
+//! crate implement `failure::Fail`, so existing code may continue to use `?` in functions
+//! returning `Fallible` while migrating to the specific error types. This module will be removed
+//! in the next release.
+
+/// The error type used by earlier releases.
+#[deprecated(since = "0.1.0", note = "use the specific error types of this crate")]
+pub type Error = failure::Error;
+
+/// The result type used by earlier releases.
+#[deprecated(since = "0.1.0", note = "use the specific error types of this crate")]
+pub type Fallible<T> = Result<T, failure::Error>;
+
+/// Conversion of results into the result type used by earlier releases.
+#[deprecated(since = "0.1.0", note = "use the specific error types of this crate")]
+pub trait IntoFallible<T> {
+    /// Convert the error of a result into a `failure::Error`.
+    #[allow(deprecated)]
+    fn into_fallible(self) -> Fallible<T>;
+}
+
+#[allow(deprecated)]
+impl<T, E> IntoFallible<T> for Result<T, E>
+where
+    E: failure::Fail,
+{
+    fn into_fallible(self) -> Fallible<T> {
+        self.map_err(failure::Error::from)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(deprecated)]
+
+    use crate::data;
+    use crate::legacy::{Fallible, IntoFallible};
+
+    fn assert_fail<E>()
+    where
+        E: failure::Fail,
+    {
+    }
+
+    #[test]
+    fn test_errors_are_fail() {
+        assert_fail::<data::EntityError>();
+        assert_fail::<data::ExpressionError>();
+        #[cfg(feature = "runtime")]
+        assert_fail::<crate::run::BulletMLError>();
+    }
+
+    #[cfg(feature = "runtime")]
+    #[test]
+    fn test_into_fallible() {
+        fn parse(expr: &str) -> Fallible<data::Expression> {
+            data::Expression::parse(expr).into_fallible()
+        }
+
+        fn double(expr: &str) -> Fallible<data::Expression> {
+            let expr = data::Expression::parse(expr)?;
+            Ok(expr * 2.)
+        }
+
+        assert!(parse("1 + 2").is_ok());
+        assert!(parse("1 +").is_err());
+        assert!(double("$rank").is_ok());
+        assert!(double("1 +").is_err());
+    }
+}
@@ -4,9 +4,90 @@
 //! BulletML
 //!
 //! A BulletML parser and interpreter.
+//!
+//! The interpreter is provided by the default `runtime` feature. Tools which only need to read
+//! BulletML files may disable it, in which case expressions are stored as their source text.
+//!
+//! Errors from parsing, compiling, and evaluating carry a stable `data::ErrorCode` which is also
+//! included in their messages. Errors from parsing XML documents give the line and column of the
+//! problem, and errors from compiling give the path to the element with the problem.
+//!
+//! The `async` feature provides futures which compile scripts incrementally for asynchronous
+//! loading pipelines.
+//!
+//! Compiled scripts (`run::CompiledBulletML`) are `Send` and `Sync`, so they may be compiled on
+//! a loading thread and shared between threads through an `Arc`. Runners and the scripts of fired
+//! bullets are `Send` whenever their manager is, so updates may be scheduled on worker threads.
+//! Runners are not `Sync`; each is updated by one thread at a time. Parsed documents
+//! (`data::BulletML`), `Pattern`, and compile jobs share their entities through `Rc` and stay on
+//! the thread which created them.
+//!
+//! The `stable` module collects the API covered by semantic versioning: the data model, compiled
+//! scripts, runners, and the `BulletManager` trait. The `unstable` feature provides the `unstable`
+//! module with faster-moving APIs for tooling, such as the intermediate representation of compiled
+//! scripts and `unstable::analysis`, which estimates properties of patterns such as their
+//! difficulty.
+//!
+//! `run::ExampleManager` is a minimal, documented `BulletManager` for games to copy as the
+//! starting point of their integration.
+//!
+//! The `run::simulate` module runs patterns with simple kinematics and no game engine so that
+//! they can be tested headlessly.
+//!
+//! The `geom` module converts bullet directions and speeds into vectors. The `mint`, `glam`, and
+//! `nalgebra` features provide conversions into the vector types of those crates.
+//!
+//! The `schema` module describes the documents accepted by the parser as XML Schema and JSON
+//! Schema documents for use by external validators and editors.
+//!
+//! The `prefabs` feature provides a small library of parameterized reference patterns.
+//!
+//! The `xml` feature provides `Pattern`, a single handle for a document, its metadata, and its
+//! compiled form. It loads, validates, and runs XML documents without wiring the parser,
+//! compiler, and runner together by hand. `PatternArchive` loads patterns from a directory, or with
+//! the `zip-archive` feature a zip file, as they are needed.
+//!
+//! The `pattern-cache` feature provides `PatternCacheDir`, which stores the compiled forms of
+//! patterns next to their sources so that large games load them without compiling them again.
+//! Cache files are replaced whenever their source or the version of this crate changes.
+//!
+//! The `json` and `yaml` features provide `data::BulletML::from_json` and
+//! `data::BulletML::from_yaml` to load documents from toolchains which do not write XML. The
+//! documents have the same structure as XML documents as described by `schema::json_schema`.
+//!
+//! The `legacy-errors` feature provides deprecated compatibility with the `failure`-based error
+//! handling of earlier releases.
+//!
+//! The `compat` feature provides the `compat` module, which maps the API of the `bulletml` crate
+//! onto this crate so that projects using it may migrate with minimal changes.
 
 #![warn(missing_docs)]
 
+#[cfg(feature = "xml")]
+mod archive;
+#[cfg(feature = "pattern-cache")]
+mod cache;
+#[cfg(feature = "compat")]
+pub mod compat;
 pub mod data;
+pub mod geom;
+#[cfg(feature = "legacy-errors")]
+pub mod legacy;
 mod parse;
+#[cfg(feature = "xml")]
+mod pattern;
+#[cfg(feature = "prefabs")]
+pub mod prefabs;
+#[cfg(feature = "runtime")]
 pub mod run;
+pub mod schema;
+pub mod stable;
+#[cfg(feature = "unstable")]
+pub mod unstable;
+
+#[cfg(feature = "xml")]
+pub use self::archive::PatternArchive;
+#[cfg(feature = "pattern-cache")]
+pub use self::cache::{CacheStats, PatternCacheDir};
+#[cfg(feature = "xml")]
+pub use self::pattern::{Pattern, PatternError, PatternMetadata, PatternValidation};
@@ -1,28 +1,985 @@
 // Distributed under the OSI-approved BSD 2-Clause License.
 // See accompanying LICENSE file for details.
 
+//! Loading documents as distributed with the original BulletML tools.
+//!
+//! Stock pattern files declare a `DOCTYPE` and may qualify elements with a prefix bound to the
+//! BulletML namespace. The deserializer does not understand either, so documents are normalized
+//! before they are deserialized: the declaration is removed, as are declarations of the BulletML
+//! namespace and the prefixes bound to it. Elements of other namespaces are left alone and are
+//! rejected as unknown elements.
+//!
+//! Normalization keeps every line of the document so that errors may be reported by line.
+//!
+//! With the `json` and `yaml` features, documents may also be loaded from JSON and YAML. These
+//! are given in the same structure as the `schema::json_schema` describes: attributes and child
+//! elements are both keys of an object, elements which repeat are repeated keys, and the content
+//! of an element with text is its `$value` key. Constant expressions may be given as numbers and
+//! references without parameters as their label.
+
+use std::borrow::Cow;
+#[cfg(feature = "xml")]
+use std::io::{self, Cursor};
+#[cfg(feature = "xml")]
+use std::str;
+
+#[cfg(feature = "xml")]
+use thiserror::Error;
+
+#[cfg(any(feature = "xml", feature = "json", feature = "yaml"))]
+use crate::data::BulletML;
+
+/// The namespace of BulletML elements.
+const NAMESPACE: &str = "http://www.asahi-net.or.jp/~cs8k-cb/bulletml";
+
+#[cfg(feature = "xml")]
+impl BulletML {
+    /// Parse a BulletML document.
+    ///
+    /// Unlike deserializing the document directly, this accepts stock pattern files unmodified:
+    /// `DOCTYPE` declarations are skipped and elements may use a prefix for the BulletML
+    /// namespace.
+    ///
+    /// Errors report the position in the document where they were found.
+    pub fn from_xml(xml: &str) -> Result<Self, ParseError> {
+        let xml = normalize_xml(xml);
+        let mut reader = Cursor::new(xml.as_bytes());
+        serde_xml_rs::from_reader(&mut reader)
+            .map_err(|source| ParseError::new(xml.as_bytes(), reader.position() as usize, source))
+    }
+
+    /// Parse a BulletML document from its UTF-8 encoded bytes.
+    ///
+    /// The document is parsed in place, so this is suitable for memory-mapped files. Documents
+    /// are only copied if they need to be normalized (see `from_xml`).
+    pub fn from_xml_bytes(xml: &[u8]) -> Result<Self, ParseError> {
+        let xml = str::from_utf8(xml).map_err(|err| {
+            let source = io::Error::new(io::ErrorKind::InvalidData, err);
+            ParseError::new(xml, err.valid_up_to(), source.into())
+        })?;
+        Self::from_xml(xml)
+    }
+}
+
+#[cfg(feature = "json")]
+impl BulletML {
+    /// Parse a BulletML document from JSON.
+    ///
+    /// Errors report the position in the document where they were found.
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+}
+
+#[cfg(feature = "yaml")]
+impl BulletML {
+    /// Parse a BulletML document from YAML.
+    ///
+    /// Errors report the position in the document where they were found.
+    pub fn from_yaml(yaml: &str) -> Result<Self, serde_yaml::Error> {
+        serde_yaml::from_str(yaml)
+    }
+}
+
+/// An error parsing a BulletML document.
+///
+/// The position is that of the parser when the error was found, which may be just past the
+/// problem.
+#[cfg(feature = "xml")]
+#[derive(Debug, Error)]
+#[error("invalid BulletML near line {line}, column {column}")]
+pub struct ParseError {
+    /// The line of the error (starting from 1).
+    pub line: usize,
+    /// The column of the error in characters (starting from 1).
+    pub column: usize,
+    /// The source of the error.
+    pub source: serde_xml_rs::Error,
+}
+
+#[cfg(feature = "xml")]
+impl ParseError {
+    fn new(xml: &[u8], offset: usize, source: serde_xml_rs::Error) -> Self {
+        let before = &xml[..offset.min(xml.len())];
+        let line_start = before
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |idx| idx + 1);
+        // Count characters by skipping UTF-8 continuation bytes.
+        let column = before[line_start..]
+            .iter()
+            .filter(|&&b| b & 0xc0 != 0x80)
+            .count();
+
+        ParseError {
+            line: before.iter().filter(|&&b| b == b'\n').count() + 1,
+            column: column + 1,
+            source,
+        }
+    }
+}
+
+/// Append the line breaks within removed text.
+fn keep_lines(out: &mut String, removed: &str) {
+    removed
+        .chars()
+        .filter(|&c| c == '\n')
+        .for_each(|c| out.push(c));
+}
+
+/// The length of the text up to and including the end of a delimited construct.
+fn through(text: &str, end: &str) -> usize {
+    text.find(end).map_or(text.len(), |idx| idx + end.len())
+}
+
+/// The length of a markup declaration or tag, skipping delimiters within quotes and, for
+/// declarations, an internal subset.
+fn markup_len(text: &str) -> usize {
+    let mut quote = None;
+    let mut depth = 0usize;
+    for (idx, c) in text.char_indices().skip(1) {
+        match (quote, c) {
+            (Some(q), c) if q == c => quote = None,
+            (Some(_), _) => (),
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '[') => depth += 1,
+            (None, ']') => depth = depth.saturating_sub(1),
+            (None, '>') if depth == 0 => return idx + 1,
+            _ => (),
+        }
+    }
+    text.len()
+}
+
+/// An attribute within a tag.
+struct Attribute<'a> {
+    /// The attribute name.
+    name: &'a str,
+    /// The value of the attribute without its quotes.
+    value: &'a str,
+    /// The source of the attribute after its name, including its value.
+    rest: &'a str,
+}
+
+/// A start or end tag.
+struct Tag<'a> {
+    /// The start of the tag (`<` or `</`).
+    open: &'a str,
+    /// The element name.
+    name: &'a str,
+    /// The attributes of the tag along with the whitespace before them.
+    attributes: Vec<(&'a str, Attribute<'a>)>,
+    /// The end of the tag (`>` or `/>` along with any whitespace before it).
+    close: &'a str,
+}
+
+impl<'a> Tag<'a> {
+    fn parse(tag: &'a str) -> Self {
+        let is_name_end = |c: char| c.is_whitespace() || c == '/' || c == '>' || c == '=';
+        let split = |text: &'a str, pred: &dyn Fn(char) -> bool| {
+            text.split_at(text.find(|c| !pred(c)).unwrap_or_else(|| text.len()))
+        };
+
+        let (open, rest) = tag.split_at(if tag.starts_with("</") { 2 } else { 1 });
+        let (name, mut rest) = split(rest, &|c| !is_name_end(c));
+
+        let mut attributes = Vec::new();
+        loop {
+            let (space, after) = split(rest, &char::is_whitespace);
+            if after.is_empty() || after.starts_with('/') || after.starts_with('>') {
+                break;
+            }
+
+            let (attr_name, after_name) = split(after, &|c| !is_name_end(c));
+            let (_, after_space) = split(after_name, &char::is_whitespace);
+            let (value, len) = if let Some(eq) = after_space.strip_prefix('=') {
+                let (_, quoted) = split(eq, &char::is_whitespace);
+                let start = after_name.len() - quoted.len();
+                let quote = quoted.chars().next().filter(|&c| c == '"' || c == '\'');
+                if let Some(quote) = quote {
+                    let inner = &quoted[1..];
+                    let end = inner.find(quote).unwrap_or_else(|| inner.len());
+                    let close = (end + 1).min(inner.len());
+                    (&inner[..end], start + 1 + close)
+                } else {
+                    let (value, _) = split(quoted, &|c| !c.is_whitespace() && c != '>');
+                    (value, start + value.len())
+                }
+            } else {
+                ("", 0)
+            };
+
+            // Avoid looping forever on malformed tags; the deserializer reports them.
+            if attr_name.is_empty() && len == 0 {
+                break;
+            }
+
+            attributes.push((
+                space,
+                Attribute {
+                    name: attr_name,
+                    value,
+                    rest: &after_name[..len],
+                },
+            ));
+            rest = &after_name[len..];
+        }
+
+        Tag {
+            open,
+            name,
+            attributes,
+            close: rest,
+        }
+    }
+}
+
+/// Remove a prefix bound to the BulletML namespace from a name.
+fn strip_prefix<'a>(name: &'a str, prefixes: &[&str]) -> &'a str {
+    prefixes
+        .iter()
+        .find_map(|prefix| {
+            name.strip_prefix(prefix)
+                .and_then(|rest| rest.strip_prefix(':'))
+        })
+        .unwrap_or(name)
+}
+
+/// Rewrite a tag without the BulletML namespace.
+///
+/// Prefixes declared by the tag are added to `prefixes`.
+fn normalize_tag<'a>(tag: &'a str, prefixes: &mut Vec<&'a str>) -> Cow<'a, str> {
+    let parsed = Tag::parse(tag);
+
+    let mut declarations = false;
+    for (_, attr) in &parsed.attributes {
+        if attr.value.trim_end_matches('/') != NAMESPACE {
+            continue;
+        }
+        if attr.name == "xmlns" {
+            declarations = true;
+        } else if let Some(prefix) = attr.name.strip_prefix("xmlns:") {
+            declarations = true;
+            prefixes.push(prefix);
+        }
+    }
+
+    let is_declaration = |attr: &Attribute| {
+        (attr.name == "xmlns" || attr.name.starts_with("xmlns:"))
+            && attr.value.trim_end_matches('/') == NAMESPACE
+    };
+    let prefixed = |name: &str| strip_prefix(name, prefixes).len() != name.len();
+    let has_prefix = prefixed(parsed.name)
+        || parsed
+            .attributes
+            .iter()
+            .any(|(_, attr)| prefixed(attr.name));
+    if !declarations && !has_prefix {
+        return Cow::Borrowed(tag);
+    }
+
+    let mut out = String::with_capacity(tag.len());
+    out.push_str(parsed.open);
+    out.push_str(strip_prefix(parsed.name, prefixes));
+    parsed.attributes.iter().for_each(|(space, attr)| {
+        if is_declaration(attr) {
+            keep_lines(&mut out, space);
+            keep_lines(&mut out, attr.rest);
+        } else {
+            out.push_str(space);
+            out.push_str(strip_prefix(attr.name, prefixes));
+            out.push_str(attr.rest);
+        }
+    });
+    out.push_str(parsed.close);
+
+    Cow::Owned(out)
+}
+
+/// Prepare a stock BulletML document for deserialization.
+///
+/// The `DOCTYPE` declaration is removed, as are declarations of the BulletML namespace and the
+/// prefixes bound to it. Line breaks within removed text are kept so that positions in the result
+/// are on the same lines as in the document. Returns the document as-is if there is nothing to
+/// remove.
+///
+/// `BulletML::from_xml` does this before deserializing. It is useful for deserializing with other
+/// XML libraries.
+pub fn normalize_xml(xml: &str) -> Cow<'_, str> {
+    let mut prefixes = Vec::new();
+    let mut out = String::new();
+    let mut changed = false;
+    let mut rest = xml;
+
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let len = if rest.starts_with("<!--") {
+            through(rest, "-->")
+        } else if rest.starts_with("<![CDATA[") {
+            through(rest, "]]>")
+        } else if rest.starts_with("<?") {
+            through(rest, "?>")
+        } else if rest.starts_with("<!") {
+            // `DOCTYPE` is the only other declaration allowed outside of a DTD.
+            changed = true;
+            let len = markup_len(rest);
+            keep_lines(&mut out, &rest[..len]);
+            rest = &rest[len..];
+            continue;
+        } else {
+            let len = markup_len(rest);
+            let tag = normalize_tag(&rest[..len], &mut prefixes);
+            if let Cow::Owned(_) = tag {
+                changed = true;
+            }
+            out.push_str(&tag);
+            rest = &rest[len..];
+            continue;
+        };
+
+        out.push_str(&rest[..len]);
+        rest = &rest[len..];
+    }
+
+    if changed {
+        out.push_str(rest);
+        Cow::Owned(out)
+    } else {
+        Cow::Borrowed(xml)
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use std::borrow::Cow;
     use std::ffi::OsStr;
-    use std::fs::File;
+    use std::fs;
+    use std::sync::Arc;
 
     use walkdir::WalkDir;
 
-    use crate::data::BulletML;
+    #[cfg(feature = "xml")]
+    use super::ParseError;
+    use crate::data::{
+        normalize_xml, BulletML, CustomStep, Dialect, Element, Expression, ExpressionLimits,
+        ParseOptions, Step,
+    };
 
-    #[test]
-    fn test_parse_examples() {
+    fn parse_examples(dir: &str) {
         let ext = OsStr::new("xml");
 
-        WalkDir::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+        WalkDir::new(dir)
             .sort_by(|e1, e2| e1.path().cmp(e2.path()))
             .into_iter()
             .filter_map(|entry| entry.ok())
             .filter(|entry| entry.path().extension() == Some(ext))
             .for_each(|entry| {
                 println!("reading {}", entry.path().display());
-                let fin = File::open(entry.path()).unwrap();
-                let _: BulletML = serde_xml_rs::from_reader(fin).unwrap();
+                // Examples are stock pattern files.
+                let xml = fs::read_to_string(entry.path()).unwrap();
+                let _: BulletML = serde_xml_rs::from_str(&normalize_xml(&xml)).unwrap();
+            });
+    }
+
+    #[test]
+    fn test_parse_examples() {
+        parse_examples(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"));
+    }
+
+    #[test]
+    fn test_parse_bench_patterns() {
+        parse_examples(concat!(env!("CARGO_MANIFEST_DIR"), "/benches/patterns"));
+    }
+
+    #[derive(Debug)]
+    struct PlaySound;
+
+    impl CustomStep for PlaySound {
+        fn name(&self) -> &str {
+            "playSound"
+        }
+    }
+
+    fn play_sound(content: &str) -> Result<Arc<dyn CustomStep>, String> {
+        if content == "boom" {
+            Ok(Arc::new(PlaySound))
+        } else {
+            Err(format!("unknown sound `{}`", content))
+        }
+    }
+
+    const CUSTOM_STEP: &str = r#"<bulletml>
+        <action label="top">
+            <playSound>boom</playSound>
+            <wait>1</wait>
+        </action>
+    </bulletml>"#;
+
+    #[test]
+    fn test_parse_custom_step() {
+        let mut options = ParseOptions::default();
+        options.custom_steps.register("playSound", play_sound);
+
+        let bulletml: BulletML = options
+            .scope(|| serde_xml_rs::from_str(CUSTOM_STEP))
+            .unwrap();
+        let action = if let Element::Action(ref action) = bulletml.elements[0] {
+            action
+        } else {
+            panic!("did not parse an action: {:?}", bulletml.elements[0]);
+        };
+
+        if let Step::Custom(ref custom) = action.steps[0] {
+            assert_eq!(custom.name(), "playSound");
+        } else {
+            panic!("did not parse a custom step: {:?}", action.steps[0]);
+        }
+    }
+
+    #[test]
+    fn test_parse_custom_step_unregistered() {
+        let res: Result<BulletML, _> = serde_xml_rs::from_str(CUSTOM_STEP);
+        assert!(res.is_err());
+    }
+
+    const MISPLACED_IN_FIRE: &str = r#"<bulletml>
+        <action label="top">
+            <fire>
+                <wait>1</wait>
+                <bullet/>
+            </fire>
+        </action>
+    </bulletml>"#;
+
+    #[test]
+    fn test_parse_misplaced_permissive() {
+        let _: BulletML = serde_xml_rs::from_str(MISPLACED_IN_FIRE).unwrap();
+    }
+
+    #[test]
+    fn test_parse_misplaced_strict() {
+        let options = ParseOptions {
+            strict: true,
+            ..Default::default()
+        };
+
+        let err = options
+            .scope(|| serde_xml_rs::from_str::<BulletML>(MISPLACED_IN_FIRE))
+            .unwrap_err();
+        let msg = err.to_string();
+        assert!(
+            msg.contains(
+                "BML1101: `<wait>` is not allowed within `<fire>`; it belongs within `<action>`"
+            ),
+            "unexpected error: {}",
+            msg,
+        );
+    }
+
+    const MISPLACED_IN_ACTION: &str = r#"<bulletml>
+        <action label="top">
+            <speed>1</speed>
+            <wait>1</wait>
+        </action>
+    </bulletml>"#;
+
+    #[test]
+    fn test_parse_misplaced_in_action_permissive() {
+        let bulletml: BulletML = serde_xml_rs::from_str(MISPLACED_IN_ACTION).unwrap();
+        let action = if let Element::Action(ref action) = bulletml.elements[0] {
+            action
+        } else {
+            panic!("did not parse an action: {:?}", bulletml.elements[0]);
+        };
+
+        // The misplaced element is skipped.
+        assert_eq!(action.steps.len(), 1);
+        assert!(matches!(action.steps[0], Step::Wait(_)));
+    }
+
+    #[test]
+    fn test_parse_misplaced_in_action() {
+        let options = ParseOptions {
+            strict: true,
+            ..Default::default()
+        };
+
+        let err = options
+            .scope(|| serde_xml_rs::from_str::<BulletML>(MISPLACED_IN_ACTION))
+            .unwrap_err();
+        let msg = err.to_string();
+        assert!(
+            msg.contains("BML1101: `<speed>` is not allowed within `<action>`"),
+            "unexpected error: {}",
+            msg,
+        );
+    }
+
+    #[test]
+    fn test_parse_repeat_times_order() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <repeat>
+                    <action><wait>1</wait></action>
+                    <actionRef label="other"/>
+                    <times>3</times>
+                </repeat>
+            </action>
+        </bulletml>"#;
+
+        let bulletml: BulletML = serde_xml_rs::from_str(doc).unwrap();
+        let action = if let Element::Action(ref action) = bulletml.elements[0] {
+            action
+        } else {
+            panic!("did not parse an action: {:?}", bulletml.elements[0]);
+        };
+
+        if let Step::Repeat(ref repeat) = action.steps[0] {
+            assert_eq!(repeat.actions.len(), 2);
+        } else {
+            panic!("did not parse a repeat: {:?}", action.steps[0]);
+        }
+    }
+
+    #[test]
+    fn test_parse_repeat_errors() {
+        let parse_repeat = |content: &str| {
+            let doc = format!(
+                "<bulletml><action label=\"top\"><repeat>{}</repeat></action></bulletml>",
+                content,
+            );
+            serde_xml_rs::from_str::<BulletML>(&doc)
+                .unwrap_err()
+                .to_string()
+        };
+
+        let msg = parse_repeat("<times>1</times><action/><times>2</times>");
+        assert!(
+            msg.contains("duplicate field `times`"),
+            "unexpected error: {}",
+            msg,
+        );
+        let msg = parse_repeat("<action/>");
+        assert!(
+            msg.contains("missing field `times`"),
+            "unexpected error: {}",
+            msg,
+        );
+        let msg = parse_repeat("<times>1</times><wait>1</wait>");
+        assert!(
+            msg.contains("BML1101: `<wait>` is not allowed within `<repeat>`"),
+            "unexpected error: {}",
+            msg,
+        );
+    }
+
+    #[test]
+    fn test_parse_element_names() {
+        let options = ParseOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let parse_in_fire = |element: &str| {
+            let doc = format!("<bulletml><fire><{}/><bullet/></fire></bulletml>", element);
+            options
+                .scope(|| serde_xml_rs::from_str::<BulletML>(&doc))
+                .err()
+                .map(|err| err.to_string())
+        };
+
+        // Every element other than the root is recognized, even when it is misplaced.
+        Dialect::Spec
+            .element_names()
+            .iter()
+            .filter(|&&element| element != "bulletml")
+            .for_each(|element| {
+                if let Some(msg) = parse_in_fire(element) {
+                    assert!(!msg.contains("BML1102"), "unexpected error: {}", msg);
+                }
             });
+        assert!(parse_in_fire("laser").unwrap().contains("BML1102"));
+
+        assert_eq!(
+            Dialect::Spec.element_names(),
+            Dialect::Extended.element_names(),
+        );
+        assert!(Dialect::Extended.is_attribute("bullet", "ttl"));
+        assert!(!Dialect::Spec.is_attribute("bullet", "ttl"));
+        assert!(Dialect::Spec
+            .attribute_names()
+            .iter()
+            .all(|&(element, attribute)| {
+                Dialect::Spec.is_element(element)
+                    && Dialect::Extended.is_attribute(element, attribute)
+            }));
+    }
+
+    const NON_PORTABLE: &str = r#"<bulletml>
+        <action label="top">
+            <wait>$my_Wait</wait>
+        </action>
+    </bulletml>"#;
+
+    #[test]
+    fn test_parse_dialect_extended() {
+        let _: BulletML = serde_xml_rs::from_str(NON_PORTABLE).unwrap();
+    }
+
+    #[test]
+    fn test_parse_dialect_spec() {
+        let options = ParseOptions {
+            dialect: Dialect::Spec,
+            ..Default::default()
+        };
+
+        let err = options
+            .scope(|| serde_xml_rs::from_str::<BulletML>(NON_PORTABLE))
+            .unwrap_err();
+        let msg = err.to_string();
+        assert!(
+            msg.contains(
+                "in expression `$my_Wait`: BML2002: non-portable character `_` at offset 3"
+            ),
+            "unexpected error: {}",
+            msg,
+        );
+    }
+
+    #[test]
+    fn test_parse_expression_limits() {
+        let doc = format!(
+            r#"<bulletml><action label="top"><wait>{}1{}</wait></action></bulletml>"#,
+            "(".repeat(1000),
+            ")".repeat(1000),
+        );
+
+        let err = serde_xml_rs::from_str::<BulletML>(&doc).unwrap_err();
+        let msg = err.to_string();
+        assert!(
+            msg.contains("BML2004: expression nesting exceeds the limit of 256"),
+            "unexpected error: {}",
+            msg,
+        );
+
+        let options = ParseOptions {
+            expression_limits: ExpressionLimits {
+                max_length: Some(4),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let err = options
+            .scope(|| serde_xml_rs::from_str::<BulletML>(NON_PORTABLE))
+            .unwrap_err();
+        let msg = err.to_string();
+        assert!(
+            msg.contains("BML2004: expression of 8 bytes exceeds the limit of 4"),
+            "unexpected error: {}",
+            msg,
+        );
+    }
+
+    fn wait_expression(wait: &str) -> String {
+        let doc = format!(
+            r#"<bulletml><action label="top"><wait>{}</wait></action></bulletml>"#,
+            wait,
+        );
+        let bulletml: BulletML = serde_xml_rs::from_str(&doc).unwrap();
+        let action = if let Element::Action(ref action) = bulletml.elements[0] {
+            action
+        } else {
+            panic!("did not parse an action: {:?}", bulletml.elements[0]);
+        };
+
+        if let Step::Wait(ref wait) = action.steps[0] {
+            wait.frames.to_string()
+        } else {
+            panic!("did not parse a wait: {:?}", action.steps[0]);
+        }
+    }
+
+    fn expression(expr: &str) -> String {
+        Expression::parse(expr).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_parse_expression_entities() {
+        assert_eq!(
+            wait_expression("&#36;rank &#42; 2"),
+            expression("$rank * 2"),
+        );
+        assert_eq!(
+            wait_expression("(1 &#x2B; 2) / 4"),
+            expression("(1 + 2) / 4"),
+        );
+    }
+
+    #[test]
+    fn test_parse_expression_cdata() {
+        assert_eq!(
+            wait_expression("<![CDATA[$rank * 2]]>"),
+            expression("$rank * 2"),
+        );
+        assert_eq!(
+            wait_expression("&lt;![CDATA[$rank * 2]]&gt;"),
+            expression("$rank * 2"),
+        );
+    }
+
+    // Without the `runtime` feature, the source of expressions is kept as-is.
+    #[cfg(feature = "runtime")]
+    #[test]
+    fn test_parse_expression_multiline() {
+        assert_eq!(
+            wait_expression("<![CDATA[\n    $rank\n    * 2\n]]>"),
+            expression("$rank * 2"),
+        );
+        assert_eq!(
+            wait_expression("\n    1 +\n    $rank\n"),
+            expression("1 + $rank"),
+        );
+    }
+
+    const STOCK: &str = r#"<?xml version="1.0" ?>
+<!DOCTYPE bulletml SYSTEM "http://www.asahi-net.or.jp/~cs8k-cb/bulletml/bulletml.dtd">
+
+<bulletml type="vertical"
+          xmlns="http://www.asahi-net.or.jp/~cs8k-cb/bulletml">
+<action label="top">
+    <wait>1</wait>
+</action>
+</bulletml>
+"#;
+
+    const STOCK_PREFIXED: &str = r#"<!DOCTYPE bulletml [
+    <!ENTITY note "a > b">
+]>
+<bml:bulletml xmlns:bml='http://www.asahi-net.or.jp/~cs8k-cb/bulletml/'>
+<bml:action bml:label = "top">
+    <bml:wait>1</bml:wait>
+    <bml:vanish/>
+</bml:action>
+</bml:bulletml>
+"#;
+
+    #[test]
+    fn test_normalize_doctype() {
+        assert_eq!(
+            normalize_xml(STOCK),
+            concat!(
+                "<?xml version=\"1.0\" ?>\n",
+                "\n",
+                "\n",
+                "<bulletml type=\"vertical\"\n>\n",
+                "<action label=\"top\">\n",
+                "    <wait>1</wait>\n",
+                "</action>\n",
+                "</bulletml>\n",
+            ),
+        );
+    }
+
+    #[test]
+    fn test_normalize_prefix() {
+        assert_eq!(
+            normalize_xml(STOCK_PREFIXED),
+            concat!(
+                "\n",
+                "\n",
+                "\n",
+                "<bulletml>\n",
+                "<action label = \"top\">\n",
+                "    <wait>1</wait>\n",
+                "    <vanish/>\n",
+                "</action>\n",
+                "</bulletml>\n",
+            ),
+        );
+    }
+
+    #[test]
+    fn test_normalize_lines() {
+        for doc in [STOCK, STOCK_PREFIXED].iter() {
+            assert_eq!(normalize_xml(doc).lines().count(), doc.lines().count());
+        }
+    }
+
+    #[test]
+    fn test_normalize_unchanged() {
+        let doc = r#"<bulletml xmlns:x="urn:other">
+            <action label="top">
+                <!-- <!DOCTYPE bulletml> -->
+                <wait><![CDATA[<!DOCTYPE bulletml>]]></wait>
+            </action>
+        </bulletml>"#;
+
+        assert!(matches!(normalize_xml(doc), Cow::Borrowed(_)));
+    }
+
+    #[cfg(feature = "xml")]
+    #[test]
+    fn test_parse_stock() {
+        let doc = r#"<bulletml type="vertical">
+            <action label="top"><wait>1</wait></action>
+        </bulletml>"#;
+        let expected = BulletML::from_xml(doc).unwrap().to_xml();
+
+        assert_eq!(BulletML::from_xml(STOCK).unwrap().to_xml(), expected);
+        assert!(BulletML::from_xml(STOCK_PREFIXED)
+            .unwrap()
+            .to_xml()
+            .contains("<vanish/>"));
+    }
+
+    #[cfg(feature = "xml")]
+    #[test]
+    fn test_parse_error_position() {
+        let doc = concat!(
+            "<!DOCTYPE bulletml SYSTEM \"bulletml.dtd\">\n",
+            "<bulletml>\n",
+            "<action label=\"top\">\n",
+            "    <wait>1</fire>\n",
+            "</action>\n",
+            "</bulletml>\n",
+        );
+        let err = BulletML::from_xml(doc).unwrap_err();
+
+        assert_eq!(err.line, 4);
+        assert!(err.to_string().contains("near line 4, column"));
+    }
+
+    #[cfg(feature = "xml")]
+    #[test]
+    fn test_parse_bytes() {
+        let doc = r#"<bulletml>
+            <action label="top"><wait>1</wait></action>
+        </bulletml>"#;
+
+        assert_eq!(
+            BulletML::from_xml_bytes(doc.as_bytes()).unwrap().to_xml(),
+            BulletML::from_xml(doc).unwrap().to_xml(),
+        );
+
+        let mut invalid = b"<bulletml>\n<action label=\"".to_vec();
+        invalid.extend_from_slice(&[0xff, b'"', b'/', b'>']);
+        let err = BulletML::from_xml_bytes(&invalid).unwrap_err();
+        assert_eq!(err.line, 2);
+        assert_eq!(err.column, 16);
+    }
+
+    #[cfg(feature = "xml")]
+    const EQUIVALENT: &str = r#"<bulletml type="vertical">
+        <action label="top">
+            <repeat>
+                <times>2 + $rank</times>
+                <action>
+                    <fire>
+                        <direction type="absolute">90</direction>
+                        <speed>1.5</speed>
+                        <bulletRef label="shot"><param>2</param></bulletRef>
+                    </fire>
+                    <wait>10</wait>
+                </action>
+            </repeat>
+            <actionRef label="finish"/>
+        </action>
+        <bullet label="shot">
+            <action>
+                <changeSpeed><speed>$1</speed><term>20</term></changeSpeed>
+            </action>
+        </bullet>
+        <action label="finish"><vanish/></action>
+    </bulletml>"#;
+
+    #[cfg(all(feature = "json", feature = "xml"))]
+    #[test]
+    fn test_parse_json() {
+        let doc = r#"{
+            "type": "vertical",
+            "action": {
+                "label": "top",
+                "repeat": {
+                    "times": { "$value": "2 + $rank" },
+                    "action": {
+                        "fire": {
+                            "direction": { "type": "absolute", "$value": 90 },
+                            "speed": { "$value": 1.5 },
+                            "bulletRef": { "label": "shot", "param": { "$value": 2 } }
+                        },
+                        "wait": { "$value": 10 }
+                    }
+                },
+                "actionRef": "finish"
+            },
+            "bullet": {
+                "label": "shot",
+                "action": {
+                    "changeSpeed": { "speed": { "$value": "$1" }, "term": { "$value": 20 } }
+                }
+            },
+            "action": { "label": "finish", "vanish": {} }
+        }"#;
+
+        assert_eq!(
+            BulletML::from_json(doc).unwrap().to_xml(),
+            BulletML::from_xml(EQUIVALENT).unwrap().to_xml(),
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_parse_json_error() {
+        let doc = r#"{
+            "action": { "label": "top", "wait": { "$value": "1 +" } }
+        }"#;
+
+        let err = BulletML::from_json(doc).unwrap_err();
+        assert!(err.to_string().contains("\"1 +\""), "{}", err);
+    }
+
+    #[cfg(all(feature = "yaml", feature = "xml"))]
+    #[test]
+    fn test_parse_yaml() {
+        let doc = r#"
+type: vertical
+action:
+  label: top
+  repeat:
+    times: { $value: 2 + $rank }
+    action:
+      fire:
+        direction: { type: absolute, $value: 90 }
+        speed: { $value: 1.5 }
+        bulletRef: { label: shot, param: { $value: 2 } }
+      wait: { $value: 10 }
+  actionRef: finish
+bullet:
+  label: shot
+  action:
+    changeSpeed: { speed: { $value: $1 }, term: { $value: 20 } }
+action: { label: finish, vanish: {} }
+"#;
+
+        assert_eq!(
+            BulletML::from_yaml(doc).unwrap().to_xml(),
+            BulletML::from_xml(EQUIVALENT).unwrap().to_xml(),
+        );
+    }
+
+    #[cfg(feature = "xml")]
+    #[test]
+    fn test_parse_error_position_multibyte() {
+        let err = ParseError::new(
+            "<bulletml>\n  <!-- é -->é".as_bytes(),
+            "<bulletml>\n  <!-- é -->é".len(),
+            serde_xml_rs::Error::Custom {
+                field: "test".into(),
+            },
+        );
+
+        assert_eq!(err.line, 2);
+        assert_eq!(err.column, 14);
     }
 }
@@ -0,0 +1,468 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+//! A single handle for loading, inspecting, and running patterns.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use thiserror::Error;
+
+use crate::data::{self, Diagnostic, ErrorCode, SequenceWarning, Severity};
+use crate::run::{BulletMLError, CompiledBulletML, Runner, RunnerOptions};
+
+/// An error when loading a pattern.
+#[derive(Debug, Error)]
+pub enum PatternError {
+    /// The pattern file could not be opened.
+    #[error("failed to open {}", path.display())]
+    Open {
+        /// The path to the file.
+        path: PathBuf,
+        /// The source of the error.
+        source: io::Error,
+    },
+    /// The document could not be read.
+    #[error("failed to read the document")]
+    Read {
+        /// The source of the error.
+        source: io::Error,
+    },
+    /// The document is not a valid BulletML document.
+    #[error("failed to parse the document")]
+    Parse {
+        /// The source of the error.
+        #[from]
+        source: data::ParseError,
+    },
+    /// The path is not a directory of patterns or a zip file of patterns.
+    #[error("{} is not a pattern archive", path.display())]
+    NotAnArchive {
+        /// The path to the archive.
+        path: PathBuf,
+    },
+    /// An archive does not contain a pattern.
+    #[error("no pattern named `{name}` in the archive")]
+    UnknownPattern {
+        /// The name of the pattern.
+        name: String,
+    },
+    /// The document could not be compiled.
+    #[error("failed to compile the document")]
+    Compile {
+        /// The source of the error.
+        #[from]
+        source: BulletMLError,
+    },
+}
+
+impl PatternError {
+    /// The code for the error.
+    ///
+    /// Only errors found while compiling have a structured code. Codes for parse errors are
+    /// included in their messages.
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            PatternError::Compile {
+                source,
+            } => Some(source.code()),
+            _ => None,
+        }
+    }
+}
+
+/// Information about a pattern which is available without compiling it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatternMetadata {
+    /// The orientation the pattern was written for.
+    pub orientation: data::Orientation,
+    /// The labels of the top-level actions.
+    pub actions: Vec<String>,
+    /// The labels of the top-level bullets.
+    pub bullets: Vec<String>,
+    /// The labels of the top-level fires.
+    pub fires: Vec<String>,
+}
+
+impl PatternMetadata {
+    fn new(bulletml: &data::BulletML) -> Self {
+        let mut metadata = PatternMetadata {
+            orientation: bulletml.orientation,
+            actions: Vec::new(),
+            bullets: Vec::new(),
+            fires: Vec::new(),
+        };
+
+        for element in &bulletml.elements {
+            let (labels, label) = match *element {
+                data::Element::Action(ref action) => (&mut metadata.actions, &action.label),
+                data::Element::Bullet(ref bullet) => (&mut metadata.bullets, &bullet.label),
+                data::Element::Fire(ref fire) => (&mut metadata.fires, &fire.label),
+            };
+            labels.extend(label.iter().cloned());
+        }
+
+        metadata
+    }
+}
+
+/// The results of validating a pattern.
+#[derive(Debug, Default)]
+pub struct PatternValidation {
+    /// The error which prevents the pattern from compiling, if any.
+    pub error: Option<PatternError>,
+    /// Problems found in the document.
+    pub diagnostics: Vec<Diagnostic>,
+    /// `sequence` directions and speeds which may be used before any bullet is fired.
+    pub sequence_warnings: Vec<SequenceWarning>,
+}
+
+impl PatternValidation {
+    /// Whether the pattern may be run.
+    ///
+    /// Warnings do not prevent a pattern from running.
+    pub fn is_valid(&self) -> bool {
+        self.error.is_none()
+            && self
+                .diagnostics
+                .iter()
+                .all(|diagnostic| diagnostic.severity == Severity::Warning)
+    }
+}
+
+/// A BulletML pattern.
+///
+/// A pattern bundles a parsed document with its metadata, where it came from, and its compiled
+/// form. Patterns may be loaded from XML with `Pattern::from_reader`, `Pattern::from_file`, or,
+/// through `FromStr`, `Pattern::from_str`. Stock pattern files with a `DOCTYPE` or the BulletML
+/// namespace load unmodified.
+///
+/// The document is compiled the first time it is needed and the compiled form is then shared by
+/// every runner created from the pattern. Compile errors are reported when compiling, so a
+/// pattern may be loaded and inspected even if it cannot be run.
+#[derive(Debug)]
+pub struct Pattern {
+    data: data::BulletML,
+    metadata: PatternMetadata,
+    source_name: Option<String>,
+    compiled: RefCell<Option<Arc<CompiledBulletML>>>,
+}
+
+impl Pattern {
+    /// Create a pattern from a parsed document.
+    pub fn new(bulletml: data::BulletML) -> Self {
+        Pattern {
+            metadata: PatternMetadata::new(&bulletml),
+            data: bulletml,
+            source_name: None,
+            compiled: RefCell::new(None),
+        }
+    }
+
+    /// Load a pattern from a reader of an XML document.
+    ///
+    /// Stock pattern files load unmodified; see `data::BulletML::from_xml`.
+    pub fn from_reader<R>(mut reader: R) -> Result<Self, PatternError>
+    where
+        R: Read,
+    {
+        let mut xml = String::new();
+        reader.read_to_string(&mut xml).map_err(|source| {
+            PatternError::Read {
+                source,
+            }
+        })?;
+        xml.parse()
+    }
+
+    /// Load a pattern from the UTF-8 encoded bytes of an XML document.
+    ///
+    /// The document is parsed in place; see `data::BulletML::from_xml_bytes`.
+    pub fn from_bytes(xml: &[u8]) -> Result<Self, PatternError> {
+        Ok(Self::new(data::BulletML::from_xml_bytes(xml)?))
+    }
+
+    /// Load a pattern from an XML file.
+    ///
+    /// The path is used as the name of the source of the pattern.
+    pub fn from_file<P>(path: P) -> Result<Self, PatternError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let fin = File::open(path).map_err(|source| {
+            PatternError::Open {
+                path: path.into(),
+                source,
+            }
+        })?;
+
+        Ok(Self::from_reader(BufReader::new(fin))?.with_source_name(path.display().to_string()))
+    }
+
+    /// Set the name of the source of the pattern.
+    ///
+    /// This is used to identify the pattern in tools and diagnostics.
+    pub fn with_source_name<N>(mut self, name: N) -> Self
+    where
+        N: Into<String>,
+    {
+        self.source_name = Some(name.into());
+        self
+    }
+
+    /// The name of the source of the pattern, if known.
+    pub fn source_name(&self) -> Option<&str> {
+        self.source_name.as_deref()
+    }
+
+    /// The parsed document.
+    pub fn data(&self) -> &data::BulletML {
+        &self.data
+    }
+
+    /// Information about the pattern.
+    pub fn metadata(&self) -> &PatternMetadata {
+        &self.metadata
+    }
+
+    /// The compiled form of the pattern.
+    ///
+    /// The pattern is compiled on first use. Errors are not cached, so a pattern which fails to
+    /// compile is compiled again on the next call.
+    pub fn compiled(&self) -> Result<Arc<CompiledBulletML>, PatternError> {
+        if let Some(compiled) = self.compiled.borrow().as_ref() {
+            return Ok(Arc::clone(compiled));
+        }
+
+        let compiled = Arc::new(CompiledBulletML::new(self.data.clone())?);
+        *self.compiled.borrow_mut() = Some(Arc::clone(&compiled));
+        Ok(compiled)
+    }
+
+    /// Whether the pattern has been compiled.
+    pub fn is_compiled(&self) -> bool {
+        self.compiled.borrow().is_some()
+    }
+
+    /// Create a runner for the pattern.
+    pub fn runner<T>(&self, manager: T) -> Result<Runner<T>, PatternError> {
+        self.runner_with_options(manager, RunnerOptions::default())
+    }
+
+    /// Create a runner for the pattern with options.
+    pub fn runner_with_options<T>(
+        &self,
+        manager: T,
+        options: RunnerOptions,
+    ) -> Result<Runner<T>, PatternError> {
+        Ok(Runner::from_compiled(manager, &*self.compiled()?, options))
+    }
+
+    /// Check the pattern for problems.
+    ///
+    /// This compiles the pattern if it has not been compiled yet.
+    pub fn validate(&self) -> PatternValidation {
+        PatternValidation {
+            error: self.compiled().err(),
+            diagnostics: data::validate(&self.data),
+            sequence_warnings: self.data.sequence_warnings(),
+        }
+    }
+
+    /// The pattern as an XML document.
+    ///
+    /// Expressions are written in a normalized form, so the output may not match the source of
+    /// the pattern exactly.
+    pub fn serialize(&self) -> String {
+        self.data.to_xml()
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = PatternError;
+
+    /// Load a pattern from an XML document.
+    fn from_str(xml: &str) -> Result<Self, Self::Err> {
+        Ok(Self::new(data::BulletML::from_xml(xml)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    use crate::data::{ErrorCode, Orientation};
+    use crate::run::testing::TestManager;
+    use crate::{Pattern, PatternError};
+
+    const DOC: &str = r#"<bulletml>
+        <action label="top">
+            <fire>
+                <direction type="absolute">$rank * 90</direction>
+                <bullet/>
+            </fire>
+        </action>
+    </bulletml>"#;
+
+    #[test]
+    fn test_pattern_runners() {
+        let pattern = Pattern::from_str(DOC).unwrap();
+
+        let mut runners = [0., 1.]
+            .iter()
+            .map(|&rank| {
+                pattern
+                    .runner(TestManager {
+                        rank,
+                        ..Default::default()
+                    })
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        for runner in &mut runners {
+            runner.update().unwrap();
+        }
+
+        assert_eq!(runners[0].manager().log, ["new_simple(0, 1)"]);
+        assert_eq!(runners[1].manager().log, ["new_simple(90, 1)"]);
+    }
+
+    #[test]
+    fn test_pattern_from_reader() {
+        let pattern = Pattern::from_reader(DOC.as_bytes()).unwrap();
+        assert_eq!(pattern.compiled().unwrap().bullet_prototypes().count(), 1);
+        assert_eq!(pattern.source_name(), None);
+
+        let stock = concat!(
+            "<?xml version=\"1.0\" ?>\n",
+            "<!DOCTYPE bulletml SYSTEM \"bulletml.dtd\">\n",
+            "<bulletml xmlns=\"http://www.asahi-net.or.jp/~cs8k-cb/bulletml\">\n",
+            "<action label=\"top\"><fire><bullet/></fire></action>\n",
+            "</bulletml>\n",
+        );
+        let pattern = Pattern::from_reader(stock.as_bytes()).unwrap();
+        assert_eq!(pattern.compiled().unwrap().bullet_prototypes().count(), 1);
+    }
+
+    #[test]
+    fn test_pattern_from_file() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("benches/patterns/sparse");
+        let file = path
+            .read_dir()
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().map_or(false, |ext| ext == "xml"))
+            .unwrap();
+        let pattern = Pattern::from_file(&file).unwrap();
+        assert_eq!(pattern.source_name(), Some(file.display().to_string().as_str()));
+
+        let err = Pattern::from_file(path.join("missing.xml")).unwrap_err();
+        if let PatternError::Open {
+            path: ref missing, ..
+        } = err
+        {
+            assert!(missing.ends_with("missing.xml"));
+        } else {
+            panic!("unexpected error: {:?}", err);
+        }
+        assert_eq!(err.code(), None);
+    }
+
+    #[test]
+    fn test_pattern_compile_error() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <actionRef label="missing"/>
+            </action>
+        </bulletml>"#;
+
+        // Loading does not compile the pattern.
+        let pattern = Pattern::from_str(doc).unwrap();
+        let err = pattern.compiled().unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::UnknownReference));
+        assert!(pattern.runner(TestManager::default()).is_err());
+        assert!(!pattern.is_compiled());
+
+        let validation = pattern.validate();
+        assert!(!validation.is_valid());
+        assert_eq!(
+            validation.error.and_then(|err| err.code()),
+            Some(ErrorCode::UnknownReference),
+        );
+        assert_eq!(validation.diagnostics.len(), 1);
+        assert_eq!(
+            validation.diagnostics[0].code,
+            ErrorCode::UnknownReference,
+        );
+    }
+
+    #[test]
+    fn test_pattern_lazy_compile() {
+        let pattern = Pattern::from_str(DOC).unwrap();
+        assert!(!pattern.is_compiled());
+
+        pattern.runner(TestManager::default()).unwrap();
+        assert!(pattern.is_compiled());
+        assert!(Arc::ptr_eq(
+            &pattern.compiled().unwrap(),
+            &pattern.compiled().unwrap(),
+        ));
+    }
+
+    #[test]
+    fn test_pattern_metadata() {
+        let doc = r#"<bulletml type="horizontal">
+            <action label="top">
+                <fireRef label="aimed"/>
+            </action>
+            <action label="top2"/>
+            <action/>
+            <fire label="aimed">
+                <bulletRef label="shot"/>
+            </fire>
+            <bullet label="shot"/>
+        </bulletml>"#;
+        let pattern = Pattern::from_str(doc).unwrap().with_source_name("metadata");
+
+        let metadata = pattern.metadata();
+        assert_eq!(metadata.orientation, Orientation::Horizontal);
+        assert_eq!(metadata.actions, ["top", "top2"]);
+        assert_eq!(metadata.bullets, ["shot"]);
+        assert_eq!(metadata.fires, ["aimed"]);
+        assert_eq!(pattern.source_name(), Some("metadata"));
+        assert_eq!(pattern.data().elements.len(), 5);
+    }
+
+    #[test]
+    fn test_pattern_validate() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <fire>
+                    <direction type="sequence">10</direction>
+                    <bullet/>
+                </fire>
+            </action>
+        </bulletml>"#;
+        let validation = Pattern::from_str(doc).unwrap().validate();
+
+        assert!(validation.is_valid());
+        assert_eq!(validation.diagnostics, []);
+        assert_eq!(validation.sequence_warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_pattern_serialize() {
+        let pattern = Pattern::from_str(DOC).unwrap();
+        let reloaded = Pattern::from_str(&pattern.serialize()).unwrap();
+
+        assert_eq!(reloaded.metadata(), pattern.metadata());
+        assert_eq!(reloaded.serialize(), pattern.serialize());
+    }
+}
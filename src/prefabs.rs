@@ -0,0 +1,158 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+//! Prefabricated patterns
+//!
+//! A small set of parameterized reference patterns. They are useful as starting points for new
+//! patterns and as examples of building BulletML documents in code.
+
+use std::rc::Rc;
+
+use crate::data::{
+    Action, Bullet, BulletML, Direction, Element, EntityRef, Expression, Fire, Repeat, Speed, Step,
+    Times, Wait,
+};
+
+fn top(steps: Vec<Step>) -> BulletML {
+    let mut action = Action::new(steps);
+    action.label = Some("top".into());
+
+    BulletML {
+        elements: vec![Element::Action(Rc::new(action))],
+        ..Default::default()
+    }
+}
+
+fn fire(direction: Direction, speed: f32) -> Step {
+    let mut fire = Fire::new(EntityRef::Real(Rc::new(Bullet::default())));
+    fire.direction = Some(direction);
+    fire.speed = Some(Speed::absolute(speed));
+
+    Step::Fire(EntityRef::Real(Rc::new(fire)))
+}
+
+fn repeat(times: usize, steps: Vec<Step>) -> Step {
+    Step::Repeat(Repeat::new(
+        Times::new(times as f32),
+        vec![EntityRef::Real(Rc::new(Action::new(steps)))],
+    ))
+}
+
+/// A fan of `count` bullets spread evenly over `spread_deg` degrees centered on the player.
+pub fn nway(count: usize, spread_deg: f32, speed: f32) -> BulletML {
+    if count <= 1 {
+        return top(vec![fire(Direction::aim(0.), speed)]);
+    }
+
+    let step = spread_deg / ((count - 1) as f32);
+    top(vec![
+        fire(Direction::aim(-spread_deg / 2.), speed),
+        repeat(count - 1, vec![fire(Direction::sequence(step), speed)]),
+    ])
+}
+
+/// A ring of `count` bullets evenly spaced around the emitter.
+pub fn ring(count: usize, speed: f32) -> BulletML {
+    if count == 0 {
+        return top(Vec::new());
+    }
+
+    let step = 360. / (count as f32);
+    top(vec![
+        fire(Direction::absolute(0.), speed),
+        repeat(count - 1, vec![fire(Direction::sequence(step), speed)]),
+    ])
+}
+
+/// A burst of `count` bullets randomly spread over `spread_deg` degrees centered on the player.
+pub fn spread(count: usize, spread_deg: f32, speed: f32) -> BulletML {
+    let direction = (Expression::rand() - 0.5) * spread_deg;
+
+    top(vec![repeat(
+        count,
+        vec![fire(Direction::aim(direction), speed)],
+    )])
+}
+
+/// A spiral of `arms` evenly spaced arms which rotates by `turn_deg` degrees between volleys.
+///
+/// The first volley is aimed at the player. Volleys are fired every `interval` frames.
+pub fn spiral(arms: usize, turn_deg: f32, volleys: usize, interval: f32, speed: f32) -> BulletML {
+    if arms == 0 {
+        return top(Vec::new());
+    }
+
+    let step = 360. / (arms as f32);
+    top(vec![repeat(
+        volleys,
+        vec![
+            fire(Direction::sequence(step + turn_deg), speed),
+            repeat(arms - 1, vec![fire(Direction::sequence(step), speed)]),
+            Step::Wait(Wait::new(interval)),
+        ],
+    )])
+}
+
+#[cfg(all(test, feature = "runtime"))]
+mod test {
+    use crate::data::BulletML;
+    use crate::prefabs;
+    use crate::run::CompiledBulletML;
+
+    /// The frame and direction of each bullet fired by a pattern.
+    ///
+    /// The simulated player is straight down (180 degrees).
+    fn fired(bulletml: BulletML, frames: u32) -> Vec<(u32, f32)> {
+        let compiled = CompiledBulletML::new(bulletml).unwrap();
+        let timeline = compiled.to_keyframes(0., 0, frames).unwrap();
+
+        timeline
+            .spawns
+            .iter()
+            .map(|spawn| {
+                let keyframe = timeline
+                    .keyframes
+                    .iter()
+                    .find(|keyframe| keyframe.bullet == Some(spawn.bullet))
+                    .unwrap();
+                (spawn.frame, keyframe.direction)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_prefab_nway() {
+        assert_eq!(
+            fired(prefabs::nway(3, 30., 2.), 1),
+            [(0, 165.), (0, 180.), (0, 195.)],
+        );
+        assert_eq!(fired(prefabs::nway(1, 30., 2.), 1), [(0, 180.)]);
+    }
+
+    #[test]
+    fn test_prefab_ring() {
+        assert_eq!(
+            fired(prefabs::ring(4, 1.), 1),
+            [(0, 0.), (0, 90.), (0, 180.), (0, 270.)],
+        );
+        assert!(fired(prefabs::ring(0, 1.), 1).is_empty());
+    }
+
+    #[test]
+    fn test_prefab_spread() {
+        let fired = fired(prefabs::spread(8, 30., 1.), 1);
+
+        assert_eq!(fired.len(), 8);
+        assert!(fired
+            .iter()
+            .all(|&(frame, direction)| frame == 0 && (165. ..=195.).contains(&direction)));
+    }
+
+    #[test]
+    fn test_prefab_spiral() {
+        assert_eq!(
+            fired(prefabs::spiral(2, 10., 3, 1., 1.), 3),
+            [(0, 180.), (0, 0.), (1, 190.), (1, 10.), (2, 200.), (2, 20.),],
+        );
+    }
+}
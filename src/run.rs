@@ -3,13 +3,69 @@
 
 //! Facilities for running a BulletML file.
 
-mod compile;
+#[cfg(feature = "pattern-cache")]
+pub(crate) mod binary;
+#[cfg(any(feature = "debug", feature = "reference-check"))]
+mod command;
+pub(crate) mod compile;
+mod coverage;
+mod event;
+mod example;
+#[cfg(feature = "async")]
+mod future;
+mod hash;
+#[cfg(feature = "debug")]
+mod history;
+mod interp;
 mod manager;
+mod options;
+mod pool;
+mod rank;
+#[cfg(feature = "reference-check")]
+mod reference;
+mod replay;
+mod rng;
 mod runner;
+mod sample;
+mod scope;
+mod semantics;
+mod sequence;
+pub mod simulate;
+mod spec;
+#[cfg(test)]
+pub(crate) mod testing;
+mod timeline;
 mod util;
+mod walk;
 mod zipper;
 
+#[cfg(feature = "debug")]
+pub use self::command::Command;
+pub use self::compile::{
+    BulletML as CompiledBulletML, BulletMLError, BulletPrototype, CompileJob, CompileOptions,
+};
+pub use self::coverage::Coverage;
+pub use self::event::{BulletId, Event, Observer};
+pub use self::example::{ExampleBullet, ExampleManager};
+#[cfg(feature = "async")]
+pub use self::future::{compile_async, CompileFuture};
+#[cfg(feature = "debug")]
+pub use self::history::History;
 pub use self::manager::BulletManager;
-pub use self::runner::Runner;
+pub use self::options::{
+    NegativeSpeed, NoTargetPolicy, RepeatEvaluation, RunnerOptions, RunnerOptionsBuilder,
+    UnknownVariablePolicy,
+};
+pub use self::pool::{PoolStats, Pooled, PooledBulletManager, RunnerPool, Spawned};
+pub use self::rank::{RankContext, RankCurve};
+pub use self::replay::{Recorder, Recording};
+pub use self::rng::Rng;
+pub use self::runner::{BulletScript, MicroStep, Runner, RunnerStatus, UpdateError, UpdateReport};
+pub use self::sample::SampleStats;
+pub use self::sequence::SequenceHandle;
+pub use self::timeline::{Keyframe, Spawn, Timeline};
+pub use self::walk::{StepInfo, StepIter, StepKind};
+#[cfg(feature = "pattern-cache")]
+pub(crate) use self::hash::source_hash;
 use self::zipper::Node;
 use self::zipper::ZipperIter;
@@ -0,0 +1,833 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use std::collections::hash_map::HashMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use thiserror::Error;
+
+use crate::data::{ExpressionLimits, Variables};
+use crate::run::compile::{
+    Accel, Action, Bullet, Change, ChangeDirection, ChangeSpeed, Direction, DirectionKind, Easing,
+    Expression, Fire, Horizontal, Library, Orientation, Repeat, Speed, Step, Term, Times, Value,
+    Vanish, Vertical, Wait,
+};
+use crate::run::CompiledBulletML;
+
+/// The start of an encoded script.
+const MAGIC: &[u8; 4] = b"BMLI";
+/// The version of the encoding.
+///
+/// This must be changed whenever the encoding changes.
+const FORMAT: u32 = 1;
+
+const ORIENTATIONS: &[Orientation] = &[
+    Orientation::None,
+    Orientation::Vertical,
+    Orientation::Horizontal,
+];
+const DIRECTION_KINDS: &[DirectionKind] = &[
+    DirectionKind::Aim,
+    DirectionKind::Absolute,
+    DirectionKind::Relative,
+    DirectionKind::Sequence,
+];
+const CHANGES: &[Change] = &[Change::Absolute, Change::Relative, Change::Sequence];
+const EASINGS: &[Easing] = &[
+    Easing::Linear,
+    Easing::EaseIn,
+    Easing::EaseOut,
+    Easing::EaseInOut,
+    Easing::Sine,
+];
+
+/// The tag for an entity which is written in full.
+const ENTITY: u8 = 0;
+/// The tag for an entity which has already been written.
+const BACK_REFERENCE: u8 = 1;
+
+/// An error encoding or decoding a compiled script.
+#[derive(Debug, Error)]
+pub(crate) enum BinaryError {
+    /// Custom steps are only known to the game which registered them.
+    #[error("custom steps may not be encoded")]
+    CustomStep,
+    /// The written form of an expression does not parse (e.g., infinite constants).
+    #[error("the expression `{}` may not be encoded", expr)]
+    Expression { expr: String },
+    /// The encoding is for another format or is not valid.
+    #[error("invalid encoding")]
+    Invalid,
+}
+
+/// The parameters given by a reference to an entity.
+type RefParams = Option<Arc<[Expression]>>;
+
+/// A writer for the binary form of a compiled script.
+///
+/// Values are written in little-endian order so that the encoding does not depend on the
+/// platform. Entities which are shared within the script are written in full once and as a
+/// back-reference afterwards, so sharing is kept when the script is read again.
+struct Writer {
+    out: Vec<u8>,
+    seen: HashMap<*const (), u32>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.out.push(value)
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.out.extend_from_slice(&value.to_le_bytes())
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.u32(value.to_bits())
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.u8(value as u8)
+    }
+
+    fn len(&mut self, len: usize) {
+        self.u32(len as u32)
+    }
+
+    fn str(&mut self, value: &str) {
+        self.len(value.len());
+        self.out.extend_from_slice(value.as_bytes())
+    }
+
+    fn tag<T>(&mut self, table: &[T], value: T)
+    where
+        T: Copy + PartialEq,
+    {
+        let tag = table
+            .iter()
+            .position(|&known| known == value)
+            .expect("every value has a tag");
+        self.u8(tag as u8)
+    }
+
+    fn opt<T, F>(&mut self, value: Option<T>, write: F) -> Result<(), BinaryError>
+    where
+        F: FnOnce(&mut Self, T) -> Result<(), BinaryError>,
+    {
+        self.bool(value.is_some());
+        value.map_or(Ok(()), |value| write(self, value))
+    }
+
+    fn opt_str(&mut self, value: Option<&str>) {
+        self.bool(value.is_some());
+        if let Some(value) = value {
+            self.str(value);
+        }
+    }
+
+    fn opt_u32(&mut self, value: Option<u32>) {
+        self.bool(value.is_some());
+        if let Some(value) = value {
+            self.u32(value);
+        }
+    }
+
+    fn expression(&mut self, expr: &Expression) -> Result<(), BinaryError> {
+        // The written form of an expression is read back exactly as long as it parses.
+        let text = expr.to_string();
+        if Expression::parse_with_limits(&text, &ExpressionLimits::unlimited()).is_err() {
+            return Err(BinaryError::Expression {
+                expr: text,
+            });
+        }
+        self.str(&text);
+        Ok(())
+    }
+
+    fn params(&mut self, params: &RefParams) -> Result<(), BinaryError> {
+        self.opt(params.as_ref(), |this, params| {
+            this.len(params.len());
+            params.iter().try_for_each(|param| this.expression(param))
+        })
+    }
+
+    /// Write a back-reference to a shared entity if it has already been written.
+    fn shared<T>(&mut self, entity: &Arc<T>) -> bool {
+        let ptr = Arc::as_ptr(entity) as *const ();
+        let next = self.seen.len() as u32;
+        if let Some(&index) = self.seen.get(&ptr) {
+            self.u8(BACK_REFERENCE);
+            self.u32(index);
+            true
+        } else {
+            self.seen.insert(ptr, next);
+            self.u8(ENTITY);
+            false
+        }
+    }
+
+    fn direction(&mut self, direction: &Direction) -> Result<(), BinaryError> {
+        self.tag(DIRECTION_KINDS, direction.kind);
+        self.bool(direction.lead);
+        self.expression(&direction.degrees)
+    }
+
+    fn speed(&mut self, speed: &Speed) -> Result<(), BinaryError> {
+        self.tag(CHANGES, speed.kind);
+        self.expression(&speed.change)
+    }
+
+    fn easing(&mut self, easing: Option<Easing>) -> Result<(), BinaryError> {
+        self.opt(easing, |this, easing| {
+            this.tag(EASINGS, easing);
+            Ok(())
+        })
+    }
+
+    fn action(&mut self, action: &Arc<Action>) -> Result<(), BinaryError> {
+        if self.shared(action) {
+            return Ok(());
+        }
+
+        self.opt_str(action.label.as_deref());
+        self.opt_u32(action.ttl);
+        self.len(action.tags.len());
+        action.tags.iter().for_each(|tag| self.str(tag));
+        self.len(action.steps.len());
+        action.steps.iter().try_for_each(|step| self.step(step))
+    }
+
+    fn ref_actions(&mut self, actions: &[(Arc<Action>, RefParams)]) -> Result<(), BinaryError> {
+        self.len(actions.len());
+        actions.iter().try_for_each(|(action, params)| {
+            self.action(action)?;
+            self.params(params)
+        })
+    }
+
+    fn step(&mut self, step: &Step) -> Result<(), BinaryError> {
+        match *step {
+            Step::Repeat(ref repeat) => {
+                self.u8(0);
+                self.expression(&repeat.times.value)?;
+                self.ref_actions(&repeat.actions)
+            },
+            Step::Fire(ref fire, ref params) => {
+                self.u8(1);
+                self.fire(fire)?;
+                self.params(params)
+            },
+            Step::ChangeSpeed(ref cs) => {
+                self.u8(2);
+                self.speed(&cs.speed)?;
+                self.expression(&cs.value.value)?;
+                self.easing(cs.easing)
+            },
+            Step::ChangeDirection(ref cd) => {
+                self.u8(3);
+                self.direction(&cd.direction)?;
+                self.expression(&cd.value.value)?;
+                self.easing(cd.easing)
+            },
+            Step::Accel(ref accel) => {
+                self.u8(4);
+                self.opt(accel.horizontal.as_ref(), |this, horizontal| {
+                    this.tag(CHANGES, horizontal.kind);
+                    this.expression(&horizontal.change)
+                })?;
+                self.opt(accel.vertical.as_ref(), |this, vertical| {
+                    this.tag(CHANGES, vertical.kind);
+                    this.expression(&vertical.change)
+                })?;
+                self.expression(&accel.duration.value)?;
+                self.easing(accel.easing)
+            },
+            Step::Wait(ref wait) => {
+                self.u8(5);
+                self.expression(&wait.frames)
+            },
+            Step::Vanish(_) => {
+                self.u8(6);
+                Ok(())
+            },
+            Step::Action(ref action, ref params) => {
+                self.u8(7);
+                self.action(action)?;
+                self.params(params)
+            },
+            Step::Custom(_) => Err(BinaryError::CustomStep),
+        }
+    }
+
+    fn fire(&mut self, fire: &Arc<Fire>) -> Result<(), BinaryError> {
+        if self.shared(fire) {
+            return Ok(());
+        }
+
+        self.opt_str(fire.label.as_deref());
+        self.opt(fire.direction.as_ref(), Self::direction)?;
+        self.opt(fire.speed.as_ref(), Self::speed)?;
+        self.bullet(&fire.bullet)?;
+        self.params(&fire.bullet_params)
+    }
+
+    fn bullet(&mut self, bullet: &Arc<Bullet>) -> Result<(), BinaryError> {
+        if self.shared(bullet) {
+            return Ok(());
+        }
+
+        self.opt_str(bullet.label.as_deref());
+        self.opt_u32(bullet.ttl);
+        self.opt(bullet.direction.as_ref(), Self::direction)?;
+        self.opt(bullet.speed.as_ref(), Self::speed)?;
+        self.ref_actions(&bullet.actions)
+    }
+
+    /// Write labeled entities in the order of their labels.
+    fn labeled<T, F>(&mut self, entities: &HashMap<String, T>, write: F) -> Result<(), BinaryError>
+    where
+        F: Fn(&mut Self, &T) -> Result<(), BinaryError>,
+    {
+        let mut labeled = entities.iter().collect::<Vec<_>>();
+        labeled.sort_unstable_by_key(|&(label, _)| label);
+
+        self.len(labeled.len());
+        labeled.into_iter().try_for_each(|(label, entity)| {
+            self.str(label);
+            write(self, entity)
+        })
+    }
+}
+
+/// A compiled entity which has been read.
+#[derive(Clone)]
+enum Entity {
+    Action(Arc<Action>),
+    Bullet(Arc<Bullet>),
+    Fire(Arc<Fire>),
+}
+
+/// A reader for the binary form of a compiled script.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    /// Entities by the order in which they were written.
+    ///
+    /// Entities are recorded before their contents are read, so their slots are empty until then.
+    entities: Vec<Option<Entity>>,
+    variables: Variables,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], BinaryError> {
+        if self.bytes.len() < len {
+            return Err(BinaryError::Invalid);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, BinaryError> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, BinaryError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(
+            <[u8; 4]>::try_from(bytes).map_err(|_| BinaryError::Invalid)?,
+        ))
+    }
+
+    fn f32(&mut self) -> Result<f32, BinaryError> {
+        self.u32().map(f32::from_bits)
+    }
+
+    fn bool(&mut self) -> Result<bool, BinaryError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(BinaryError::Invalid),
+        }
+    }
+
+    fn len(&mut self) -> Result<usize, BinaryError> {
+        self.u32().map(|len| len as usize)
+    }
+
+    fn str(&mut self) -> Result<String, BinaryError> {
+        let len = self.len()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.into()).map_err(|_| BinaryError::Invalid)
+    }
+
+    fn tag<T>(&mut self, table: &[T]) -> Result<T, BinaryError>
+    where
+        T: Copy,
+    {
+        let tag = self.u8()?;
+        table.get(tag as usize).copied().ok_or(BinaryError::Invalid)
+    }
+
+    fn opt<T, F>(&mut self, read: F) -> Result<Option<T>, BinaryError>
+    where
+        F: FnOnce(&mut Self) -> Result<T, BinaryError>,
+    {
+        if self.bool()? {
+            read(self).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn list<T, F>(&mut self, mut read: F) -> Result<Vec<T>, BinaryError>
+    where
+        F: FnMut(&mut Self) -> Result<T, BinaryError>,
+    {
+        let len = self.len()?;
+        (0..len).map(|_| read(self)).collect()
+    }
+
+    fn expression(&mut self) -> Result<Expression, BinaryError> {
+        let text = self.str()?;
+        let mut expr = Expression::parse_with_limits(&text, &ExpressionLimits::unlimited())
+            .map_err(|_| BinaryError::Invalid)?;
+        // Variables were declared up front, so they are given the indices they were written with.
+        expr.intern(&mut self.variables);
+        Ok(expr)
+    }
+
+    fn params(&mut self) -> Result<RefParams, BinaryError> {
+        self.opt(|this| this.list(Self::expression).map(Into::into))
+    }
+
+    /// Read an entity which has already been read or reserve a slot for a new one.
+    fn shared(&mut self) -> Result<Result<Entity, usize>, BinaryError> {
+        match self.u8()? {
+            ENTITY => {
+                self.entities.push(None);
+                Ok(Err(self.entities.len() - 1))
+            },
+            BACK_REFERENCE => {
+                let index = self.len()?;
+                self.entities
+                    .get(index)
+                    .cloned()
+                    .flatten()
+                    .map(Ok)
+                    .ok_or(BinaryError::Invalid)
+            },
+            _ => Err(BinaryError::Invalid),
+        }
+    }
+
+    fn direction(&mut self) -> Result<Direction, BinaryError> {
+        Ok(Direction {
+            kind: self.tag(DIRECTION_KINDS)?,
+            lead: self.bool()?,
+            degrees: self.expression()?,
+        })
+    }
+
+    fn speed(&mut self) -> Result<Speed, BinaryError> {
+        Ok(Speed {
+            kind: self.tag(CHANGES)?,
+            change: self.expression()?,
+        })
+    }
+
+    fn easing(&mut self) -> Result<Option<Easing>, BinaryError> {
+        self.opt(|this| this.tag(EASINGS))
+    }
+
+    fn action(&mut self) -> Result<Arc<Action>, BinaryError> {
+        let slot = match self.shared()? {
+            Ok(Entity::Action(action)) => return Ok(action),
+            Ok(_) => return Err(BinaryError::Invalid),
+            Err(slot) => slot,
+        };
+
+        let label = self.opt(Self::str)?;
+        let ttl = self.opt(Self::u32)?;
+        let tags = self.list(Self::str)?;
+        let steps = self.list(Self::step)?;
+        let action = Arc::new(Action {
+            label: label.map(Into::into),
+            ttl,
+            tags: tags.into(),
+            steps,
+        });
+
+        self.entities[slot] = Some(Entity::Action(Arc::clone(&action)));
+        Ok(action)
+    }
+
+    fn ref_actions(&mut self) -> Result<Vec<(Arc<Action>, RefParams)>, BinaryError> {
+        self.list(|this| Ok((this.action()?, this.params()?)))
+    }
+
+    fn step(&mut self) -> Result<Step, BinaryError> {
+        Ok(match self.u8()? {
+            0 => {
+                Step::Repeat(Repeat {
+                    times: Times {
+                        value: self.expression()?,
+                    },
+                    actions: self.ref_actions()?,
+                })
+            },
+            1 => Step::Fire(self.fire()?, self.params()?),
+            2 => {
+                Step::ChangeSpeed(ChangeSpeed {
+                    speed: self.speed()?,
+                    value: Term {
+                        value: self.expression()?,
+                    },
+                    easing: self.easing()?,
+                })
+            },
+            3 => {
+                Step::ChangeDirection(ChangeDirection {
+                    direction: self.direction()?,
+                    value: Term {
+                        value: self.expression()?,
+                    },
+                    easing: self.easing()?,
+                })
+            },
+            4 => {
+                Step::Accel(Accel {
+                    horizontal: self.opt(|this| {
+                        Ok(Horizontal {
+                            kind: this.tag(CHANGES)?,
+                            change: this.expression()?,
+                        })
+                    })?,
+                    vertical: self.opt(|this| {
+                        Ok(Vertical {
+                            kind: this.tag(CHANGES)?,
+                            change: this.expression()?,
+                        })
+                    })?,
+                    duration: Term {
+                        value: self.expression()?,
+                    },
+                    easing: self.easing()?,
+                })
+            },
+            5 => {
+                Step::Wait(Wait {
+                    frames: self.expression()?,
+                })
+            },
+            6 => Step::Vanish(Vanish {}),
+            7 => Step::Action(self.action()?, self.params()?),
+            _ => return Err(BinaryError::Invalid),
+        })
+    }
+
+    fn fire(&mut self) -> Result<Arc<Fire>, BinaryError> {
+        let slot = match self.shared()? {
+            Ok(Entity::Fire(fire)) => return Ok(fire),
+            Ok(_) => return Err(BinaryError::Invalid),
+            Err(slot) => slot,
+        };
+
+        let fire = Arc::new(Fire {
+            label: self.opt(Self::str)?,
+            direction: self.opt(Self::direction)?,
+            speed: self.opt(Self::speed)?,
+            bullet: self.bullet()?,
+            bullet_params: self.params()?,
+        });
+
+        self.entities[slot] = Some(Entity::Fire(Arc::clone(&fire)));
+        Ok(fire)
+    }
+
+    fn bullet(&mut self) -> Result<Arc<Bullet>, BinaryError> {
+        let slot = match self.shared()? {
+            Ok(Entity::Bullet(bullet)) => return Ok(bullet),
+            Ok(_) => return Err(BinaryError::Invalid),
+            Err(slot) => slot,
+        };
+
+        let bullet = Arc::new(Bullet {
+            label: self.opt(Self::str)?,
+            ttl: self.opt(Self::u32)?,
+            direction: self.opt(Self::direction)?,
+            speed: self.opt(Self::speed)?,
+            actions: self.ref_actions()?,
+        });
+
+        self.entities[slot] = Some(Entity::Bullet(Arc::clone(&bullet)));
+        Ok(bullet)
+    }
+
+    /// Read labeled entities.
+    fn labeled<T, F>(&mut self, mut read: F) -> Result<HashMap<String, T>, BinaryError>
+    where
+        F: FnMut(&mut Self) -> Result<T, BinaryError>,
+    {
+        self.list(|this| Ok((this.str()?, read(this)?)))
+            .map(|entities| entities.into_iter().collect())
+    }
+}
+
+/// Encode a compiled script.
+///
+/// Scripts with custom steps may not be encoded.
+pub(crate) fn encode(bulletml: &CompiledBulletML) -> Result<Vec<u8>, BinaryError> {
+    let mut writer = Writer {
+        out: MAGIC.to_vec(),
+        seen: HashMap::new(),
+    };
+    writer.u32(FORMAT);
+
+    let library = bulletml.library();
+    writer.tag(ORIENTATIONS, bulletml.orientation);
+    writer.opt(library.variables.rank(), |this, rank| {
+        this.f32(rank);
+        Ok(())
+    })?;
+    writer.len(library.variables.names().len());
+    library
+        .variables
+        .names()
+        .iter()
+        .for_each(|name| writer.str(name));
+
+    writer.labeled(&library.actions, Writer::action)?;
+    writer.labeled(&library.bullets, Writer::bullet)?;
+    writer.labeled(&library.fires, Writer::fire)?;
+    writer.len(bulletml.actions().len());
+    bulletml
+        .actions()
+        .iter()
+        .try_for_each(|action| writer.action(action))?;
+
+    Ok(writer.out)
+}
+
+/// Decode a compiled script.
+pub(crate) fn decode(bytes: &[u8]) -> Result<CompiledBulletML, BinaryError> {
+    let mut reader = Reader {
+        bytes,
+        entities: Vec::new(),
+        variables: Variables::default(),
+    };
+    if reader.take(MAGIC.len())? != MAGIC || reader.u32()? != FORMAT {
+        return Err(BinaryError::Invalid);
+    }
+
+    let orientation = reader.tag(ORIENTATIONS)?;
+    let rank: Option<Value> = reader.opt(Reader::f32)?;
+    let names = reader.list(Reader::str)?;
+    reader.variables = Variables::with_rank(rank);
+    names.iter().for_each(|name| {
+        reader.variables.index(name);
+    });
+
+    let actions = reader.labeled(Reader::action)?;
+    let bullets = reader.labeled(Reader::bullet)?;
+    let fires = reader.labeled(Reader::fire)?;
+    let top_actions = reader.list(Reader::action)?;
+    if !reader.bytes.is_empty() || reader.variables.names().len() != names.len() {
+        return Err(BinaryError::Invalid);
+    }
+
+    let library = Library {
+        actions,
+        bullets,
+        fires,
+        variables: reader.variables,
+    };
+    Ok(CompiledBulletML::from_parts(
+        orientation,
+        top_actions,
+        library,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::data;
+    use crate::run::binary::{self, BinaryError};
+    use crate::run::testing::TestManager;
+    use crate::run::{CompileOptions, CompiledBulletML, Runner, RunnerOptions};
+
+    const DOC: &str = r#"<bulletml type="horizontal">
+        <bullet label="shot" ttl="30">
+            <direction type="relative">$1</direction>
+            <speed>1 + $rank</speed>
+            <actionRef label="curve">
+                <param>$2 * 2</param>
+            </actionRef>
+        </bullet>
+        <action label="curve" tags="hard,extra">
+            <changeDirection>
+                <direction type="sequence">$1</direction>
+                <term>10</term>
+            </changeDirection>
+            <changeSpeed easing="easeOut">
+                <speed type="relative">-0.5</speed>
+                <term>5</term>
+            </changeSpeed>
+            <accel>
+                <vertical type="sequence">0.1</vertical>
+                <term>$level</term>
+            </accel>
+            <wait>3</wait>
+            <vanish/>
+        </action>
+        <fire label="volley">
+            <direction type="aim">$rand * 20 - 10</direction>
+            <bulletRef label="shot">
+                <param>15</param>
+                <param>$1</param>
+            </bulletRef>
+        </fire>
+        <action label="unused">
+            <wait>1</wait>
+        </action>
+        <action label="top">
+            <repeat>
+                <times>2 + $rank * 4</times>
+                <action>
+                    <fireRef label="volley">
+                        <param>max($level, 1)</param>
+                    </fireRef>
+                    <fireRef label="volley">
+                        <param>-1</param>
+                    </fireRef>
+                    <wait>4</wait>
+                </action>
+            </repeat>
+        </action>
+    </bulletml>"#;
+
+    fn compile(options: CompileOptions) -> CompiledBulletML {
+        let bulletml: data::BulletML = serde_xml_rs::from_str(DOC).unwrap();
+        CompiledBulletML::with_options(bulletml, options).unwrap()
+    }
+
+    fn round_trip(compiled: &CompiledBulletML) -> CompiledBulletML {
+        binary::decode(&binary::encode(compiled).unwrap()).unwrap()
+    }
+
+    fn trace(compiled: &CompiledBulletML) -> Vec<String> {
+        let manager = TestManager {
+            rank: 0.5,
+            variables: vec![("level", 3.)],
+            ..TestManager::default()
+        };
+        let mut runner = Runner::from_compiled(manager, compiled, RunnerOptions::default());
+        let mut scripts = Vec::new();
+        let mut trace = Vec::new();
+
+        for turn in 0..40 {
+            runner.manager_mut().turn = turn;
+            runner.update().unwrap();
+            scripts.append(&mut runner.manager_mut().scripts);
+            trace.extend(runner.manager_mut().log.drain(..));
+        }
+
+        // The actions of fired bullets are part of the script too.
+        let script = scripts.remove(0);
+        let mut runner = script.runner(TestManager {
+            variables: vec![("level", 3.)],
+            ..TestManager::default()
+        });
+        for turn in 0..20 {
+            runner.manager_mut().turn = turn;
+            runner.update().unwrap();
+            trace.extend(runner.manager_mut().log.drain(..));
+        }
+
+        trace
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let compiled = compile(CompileOptions::default());
+        let decoded = round_trip(&compiled);
+
+        assert_eq!(decoded.content_hash(), compiled.content_hash());
+        assert_eq!(decoded.orientation, compiled.orientation);
+        assert_eq!(decoded.variables(), compiled.variables());
+        assert_eq!(decoded.tags(), compiled.tags());
+        assert_eq!(
+            decoded.action_labels().collect::<Vec<_>>(),
+            compiled.action_labels().collect::<Vec<_>>(),
+        );
+        assert_eq!(
+            decoded.bullet_prototypes().collect::<Vec<_>>(),
+            compiled.bullet_prototypes().collect::<Vec<_>>(),
+        );
+        assert!(decoded.action("unused").is_some());
+        assert_eq!(trace(&decoded), trace(&compiled));
+
+        // Encoding is deterministic.
+        assert_eq!(
+            binary::encode(&decoded).unwrap(),
+            binary::encode(&compiled).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_binary_sharing() {
+        let decoded = round_trip(&compile(CompileOptions::default()));
+
+        let volley = decoded.fire("volley").unwrap();
+        let shot = decoded.bullet("shot").unwrap();
+        assert!(std::ptr::eq(volley.bullet.as_ref(), shot));
+        let curve = decoded.action("curve").unwrap();
+        assert!(std::ptr::eq(shot.actions[0].0.as_ref(), curve));
+    }
+
+    #[test]
+    fn test_binary_fixed_rank() {
+        let compiled = compile(CompileOptions {
+            rank: Some(0.5),
+        });
+        let decoded = round_trip(&compiled);
+
+        assert_eq!(decoded.fixed_rank(), Some(0.5));
+        assert_eq!(decoded.content_hash(), compiled.content_hash());
+        assert_eq!(trace(&decoded), trace(&compiled));
+    }
+
+    #[test]
+    fn test_binary_invalid() {
+        let bytes = binary::encode(&compile(CompileOptions::default())).unwrap();
+
+        for len in [0, 4, 8, bytes.len() / 2, bytes.len() - 1].iter() {
+            let err = binary::decode(&bytes[..*len]).unwrap_err();
+            assert!(matches!(err, BinaryError::Invalid));
+        }
+
+        let mut extended = bytes.clone();
+        extended.push(0);
+        let err = binary::decode(&extended).unwrap_err();
+        assert!(matches!(err, BinaryError::Invalid));
+
+        let mut other_format = bytes;
+        other_format[4] ^= 0xff;
+        let err = binary::decode(&other_format).unwrap_err();
+        assert!(matches!(err, BinaryError::Invalid));
+    }
+
+    #[test]
+    fn test_binary_unencodable_expression() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <wait>1 / 0</wait>
+            </action>
+        </bulletml>"#;
+        let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+        let compiled = CompiledBulletML::new(bulletml).unwrap();
+
+        let err = binary::encode(&compiled).unwrap_err();
+        assert!(matches!(err, BinaryError::Expression { .. }));
+    }
+}
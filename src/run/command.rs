@@ -0,0 +1,63 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use crate::run::BulletManager;
+
+/// A command given to a manager by a runner.
+///
+/// Commands are compared bit for bit so that `NaN` values match as well.
+#[derive(Debug, Clone, Copy)]
+pub enum Command {
+    /// A simple bullet was fired with a direction and speed.
+    NewSimple(f32, f32),
+    /// A bullet with actions was fired with a direction and speed.
+    NewBullet(f32, f32),
+    /// The bullet vanished.
+    Vanish,
+    /// The direction of the bullet changed.
+    ChangeDirection(f32),
+    /// The speed of the bullet changed.
+    ChangeSpeed(f32),
+    /// The bullet accelerated along the `x` axis.
+    AccelX(f32),
+    /// The bullet accelerated along the `y` axis.
+    AccelY(f32),
+}
+
+impl Command {
+    fn values(self) -> (u8, [u32; 2]) {
+        match self {
+            Command::NewSimple(dir, speed) => (0, [dir.to_bits(), speed.to_bits()]),
+            Command::NewBullet(dir, speed) => (1, [dir.to_bits(), speed.to_bits()]),
+            Command::Vanish => (2, [0, 0]),
+            Command::ChangeDirection(v) => (3, [v.to_bits(), 0]),
+            Command::ChangeSpeed(v) => (4, [v.to_bits(), 0]),
+            Command::AccelX(v) => (5, [v.to_bits(), 0]),
+            Command::AccelY(v) => (6, [v.to_bits(), 0]),
+        }
+    }
+
+    /// Give the command to a manager.
+    ///
+    /// Scripts of fired bullets are not kept, so bullets with actions are given to `new_bullet`.
+    pub fn apply<T>(self, manager: &mut T)
+    where
+        T: BulletManager + ?Sized,
+    {
+        match self {
+            Command::NewSimple(dir, speed) => manager.new_simple(dir, speed),
+            Command::NewBullet(dir, speed) => manager.new_bullet(dir, speed),
+            Command::Vanish => manager.vanish(),
+            Command::ChangeDirection(v) => manager.change_direction(v),
+            Command::ChangeSpeed(v) => manager.change_speed(v),
+            Command::AccelX(v) => manager.accel_x(v),
+            Command::AccelY(v) => manager.accel_y(v),
+        }
+    }
+}
+
+impl PartialEq for Command {
+    fn eq(&self, other: &Self) -> bool {
+        self.values() == other.values()
+    }
+}
@@ -2,28 +2,149 @@
 // See accompanying LICENSE file for details.
 
 use std::collections::hash_map::HashMap;
+use std::collections::hash_set::HashSet;
+use std::collections::BTreeSet;
 use std::iter;
+use std::mem;
 use std::rc::Rc;
+use std::slice;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use std::vec;
 
 use thiserror::Error;
 
-use crate::data::{self, EntityLookup, ExpressionError};
+use crate::data::{self, EntityLookup, ErrorCode, ExpressionError, Variables};
 pub use crate::data::{
-    Accel, Change, ChangeDirection, ChangeSpeed, Direction, DirectionKind, Expression,
-    ExpressionContext, Horizontal, Orientation, Speed, Term, Times, Value, Vanish, Vertical, Wait,
+    Accel, Change, ChangeDirection, ChangeSpeed, CustomStep, Direction, DirectionKind, Easing,
+    Expression, ExpressionContext, Horizontal, Orientation, Speed, Term, Times, Value, Vanish,
+    Vertical, Wait,
 };
 use crate::run::compile;
+use crate::run::semantics;
 use crate::run::util;
 use crate::run::{Node, ZipperIter};
 
+/// Entities whose expressions may have indices assigned to their variables.
+trait Intern {
+    fn intern(&mut self, vars: &mut Variables);
+
+    fn interned(&self, vars: &mut Variables) -> Self
+    where
+        Self: Clone,
+    {
+        let mut interned = self.clone();
+        interned.intern(vars);
+        interned
+    }
+}
+
+impl<T> Intern for Option<T>
+where
+    T: Intern,
+{
+    fn intern(&mut self, vars: &mut Variables) {
+        if let Some(inner) = self.as_mut() {
+            inner.intern(vars);
+        }
+    }
+}
+
+impl Intern for Expression {
+    fn intern(&mut self, vars: &mut Variables) {
+        Expression::intern(self, vars)
+    }
+}
+
+macro_rules! impl_intern {
+    ( $type:ty, $field:ident ) => {
+        impl Intern for $type {
+            fn intern(&mut self, vars: &mut Variables) {
+                self.$field.intern(vars);
+            }
+        }
+    };
+}
+
+impl_intern!(Term, value);
+impl_intern!(Times, value);
+impl_intern!(Wait, frames);
+impl_intern!(Direction, degrees);
+impl_intern!(Speed, change);
+impl_intern!(Horizontal, change);
+impl_intern!(Vertical, change);
+
+impl Intern for ChangeDirection {
+    fn intern(&mut self, vars: &mut Variables) {
+        self.direction.intern(vars);
+        self.value.intern(vars);
+    }
+}
+
+impl Intern for ChangeSpeed {
+    fn intern(&mut self, vars: &mut Variables) {
+        self.speed.intern(vars);
+        self.value.intern(vars);
+    }
+}
+
+impl Intern for Accel {
+    fn intern(&mut self, vars: &mut Variables) {
+        self.horizontal.intern(vars);
+        self.vertical.intern(vars);
+        self.duration.intern(vars);
+    }
+}
+
+/// The parameters given by a reference to an entity.
+///
+/// Inline entities have no parameters of their own and use those of the enclosing action.
+type RefParams = Option<Arc<[Expression]>>;
+
+fn ref_params<T>(lib: &mut Library, entity: &data::EntityRef<T>) -> RefParams {
+    if let data::EntityRef::Ref(ref refer) = *entity {
+        Some(
+            refer
+                .params()
+                .iter()
+                .map(|param| param.value().interned(&mut lib.variables))
+                .collect(),
+        )
+    } else {
+        None
+    }
+}
+
+/// The parameters of a running action.
+#[derive(Debug)]
+pub enum Frame {
+    /// The action uses the parameters of the enclosing action.
+    Inherited,
+    /// The parameters given by the reference to the action, evaluated once it starts.
+    Unbound(Arc<[Expression]>),
+    /// The values of the parameters.
+    Bound(Vec<Value>),
+}
+
+/// The tags of an action.
+pub type Tags = Arc<[String]>;
+
 /// Entities which may appear within an action tree.
 #[derive(Debug)]
 pub enum NodeStep {
+    /// The root of a tree of actions.
     Root,
+    /// The start of an action.
+    Action(Option<Arc<str>>, Tags, Frame),
     /// Cause a set of actions to be repeated a number of times.
     Repeat(Repeat),
+    /// An iteration of a repeat.
+    ///
+    /// The count is either fixed when the repeat starts or evaluated for each iteration.
+    RepeatIteration(Repeat, usize, Option<usize>),
     /// Cause a set bullets to be fired.
-    Fire(Rc<Fire>),
+    Fire(Arc<Fire>, RefParams),
     /// A change of speed.
     ChangeSpeed(ChangeSpeed),
     /// A change of direction.
@@ -34,15 +155,41 @@ pub enum NodeStep {
     Wait(Wait),
     /// Destroy the bullet.
     Vanish(Vanish),
+    /// A user-defined step.
+    Custom(Arc<dyn CustomStep>),
+    /// Vanish the bullet after a number of frames.
+    Ttl(u32),
+}
+
+impl NodeStep {
+    /// The name of the element for the step.
+    pub fn name(&self) -> &str {
+        match *self {
+            NodeStep::Root => "bulletml",
+            NodeStep::Action(..) => "action",
+            NodeStep::Repeat(_) | NodeStep::RepeatIteration(..) => "repeat",
+            NodeStep::Fire(..) => "fire",
+            NodeStep::ChangeSpeed(_) => "changeSpeed",
+            NodeStep::ChangeDirection(_) => "changeDirection",
+            NodeStep::Accel(_) => "accel",
+            NodeStep::Wait(_) => "wait",
+            NodeStep::Vanish(_) => "vanish",
+            NodeStep::Custom(ref c) => c.name(),
+            NodeStep::Ttl(_) => "ttl",
+        }
+    }
 }
 
 /// Entities which may appear within an action.
+///
+/// Steps which use another entity share its compiled form. Entities given by a reference carry
+/// the expressions of the `<param>` elements of the reference.
 #[derive(Debug, Clone)]
-enum Step {
+pub enum Step {
     /// Cause a set of actions to be repeated a number of times.
     Repeat(Repeat),
     /// Cause a set bullets to be fired.
-    Fire(Rc<Fire>),
+    Fire(Arc<Fire>, RefParams),
     /// A change of speed.
     ChangeSpeed(ChangeSpeed),
     /// A change of direction.
@@ -54,7 +201,9 @@ enum Step {
     /// Destroy the bullet.
     Vanish(Vanish),
     /// Chain into another action.
-    Action(Rc<Action>),
+    Action(Arc<Action>, RefParams),
+    /// A user-defined step.
+    Custom(Arc<dyn CustomStep>),
 }
 
 #[derive(Debug, Error)]
@@ -81,28 +230,67 @@ pub enum StepError {
     },
 }
 
+impl StepError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            StepError::EntityLookup {
+                source,
+            } => source.code(),
+            StepError::Repeat {
+                source,
+            } => source.code(),
+            StepError::Fire {
+                source,
+            } => source.code(),
+            StepError::Action {
+                source,
+            } => source.code(),
+        }
+    }
+}
+
 impl Step {
     fn new(
         lib: &mut Library,
         data_lib: &mut DataLibrary,
         step: &data::Step,
     ) -> Result<Self, StepError> {
+        let location = &mut data_lib.location;
         match *step {
-            data::Step::ChangeSpeed(ref cs) => Ok(Step::ChangeSpeed(cs.clone())),
-            data::Step::ChangeDirection(ref cd) => Ok(Step::ChangeDirection(cd.clone())),
-            data::Step::Accel(ref accel) => Ok(Step::Accel(accel.clone())),
-            data::Step::Wait(ref wait) => Ok(Step::Wait(wait.clone())),
-            data::Step::Vanish(vanish) => Ok(Step::Vanish(vanish)),
+            data::Step::ChangeSpeed(ref cs) => {
+                location.skip("changeSpeed");
+                Ok(Step::ChangeSpeed(cs.interned(&mut lib.variables)))
+            },
+            data::Step::ChangeDirection(ref cd) => {
+                location.skip("changeDirection");
+                Ok(Step::ChangeDirection(cd.interned(&mut lib.variables)))
+            },
+            data::Step::Accel(ref accel) => {
+                location.skip("accel");
+                Ok(Step::Accel(accel.interned(&mut lib.variables)))
+            },
+            data::Step::Wait(ref wait) => {
+                location.skip("wait");
+                Ok(Step::Wait(wait.interned(&mut lib.variables)))
+            },
+            data::Step::Vanish(vanish) => {
+                location.skip("vanish");
+                Ok(Step::Vanish(vanish))
+            },
+            data::Step::Custom(ref custom) => Ok(Step::Custom(custom.clone())),
             data::Step::Repeat(ref repeat) => {
-                Ok(Repeat::new(lib, data_lib, repeat).map(Step::Repeat)?)
+                location.enter("repeat", None);
+                let compiled = Repeat::new(lib, data_lib, repeat)?;
+                data_lib.location.leave();
+                Ok(Step::Repeat(compiled))
             },
             data::Step::Fire(ref fire) => {
-                let entity = fire.entity(data_lib)?;
-                Ok(Fire::new(lib, data_lib, entity).map(Step::Fire)?)
+                let compiled = Fire::resolve(lib, data_lib, fire)?;
+                Ok(Step::Fire(compiled, ref_params(lib, fire)))
             },
             data::Step::Action(ref action) => {
-                let entity = action.entity(data_lib)?;
-                Ok(Action::new(lib, data_lib, entity).map(Step::Action)?)
+                let compiled = Action::resolve(lib, data_lib, action)?;
+                Ok(Step::Action(compiled, ref_params(lib, action)))
             },
         }
     }
@@ -115,17 +303,9 @@ impl Step {
             Step::Wait(wait) => Node::new(NodeStep::Wait(wait)),
             Step::Vanish(vanish) => Node::new(NodeStep::Vanish(vanish)),
             Step::Repeat(repeat) => Node::new(NodeStep::Repeat(repeat)),
-            Step::Fire(fire) => Node::new(NodeStep::Fire(fire)),
-            Step::Action(action) => {
-                let mut node = Node::new(NodeStep::Root);
-                action
-                    .steps
-                    .iter()
-                    .cloned()
-                    .for_each(|step| node.add_child(step.into_node()));
-
-                node
-            },
+            Step::Fire(fire, params) => Node::new(NodeStep::Fire(fire, params)),
+            Step::Custom(custom) => Node::new(NodeStep::Custom(custom)),
+            Step::Action(action, params) => action.node(params),
         }
     }
 }
@@ -133,8 +313,14 @@ impl Step {
 /// An action that may be performed for a bullet.
 #[derive(Debug)]
 pub struct Action {
+    /// The label of the action.
+    pub(crate) label: Option<Arc<str>>,
+    /// The number of frames after which the bullet vanishes once the action starts.
+    pub(crate) ttl: Option<u32>,
+    /// The tags of the action.
+    pub(crate) tags: Tags,
     /// The steps which make up the action.
-    steps: Vec<Step>,
+    pub(crate) steps: Vec<Step>,
 }
 
 #[derive(Debug, Error)]
@@ -156,13 +342,89 @@ pub enum ActionError {
     },
 }
 
+impl ActionError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ActionError::EntityLookup {
+                source,
+            } => source.code(),
+            ActionError::EntityUse {
+                source,
+            } => source.code(),
+            ActionError::Step {
+                source,
+            } => source.code(),
+        }
+    }
+}
+
 impl Action {
+    /// The label of the action.
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// The number of frames after which the bullet vanishes once the action starts.
+    pub fn ttl(&self) -> Option<u32> {
+        self.ttl
+    }
+
+    /// The tags of the action.
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    /// The steps which make up the action.
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    /// Resolve a reference to an already compiled entity or compile it.
+    fn resolve(
+        lib: &mut Library,
+        data_lib: &mut DataLibrary,
+        action: &data::EntityRef<data::Action>,
+    ) -> Result<Arc<Self>, ActionError> {
+        data_lib
+            .location
+            .enter_entity(action, "action", "actionRef");
+
+        let compiled = if let Some(compiled) = Self::compiled(lib, action) {
+            compiled
+        } else {
+            let entity = action.entity(data_lib)?;
+            data_lib.location.enter_declaration(action, "action");
+            let compiled = Self::new(lib, data_lib, entity)?;
+            data_lib.location.leave_declaration(action);
+            compiled
+        };
+
+        data_lib.location.leave();
+        Ok(compiled)
+    }
+
+    /// The compiled entity for a reference, if it has been compiled.
+    fn compiled(lib: &Library, action: &data::EntityRef<data::Action>) -> Option<Arc<Self>> {
+        if let data::EntityRef::Ref(ref refer) = *action {
+            lib.actions.get(refer.label()).cloned()
+        } else {
+            None
+        }
+    }
+
     fn new(
         lib: &mut Library,
         data_lib: &mut DataLibrary,
         action: Rc<data::Action>,
-    ) -> Result<Rc<Self>, ActionError> {
-        let comp_action = Rc::new(Action {
+    ) -> Result<Arc<Self>, ActionError> {
+        if let Some(name) = action.label.as_ref() {
+            data_lib.compiling_actions.insert(name.clone());
+        }
+
+        let comp_action = Arc::new(Action {
+            label: action.label.as_ref().map(|label| label.as_str().into()),
+            ttl: action.ttl,
+            tags: action.tags.as_slice().into(),
             steps: action
                 .steps
                 .iter()
@@ -175,28 +437,37 @@ impl Action {
             .label
             .as_ref()
             .map(|name| {
+                data_lib.compiling_actions.remove(name);
+                data_lib
+                    .actions
+                    .entry(name.clone())
+                    .or_insert_with(|| action.clone());
                 util::try_insert(
                     name.clone(),
                     &mut lib.actions,
                     || comp_action.clone(),
                     "action",
                 )
-                .and_then(|_| {
-                    util::try_insert(
-                        name.clone(),
-                        &mut data_lib.actions,
-                        || action.clone(),
-                        "action",
-                    )
-                })
             })
             .transpose()?;
 
         Ok(comp_action)
     }
 
-    fn node(&self) -> Node<NodeStep> {
-        let mut node = Node::new(NodeStep::Root);
+    fn node(&self, params: RefParams) -> Node<NodeStep> {
+        self.node_in(params.map_or(Frame::Inherited, Frame::Unbound))
+    }
+
+    /// The node for the action running in a given frame.
+    pub(crate) fn node_in(&self, frame: Frame) -> Node<NodeStep> {
+        let mut node = Node::new(NodeStep::Action(
+            self.label.clone(),
+            self.tags.clone(),
+            frame,
+        ));
+        if let Some(ttl) = self.ttl {
+            node.add_child(Node::new(NodeStep::Ttl(ttl)));
+        }
         self.steps
             .iter()
             .cloned()
@@ -208,6 +479,11 @@ impl Action {
 
 #[derive(Debug, Error)]
 pub enum BulletError {
+    #[error("lookup entity")]
+    EntityLookup {
+        #[from]
+        source: data::EntityError,
+    },
     #[error("using entity")]
     EntityUse {
         #[from]
@@ -220,32 +496,94 @@ pub enum BulletError {
     },
 }
 
+impl BulletError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            BulletError::EntityLookup {
+                source,
+            } => source.code(),
+            BulletError::EntityUse {
+                source,
+            } => source.code(),
+            BulletError::Action {
+                source,
+            } => source.code(),
+        }
+    }
+}
+
 /// A bullet.
 #[derive(Debug)]
 pub struct Bullet {
+    /// The label of the bullet.
+    pub label: Option<String>,
+    /// The number of frames after which the bullet vanishes.
+    ///
+    /// This is enforced by the runner for the bullet's actions; simple bullets need to be
+    /// vanished by the manager.
+    pub ttl: Option<u32>,
     /// The direction to fire the bullet.
     pub direction: Option<Direction>,
     /// The initial speed of the bullet.
     pub speed: Option<Speed>,
     /// The set of actions to perform on the bullet.
-    pub actions: Vec<Rc<Action>>,
+    pub actions: Vec<(Arc<Action>, RefParams)>,
 }
 
 impl Bullet {
+    /// Resolve a reference to an already compiled entity or compile it.
+    fn resolve(
+        lib: &mut Library,
+        data_lib: &mut DataLibrary,
+        bullet: &data::EntityRef<data::Bullet>,
+    ) -> Result<Arc<Self>, BulletError> {
+        data_lib
+            .location
+            .enter_entity(bullet, "bullet", "bulletRef");
+
+        let compiled = if let Some(compiled) = Self::compiled(lib, bullet) {
+            compiled
+        } else {
+            let entity = bullet.entity(data_lib)?;
+            data_lib.location.enter_declaration(bullet, "bullet");
+            let compiled = Self::new(lib, data_lib, entity)?;
+            data_lib.location.leave_declaration(bullet);
+            compiled
+        };
+
+        data_lib.location.leave();
+        Ok(compiled)
+    }
+
+    /// The compiled entity for a reference, if it has been compiled.
+    fn compiled(lib: &Library, bullet: &data::EntityRef<data::Bullet>) -> Option<Arc<Self>> {
+        if let data::EntityRef::Ref(ref refer) = *bullet {
+            lib.bullets.get(refer.label()).cloned()
+        } else {
+            None
+        }
+    }
+
     fn new(
         lib: &mut Library,
         data_lib: &mut DataLibrary,
         bullet: Rc<data::Bullet>,
-    ) -> Result<Rc<Self>, BulletError> {
-        let comp_bullet = Rc::new(Bullet {
-            direction: bullet.direction.clone(),
-            speed: bullet.speed.clone(),
+    ) -> Result<Arc<Self>, BulletError> {
+        if let Some(name) = bullet.label.as_ref() {
+            data_lib.compiling_bullets.insert(name.clone());
+        }
+
+        let comp_bullet = Arc::new(Bullet {
+            label: bullet.label.clone(),
+            ttl: bullet.ttl,
+            direction: bullet.direction.interned(&mut lib.variables),
+            speed: bullet.speed.interned(&mut lib.variables),
             actions: bullet
                 .actions
                 .iter()
                 .map(|action| {
-                    let entity = action.entity(data_lib)?;
-                    Action::new(lib, data_lib, entity)
+                    Action::resolve(lib, data_lib, action)
+                        .map(|compiled| (compiled, ref_params(lib, action)))
                 })
                 .collect::<Result<Vec<_>, _>>()?,
         });
@@ -254,20 +592,17 @@ impl Bullet {
             .label
             .as_ref()
             .map(|name| {
+                data_lib.compiling_bullets.remove(name);
+                data_lib
+                    .bullets
+                    .entry(name.clone())
+                    .or_insert_with(|| bullet.clone());
                 util::try_insert(
                     name.clone(),
                     &mut lib.bullets,
                     || comp_bullet.clone(),
                     "bullet",
                 )
-                .and_then(|_| {
-                    util::try_insert(
-                        name.clone(),
-                        &mut data_lib.bullets,
-                        || bullet.clone(),
-                        "bullet",
-                    )
-                })
             })
             .transpose()?;
 
@@ -275,11 +610,13 @@ impl Bullet {
     }
 }
 
+/// The labeled entities of a script.
 #[derive(Debug, Clone, Default)]
-struct Library {
-    actions: HashMap<String, Rc<Action>>,
-    bullets: HashMap<String, Rc<Bullet>>,
-    fires: HashMap<String, Rc<Fire>>,
+pub(crate) struct Library {
+    pub(crate) actions: HashMap<String, Arc<Action>>,
+    pub(crate) bullets: HashMap<String, Arc<Bullet>>,
+    pub(crate) fires: HashMap<String, Arc<Fire>>,
+    pub(crate) variables: Variables,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -287,107 +624,707 @@ struct DataLibrary {
     actions: HashMap<String, Rc<data::Action>>,
     bullets: HashMap<String, Rc<data::Bullet>>,
     fires: HashMap<String, Rc<data::Fire>>,
+    /// The labels of the entities which are being compiled.
+    ///
+    /// References to these entities are recursive and may not be compiled, so they are treated
+    /// as unknown.
+    compiling_actions: HashSet<String>,
+    compiling_bullets: HashSet<String>,
+    compiling_fires: HashSet<String>,
+    location: Location,
+}
+
+impl DataLibrary {
+    /// Declare the labeled top-level entities of a document.
+    ///
+    /// This allows entities to be referenced before they appear in the document. If a label is
+    /// used more than once, references find the first entity with the label and the others are
+    /// reported as duplicates when they are compiled.
+    fn declare(&mut self, elements: &[data::Element]) {
+        for element in elements {
+            match *element {
+                data::Element::Bullet(ref bullet) => {
+                    declare(&mut self.bullets, bullet.label.as_ref(), bullet)
+                },
+                data::Element::Action(ref action) => {
+                    declare(&mut self.actions, action.label.as_ref(), action)
+                },
+                data::Element::Fire(ref fire) => {
+                    declare(&mut self.fires, fire.label.as_ref(), fire)
+                },
+            }
+        }
+    }
+}
+
+/// Declare an entity under its label unless the label is already in use.
+fn declare<T>(entities: &mut HashMap<String, Rc<T>>, label: Option<&String>, entity: &Rc<T>) {
+    if let Some(label) = label {
+        entities
+            .entry(label.clone())
+            .or_insert_with(|| Rc::clone(entity));
+    }
+}
+
+/// The compiled form of a top-level entity which has been compiled through a forward reference.
+fn precompiled<T, C>(
+    compiled: &HashMap<String, Arc<C>>,
+    declared: &HashMap<String, Rc<T>>,
+    entity: &Rc<T>,
+    label: Option<&str>,
+) -> Option<Arc<C>> {
+    let label = label?;
+    declared
+        .get(label)
+        .filter(|declared| Rc::ptr_eq(declared, entity))?;
+    compiled.get(label).cloned()
+}
+
+/// The location of the element being compiled.
+///
+/// Elements are entered as compilation reaches them and left once they have compiled. Elements
+/// which fail to compile are not left, so after an error, the location is that of the element
+/// with the problem. Paths use the syntax of `data::Diagnostic::path`.
+#[derive(Debug, Clone)]
+struct Location {
+    /// The path to each entered element and the number of its children with each name.
+    elements: Vec<(String, HashMap<&'static str, usize>)>,
+}
+
+impl Default for Location {
+    fn default() -> Self {
+        Location {
+            elements: vec![("/bulletml".into(), HashMap::new())],
+        }
+    }
+}
+
+impl Location {
+    /// Count the next child of the current element with a name.
+    fn next_position(&mut self, name: &'static str) -> usize {
+        if let Some((_, counts)) = self.elements.last_mut() {
+            let position = counts.entry(name).or_insert(0);
+            *position += 1;
+            *position
+        } else {
+            0
+        }
+    }
+
+    /// Skip over the next child of the current element with a name.
+    fn skip(&mut self, name: &'static str) {
+        self.next_position(name);
+    }
+
+    /// The path to the next child of the current element with a name.
+    fn child(&mut self, name: &'static str, label: Option<&str>) -> String {
+        let position = self.next_position(name);
+        data::child_path(&self.path(), name, label, position)
+    }
+
+    /// Enter the next child of the current element with a name.
+    fn enter(&mut self, name: &'static str, label: Option<&str>) {
+        let path = self.child(name, label);
+        self.enter_path(path);
+    }
+
+    /// Enter an inline entity or a reference.
+    fn enter_entity<T>(
+        &mut self,
+        entity: &data::EntityRef<T>,
+        name: &'static str,
+        ref_name: &'static str,
+    ) {
+        match *entity {
+            data::EntityRef::Ref(ref refer) => self.enter(ref_name, Some(refer.label())),
+            data::EntityRef::Real(_) => self.enter(name, None),
+        }
+    }
+
+    /// Enter the declaration of a reference to an entity which has not been compiled.
+    ///
+    /// Entities which are referenced before their declaration are compiled through the
+    /// reference, but are located where they are declared.
+    fn enter_declaration<T>(&mut self, entity: &data::EntityRef<T>, name: &'static str) {
+        if let data::EntityRef::Ref(ref refer) = *entity {
+            let root = self.elements.first().map_or("", |(path, _)| path.as_str());
+            let path = data::child_path(root, name, Some(refer.label()), 0);
+            self.enter_path(path);
+        }
+    }
+
+    /// Leave the declaration of a reference.
+    fn leave_declaration<T>(&mut self, entity: &data::EntityRef<T>) {
+        if let data::EntityRef::Ref(_) = *entity {
+            self.leave();
+        }
+    }
+
+    /// Enter an element at a path.
+    fn enter_path(&mut self, path: String) {
+        self.elements.push((path, HashMap::new()));
+    }
+
+    /// Leave the current element.
+    fn leave(&mut self) {
+        if self.elements.len() > 1 {
+            self.elements.pop();
+        }
+    }
+
+    /// The path to the current element.
+    fn path(&self) -> String {
+        self.elements
+            .last()
+            .map(|(path, _)| path.clone())
+            .unwrap_or_default()
+    }
 }
 
 impl EntityLookup<data::Action> for DataLibrary {
     fn find(&self, name: &str) -> Option<Rc<data::Action>> {
+        if self.compiling_actions.contains(name) {
+            return None;
+        }
         self.actions.get(name).map(Clone::clone)
     }
 }
 
 impl EntityLookup<data::Bullet> for DataLibrary {
     fn find(&self, name: &str) -> Option<Rc<data::Bullet>> {
+        if self.compiling_bullets.contains(name) {
+            return None;
+        }
         self.bullets.get(name).map(Clone::clone)
     }
 }
 
 impl EntityLookup<data::Fire> for DataLibrary {
     fn find(&self, name: &str) -> Option<Rc<data::Fire>> {
+        if self.compiling_fires.contains(name) {
+            return None;
+        }
         self.fires.get(name).map(Clone::clone)
     }
 }
 
+/// An error when compiling a BulletML script.
 #[derive(Debug, Error)]
 pub enum BulletMLError {
-    #[error("<action> error")]
+    /// An error within an `<action>` element.
+    #[error("<action> error at {path}")]
     Action {
-        #[from]
+        /// The path to the element with the problem.
+        path: String,
+        /// The source of the error.
         source: compile::ActionError,
     },
-    #[error("<bullet> error")]
+    /// An error within a `<bullet>` element.
+    #[error("<bullet> error at {path}")]
     Bullet {
-        #[from]
+        /// The path to the element with the problem.
+        path: String,
+        /// The source of the error.
         source: compile::BulletError,
     },
-    #[error("<fire> error")]
+    /// An error within a `<fire>` element.
+    #[error("<fire> error at {path}")]
     Fire {
-        #[from]
+        /// The path to the element with the problem.
+        path: String,
+        /// The source of the error.
         source: compile::FireError,
     },
 }
 
-/// The top-level BulletML entity.
+impl BulletMLError {
+    /// The code for the error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            BulletMLError::Action {
+                source, ..
+            } => source.code(),
+            BulletMLError::Bullet {
+                source, ..
+            } => source.code(),
+            BulletMLError::Fire {
+                source, ..
+            } => source.code(),
+        }
+    }
+
+    /// The path to the element with the problem.
+    ///
+    /// Paths are written in XPath syntax as for `data::Diagnostic::path`. The element is the
+    /// innermost one being compiled, e.g., the `<actionRef>` with an unknown label.
+    pub fn path(&self) -> &str {
+        match self {
+            BulletMLError::Action {
+                path, ..
+            }
+            | BulletMLError::Bullet {
+                path, ..
+            }
+            | BulletMLError::Fire {
+                path, ..
+            } => path,
+        }
+    }
+}
+
+/// A kind of bullet which a script may fire.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BulletPrototype {
+    /// The label of the bullet.
+    pub label: Option<String>,
+    /// How the direction of the bullet is interpreted.
+    ///
+    /// This is the kind of the direction of the bullet or, if it has none, that of its fire.
+    pub direction: Option<DirectionKind>,
+    /// Whether the bullet has actions.
+    ///
+    /// Bullets with actions are created by `BulletManager::new_bullet_with_script` and others by
+    /// `BulletManager::new_simple`.
+    pub has_actions: bool,
+}
+
+/// A collection of the actions and bullet prototypes reachable from a set of actions.
+#[derive(Debug, Default)]
+struct Reachable {
+    actions: Vec<Arc<Action>>,
+    prototypes: Vec<BulletPrototype>,
+}
+
+impl Reachable {
+    fn action(&mut self, action: &Arc<Action>) {
+        if self.actions.iter().any(|seen| Arc::ptr_eq(seen, action)) {
+            return;
+        }
+        self.actions.push(Arc::clone(action));
+
+        action.steps.iter().for_each(|step| {
+            match *step {
+                Step::Repeat(ref repeat) => {
+                    repeat
+                        .actions
+                        .iter()
+                        .for_each(|(action, _)| self.action(action))
+                },
+                Step::Fire(ref fire, _) => self.fire(fire),
+                Step::Action(ref action, _) => self.action(action),
+                _ => (),
+            }
+        })
+    }
+
+    fn fire(&mut self, fire: &Fire) {
+        let bullet = &fire.bullet;
+        let prototype = BulletPrototype {
+            label: bullet.label.clone(),
+            direction: bullet
+                .direction
+                .as_ref()
+                .or_else(|| fire.direction.as_ref())
+                .map(|direction| direction.kind),
+            has_actions: !bullet.actions.is_empty(),
+        };
+        if !self.prototypes.contains(&prototype) {
+            self.prototypes.push(prototype);
+        }
+
+        // Fired bullets may fire more bullets.
+        bullet
+            .actions
+            .iter()
+            .for_each(|(action, _)| self.action(action))
+    }
+}
+
+/// Options for compiling BulletML scripts.
+#[derive(Debug, Clone, Default)]
+pub struct CompileOptions {
+    /// A fixed value for `$rank`.
+    ///
+    /// Expressions using `$rank` are folded while compiling so that they are not evaluated each
+    /// frame. `BulletManager::rank` (and `RankContext`) no longer affect the compiled script, so
+    /// scripts need to be compiled for each rank the game uses.
+    pub rank: Option<Value>,
+}
+
+/// A compiled BulletML script.
+///
+/// Compilation resolves references between entities once so that any number of runners may be
+/// created from the same script. Compiled entities are shared through `Arc`, so a script may be
+/// compiled on a loading thread and then shared with runners on other threads.
 #[derive(Debug)]
 pub struct BulletML {
     /// The orientation of the game.
     pub orientation: Orientation,
-    /// The actions which make up the entity.
-    pub steps: ZipperIter<NodeStep>,
+    /// The top-level actions.
+    actions: Vec<Arc<Action>>,
+    /// The labeled entities.
+    library: Library,
+    /// The kinds of bullets which may be fired.
+    prototypes: Vec<BulletPrototype>,
+    /// The tags of the actions which may run.
+    tags: Vec<String>,
 }
 
 impl BulletML {
+    /// Compile a BulletML script.
     pub fn new(bulletml: data::BulletML) -> Result<Self, BulletMLError> {
-        let mut library = Library::default();
+        Self::with_options(bulletml, CompileOptions::default())
+    }
+
+    /// Compile a BulletML script with options.
+    pub fn with_options(
+        bulletml: data::BulletML,
+        options: CompileOptions,
+    ) -> Result<Self, BulletMLError> {
+        let mut job = CompileJob::with_options(bulletml, options);
+        while !job.advance()? {}
+        Ok(job.finish())
+    }
+
+    /// The value of `$rank` fixed while compiling the script, if any.
+    ///
+    /// See `CompileOptions::rank`.
+    pub fn fixed_rank(&self) -> Option<Value> {
+        self.library.variables.rank()
+    }
+
+    pub(crate) fn steps(&self) -> ZipperIter<NodeStep> {
+        Self::root(&self.actions)
+    }
+
+    /// The steps of a single top-level action.
+    pub(crate) fn top_action_steps(&self, label: &str) -> Option<ZipperIter<NodeStep>> {
+        self.top_action(label)
+            .map(|action| Self::root(slice::from_ref(action)))
+    }
+
+    /// The top-level actions.
+    pub(crate) fn actions(&self) -> &[Arc<Action>] {
+        &self.actions
+    }
+
+    /// The labeled entities of the script.
+    #[cfg(feature = "pattern-cache")]
+    pub(crate) fn library(&self) -> &Library {
+        &self.library
+    }
+
+    /// Assemble a script from its compiled entities.
+    pub(crate) fn from_parts(
+        orientation: Orientation,
+        actions: Vec<Arc<Action>>,
+        library: Library,
+    ) -> Self {
+        let mut reachable = Reachable::default();
+        actions.iter().for_each(|action| reachable.action(action));
+        let tags = reachable
+            .actions
+            .iter()
+            .flat_map(|action| action.tags.iter().cloned())
+            .collect::<BTreeSet<_>>();
+
+        BulletML {
+            orientation,
+            actions,
+            library,
+            prototypes: reachable.prototypes,
+            tags: tags.into_iter().collect(),
+        }
+    }
+
+    /// The top-level action with a label.
+    pub(crate) fn top_action(&self, label: &str) -> Option<&Arc<Action>> {
+        self.actions
+            .iter()
+            .find(|action| action.label.as_deref() == Some(label))
+    }
+
+    fn root(actions: &[Arc<Action>]) -> ZipperIter<NodeStep> {
+        let mut node = Node::new(NodeStep::Root);
+        actions
+            .iter()
+            .for_each(|action| node.add_child(action.node(None)));
+        node.zipper().iter()
+    }
+
+    /// The labels of the top-level actions in document order.
+    ///
+    /// Runners created with `Runner::from_compiled` run these actions one after another. Each
+    /// may instead be run independently with `Runner::from_top_action`.
+    pub fn top_actions(&self) -> impl Iterator<Item = &str> {
+        self.actions
+            .iter()
+            .filter_map(|action| action.label.as_deref())
+    }
+
+    /// The names of the variables referenced by the script.
+    ///
+    /// Variables are passed to `ExpressionContext::get_index` by their index in this list.
+    pub fn variables(&self) -> &[String] {
+        self.library.variables.names()
+    }
+
+    /// The distinct kinds of bullets the script may fire.
+    ///
+    /// This is intended for prewarming pools of bullets before the script runs. Prototypes are
+    /// listed in the order they are first reached from the top actions, including through the
+    /// actions of fired bullets, so the order is the same for every compilation of a document.
+    pub fn bullet_prototypes(&self) -> impl Iterator<Item = &BulletPrototype> {
+        self.prototypes.iter()
+    }
+
+    /// The distinct tags of the actions which may run, in sorted order.
+    ///
+    /// This is intended for, e.g., listing the game modes a script supports. See
+    /// `RunnerOptions::tags`.
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    /// The labels of actions within the script in sorted order.
+    pub fn action_labels(&self) -> impl Iterator<Item = &str> {
+        sorted_labels(&self.library.actions)
+    }
+
+    /// The labels of bullets within the script in sorted order.
+    pub fn bullet_labels(&self) -> impl Iterator<Item = &str> {
+        sorted_labels(&self.library.bullets)
+    }
+
+    /// The labels of fires within the script in sorted order.
+    pub fn fire_labels(&self) -> impl Iterator<Item = &str> {
+        sorted_labels(&self.library.fires)
+    }
+
+    /// The compiled action with a label.
+    ///
+    /// Unlike `Runner::from_top_action`, this includes actions which are only run through
+    /// references.
+    pub fn action(&self, label: &str) -> Option<&Action> {
+        self.library.actions.get(label).map(AsRef::as_ref)
+    }
+
+    /// The compiled bullet with a label.
+    pub fn bullet(&self, label: &str) -> Option<&Bullet> {
+        self.library.bullets.get(label).map(AsRef::as_ref)
+    }
+
+    /// The compiled fire with a label.
+    pub fn fire(&self, label: &str) -> Option<&Fire> {
+        self.library.fires.get(label).map(AsRef::as_ref)
+    }
+
+    /// The number of bullets fired by running the script to completion.
+    ///
+    /// This includes bullets fired by the actions of fired bullets. The count is `None` if it
+    /// depends on the state of the game; i.e., when the number of times a `<repeat>` runs uses
+    /// variables, `$rank`, `$rand`, or parameters. Steps which end an action early, such as
+    /// `<vanish>` or a `ttl`, are not taken into account.
+    pub fn fire_count(&self) -> Option<u64> {
+        self.actions.iter().try_fold(0_u64, |count, action| {
+            count.checked_add(action_fire_count(action)?)
+        })
+    }
+}
+
+/// The labels of a collection of entities in sorted order.
+fn sorted_labels<V>(entities: &HashMap<String, V>) -> impl Iterator<Item = &str> {
+    let mut labels = entities.keys().map(String::as_str).collect::<Vec<_>>();
+    labels.sort_unstable();
+    labels.into_iter()
+}
+
+/// The number of bullets fired by an action and the actions of those bullets.
+fn action_fire_count(action: &Action) -> Option<u64> {
+    action.steps.iter().try_fold(0_u64, |count, step| {
+        let fired = match *step {
+            Step::Repeat(ref repeat) => {
+                let times = repeat.times.value.constant_value()?;
+                let once = ref_actions_fire_count(&repeat.actions)?;
+                once.checked_mul(semantics::repeat_count(times) as u64)?
+            },
+            Step::Fire(ref fire, _) => {
+                ref_actions_fire_count(&fire.bullet.actions)?.checked_add(1)?
+            },
+            Step::Action(ref action, _) => action_fire_count(action)?,
+            _ => 0,
+        };
+        count.checked_add(fired)
+    })
+}
+
+/// The number of bullets fired by actions given along with their parameters.
+fn ref_actions_fire_count(actions: &[(Arc<Action>, RefParams)]) -> Option<u64> {
+    actions.iter().try_fold(0_u64, |count, (action, _)| {
+        count.checked_add(action_fire_count(action)?)
+    })
+}
+
+/// An incremental compilation of a BulletML script.
+///
+/// Compiling a large script at once may take longer than a frame. Instead, a job may be stepped
+/// once per frame until the script is ready.
+#[derive(Debug)]
+pub struct CompileJob {
+    orientation: Orientation,
+    elements: vec::IntoIter<data::Element>,
+    /// Top-level actions with their paths.
+    top_actions: Vec<(Rc<data::Action>, String)>,
+    actions: Vec<Arc<Action>>,
+    library: Library,
+    data_library: DataLibrary,
+}
+
+impl CompileJob {
+    /// Start compiling a BulletML script.
+    pub fn new(bulletml: data::BulletML) -> Self {
+        Self::with_options(bulletml, CompileOptions::default())
+    }
+
+    /// Start compiling a BulletML script with options.
+    pub fn with_options(bulletml: data::BulletML, options: CompileOptions) -> Self {
         let mut data_library = DataLibrary::default();
+        data_library.declare(&bulletml.elements);
 
-        let top_actions = bulletml
-            .elements
-            .into_iter()
-            .filter_map(|element| {
-                match element {
-                    data::Element::Bullet(bullet) => {
-                        let bullet = Bullet::new(&mut library, &mut data_library, bullet);
-                        match bullet {
-                            Ok(_) => None,
-                            Err(err) => Some(Err(err.into())),
-                        }
-                    },
-                    data::Element::Fire(fire) => {
-                        let fire = Fire::new(&mut library, &mut data_library, fire);
-                        match fire {
-                            Ok(_) => None,
-                            Err(err) => Some(Err(err.into())),
+        CompileJob {
+            orientation: bulletml.orientation,
+            elements: bulletml.elements.into_iter(),
+            top_actions: Vec::new(),
+            actions: Vec::new(),
+            library: Library {
+                variables: Variables::with_rank(options.rank),
+                ..Library::default()
+            },
+            data_library,
+        }
+    }
+
+    /// The number of top-level elements which remain to be compiled.
+    pub fn remaining(&self) -> usize {
+        let top_actions = self.top_actions.len() - self.actions.len();
+        self.elements.len() + top_actions
+    }
+
+    /// Compile until the script is ready or the budget is used up.
+    ///
+    /// At least one element is compiled per step so that compilation always progresses. Once the
+    /// result is ready, the job is empty and further steps produce an empty script. After an
+    /// error, the job should be discarded.
+    pub fn step(&mut self, budget: Duration) -> Poll<Result<BulletML, BulletMLError>> {
+        let start = Instant::now();
+
+        loop {
+            match self.advance() {
+                Ok(true) => return Poll::Ready(Ok(self.finish())),
+                Ok(false) => (),
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+
+            if start.elapsed() >= budget {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    /// Compile the next element.
+    ///
+    /// Returns `true` once every element has been compiled.
+    fn advance(&mut self) -> Result<bool, BulletMLError> {
+        if let Some(element) = self.elements.next() {
+            self.compile_element(element)?;
+        } else if let Some((action, path)) = self.top_actions.get(self.actions.len()).cloned() {
+            let library = &mut self.library;
+            let data_library = &mut self.data_library;
+            data_library.location.enter_path(path);
+            let label = action.label.as_deref();
+            let action = if let Some(compiled) =
+                precompiled(&library.actions, &data_library.actions, &action, label)
+            {
+                compiled
+            } else {
+                Action::new(library, data_library, action).map_err(|source| {
+                    BulletMLError::Action {
+                        path: data_library.location.path(),
+                        source,
+                    }
+                })?
+            };
+            data_library.location.leave();
+            self.actions.push(action);
+        }
+
+        Ok(self.remaining() == 0)
+    }
+
+    fn compile_element(&mut self, element: data::Element) -> Result<(), BulletMLError> {
+        let library = &mut self.library;
+        let data_library = &mut self.data_library;
+
+        match element {
+            data::Element::Bullet(bullet) => {
+                let label = bullet.label.as_deref();
+                data_library.location.enter("bullet", label);
+                if precompiled(&library.bullets, &data_library.bullets, &bullet, label).is_none() {
+                    Bullet::new(library, data_library, bullet).map_err(|source| {
+                        BulletMLError::Bullet {
+                            path: data_library.location.path(),
+                            source,
                         }
-                    },
-                    data::Element::Action(action) => {
-                        if let Some(label) = action.label.clone() {
-                            if label.starts_with("top") {
-                                return Some(Ok(action));
-                            }
+                    })?;
+                }
+            },
+            data::Element::Fire(fire) => {
+                let label = fire.label.as_deref();
+                data_library.location.enter("fire", label);
+                if precompiled(&library.fires, &data_library.fires, &fire, label).is_none() {
+                    Fire::new(library, data_library, fire).map_err(|source| {
+                        BulletMLError::Fire {
+                            path: data_library.location.path(),
+                            source,
                         }
+                    })?;
+                }
+            },
+            data::Element::Action(action) => {
+                // Top-level actions are compiled last.
+                if action.label.as_deref().map_or(false, data::is_top_label) {
+                    let path = data_library
+                        .location
+                        .child("action", action.label.as_deref());
+                    self.top_actions.push((action, path));
+                    return Ok(());
+                }
 
-                        let action = Action::new(&mut library, &mut data_library, action);
-                        match action {
-                            Ok(_) => None,
-                            Err(err) => Some(Err(err.into())),
+                let label = action.label.as_deref();
+                data_library.location.enter("action", label);
+                if precompiled(&library.actions, &data_library.actions, &action, label).is_none() {
+                    Action::new(library, data_library, action).map_err(|source| {
+                        BulletMLError::Action {
+                            path: data_library.location.path(),
+                            source,
                         }
-                    },
+                    })?;
                 }
-            })
-            .collect::<Result<Vec<_>, BulletMLError>>()?;
-        let actions = top_actions
-            .into_iter()
-            .map(|action| Action::new(&mut library, &mut data_library, action))
-            .collect::<Result<Vec<_>, _>>()?;
-        let mut node = Node::new(NodeStep::Root);
-        actions
-            .into_iter()
-            .for_each(|action| node.add_child(action.node()));
+            },
+        }
 
-        Ok(BulletML {
-            orientation: bulletml.orientation,
-            steps: node.zipper().iter(),
-        })
+        data_library.location.leave();
+        Ok(())
+    }
+
+    fn finish(&mut self) -> BulletML {
+        self.top_actions.clear();
+
+        BulletML::from_parts(
+            self.orientation,
+            mem::take(&mut self.actions),
+            mem::take(&mut self.library),
+        )
     }
 }
 
@@ -410,39 +1347,95 @@ pub enum FireError {
     },
 }
 
+impl FireError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            FireError::EntityLookup {
+                source,
+            } => source.code(),
+            FireError::EntityUse {
+                source,
+            } => source.code(),
+            FireError::Bullet {
+                source,
+            } => source.code(),
+        }
+    }
+}
+
 /// Create a new bullet.
 #[derive(Debug)]
 pub struct Fire {
+    /// The label of the fire.
+    pub label: Option<String>,
     /// The direction to fire in.
     pub direction: Option<Direction>,
     /// The initial speed of the bullet.
     pub speed: Option<Speed>,
     /// The bullet to fire.
-    pub bullet: Rc<Bullet>,
+    pub bullet: Arc<Bullet>,
+    /// The parameters given by the reference to the bullet.
+    pub bullet_params: RefParams,
 }
 
 impl Fire {
+    /// Resolve a reference to an already compiled entity or compile it.
+    fn resolve(
+        lib: &mut Library,
+        data_lib: &mut DataLibrary,
+        fire: &data::EntityRef<data::Fire>,
+    ) -> Result<Arc<Self>, FireError> {
+        data_lib.location.enter_entity(fire, "fire", "fireRef");
+
+        let compiled = if let Some(compiled) = Self::compiled(lib, fire) {
+            compiled
+        } else {
+            let entity = fire.entity(data_lib)?;
+            data_lib.location.enter_declaration(fire, "fire");
+            let compiled = Self::new(lib, data_lib, entity)?;
+            data_lib.location.leave_declaration(fire);
+            compiled
+        };
+
+        data_lib.location.leave();
+        Ok(compiled)
+    }
+
+    /// The compiled entity for a reference, if it has been compiled.
+    fn compiled(lib: &Library, fire: &data::EntityRef<data::Fire>) -> Option<Arc<Self>> {
+        if let data::EntityRef::Ref(ref refer) = *fire {
+            lib.fires.get(refer.label()).cloned()
+        } else {
+            None
+        }
+    }
+
     fn new(
         lib: &mut Library,
         data_lib: &mut DataLibrary,
         fire: Rc<data::Fire>,
-    ) -> Result<Rc<Self>, FireError> {
-        let comp_fire = Rc::new(Fire {
-            direction: fire.direction.clone(),
-            speed: fire.speed.clone(),
-            bullet: {
-                let entity = fire.bullet.entity(data_lib)?;
-                Bullet::new(lib, data_lib, entity)?
-            },
+    ) -> Result<Arc<Self>, FireError> {
+        if let Some(name) = fire.label.as_ref() {
+            data_lib.compiling_fires.insert(name.clone());
+        }
+
+        let comp_fire = Arc::new(Fire {
+            label: fire.label.clone(),
+            direction: fire.direction.interned(&mut lib.variables),
+            speed: fire.speed.interned(&mut lib.variables),
+            bullet: Bullet::resolve(lib, data_lib, &fire.bullet)?,
+            bullet_params: ref_params(lib, &fire.bullet),
         });
 
         fire.label
             .as_ref()
             .map(|name| {
+                data_lib.compiling_fires.remove(name);
+                data_lib
+                    .fires
+                    .entry(name.clone())
+                    .or_insert_with(|| fire.clone());
                 util::try_insert(name.clone(), &mut lib.fires, || comp_fire.clone(), "fire")
-                    .and_then(|_| {
-                        util::try_insert(name.clone(), &mut data_lib.fires, || fire.clone(), "fire")
-                    })
             })
             .transpose()?;
 
@@ -459,40 +1452,63 @@ pub enum RepeatError {
     },
 }
 
+impl RepeatError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            RepeatError::Action {
+                source,
+            } => source.code(),
+        }
+    }
+}
+
 /// Repetition action.
 #[derive(Debug, Clone)]
 pub struct Repeat {
     /// How many times to repeat the actions.
     pub times: Times,
     /// The actions to repeat.
-    actions: Vec<Rc<Action>>,
+    pub(crate) actions: Vec<(Arc<Action>, RefParams)>,
 }
 
 impl Repeat {
+    /// The actions to repeat along with the parameters given by their references.
+    pub fn actions(&self) -> &[(Arc<Action>, RefParams)] {
+        &self.actions
+    }
+
     fn new(
         lib: &mut Library,
         data_lib: &mut DataLibrary,
         repeat: &data::Repeat,
     ) -> Result<Self, RepeatError> {
         Ok(Repeat {
-            times: repeat.times.clone(),
+            times: repeat.times.interned(&mut lib.variables),
             actions: repeat
                 .actions
                 .iter()
                 .map(|action| {
-                    let entity = action.entity(data_lib)?;
-                    Action::new(lib, data_lib, entity)
+                    Action::resolve(lib, data_lib, action)
+                        .map(|compiled| (compiled, ref_params(lib, action)))
                 })
                 .collect::<Result<Vec<_>, _>>()?,
         })
     }
 
-    pub fn new_steps(&self, count: usize) -> Vec<Node<NodeStep>> {
-        iter::repeat(())
-            .take(count)
-            .map(|_| self.actions.iter().cloned())
-            .flatten()
-            .map(|action| Step::Action(action).into_node())
+    /// The steps for a single iteration followed by a check for the next iteration.
+    ///
+    /// Iterations are expanded one at a time so that large counts do not allocate every
+    /// iteration up front.
+    pub fn iteration_steps(&self, index: usize, count: Option<usize>) -> Vec<Node<NodeStep>> {
+        self.actions
+            .iter()
+            .cloned()
+            .map(|(action, params)| Step::Action(action, params).into_node())
+            .chain(iter::once(Node::new(NodeStep::RepeatIteration(
+                self.clone(),
+                index + 1,
+                count,
+            ))))
             .collect()
     }
 }
@@ -521,3 +1537,499 @@ impl Acceleration for Vertical {
         self.kind.modify(value, current, duration)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::task::Poll;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::data::{self, DirectionKind};
+    use crate::run::compile::Step;
+    use crate::run::testing::TestManager;
+    use crate::run::{
+        BulletMLError, BulletPrototype, CompileJob, CompileOptions, CompiledBulletML, Runner,
+        RunnerOptions,
+    };
+
+    const LIBRARY: &str = r#"<bulletml>
+        <bullet label="shot">
+            <speed>$speed</speed>
+        </bullet>
+        <fire label="aimed">
+            <direction type="aim">0</direction>
+            <bulletRef label="shot"/>
+        </fire>
+        <action label="top">
+            <fireRef label="aimed"/>
+        </action>
+        <action label="volley">
+            <repeat>
+                <times>$count</times>
+                <action><fireRef label="aimed"/></action>
+            </repeat>
+        </action>
+    </bulletml>"#;
+
+    fn job(doc: &str) -> CompileJob {
+        let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+        CompileJob::new(bulletml)
+    }
+
+    #[test]
+    fn test_compile_job() {
+        let mut job = job(LIBRARY);
+        assert_eq!(job.remaining(), 4);
+
+        let mut steps = 1;
+        let compiled = loop {
+            match job.step(Duration::from_secs(0)) {
+                Poll::Ready(compiled) => break compiled.unwrap(),
+                Poll::Pending => steps += 1,
+            }
+        };
+
+        // The top action is deferred until after the other elements.
+        assert_eq!(steps, 5);
+        assert_eq!(job.remaining(), 0);
+
+        let bulletml: data::BulletML = serde_xml_rs::from_str(LIBRARY).unwrap();
+        let expected = CompiledBulletML::new(bulletml).unwrap();
+        assert_eq!(compiled.variables(), expected.variables());
+        let labels = |compiled: &CompiledBulletML| {
+            let mut labels = compiled
+                .action_labels()
+                .chain(compiled.bullet_labels())
+                .chain(compiled.fire_labels())
+                .map(String::from)
+                .collect::<Vec<_>>();
+            labels.sort();
+            labels
+        };
+        assert_eq!(labels(&compiled), labels(&expected));
+        assert_eq!(labels(&compiled), ["aimed", "shot", "top", "volley"]);
+    }
+
+    #[test]
+    fn test_compile_job_budget() {
+        let mut job = job(LIBRARY);
+
+        match job.step(Duration::from_secs(60)) {
+            Poll::Ready(compiled) => assert_eq!(compiled.unwrap().variables(), ["speed", "count"]),
+            Poll::Pending => panic!("compilation did not finish within its budget"),
+        }
+    }
+
+    #[test]
+    fn test_compile_job_error() {
+        let mut job = job(r#"<bulletml>
+            <action label="top">
+                <actionRef label="missing"/>
+            </action>
+        </bulletml>"#);
+
+        assert!(job.step(Duration::from_secs(0)).is_pending());
+        match job.step(Duration::from_secs(0)) {
+            Poll::Ready(Err(err)) => {
+                assert_eq!(err.code(), data::ErrorCode::UnknownReference);
+                assert_eq!(
+                    err.path(),
+                    "/bulletml/action[@label=\"top\"]/actionRef[@label=\"missing\"]",
+                );
+            },
+            res => panic!("unexpected result: {:?}", res.map(|res| res.map(|_| ()))),
+        }
+    }
+
+    fn compile_error(doc: &str) -> BulletMLError {
+        let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+        let err = CompiledBulletML::new(bulletml.clone()).unwrap_err();
+
+        // Errors are located as by validation.
+        let diagnostics = data::validate(&bulletml);
+        assert!(
+            diagnostics
+                .iter()
+                .any(|diagnostic| diagnostic.path == err.path() && diagnostic.code == err.code()),
+            "{} not found in {:?}",
+            err.path(),
+            diagnostics,
+        );
+
+        err
+    }
+
+    #[test]
+    fn test_compile_error_path() {
+        let err = compile_error(
+            r#"<bulletml>
+                <bullet label="shot"/>
+                <action>
+                    <wait>1</wait>
+                    <repeat>
+                        <times>2</times>
+                        <action><fire><bulletRef label="shot"/></fire></action>
+                        <action><fire><bulletRef label="missing"/></fire></action>
+                    </repeat>
+                </action>
+            </bulletml>"#,
+        );
+
+        assert_eq!(
+            err.path(),
+            "/bulletml/action[1]/repeat[1]/action[2]/fire[1]/bulletRef[@label=\"missing\"]",
+        );
+        assert!(err.to_string().ends_with(err.path()));
+    }
+
+    #[test]
+    fn test_compile_error_path_entity() {
+        let err = compile_error(
+            r#"<bulletml>
+                <bullet label="shot">
+                    <action><actionRef label="missing"/></action>
+                </bullet>
+                <action label="top">
+                    <fire><bulletRef label="shot"/></fire>
+                </action>
+            </bulletml>"#,
+        );
+
+        assert_eq!(
+            err.path(),
+            "/bulletml/bullet[@label=\"shot\"]/action[1]/actionRef[@label=\"missing\"]",
+        );
+    }
+
+    #[test]
+    fn test_compile_error_path_forward() {
+        let err = compile_error(
+            r#"<bulletml>
+                <action label="volley">
+                    <fire><bulletRef label="shot"/></fire>
+                </action>
+                <bullet label="shot">
+                    <action><actionRef label="missing"/></action>
+                </bullet>
+            </bulletml>"#,
+        );
+
+        // Entities are located where they are declared rather than where they are referenced.
+        assert_eq!(
+            err.path(),
+            "/bulletml/bullet[@label=\"shot\"]/action[1]/actionRef[@label=\"missing\"]",
+        );
+    }
+
+    #[test]
+    fn test_forward_references() {
+        let bulletml: data::BulletML = serde_xml_rs::from_str(
+            r#"<bulletml>
+                <action label="top1">
+                    <actionRef label="top2"/>
+                    <fireRef label="aimed"/>
+                </action>
+                <action label="top2">
+                    <fire><bulletRef label="shot"/></fire>
+                </action>
+                <fire label="aimed">
+                    <direction type="aim">0</direction>
+                    <bulletRef label="shot"/>
+                </fire>
+                <bullet label="shot">
+                    <action><actionRef label="spin"/></action>
+                </bullet>
+                <action label="spin">
+                    <changeDirection>
+                        <direction type="relative">90</direction>
+                        <term>10</term>
+                    </changeDirection>
+                </action>
+            </bulletml>"#,
+        )
+        .unwrap();
+        let compiled = CompiledBulletML::new(bulletml).unwrap();
+
+        assert_eq!(
+            compiled.action_labels().collect::<Vec<_>>(),
+            ["spin", "top1", "top2"],
+        );
+        assert_eq!(compiled.actions().len(), 2);
+
+        // Entities are compiled once and shared with the references to them.
+        let top1 = &compiled.actions()[0];
+        let top2 = &compiled.actions()[1];
+        match top1.steps()[0] {
+            Step::Action(ref action, _) => assert!(Arc::ptr_eq(action, top2)),
+            ref step => panic!("unexpected step: {:?}", step),
+        }
+        let shot = compiled.bullet("shot").unwrap();
+        let aimed = compiled.fire("aimed").unwrap();
+        assert!(std::ptr::eq(&*aimed.bullet, shot));
+        assert!(std::ptr::eq(
+            &*shot.actions[0].0,
+            compiled.action("spin").unwrap(),
+        ));
+    }
+
+    #[test]
+    fn test_recursive_reference() {
+        let bulletml: data::BulletML = serde_xml_rs::from_str(
+            r#"<bulletml>
+                <action label="top">
+                    <actionRef label="loop"/>
+                </action>
+                <action label="loop">
+                    <wait>1</wait>
+                    <actionRef label="loop"/>
+                </action>
+            </bulletml>"#,
+        )
+        .unwrap();
+        let err = CompiledBulletML::new(bulletml).unwrap_err();
+
+        assert_eq!(err.code(), data::ErrorCode::UnknownReference);
+        assert_eq!(
+            err.path(),
+            "/bulletml/action[@label=\"loop\"]/actionRef[@label=\"loop\"]",
+        );
+    }
+
+    fn assert_send_sync<T>()
+    where
+        T: Send + Sync,
+    {
+    }
+
+    #[test]
+    fn test_compiled_send_sync() {
+        assert_send_sync::<CompiledBulletML>();
+    }
+
+    #[test]
+    fn test_compile_thread() {
+        // Documents share their entities through `Rc`, so parse them where they are compiled.
+        let compiled = thread::spawn(|| {
+            let bulletml: data::BulletML = serde_xml_rs::from_str(LIBRARY).unwrap();
+            CompiledBulletML::new(bulletml).unwrap()
+        })
+        .join()
+        .unwrap();
+        let compiled = Arc::new(compiled);
+
+        let shared = Arc::clone(&compiled);
+        let labels = thread::spawn(move || {
+            shared
+                .top_actions()
+                .map(String::from)
+                .collect::<Vec<_>>()
+        })
+        .join()
+        .unwrap();
+        assert_eq!(labels, compiled.top_actions().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_bullet_prototypes() {
+        let doc = r#"<bulletml>
+            <bullet label="splitter">
+                <action>
+                    <fire>
+                        <direction type="sequence">90</direction>
+                        <bullet/>
+                    </fire>
+                    <vanish/>
+                </action>
+            </bullet>
+            <action label="top">
+                <repeat>
+                    <times>4</times>
+                    <action>
+                        <fire>
+                            <direction type="aim">0</direction>
+                            <bulletRef label="splitter"/>
+                        </fire>
+                    </action>
+                </repeat>
+                <fire>
+                    <bullet/>
+                </fire>
+                <fire>
+                    <direction type="aim">0</direction>
+                    <bulletRef label="splitter"/>
+                </fire>
+            </action>
+            <action label="unused">
+                <fire>
+                    <bullet label="unreachable"/>
+                </fire>
+            </action>
+        </bulletml>"#;
+        let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+        let compiled = CompiledBulletML::new(bulletml).unwrap();
+
+        assert_eq!(
+            compiled.bullet_prototypes().cloned().collect::<Vec<_>>(),
+            [
+                BulletPrototype {
+                    label: Some("splitter".into()),
+                    direction: Some(DirectionKind::Aim),
+                    has_actions: true,
+                },
+                BulletPrototype {
+                    label: None,
+                    direction: Some(DirectionKind::Sequence),
+                    has_actions: false,
+                },
+                BulletPrototype {
+                    label: None,
+                    direction: None,
+                    has_actions: false,
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn test_introspection() {
+        let bulletml: data::BulletML = serde_xml_rs::from_str(LIBRARY).unwrap();
+        let compiled = CompiledBulletML::new(bulletml).unwrap();
+
+        assert_eq!(
+            compiled.action_labels().collect::<Vec<_>>(),
+            ["top", "volley"],
+        );
+        assert_eq!(compiled.bullet_labels().collect::<Vec<_>>(), ["shot"]);
+        assert_eq!(compiled.fire_labels().collect::<Vec<_>>(), ["aimed"]);
+        assert!(compiled.action("missing").is_none());
+
+        let volley = compiled.action("volley").unwrap();
+        assert_eq!(volley.label(), Some("volley"));
+        assert_eq!(volley.steps().len(), 1);
+        let repeat = if let Step::Repeat(ref repeat) = volley.steps()[0] {
+            repeat
+        } else {
+            panic!("unexpected step: {:?}", volley.steps()[0]);
+        };
+        assert_eq!(repeat.times.value.to_string(), "$count");
+        let (action, params) = &repeat.actions()[0];
+        assert!(params.is_none());
+
+        // References share the compiled entity.
+        let fire = compiled.fire("aimed").unwrap();
+        if let Step::Fire(ref step_fire, _) = action.steps()[0] {
+            assert!(std::ptr::eq(step_fire.as_ref(), fire));
+        } else {
+            panic!("unexpected step: {:?}", action.steps()[0]);
+        }
+        assert!(std::ptr::eq(
+            fire.bullet.as_ref(),
+            compiled.bullet("shot").unwrap(),
+        ));
+    }
+
+    #[test]
+    fn test_fire_count() {
+        let count = |doc: &str| {
+            let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+            CompiledBulletML::new(bulletml).unwrap().fire_count()
+        };
+
+        assert_eq!(count(LIBRARY), Some(1));
+        assert_eq!(
+            count(
+                r#"<bulletml>
+                    <bullet label="splitter">
+                        <action>
+                            <repeat>
+                                <times>2 + 1</times>
+                                <action><fire><bullet/></fire></action>
+                            </repeat>
+                        </action>
+                    </bullet>
+                    <action label="top1">
+                        <repeat>
+                            <times>4</times>
+                            <action><fire><bulletRef label="splitter"/></fire></action>
+                        </repeat>
+                    </action>
+                    <action label="top2">
+                        <fire><bullet/></fire>
+                    </action>
+                </bulletml>"#,
+            ),
+            Some(4 * (1 + 3) + 1),
+        );
+        assert_eq!(
+            count(
+                r#"<bulletml>
+                    <action label="top">
+                        <repeat>
+                            <times>$rank * 4</times>
+                            <action><fire><bullet/></fire></action>
+                        </repeat>
+                    </action>
+                </bulletml>"#,
+            ),
+            None,
+        );
+    }
+
+    #[test]
+    fn test_fixed_rank() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <repeat>
+                    <times>$rank * 4</times>
+                    <action>
+                        <fire>
+                            <direction type="absolute">$rank * 90 + $rand</direction>
+                            <bullet/>
+                        </fire>
+                    </action>
+                </repeat>
+            </action>
+        </bulletml>"#;
+        let compile = |options| {
+            let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+            CompiledBulletML::with_options(bulletml, options).unwrap()
+        };
+
+        let compiled = compile(CompileOptions::default());
+        assert_eq!(compiled.fixed_rank(), None);
+        assert_eq!(compiled.fire_count(), None);
+
+        let compiled = compile(CompileOptions {
+            rank: Some(0.5),
+        });
+        assert_eq!(compiled.fixed_rank(), Some(0.5));
+        assert_eq!(compiled.fire_count(), Some(2));
+
+        let repeat = if let Step::Repeat(ref repeat) = compiled.action("top").unwrap().steps()[0] {
+            repeat
+        } else {
+            panic!("unexpected step");
+        };
+        assert_eq!(repeat.times.value.to_string(), "2");
+        let fire = if let Step::Fire(ref fire, _) = repeat.actions()[0].0.steps()[0] {
+            fire
+        } else {
+            panic!("unexpected step");
+        };
+        assert_eq!(
+            fire.direction.as_ref().unwrap().degrees.to_string(),
+            "45 + $rand",
+        );
+
+        // The rank of the manager no longer matters.
+        let mut manager = TestManager::default();
+        manager.rank = 1.;
+        let mut runner = Runner::from_compiled(manager, &compiled, RunnerOptions::default());
+        runner.update().unwrap();
+        assert_eq!(
+            runner.manager().log,
+            ["new_simple(45.5, 1)", "new_simple(45.5, 1)"],
+        );
+    }
+}
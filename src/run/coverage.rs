@@ -0,0 +1,160 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use std::collections::btree_map::BTreeMap;
+
+use crate::data::{ExpressionError, Value};
+use crate::run::timeline::TimelineManager;
+use crate::run::{CompiledBulletML, Runner, RunnerOptions};
+
+/// How often labeled entities executed while running a script.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Coverage {
+    /// The number of times each labeled action started.
+    pub actions: BTreeMap<String, usize>,
+    /// The number of times each labeled bullet was fired.
+    pub bullets: BTreeMap<String, usize>,
+    /// The number of times each labeled fire executed.
+    pub fires: BTreeMap<String, usize>,
+}
+
+impl Coverage {
+    pub(crate) fn record(counts: &mut BTreeMap<String, usize>, label: &str) {
+        if let Some(count) = counts.get_mut(label) {
+            *count += 1;
+        } else {
+            counts.insert(label.into(), 1);
+        }
+    }
+
+    fn unexecuted(counts: &BTreeMap<String, usize>) -> Vec<&str> {
+        counts
+            .iter()
+            .filter(|&(_, &count)| count == 0)
+            .map(|(label, _)| label.as_str())
+            .collect()
+    }
+
+    /// Labeled actions which never started.
+    pub fn unexecuted_actions(&self) -> Vec<&str> {
+        Self::unexecuted(&self.actions)
+    }
+
+    /// Labeled bullets which were never fired.
+    pub fn unexecuted_bullets(&self) -> Vec<&str> {
+        Self::unexecuted(&self.bullets)
+    }
+
+    /// Labeled fires which never executed.
+    pub fn unexecuted_fires(&self) -> Vec<&str> {
+        Self::unexecuted(&self.fires)
+    }
+
+    /// Whether every labeled entity executed.
+    pub fn is_complete(&self) -> bool {
+        self.actions
+            .values()
+            .chain(self.bullets.values())
+            .chain(self.fires.values())
+            .all(|&count| count > 0)
+    }
+}
+
+impl CompiledBulletML {
+    /// Simulate the script and report which labeled entities execute.
+    ///
+    /// The simulation is the same as for `to_keyframes`. Every label in the script is reported,
+    /// so labels which never execute have a count of zero. Since the actions of fired bullets are
+    /// not run by the simulation, actions only referenced by bullets are reported as unexecuted.
+    pub fn coverage(
+        &self,
+        rank: Value,
+        seed: u64,
+        frames: u32,
+    ) -> Result<Coverage, ExpressionError> {
+        let manager = TimelineManager::new(rank, seed);
+        let mut runner = Runner::from_compiled(manager, self, RunnerOptions::default());
+        runner.track_coverage();
+
+        for frame in 0..frames {
+            runner.manager_mut().turn = frame;
+            runner.update()?;
+        }
+
+        let mut coverage = runner.coverage().cloned().unwrap_or_default();
+        self.action_labels().for_each(|label| {
+            coverage.actions.entry(label.into()).or_insert(0);
+        });
+        self.bullet_labels().for_each(|label| {
+            coverage.bullets.entry(label.into()).or_insert(0);
+        });
+        self.fire_labels().for_each(|label| {
+            coverage.fires.entry(label.into()).or_insert(0);
+        });
+
+        Ok(coverage)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::data;
+    use crate::run::CompiledBulletML;
+
+    const DOC: &str = r#"<bulletml>
+        <bullet label="fast">
+            <speed>2</speed>
+        </bullet>
+        <bullet label="slow">
+            <speed>0.5</speed>
+        </bullet>
+        <fire label="shot">
+            <bulletRef label="fast"/>
+        </fire>
+        <action label="burst">
+            <fireRef label="shot"/>
+        </action>
+        <action label="late">
+            <fire>
+                <bulletRef label="slow"/>
+            </fire>
+        </action>
+        <action label="unused">
+            <vanish/>
+        </action>
+        <action label="top">
+            <repeat>
+                <times>2</times>
+                <actionRef label="burst"/>
+            </repeat>
+            <wait>100</wait>
+            <actionRef label="late"/>
+        </action>
+    </bulletml>"#;
+
+    fn compile(doc: &str) -> CompiledBulletML {
+        let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+        CompiledBulletML::new(bulletml).unwrap()
+    }
+
+    #[test]
+    fn test_coverage() {
+        let compiled = compile(DOC);
+
+        let coverage = compiled.coverage(0., 0, 10).unwrap();
+
+        assert_eq!(coverage.actions["top"], 1);
+        assert_eq!(coverage.actions["burst"], 2);
+        assert_eq!(coverage.fires["shot"], 2);
+        assert_eq!(coverage.bullets["fast"], 2);
+        assert_eq!(coverage.unexecuted_actions(), ["late", "unused"]);
+        assert_eq!(coverage.unexecuted_bullets(), ["slow"]);
+        assert!(coverage.unexecuted_fires().is_empty());
+        assert!(!coverage.is_complete());
+
+        let coverage = compiled.coverage(0., 0, 101).unwrap();
+
+        assert_eq!(coverage.unexecuted_actions(), ["unused"]);
+        assert!(coverage.unexecuted_bullets().is_empty());
+    }
+}
@@ -0,0 +1,66 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use serde::Serialize;
+
+/// An identifier for a bullet fired by a runner.
+///
+/// Identifiers are allocated by the runner in monotonically increasing order and are never
+/// reused by the same runner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub struct BulletId(u64);
+
+impl BulletId {
+    pub(crate) fn new(id: u64) -> Self {
+        BulletId(id)
+    }
+
+    /// The raw value of the identifier.
+    pub fn get(self) -> u64 {
+        self.0
+    }
+}
+
+/// Events which occur while running a script.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    /// A bullet has been fired.
+    Fired {
+        /// The bullet whose runner fired the bullet (`None` for the top-level runner).
+        source: Option<BulletId>,
+        /// The identifier of the new bullet.
+        id: BulletId,
+        /// The index of the bullet among those fired within the current frame.
+        ///
+        /// Frames split across updates by a step budget continue counting.
+        index: usize,
+        /// The direction of the new bullet.
+        direction: f32,
+        /// The speed of the new bullet.
+        speed: f32,
+        /// Whether the new bullet is simple (i.e., has no actions).
+        simple: bool,
+        /// The number of frames after which the new bullet should vanish.
+        ttl: Option<u32>,
+    },
+    /// The bullet has vanished.
+    Vanished {
+        /// The bullet which vanished (`None` for the top-level runner).
+        source: Option<BulletId>,
+    },
+}
+
+/// An observer of events within a runner.
+pub trait Observer {
+    /// Notification of an event.
+    fn notify(&mut self, event: &Event);
+}
+
+impl<F> Observer for F
+where
+    F: FnMut(&Event),
+{
+    fn notify(&mut self, event: &Event) {
+        self(event)
+    }
+}
@@ -0,0 +1,386 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use std::mem;
+
+use crate::data::{ExpressionContext, Value};
+use crate::geom::Velocity;
+use crate::run::{BulletManager, BulletScript};
+
+/// The speed of bullets fired without a `<speed>`.
+const DEFAULT_SPEED: f32 = 1.;
+
+/// A bullet fired by an `ExampleManager`.
+#[derive(Debug)]
+pub struct ExampleBullet {
+    /// The manager for the new bullet.
+    ///
+    /// It starts at the position of the manager which fired it.
+    pub manager: ExampleManager,
+    /// The actions of the bullet.
+    ///
+    /// Bullets with a script should be driven by a runner from `BulletScript::runner`. Bullets
+    /// without one only move.
+    pub script: Option<BulletScript>,
+}
+
+/// A minimal manager intended as the starting point of an integration.
+///
+/// Most integration bugs come from the first `BulletManager` written for a game. This manager is
+/// small enough to be copied into a game and adapted, and its behavior is checked against golden
+/// traces in both orientations. The conventions it follows are:
+///
+///   - directions are in degrees where `0` points up and `90` points right;
+///   - positions use screen coordinates, so `y` increases downwards;
+///   - the motion is kept as a direction and speed, which is what the runner asks for, and
+///     converted to a velocity only to move or to answer `speed_x` and `speed_y`;
+///   - the direction is kept while the bullet is at rest so that it resumes in the same
+///     direction when it speeds up again;
+///   - the orientation of the document is ignored since the runner already accounts for it (a
+///     horizontal document rotates absolute directions and swaps the axes of `<accel>`); and
+///   - bullets are fired from the current position.
+///
+/// Each frame, a game sets `turn`, updates the runner driving the manager, picks up the bullets
+/// it fired from `fired`, and then calls `advance`. Runners driving it should be given a random
+/// number generator with `Runner::set_rng`; otherwise `$rand` is always `0.5`.
+#[derive(Debug)]
+pub struct ExampleManager {
+    /// The position of the bullet.
+    pub position: (f32, f32),
+    /// The direction of motion in degrees.
+    pub direction: f32,
+    /// The speed of motion in units per frame.
+    pub speed: f32,
+    /// The position of the player, which aimed bullets are fired at.
+    pub player: (f32, f32),
+    /// The value of `$rank`.
+    pub rank: Value,
+    /// The turn of the game.
+    pub turn: u32,
+    /// Whether the bullet has vanished.
+    pub vanished: bool,
+    /// The bullets fired since they were last taken.
+    pub fired: Vec<ExampleBullet>,
+}
+
+impl ExampleManager {
+    /// Create a manager for a bullet at rest.
+    pub fn new(position: (f32, f32), player: (f32, f32)) -> Self {
+        ExampleManager {
+            position,
+            direction: 0.,
+            speed: 0.,
+            player,
+            rank: 0.,
+            turn: 0,
+            vanished: false,
+            fired: Vec::new(),
+        }
+    }
+
+    /// The velocity of the bullet.
+    pub fn velocity(&self) -> Velocity {
+        Velocity::new(self.direction, self.speed)
+    }
+
+    /// Move the bullet by a frame of its motion.
+    pub fn advance(&mut self) {
+        let velocity = self.velocity();
+        self.position.0 += velocity.x;
+        self.position.1 += velocity.y;
+    }
+
+    /// Take the bullets fired since the last call.
+    pub fn take_fired(&mut self) -> Vec<ExampleBullet> {
+        mem::take(&mut self.fired)
+    }
+
+    fn fire(&mut self, direction: f32, speed: f32, script: Option<BulletScript>) {
+        let mut manager = ExampleManager::new(self.position, self.player);
+        manager.direction = direction;
+        manager.speed = speed;
+        manager.rank = self.rank;
+        manager.turn = self.turn;
+
+        self.fired.push(ExampleBullet {
+            manager,
+            script,
+        });
+    }
+
+    fn set_velocity(&mut self, velocity: Velocity) {
+        self.speed = velocity.speed();
+        // Keep the direction while at rest.
+        if self.speed > 0. {
+            self.direction = velocity.direction();
+        }
+    }
+}
+
+impl ExpressionContext for ExampleManager {
+    fn get(&self, _: &str) -> Option<Value> {
+        None
+    }
+
+    fn get_param(&self, _: usize) -> Option<Value> {
+        None
+    }
+
+    fn rand(&self) -> Value {
+        0.5
+    }
+
+    fn rank(&self) -> Value {
+        self.rank
+    }
+}
+
+impl BulletManager for ExampleManager {
+    fn new_simple(&mut self, direction: f32, speed: f32) {
+        self.fire(direction, speed, None)
+    }
+
+    fn new_bullet(&mut self, direction: f32, speed: f32) {
+        self.fire(direction, speed, None)
+    }
+
+    fn new_bullet_with_script(&mut self, direction: f32, speed: f32, script: BulletScript) {
+        self.fire(direction, speed, Some(script))
+    }
+
+    fn turn(&self) -> u32 {
+        self.turn
+    }
+
+    fn direction(&self) -> f32 {
+        self.direction
+    }
+
+    fn aim_direction(&self) -> f32 {
+        let (x, y) = self.position;
+        let (player_x, player_y) = self.player;
+        // Screen coordinates increase downwards while directions increase clockwise from up.
+        (player_x - x).atan2(y - player_y).to_degrees()
+    }
+
+    fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    fn speed_x(&self) -> f32 {
+        self.velocity().x
+    }
+
+    fn speed_y(&self) -> f32 {
+        self.velocity().y
+    }
+
+    fn default_speed(&self) -> f32 {
+        DEFAULT_SPEED
+    }
+
+    fn vanish(&mut self) {
+        self.vanished = true;
+    }
+
+    fn change_direction(&mut self, degrees: f32) {
+        self.direction = degrees;
+    }
+
+    fn change_speed(&mut self, speed: f32) {
+        self.speed = speed;
+    }
+
+    fn accel_x(&mut self, amount: f32) {
+        let velocity = self.velocity();
+        self.set_velocity(Velocity {
+            x: amount,
+            y: velocity.y,
+        });
+    }
+
+    fn accel_y(&mut self, amount: f32) {
+        let velocity = self.velocity();
+        self.set_velocity(Velocity {
+            x: velocity.x,
+            y: amount,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::data;
+    use crate::run::example::ExampleManager;
+    use crate::run::testing::{self, canonical};
+    use crate::run::{BulletManager, Runner};
+
+    /// Run a document the way a game would and trace the position of every bullet.
+    ///
+    /// The emitter starts at the origin. Runners are traced first, starting with the emitter,
+    /// followed by bullets without actions.
+    fn play(doc: &str, player: (f32, f32), frames: u32) -> Vec<String> {
+        let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+        let emitter = ExampleManager::new((0., 0.), player);
+        let mut runners = vec![Runner::new(emitter, bulletml).unwrap()];
+        let mut bullets = Vec::new();
+        let mut trace = Vec::new();
+
+        for turn in 0..frames {
+            let mut fired = Vec::new();
+            for runner in &mut runners {
+                runner.manager_mut().turn = turn;
+                runner.update().unwrap();
+                fired.extend(runner.manager_mut().take_fired());
+            }
+            runners.retain(|runner| !runner.manager().vanished);
+
+            for bullet in fired {
+                if let Some(script) = bullet.script {
+                    runners.push(script.runner(bullet.manager));
+                } else {
+                    bullets.push(bullet.manager);
+                }
+            }
+
+            let managers = runners
+                .iter_mut()
+                .map(Runner::manager_mut)
+                .chain(bullets.iter_mut());
+            for manager in managers {
+                manager.advance();
+                trace.push(format!(
+                    "{}: ({}, {})",
+                    turn,
+                    canonical(manager.position.0),
+                    canonical(manager.position.1),
+                ));
+            }
+        }
+
+        trace
+    }
+
+    fn orientation_doc(orientation: &str) -> String {
+        format!(
+            r#"<bulletml type="{}">
+                <action label="top">
+                    <fire>
+                        <direction type="absolute">90</direction>
+                        <speed>2</speed>
+                        <bullet/>
+                    </fire>
+                    <changeDirection>
+                        <direction type="absolute">180</direction>
+                        <term>2</term>
+                    </changeDirection>
+                    <accel>
+                        <horizontal>2</horizontal>
+                        <vertical>1</vertical>
+                        <term>2</term>
+                    </accel>
+                </action>
+            </bulletml>"#,
+            orientation,
+        )
+    }
+
+    #[test]
+    fn test_example_golden_vertical() {
+        testing::assert_trace_eq(
+            &play(&orientation_doc("vertical"), (0., 100.), 4),
+            &[
+                "0: (0, 0)",
+                "0: (2, 0)",
+                "1: (1, 0.5)",
+                "1: (4, 0)",
+                "2: (3, 1.5)",
+                "2: (6, 0)",
+                "3: (5, 2.5)",
+                "3: (8, 0)",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_example_golden_horizontal() {
+        // Absolute directions are rotated and the axes of accelerations are swapped by the runner.
+        testing::assert_trace_eq(
+            &play(&orientation_doc("horizontal"), (0., 100.), 4),
+            &[
+                "0: (0, 0)",
+                "0: (0, -2)",
+                "1: (0.5, 1)",
+                "1: (0, -4)",
+                "2: (1.5, 3)",
+                "2: (0, -6)",
+                "3: (2.5, 5)",
+                "3: (0, -8)",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_example_golden_aim() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <fire>
+                    <direction type="aim">0</direction>
+                    <speed>1</speed>
+                    <bullet/>
+                </fire>
+                <fire>
+                    <direction type="aim">0</direction>
+                    <speed>5</speed>
+                    <bullet>
+                        <action>
+                            <vanish/>
+                        </action>
+                    </bullet>
+                </fire>
+            </action>
+        </bulletml>"#;
+
+        testing::assert_trace_eq(
+            &play(doc, (30., 40.), 3),
+            &[
+                "0: (0, 0)",
+                "0: (3, 4)",
+                "0: (0.6, 0.8)",
+                "1: (0, 0)",
+                "1: (1.2, 1.6)",
+                "2: (0, 0)",
+                "2: (1.8, 2.4)",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_example_motion() {
+        let mut manager = ExampleManager::new((10., 10.), (10., 0.));
+        assert_eq!(manager.aim_direction(), 0.);
+        manager.player = (20., 10.);
+        assert_eq!(manager.aim_direction(), 90.);
+
+        manager.change_direction(90.);
+        manager.change_speed(2.);
+        assert!((manager.speed_x() - 2.).abs() < 1e-6);
+        assert!(manager.speed_y().abs() < 1e-6);
+
+        // The direction survives coming to rest.
+        manager.change_speed(0.);
+        manager.advance();
+        assert_eq!(manager.position, (10., 10.));
+        manager.change_speed(1.);
+        assert_eq!(manager.direction(), 90.);
+
+        manager.accel_y(-3.);
+        assert!((manager.speed() - 10f32.sqrt()).abs() < 1e-6);
+        manager.accel_x(0.);
+        assert!((manager.speed() - 3.).abs() < 1e-6);
+        assert!(manager.direction().abs() < 1e-4);
+        manager.accel_y(0.);
+        assert!(manager.speed() < 1e-6);
+        assert!(manager.direction().abs() < 1e-4);
+    }
+}
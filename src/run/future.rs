@@ -0,0 +1,105 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::data;
+use crate::run::{BulletMLError, CompileJob, CompiledBulletML};
+
+/// The default time to spend compiling per poll.
+const DEFAULT_BUDGET: Duration = Duration::from_millis(1);
+
+/// A future which compiles a BulletML script.
+///
+/// Documents share their entities through `Rc` and may not be sent between threads, so
+/// compilation happens on the thread polling the future. Each poll compiles for a limited time
+/// and then yields to the executor. The compiled script may then be sent to other threads.
+#[derive(Debug)]
+pub struct CompileFuture {
+    job: CompileJob,
+    budget: Duration,
+}
+
+impl CompileFuture {
+    /// Set the time to spend compiling per poll.
+    pub fn with_budget(mut self, budget: Duration) -> Self {
+        self.budget = budget;
+        self
+    }
+}
+
+impl Future for CompileFuture {
+    type Output = Result<CompiledBulletML, BulletMLError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let budget = self.budget;
+        let poll = self.job.step(budget);
+
+        if poll.is_pending() {
+            // There is more work to do; ask to be polled again.
+            cx.waker().wake_by_ref();
+        }
+
+        poll
+    }
+}
+
+/// Compile a BulletML script asynchronously.
+pub fn compile_async(bulletml: data::BulletML) -> CompileFuture {
+    CompileFuture {
+        job: CompileJob::new(bulletml),
+        budget: DEFAULT_BUDGET,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::ptr;
+    use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+    use std::time::Duration;
+
+    use crate::data;
+    use crate::run::compile_async;
+
+    fn noop_waker() -> Waker {
+        fn clone(_: *const ()) -> RawWaker {
+            RawWaker::new(ptr::null(), &VTABLE)
+        }
+        fn noop(_: *const ()) {}
+
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+
+        // The waker does not use its data pointer.
+        unsafe { Waker::from_raw(RawWaker::new(ptr::null(), &VTABLE)) }
+    }
+
+    #[test]
+    fn test_compile_async() {
+        let doc = r#"<bulletml>
+            <bullet label="shot"/>
+            <action label="top">
+                <fire><direction>$angle</direction><bulletRef label="shot"/></fire>
+            </action>
+        </bulletml>"#;
+        let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+        let mut future = compile_async(bulletml).with_budget(Duration::from_secs(0));
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let mut polls = 1;
+        let compiled = loop {
+            match Pin::new(&mut future).poll(&mut cx) {
+                Poll::Ready(compiled) => break compiled.unwrap(),
+                Poll::Pending => polls += 1,
+            }
+        };
+
+        assert_eq!(polls, 3);
+        assert_eq!(compiled.variables(), ["angle"]);
+    }
+}
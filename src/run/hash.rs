@@ -0,0 +1,322 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use std::collections::hash_map::HashMap;
+use std::sync::Arc;
+
+use crate::data::{self, Expression};
+use crate::run::compile::{
+    Accel, Action, Bullet, ChangeDirection, ChangeSpeed, Direction, Fire, Repeat, Speed, Step,
+};
+use crate::run::CompiledBulletML;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// A hasher for the normalized form of a compiled script.
+///
+/// This uses 64-bit FNV-1a over a byte encoding which does not depend on the platform, so hashes
+/// may be stored and compared across builds. Entities which are shared within the script are
+/// written in full once and as a back-reference afterwards.
+struct ContentHasher {
+    state: u64,
+    seen: HashMap<*const (), usize>,
+}
+
+impl ContentHasher {
+    fn new() -> Self {
+        ContentHasher {
+            state: FNV_OFFSET,
+            seen: HashMap::new(),
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.state ^= u64::from(byte);
+            self.state = self.state.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn str(&mut self, value: &str) {
+        self.u64(value.len() as u64);
+        self.bytes(value.as_bytes())
+    }
+
+    fn opt_str(&mut self, value: Option<&str>) {
+        match value {
+            Some(value) => {
+                self.u64(1);
+                self.str(value);
+            },
+            None => self.u64(0),
+        }
+    }
+
+    fn opt_u32(&mut self, value: Option<u32>) {
+        self.u64(value.map_or(0, |value| u64::from(value) + 1))
+    }
+
+    fn expression(&mut self, expr: &Expression) {
+        // The written form of an expression is normalized.
+        self.str(&expr.to_string())
+    }
+
+    fn params(&mut self, params: &Option<Arc<[Expression]>>) {
+        match params {
+            Some(params) => {
+                self.u64(params.len() as u64 + 1);
+                params.iter().for_each(|param| self.expression(param));
+            },
+            None => self.u64(0),
+        }
+    }
+
+    /// Write a back-reference to a shared entity if it has already been written.
+    fn shared<T>(&mut self, entity: &Arc<T>) -> bool {
+        let ptr = Arc::as_ptr(entity) as *const ();
+        let next = self.seen.len();
+        if let Some(&index) = self.seen.get(&ptr) {
+            self.str("ref");
+            self.u64(index as u64);
+            true
+        } else {
+            self.seen.insert(ptr, next);
+            false
+        }
+    }
+
+    fn direction(&mut self, direction: Option<&Direction>) {
+        if let Some(direction) = direction {
+            self.str(data::direction_kind_name(direction.kind));
+            self.u64(direction.lead as u64);
+            self.expression(&direction.degrees);
+        } else {
+            self.str("");
+        }
+    }
+
+    fn speed(&mut self, speed: Option<&Speed>) {
+        if let Some(speed) = speed {
+            self.str(data::change_name(speed.kind));
+            self.expression(&speed.change);
+        } else {
+            self.str("");
+        }
+    }
+
+    fn action(&mut self, action: &Arc<Action>) {
+        if self.shared(action) {
+            return;
+        }
+
+        self.str("action");
+        self.opt_str(action.label.as_deref());
+        self.opt_u32(action.ttl);
+        self.u64(action.tags.len() as u64);
+        action.tags.iter().for_each(|tag| self.str(tag));
+        self.u64(action.steps.len() as u64);
+        action.steps.iter().for_each(|step| self.step(step));
+    }
+
+    fn step(&mut self, step: &Step) {
+        match *step {
+            Step::Repeat(ref repeat) => self.repeat(repeat),
+            Step::Fire(ref fire, ref params) => {
+                self.fire(fire);
+                self.params(params);
+            },
+            Step::ChangeSpeed(ref cs) => self.change_speed(cs),
+            Step::ChangeDirection(ref cd) => self.change_direction(cd),
+            Step::Accel(ref accel) => self.accel(accel),
+            Step::Wait(ref wait) => {
+                self.str("wait");
+                self.expression(&wait.frames);
+            },
+            Step::Vanish(_) => self.str("vanish"),
+            Step::Action(ref action, ref params) => {
+                self.action(action);
+                self.params(params);
+            },
+            Step::Custom(ref custom) => {
+                self.str("custom");
+                self.str(custom.name());
+                self.str(&custom.content());
+            },
+        }
+    }
+
+    fn repeat(&mut self, repeat: &Repeat) {
+        self.str("repeat");
+        self.expression(&repeat.times.value);
+        self.u64(repeat.actions.len() as u64);
+        repeat.actions.iter().for_each(|(action, params)| {
+            self.action(action);
+            self.params(params);
+        });
+    }
+
+    fn fire(&mut self, fire: &Arc<Fire>) {
+        if self.shared(fire) {
+            return;
+        }
+
+        self.str("fire");
+        self.opt_str(fire.label.as_deref());
+        self.direction(fire.direction.as_ref());
+        self.speed(fire.speed.as_ref());
+        self.bullet(&fire.bullet);
+        self.params(&fire.bullet_params);
+    }
+
+    fn bullet(&mut self, bullet: &Arc<Bullet>) {
+        if self.shared(bullet) {
+            return;
+        }
+
+        self.str("bullet");
+        self.opt_str(bullet.label.as_deref());
+        self.opt_u32(bullet.ttl);
+        self.direction(bullet.direction.as_ref());
+        self.speed(bullet.speed.as_ref());
+        self.u64(bullet.actions.len() as u64);
+        bullet.actions.iter().for_each(|(action, params)| {
+            self.action(action);
+            self.params(params);
+        });
+    }
+
+    fn change_speed(&mut self, cs: &ChangeSpeed) {
+        self.str("changeSpeed");
+        self.speed(Some(&cs.speed));
+        self.expression(&cs.value.value);
+        self.opt_str(cs.easing.map(data::easing_name));
+    }
+
+    fn change_direction(&mut self, cd: &ChangeDirection) {
+        self.str("changeDirection");
+        self.direction(Some(&cd.direction));
+        self.expression(&cd.value.value);
+        self.opt_str(cd.easing.map(data::easing_name));
+    }
+
+    fn accel(&mut self, accel: &Accel) {
+        self.str("accel");
+        if let Some(horizontal) = accel.horizontal.as_ref() {
+            self.str(data::change_name(horizontal.kind));
+            self.expression(&horizontal.change);
+        } else {
+            self.str("");
+        }
+        if let Some(vertical) = accel.vertical.as_ref() {
+            self.str(data::change_name(vertical.kind));
+            self.expression(&vertical.change);
+        } else {
+            self.str("");
+        }
+        self.expression(&accel.duration.value);
+        self.opt_str(accel.easing.map(data::easing_name));
+    }
+}
+
+/// A hash of the bytes of a source document.
+///
+/// Unlike `CompiledBulletML::content_hash`, any change to the document changes the hash.
+#[cfg(feature = "pattern-cache")]
+pub(crate) fn source_hash(bytes: &[u8]) -> u64 {
+    let mut hasher = ContentHasher::new();
+    hasher.bytes(bytes);
+    hasher.state
+}
+
+impl CompiledBulletML {
+    /// A hash of the content of the script.
+    ///
+    /// The hash is computed from the compiled form of the script, so it is stable across
+    /// platforms and runs. Changes which do not affect how the script runs, such as formatting,
+    /// comments, the order of labeled entities, and the way expressions are written (e.g., `1+2`
+    /// and `3`), do not change the hash. Labels are included since runners report them. Entities
+    /// which are not reachable from the top-level actions are ignored.
+    ///
+    /// Asset pipelines may use this to cache work done for a pattern. Hashes are only comparable
+    /// between the same versions of this crate.
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = ContentHasher::new();
+        hasher.str(data::orientation_name(self.orientation));
+        hasher.u64(self.actions().len() as u64);
+        self.actions()
+            .iter()
+            .for_each(|action| hasher.action(action));
+        hasher.state
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::data;
+    use crate::run::CompiledBulletML;
+
+    fn hash(doc: &str) -> u64 {
+        let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+        CompiledBulletML::new(bulletml).unwrap().content_hash()
+    }
+
+    const DOC: &str = r#"<bulletml>
+        <bullet label="shot">
+            <speed>2</speed>
+        </bullet>
+        <action label="top">
+            <repeat>
+                <times>3</times>
+                <action>
+                    <fire>
+                        <direction type="sequence">10</direction>
+                        <bulletRef label="shot"/>
+                    </fire>
+                    <wait>$rank * 4</wait>
+                </action>
+            </repeat>
+        </action>
+    </bulletml>"#;
+
+    #[test]
+    fn test_content_hash_formatting() {
+        let reformatted = r#"<bulletml>
+            <!-- The main action. -->
+            <action label="top"><repeat><times>1 + 2</times><action>
+                <fire><direction type="sequence">(10)</direction><bulletRef label="shot"/></fire>
+                <wait>$rank*4</wait>
+            </action></repeat></action>
+            <bullet label="shot"><speed type="absolute">2.0</speed></bullet>
+            <bullet label="unused"/>
+        </bulletml>"#;
+
+        assert_eq!(hash(DOC), hash(DOC));
+        assert_eq!(hash(DOC), hash(reformatted));
+    }
+
+    #[test]
+    fn test_content_hash_semantic() {
+        let changes = [
+            DOC.replace("<speed>2</speed>", "<speed>3</speed>"),
+            DOC.replace("<speed>2</speed>", "<speed type=\"relative\">2</speed>"),
+            DOC.replace("type=\"sequence\"", "type=\"absolute\""),
+            DOC.replace("type=\"sequence\"", "type=\"sequence\" lead=\"true\""),
+            DOC.replace("$rank * 4", "$rank * 5"),
+            DOC.replace("<times>3</times>", "<times>4</times>"),
+            DOC.replace("label=\"top\"", "label=\"top\" ttl=\"60\""),
+            DOC.replace("<bulletml>", "<bulletml type=\"vertical\">"),
+        ];
+
+        let base = hash(DOC);
+        let hashes = changes.iter().map(|doc| hash(doc)).collect::<Vec<_>>();
+        for (idx, &changed) in hashes.iter().enumerate() {
+            assert_ne!(changed, base, "change {}", idx);
+            assert!(!hashes[..idx].contains(&changed), "change {}", idx);
+        }
+    }
+}
@@ -0,0 +1,120 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use std::collections::VecDeque;
+
+use crate::run::Command;
+
+/// The commands given to a manager by a runner, stamped with the turn they were given on.
+///
+/// Histories may keep every command or only the most recent ones. Commands are kept in the
+/// order they were given, so their turns never decrease.
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    commands: VecDeque<(u32, Command)>,
+    limit: Option<usize>,
+    dropped: usize,
+    dropped_through: Option<u32>,
+}
+
+impl History {
+    /// Create a history which keeps at most `limit` commands.
+    ///
+    /// Without a limit, every command is kept.
+    pub fn new(limit: Option<usize>) -> Self {
+        History {
+            commands: VecDeque::new(),
+            limit,
+            dropped: 0,
+            dropped_through: None,
+        }
+    }
+
+    /// The most commands the history keeps.
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// The commands in the history with the turn they were given on.
+    pub fn commands(&self) -> impl Iterator<Item = &(u32, Command)> + '_ {
+        self.commands.iter()
+    }
+
+    /// The commands given on or before a turn.
+    pub fn until(&self, turn: u32) -> impl Iterator<Item = &(u32, Command)> + '_ {
+        self.commands
+            .iter()
+            .take_while(move |&&(command_turn, _)| command_turn <= turn)
+    }
+
+    /// The number of commands in the history.
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    /// Whether the history has any commands.
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// The number of commands which have been dropped to stay within the limit.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Whether the history covers a turn.
+    ///
+    /// Once commands have been dropped, turns before the last dropped command are no longer
+    /// covered.
+    pub fn covers(&self, turn: u32) -> bool {
+        self.dropped_through
+            .map_or(true, |dropped_turn| dropped_turn <= turn)
+    }
+
+    /// Add a command to the history.
+    ///
+    /// Returns the command which was dropped to make room for it, if any.
+    pub(crate) fn push(&mut self, turn: u32, command: Command) -> Option<Command> {
+        self.commands.push_back((turn, command));
+        let len = self.commands.len();
+        if self.limit.map_or(true, |limit| len <= limit) {
+            return None;
+        }
+
+        let (dropped_turn, dropped) = self.commands.pop_front()?;
+        self.dropped += 1;
+        self.dropped_through = Some(dropped_turn);
+        Some(dropped)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::run::{Command, History};
+
+    #[test]
+    fn test_history_limit() {
+        let mut history = History::new(Some(2));
+        assert_eq!(history.push(0, Command::ChangeSpeed(1.)), None);
+        assert_eq!(history.push(1, Command::ChangeSpeed(2.)), None);
+        assert!(history.covers(0));
+
+        assert_eq!(
+            history.push(2, Command::Vanish),
+            Some(Command::ChangeSpeed(1.)),
+        );
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.dropped(), 1);
+        assert!(history.covers(0));
+
+        let until = history
+            .until(1)
+            .map(|&(_, command)| command)
+            .collect::<Vec<_>>();
+        assert_eq!(until, [Command::ChangeSpeed(2.)]);
+
+        history.push(3, Command::Vanish);
+        assert!(!history.covers(0));
+        assert!(history.covers(1));
+    }
+}
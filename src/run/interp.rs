@@ -0,0 +1,210 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+//! Interpolation of values which change over time.
+//!
+//! `<changeDirection>`, `<changeSpeed>`, and `<accel>` move a value from where it is to a target
+//! over a number of frames. The specification moves at a constant rate; an `Easing` bends the
+//! path between the same endpoints.
+
+use std::f64::consts::PI;
+use std::fmt;
+
+use crate::data::{self, Easing, Numeric};
+
+/// A function over a range of turns.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Function<N = f32> {
+    min: u32,
+    max: u32,
+
+    start: N,
+    end: N,
+    step: N,
+
+    easing: Easing,
+}
+
+impl<N> Function<N>
+where
+    N: Numeric,
+{
+    fn new(min: u32, max: u32, start: N, end: N) -> Self {
+        Function {
+            min,
+            max,
+            start,
+            end,
+            step: (end - start) / N::from_frames(max - min),
+            easing: Easing::Linear,
+        }
+    }
+
+    /// Use an easing to move between the endpoints.
+    pub(crate) fn with_easing(mut self, easing: Easing) -> Self {
+        self.easing = easing;
+        self
+    }
+
+    fn call(&self, x: u32) -> N {
+        // Only the offset from the start of the function is converted to floating point. Turns
+        // themselves lose precision as `f32` once sessions exceed 2^24 frames.
+        let offset = x.saturating_sub(self.min);
+        if let Easing::Linear = self.easing {
+            return self.start + self.step * N::from_frames(offset);
+        }
+
+        let fraction = f64::from(offset) / f64::from(self.max - self.min);
+        self.start + (self.end - self.start) * N::from_fraction(ease(self.easing, fraction))
+    }
+
+    fn is_in_domain(&self, x: u32) -> bool {
+        self.min <= x && x < self.max
+    }
+
+    fn last(&self) -> N {
+        self.end
+    }
+
+    /// The value of the function at a turn and whether the function continues afterwards.
+    pub(crate) fn update(&self, turn: u32) -> (bool, N) {
+        if self.is_in_domain(turn) {
+            (true, self.call(turn))
+        } else {
+            (false, self.last())
+        }
+    }
+}
+
+impl<N> fmt::Display for Function<N>
+where
+    N: Numeric,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {} over turns {}..{}",
+            self.start, self.end, self.min, self.max,
+        )?;
+        if self.easing != Easing::Linear {
+            write!(f, " ({})", data::easing_name(self.easing))?;
+        }
+        Ok(())
+    }
+}
+
+/// The fraction of the way from the start to the end after `fraction` of the duration.
+pub(crate) fn ease(easing: Easing, fraction: f64) -> f64 {
+    match easing {
+        Easing::Linear => fraction,
+        Easing::EaseIn => fraction * fraction,
+        Easing::EaseOut => fraction * (2. - fraction),
+        Easing::EaseInOut => {
+            if fraction < 0.5 {
+                2. * fraction * fraction
+            } else {
+                1. - 2. * (1. - fraction) * (1. - fraction)
+            }
+        },
+        Easing::Sine => (1. - (PI * fraction).cos()) / 2.,
+    }
+}
+
+/// Interpolate from `start` to `end` over `duration` frames beginning at `turn`.
+pub(crate) fn interpolate<N>(turn: u32, duration: f32, start: N, end: N) -> Function<N>
+where
+    N: Numeric,
+{
+    Function::new(turn, turn.saturating_add(duration.ceil() as u32), start, end)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::data::Easing;
+    use crate::run::interp;
+
+    const EASINGS: &[Easing] = &[
+        Easing::Linear,
+        Easing::EaseIn,
+        Easing::EaseOut,
+        Easing::EaseInOut,
+        Easing::Sine,
+    ];
+
+    #[test]
+    fn test_interpolate_endpoints() {
+        for &easing in EASINGS {
+            for duration in 0..8 {
+                for &(start, end) in &[(0., 1.), (-3., 5.), (2., 2.)] {
+                    let func =
+                        interp::interpolate(10, duration as f32, start, end).with_easing(easing);
+
+                    if duration > 0 {
+                        assert_eq!(func.update(10), (true, start));
+                    }
+                    assert_eq!(func.update(10 + duration), (false, end));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_interpolate_f64() {
+        let func = interp::interpolate(0, 3., 0_f64, 1.);
+
+        assert_eq!(func.update(1), (true, 1. / 3.));
+        assert_eq!(func.update(3), (false, 1.));
+    }
+
+    #[test]
+    fn test_interpolate_large_turn() {
+        // Turns beyond 2^24 are not representable as `f32`.
+        let base = (1 << 24) * 6 + 7;
+        let func = interp::interpolate(base, 4., 0., 4.);
+
+        assert_eq!(func.update(base), (true, 0.));
+        assert_eq!(func.update(base + 1), (true, 1.));
+        assert_eq!(func.update(base + 3), (true, 3.));
+        assert_eq!(func.update(base + 4), (false, 4.));
+
+        let func = interp::interpolate(u32::MAX - 1, 4., 0., 4.);
+        assert_eq!(func.update(u32::MAX - 1), (true, 0.));
+        assert_eq!(func.update(u32::MAX), (false, 4.));
+
+        let func = interp::interpolate(base, 4., 0., 4.).with_easing(Easing::EaseIn);
+        assert_eq!(func.update(base + 2), (true, 1.));
+    }
+
+    #[test]
+    fn test_easing() {
+        let values = |easing| {
+            let func = interp::interpolate(0, 4., 0., 16.).with_easing(easing);
+            (0..5).map(|turn| func.update(turn).1).collect::<Vec<_>>()
+        };
+
+        assert_eq!(values(Easing::Linear), [0., 4., 8., 12., 16.]);
+        assert_eq!(values(Easing::EaseIn), [0., 1., 4., 9., 16.]);
+        assert_eq!(values(Easing::EaseOut), [0., 7., 12., 15., 16.]);
+        assert_eq!(values(Easing::EaseInOut), [0., 2., 8., 14., 16.]);
+
+        let sine = values(Easing::Sine);
+        assert!((sine[1] - 8. * (1. - 0.5_f32.sqrt())).abs() < 1e-5);
+        assert_eq!(sine[2], 8.);
+
+        // Every easing is monotonic and symmetric easings pass through the midpoint.
+        for &easing in EASINGS {
+            let fractions = (0..=100)
+                .map(|step| interp::ease(easing, f64::from(step) / 100.))
+                .collect::<Vec<_>>();
+            assert!(fractions.windows(2).all(|pair| pair[0] <= pair[1]));
+            assert_eq!(fractions[0], 0.);
+            assert!((fractions[100] - 1.).abs() < 1e-12);
+        }
+        for &easing in &[Easing::Linear, Easing::EaseInOut, Easing::Sine] {
+            assert!((interp::ease(easing, 0.5) - 0.5).abs() < 1e-12);
+        }
+
+        let func = interp::interpolate(3, 2., 1., 2.).with_easing(Easing::Sine);
+        assert_eq!(func.to_string(), "1 -> 2 over turns 3..5 (sine)");
+    }
+}
@@ -2,16 +2,31 @@
 // See accompanying LICENSE file for details.
 
 use crate::run::compile::ExpressionContext;
+use crate::run::BulletScript;
 
 /// The implementation of a bullet.
 ///
 /// This trait is driven by the `Runner` structure to perform the actions indicated by the
 /// BulletML script.
+///
+/// When multiple bullets are fired within a single update, `new_simple` and `new_bullet` are
+/// called in document order. The steps of each iteration of a `<repeat>` complete before the
+/// next iteration begins.
+///
+/// `ExampleManager` is a minimal implementation which may be copied as a starting point.
 pub trait BulletManager: ExpressionContext {
     /// Create a new, simple, bullet.
     fn new_simple(&mut self, direction: f32, speed: f32);
     /// Create a new bullet.
     fn new_bullet(&mut self, direction: f32, speed: f32);
+    /// Create a new bullet which runs actions.
+    ///
+    /// The actions run once the script is turned into a runner with a manager for the new
+    /// bullet (see `BulletScript::runner`). The default implementation drops the actions and
+    /// calls `new_bullet`.
+    fn new_bullet_with_script(&mut self, direction: f32, speed: f32, _script: BulletScript) {
+        self.new_bullet(direction, speed)
+    }
     /// The turn of the simulation.
     fn turn(&self) -> u32;
 
@@ -19,6 +34,21 @@ pub trait BulletManager: ExpressionContext {
     fn direction(&self) -> f32;
     /// The direction the bullet should aim for.
     fn aim_direction(&self) -> f32;
+    /// The direction the bullet should aim for, if there is a target.
+    ///
+    /// Managers should return `None` while there is nothing to aim at (e.g., while the player is
+    /// respawning). The runner then chooses a direction according to `RunnerOptions::no_target`.
+    fn try_aim_direction(&self) -> Option<f32> {
+        Some(self.aim_direction())
+    }
+    /// The velocity of the target, if it is known.
+    ///
+    /// This is given along the same axes as `speed_x` and `speed_y` and is used by aimed
+    /// directions which lead the target (see `Direction::lead`). Without a velocity, such
+    /// directions aim directly at the target.
+    fn target_velocity(&self) -> Option<(f32, f32)> {
+        None
+    }
     /// The current speed of the bullet.
     fn speed(&self) -> f32;
     /// The current `x`-axis speed of the bullet.
@@ -27,6 +57,13 @@ pub trait BulletManager: ExpressionContext {
     fn speed_y(&self) -> f32;
     /// The default speed of the bullet.
     fn default_speed(&self) -> f32;
+    /// The velocity of the bullet given to bullets it fires when `RunnerOptions::inherit_velocity`
+    /// is set.
+    ///
+    /// This defaults to the current speed along each axis.
+    fn owner_velocity(&self) -> (f32, f32) {
+        (self.speed_x(), self.speed_y())
+    }
 
     /// Destroy the bullet.
     fn vanish(&mut self);
@@ -0,0 +1,345 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use serde::Deserialize;
+
+use crate::data::Easing;
+use crate::run::semantics;
+
+/// How to aim when the manager has no target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NoTargetPolicy {
+    /// Aim at the last known target.
+    ///
+    /// If there has never been a target, `BulletManager::aim_direction` is used.
+    KeepLast,
+    /// Aim at the direction `0` in the orientation of the script.
+    Up,
+    /// Aim straight down (`180` degrees).
+    Down,
+}
+
+impl Default for NoTargetPolicy {
+    fn default() -> Self {
+        NoTargetPolicy::KeepLast
+    }
+}
+
+/// How to evaluate variables which are not defined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownVariablePolicy {
+    /// Evaluation fails with `ExpressionError::UndefinedVariable`.
+    Error,
+    /// The variable evaluates to `0`.
+    ///
+    /// The names of such variables are available from `Runner::unknown_variables`.
+    Zero,
+}
+
+impl Default for UnknownVariablePolicy {
+    fn default() -> Self {
+        UnknownVariablePolicy::Error
+    }
+}
+
+/// When the number of iterations of a `<repeat>` is evaluated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RepeatEvaluation {
+    /// `<times>` is evaluated once when the repeat starts.
+    Once,
+    /// `<times>` is evaluated before each iteration and the repeat ends once that many
+    /// iterations have completed.
+    ///
+    /// Some dialects use this so that, e.g., `$rank` may change the length of a running loop.
+    EachIteration,
+}
+
+impl Default for RepeatEvaluation {
+    fn default() -> Self {
+        RepeatEvaluation::Once
+    }
+}
+
+/// How to handle speeds which become negative.
+///
+/// Speeds may become negative through, e.g., a `relative` `<speed>` or a `<changeSpeed>` which
+/// interpolates past zero. The specification allows this, but engines differ in how they move
+/// such bullets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NegativeSpeed {
+    /// Negative speeds are given to the manager as-is.
+    Allow,
+    /// The direction is turned by `180` degrees and the magnitude of the speed is used instead.
+    ReflectDirection,
+    /// Negative speeds are replaced by `0`.
+    ClampZero,
+}
+
+impl Default for NegativeSpeed {
+    fn default() -> Self {
+        NegativeSpeed::Allow
+    }
+}
+
+/// Options for running a script.
+///
+/// Options may be deserialized so that they can be loaded from configuration files. Missing
+/// fields take their default values.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RunnerOptions {
+    /// The number of frames after which the bullet vanishes.
+    ///
+    /// A `ttl` given by an action in the script takes effect if it expires sooner.
+    pub default_ttl: Option<u32>,
+    /// The maximum number of steps to execute in a single update.
+    ///
+    /// This allows bursts of activity to be spread across multiple frames.
+    pub step_budget: Option<usize>,
+    /// Whether fractional `<wait>` durations accumulate across waits.
+    ///
+    /// By default, each wait is rounded up to a whole number of frames. When accumulating, the
+    /// fractional remainder of each wait carries over to the next one instead so that, e.g.,
+    /// waiting `0.5` frames twice waits a single frame in total.
+    pub accumulate_wait: bool,
+    /// The number of distinct directions the manager is given, if quantized.
+    ///
+    /// Directions sent to the manager are rounded to the nearest multiple of `360 / steps`
+    /// degrees. Many classic games use `256` steps.
+    pub direction_steps: Option<u32>,
+    /// The number of fractional bits of speeds the manager is given, if quantized.
+    ///
+    /// Speeds sent to the manager are rounded to the nearest multiple of `2^-bits` as if stored
+    /// in a fixed-point format.
+    pub speed_fraction_bits: Option<u32>,
+    /// How to aim when the manager has no target.
+    pub no_target: NoTargetPolicy,
+    /// Whether fired bullets add the velocity of their owner to their own.
+    ///
+    /// The owner's velocity is given by `BulletManager::owner_velocity`. Sequence directions and
+    /// speeds continue from the values given by the script rather than the combined velocity.
+    pub inherit_velocity: bool,
+    /// How to evaluate variables which are not defined.
+    pub unknown_variable: UnknownVariablePolicy,
+    /// When the number of iterations of a `<repeat>` is evaluated.
+    pub repeat_evaluation: RepeatEvaluation,
+    /// How to handle speeds which become negative.
+    ///
+    /// The policy applies to the speeds of fired bullets and to `<changeSpeed>`. `<accel>`
+    /// changes the components of the velocity, which are signed, and is not affected. `sequence`
+    /// speeds continue from the values given by the script rather than those given to the
+    /// manager.
+    pub negative_speed: NegativeSpeed,
+    /// The tags of the actions to run, if restricted.
+    ///
+    /// Actions with tags only run if any of their tags is given here; their steps are skipped
+    /// otherwise. Actions without tags always run. This allows a single script to provide, e.g.,
+    /// variants for each difficulty.
+    pub tags: Option<Vec<String>>,
+    /// The number of frames per second for runners stepped by elapsed time.
+    ///
+    /// Runners updated with `Runner::update_dt` convert elapsed time into frames at this rate.
+    /// Durations in scripts are given in frames, so this maps them to seconds. Defaults to `60`.
+    pub frames_per_second: Option<f32>,
+    /// Whether bullets vanish along with the bullet which fired them.
+    ///
+    /// Runners created from the scripts of fired bullets vanish on their next update once the
+    /// runner which fired them has vanished. Vanishing cascades to the bullets they fired in
+    /// turn as their runners update.
+    pub vanish_children: bool,
+    /// How changes over time move between their endpoints.
+    ///
+    /// This applies to `<changeDirection>`, `<changeSpeed>`, and `<accel>` steps which do not
+    /// give their own `easing`. The specification changes values at a constant rate.
+    pub easing: Easing,
+}
+
+impl RunnerOptions {
+    /// Create a builder for runner options.
+    pub fn builder() -> RunnerOptionsBuilder {
+        RunnerOptionsBuilder::default()
+    }
+
+    pub(crate) fn runs_tags(&self, tags: &[String]) -> bool {
+        if tags.is_empty() {
+            return true;
+        }
+
+        self.tags
+            .as_ref()
+            .map_or(true, |allowed| tags.iter().any(|tag| allowed.contains(tag)))
+    }
+
+    pub(crate) fn quantize_direction(&self, degrees: f32) -> f32 {
+        self.direction_steps
+            .map_or(degrees, |steps| semantics::quantize_direction(degrees, steps))
+    }
+
+    pub(crate) fn quantize_speed(&self, speed: f32) -> f32 {
+        self.speed_fraction_bits
+            .map_or(speed, |bits| semantics::quantize_speed(speed, bits))
+    }
+}
+
+/// A builder for `RunnerOptions`.
+///
+/// Options which are not set keep their default values.
+#[derive(Debug, Clone, Default)]
+pub struct RunnerOptionsBuilder {
+    options: RunnerOptions,
+}
+
+impl RunnerOptionsBuilder {
+    /// The number of frames after which the bullet vanishes.
+    pub fn default_ttl(mut self, ttl: u32) -> Self {
+        self.options.default_ttl = Some(ttl);
+        self
+    }
+
+    /// The maximum number of steps to execute in a single update.
+    pub fn step_budget(mut self, budget: usize) -> Self {
+        self.options.step_budget = Some(budget);
+        self
+    }
+
+    /// Whether fractional `<wait>` durations accumulate across waits.
+    pub fn accumulate_wait(mut self, accumulate: bool) -> Self {
+        self.options.accumulate_wait = accumulate;
+        self
+    }
+
+    /// The number of distinct directions the manager is given.
+    pub fn direction_steps(mut self, steps: u32) -> Self {
+        self.options.direction_steps = Some(steps);
+        self
+    }
+
+    /// The number of fractional bits of speeds the manager is given.
+    pub fn speed_fraction_bits(mut self, bits: u32) -> Self {
+        self.options.speed_fraction_bits = Some(bits);
+        self
+    }
+
+    /// How to aim when the manager has no target.
+    pub fn no_target(mut self, policy: NoTargetPolicy) -> Self {
+        self.options.no_target = policy;
+        self
+    }
+
+    /// Whether fired bullets add the velocity of their owner to their own.
+    pub fn inherit_velocity(mut self, inherit: bool) -> Self {
+        self.options.inherit_velocity = inherit;
+        self
+    }
+
+    /// How to evaluate variables which are not defined.
+    pub fn unknown_variable(mut self, policy: UnknownVariablePolicy) -> Self {
+        self.options.unknown_variable = policy;
+        self
+    }
+
+    /// When the number of iterations of a `<repeat>` is evaluated.
+    pub fn repeat_evaluation(mut self, evaluation: RepeatEvaluation) -> Self {
+        self.options.repeat_evaluation = evaluation;
+        self
+    }
+
+    /// How to handle speeds which become negative.
+    pub fn negative_speed(mut self, policy: NegativeSpeed) -> Self {
+        self.options.negative_speed = policy;
+        self
+    }
+
+    /// The tags of the actions to run.
+    pub fn tags<I, T>(mut self, tags: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.options.tags = Some(tags.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// The number of frames per second for runners stepped by elapsed time.
+    pub fn frames_per_second(mut self, fps: f32) -> Self {
+        self.options.frames_per_second = Some(fps);
+        self
+    }
+
+    /// Whether bullets vanish along with the bullet which fired them.
+    pub fn vanish_children(mut self, vanish: bool) -> Self {
+        self.options.vanish_children = vanish;
+        self
+    }
+
+    /// How changes over time move between their endpoints.
+    pub fn easing(mut self, easing: Easing) -> Self {
+        self.options.easing = easing;
+        self
+    }
+
+    /// Build the options.
+    pub fn build(self) -> RunnerOptions {
+        self.options
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::data::Easing;
+    use crate::run::{NoTargetPolicy, RunnerOptions};
+
+    #[test]
+    fn test_builder() {
+        let options = RunnerOptions::builder()
+            .default_ttl(30)
+            .step_budget(100)
+            .no_target(NoTargetPolicy::Down)
+            .build();
+
+        assert_eq!(
+            options,
+            RunnerOptions {
+                default_ttl: Some(30),
+                step_budget: Some(100),
+                no_target: NoTargetPolicy::Down,
+                ..Default::default()
+            },
+        );
+        assert_eq!(RunnerOptions::builder().build(), RunnerOptions::default());
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let options: RunnerOptions = serde_json::from_str(
+            r#"{
+                "default_ttl": 30,
+                "accumulate_wait": true,
+                "no_target": "keep_last",
+                "direction_steps": 256,
+                "easing": "ease_in_out"
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            options,
+            RunnerOptions::builder()
+                .default_ttl(30)
+                .accumulate_wait(true)
+                .direction_steps(256)
+                .easing(Easing::EaseInOut)
+                .build(),
+        );
+
+        let options: RunnerOptions = serde_json::from_str("{}").unwrap();
+        assert_eq!(options, RunnerOptions::default());
+
+        let err = serde_json::from_str::<RunnerOptions>(r#"{"step_limit": 4}"#).unwrap_err();
+        assert!(err.to_string().contains("unknown field `step_limit`"));
+    }
+}
@@ -0,0 +1,764 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use std::iter;
+use std::mem;
+
+use crate::data::{ExpressionContext, ExpressionError, Value};
+use crate::run::{BulletManager, BulletScript, Runner};
+
+/// The implementation of a bullet whose game allocates bullets from a pool.
+///
+/// This is the same as `BulletManager` except that creating a bullet returns a handle to it
+/// (e.g., a slot in an object pool or an entity in an ECS world). Wrapping the manager in
+/// `Pooled` gives a `BulletManager` for a `Runner` which collects the handles along with the
+/// scripts of the new bullets so that each script may be run by a manager for its bullet.
+pub trait PooledBulletManager: ExpressionContext {
+    /// A handle to a bullet within the game.
+    type Handle;
+
+    /// Create a new, simple, bullet.
+    fn new_simple(&mut self, direction: f32, speed: f32) -> Self::Handle;
+    /// Create a new bullet which runs actions.
+    ///
+    /// The actions are given to the game by `Pooled::take_spawned` along with the handle.
+    fn new_bullet(&mut self, direction: f32, speed: f32) -> Self::Handle;
+    /// The turn of the simulation.
+    fn turn(&self) -> u32;
+
+    /// The current direction of the bullet.
+    fn direction(&self) -> f32;
+    /// The direction the bullet should aim for.
+    fn aim_direction(&self) -> f32;
+    /// The direction the bullet should aim for, if there is a target.
+    ///
+    /// See `BulletManager::try_aim_direction`.
+    fn try_aim_direction(&self) -> Option<f32> {
+        Some(self.aim_direction())
+    }
+    /// The velocity of the target, if it is known.
+    ///
+    /// See `BulletManager::target_velocity`.
+    fn target_velocity(&self) -> Option<(f32, f32)> {
+        None
+    }
+    /// The current speed of the bullet.
+    fn speed(&self) -> f32;
+    /// The current `x`-axis speed of the bullet.
+    fn speed_x(&self) -> f32;
+    /// The current `y`-axis speed of the bullet.
+    fn speed_y(&self) -> f32;
+    /// The default speed of the bullet.
+    fn default_speed(&self) -> f32;
+    /// The velocity of the bullet given to bullets it fires.
+    ///
+    /// See `BulletManager::owner_velocity`.
+    fn owner_velocity(&self) -> (f32, f32) {
+        (self.speed_x(), self.speed_y())
+    }
+
+    /// Destroy the bullet.
+    fn vanish(&mut self);
+    /// Change the direction of the bullet.
+    fn change_direction(&mut self, degrees: f32);
+    /// Change the speed of the bullet.
+    fn change_speed(&mut self, speed: f32);
+    /// Accelerate the bullet along the `x` axis.
+    fn accel_x(&mut self, amount: f32);
+    /// Accelerate the bullet along the `y` axis.
+    fn accel_y(&mut self, amount: f32);
+}
+
+/// A bullet created by a `Pooled` manager.
+#[derive(Debug)]
+pub struct Spawned<H> {
+    /// The handle returned by the manager for the bullet.
+    pub handle: H,
+    /// The actions of the bullet, if it has any.
+    pub script: Option<BulletScript>,
+}
+
+impl<H> Spawned<H> {
+    /// Create a runner for the actions of the bullet with a manager for it.
+    ///
+    /// Returns `None` for simple bullets.
+    pub fn runner<T, F>(self, manager: F) -> Option<Runner<T>>
+    where
+        F: FnOnce(H) -> T,
+    {
+        let handle = self.handle;
+        self.script.map(|script| script.runner(manager(handle)))
+    }
+}
+
+/// A `BulletManager` for a `PooledBulletManager`.
+///
+/// Bullets created by the runner are collected in the order they are fired until they are taken
+/// by the game. All other calls are forwarded to the wrapped manager.
+#[derive(Debug)]
+pub struct Pooled<T>
+where
+    T: PooledBulletManager,
+{
+    inner: T,
+    spawned: Vec<Spawned<T::Handle>>,
+}
+
+impl<T> Pooled<T>
+where
+    T: PooledBulletManager,
+{
+    /// Wrap a manager.
+    pub fn new(inner: T) -> Self {
+        Pooled {
+            inner,
+            spawned: Vec::new(),
+        }
+    }
+
+    /// The bullets which have been created and not yet taken.
+    pub fn spawned(&self) -> &[Spawned<T::Handle>] {
+        &self.spawned
+    }
+
+    /// Take the bullets which have been created.
+    pub fn take_spawned(&mut self) -> Vec<Spawned<T::Handle>> {
+        mem::take(&mut self.spawned)
+    }
+
+    /// Take the bullets which have been created and create runners for those with actions.
+    ///
+    /// The manager for each runner is created from the handle of its bullet.
+    pub fn take_runners<U, F>(&mut self, mut manager: F) -> Vec<Runner<U>>
+    where
+        F: FnMut(T::Handle) -> U,
+    {
+        self.take_spawned()
+            .into_iter()
+            .filter_map(|spawned| spawned.runner(&mut manager))
+            .collect()
+    }
+
+    /// The wrapped manager.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// The wrapped manager.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Unwrap the manager.
+    ///
+    /// Bullets which have not been taken are dropped.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T> ExpressionContext for Pooled<T>
+where
+    T: PooledBulletManager,
+{
+    fn get(&self, name: &str) -> Option<Value> {
+        self.inner.get(name)
+    }
+
+    fn get_index(&self, idx: usize, name: &str) -> Option<Value> {
+        self.inner.get_index(idx, name)
+    }
+
+    fn get_param(&self, idx: usize) -> Option<Value> {
+        self.inner.get_param(idx)
+    }
+
+    fn rand(&self) -> Value {
+        self.inner.rand()
+    }
+
+    fn rank(&self) -> Value {
+        self.inner.rank()
+    }
+}
+
+impl<T> BulletManager for Pooled<T>
+where
+    T: PooledBulletManager,
+{
+    fn new_simple(&mut self, direction: f32, speed: f32) {
+        let handle = self.inner.new_simple(direction, speed);
+        self.spawned.push(Spawned {
+            handle,
+            script: None,
+        });
+    }
+
+    fn new_bullet(&mut self, direction: f32, speed: f32) {
+        let handle = self.inner.new_bullet(direction, speed);
+        self.spawned.push(Spawned {
+            handle,
+            script: None,
+        });
+    }
+
+    fn new_bullet_with_script(&mut self, direction: f32, speed: f32, script: BulletScript) {
+        let handle = self.inner.new_bullet(direction, speed);
+        self.spawned.push(Spawned {
+            handle,
+            script: Some(script),
+        });
+    }
+
+    fn turn(&self) -> u32 {
+        self.inner.turn()
+    }
+
+    fn direction(&self) -> f32 {
+        self.inner.direction()
+    }
+
+    fn aim_direction(&self) -> f32 {
+        self.inner.aim_direction()
+    }
+
+    fn try_aim_direction(&self) -> Option<f32> {
+        self.inner.try_aim_direction()
+    }
+
+    fn target_velocity(&self) -> Option<(f32, f32)> {
+        self.inner.target_velocity()
+    }
+
+    fn speed(&self) -> f32 {
+        self.inner.speed()
+    }
+
+    fn speed_x(&self) -> f32 {
+        self.inner.speed_x()
+    }
+
+    fn speed_y(&self) -> f32 {
+        self.inner.speed_y()
+    }
+
+    fn default_speed(&self) -> f32 {
+        self.inner.default_speed()
+    }
+
+    fn owner_velocity(&self) -> (f32, f32) {
+        self.inner.owner_velocity()
+    }
+
+    fn vanish(&mut self) {
+        self.inner.vanish()
+    }
+
+    fn change_direction(&mut self, degrees: f32) {
+        self.inner.change_direction(degrees)
+    }
+
+    fn change_speed(&mut self, speed: f32) {
+        self.inner.change_speed(speed)
+    }
+
+    fn accel_x(&mut self, amount: f32) {
+        self.inner.accel_x(amount)
+    }
+
+    fn accel_y(&mut self, amount: f32) {
+        self.inner.accel_y(amount)
+    }
+}
+
+/// Statistics of a `RunnerPool` for tuning its capacity.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// The number of runners in the pool.
+    pub live: usize,
+    /// The number of runners the pool has storage for.
+    pub capacity: usize,
+    /// The largest number of runners which have been in the pool at once.
+    pub peak: usize,
+    /// The number of runners which have been added to the pool.
+    pub spawned: u64,
+    /// The number of runners which were stored in a slot released by an earlier runner.
+    pub reused: u64,
+}
+
+enum Slot<T> {
+    Occupied(Runner<T>),
+    /// A free slot along with the next free slot.
+    Vacant(Option<usize>),
+}
+
+/// A collection of runners for fired bullets.
+///
+/// Runners are stored in slots which are kept once their runner is released and reused for later
+/// runners, so bursts of fired bullets do not grow the storage once the pool has warmed up.
+/// Each runner is identified by the index of its slot, which may be given to a later runner once
+/// the runner is released.
+pub struct RunnerPool<T> {
+    slots: Vec<Slot<T>>,
+    free: Option<usize>,
+    stats: PoolStats,
+}
+
+impl<T> Default for RunnerPool<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> RunnerPool<T> {
+    /// Create an empty pool.
+    pub fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    /// Create an empty pool with storage for a number of runners.
+    pub fn with_capacity(capacity: usize) -> Self {
+        RunnerPool {
+            slots: Vec::with_capacity(capacity),
+            free: None,
+            stats: PoolStats::default(),
+        }
+    }
+
+    /// Statistics of the pool.
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            capacity: self.slots.capacity(),
+            ..self.stats
+        }
+    }
+
+    /// The number of runners in the pool.
+    pub fn len(&self) -> usize {
+        self.stats.live
+    }
+
+    /// Whether the pool has no runners.
+    pub fn is_empty(&self) -> bool {
+        self.stats.live == 0
+    }
+
+    /// Add a runner to the pool.
+    ///
+    /// Returns the index of the slot for the runner.
+    pub fn insert(&mut self, runner: Runner<T>) -> usize {
+        self.stats.spawned += 1;
+        self.stats.live += 1;
+        self.stats.peak = self.stats.peak.max(self.stats.live);
+
+        if let Some(index) = self.free {
+            if let Slot::Vacant(next) = self.slots[index] {
+                self.free = next;
+            }
+            self.slots[index] = Slot::Occupied(runner);
+            self.stats.reused += 1;
+            index
+        } else {
+            self.slots.push(Slot::Occupied(runner));
+            self.slots.len() - 1
+        }
+    }
+
+    /// Add a runner for the actions of a fired bullet to the pool.
+    pub fn spawn(&mut self, manager: T, script: BulletScript) -> usize {
+        self.insert(script.runner(manager))
+    }
+
+    /// The runner in a slot.
+    pub fn get(&self, index: usize) -> Option<&Runner<T>> {
+        match self.slots.get(index) {
+            Some(Slot::Occupied(runner)) => Some(runner),
+            _ => None,
+        }
+    }
+
+    /// The runner in a slot.
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut Runner<T>> {
+        match self.slots.get_mut(index) {
+            Some(Slot::Occupied(runner)) => Some(runner),
+            _ => None,
+        }
+    }
+
+    /// Remove the runner in a slot from the pool.
+    pub fn remove(&mut self, index: usize) -> Option<Runner<T>> {
+        let slot = self.slots.get_mut(index)?;
+        let runner = match mem::replace(slot, Slot::Vacant(self.free)) {
+            Slot::Occupied(runner) => runner,
+            vacant => {
+                // Free slots are left as they were.
+                *slot = vacant;
+                return None;
+            },
+        };
+
+        self.free = Some(index);
+        self.stats.live -= 1;
+        Some(runner)
+    }
+
+    /// Keep only the runners for which a predicate returns `true`.
+    pub fn retain<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&Runner<T>) -> bool,
+    {
+        for index in 0..self.slots.len() {
+            if let Slot::Occupied(ref runner) = self.slots[index] {
+                if !keep(runner) {
+                    self.remove(index);
+                }
+            }
+        }
+    }
+
+    /// The runners in the pool along with the indices of their slots.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Runner<T>)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            if let Slot::Occupied(ref runner) = *slot {
+                Some((index, runner))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// The runners in the pool along with the indices of their slots.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (usize, &mut Runner<T>)> {
+        self.slots
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(index, slot)| {
+                if let Slot::Occupied(ref mut runner) = *slot {
+                    Some((index, runner))
+                } else {
+                    None
+                }
+            })
+    }
+}
+
+impl<T> RunnerPool<T>
+where
+    T: BulletManager,
+{
+    /// Update every runner in the pool in the order of their slots.
+    ///
+    /// Updates stop at the first runner which fails.
+    pub fn update(&mut self) -> Result<(), ExpressionError> {
+        self.iter_mut()
+            .try_for_each(|(_, runner)| runner.update().map(|_| ()))
+    }
+
+    /// Release the runners which have nothing left to do.
+    ///
+    /// See `Runner::is_done`.
+    pub fn release_done(&mut self) {
+        self.retain(|runner| !runner.is_done())
+    }
+}
+
+impl<T> Extend<Runner<T>> for RunnerPool<T> {
+    fn extend<I>(&mut self, runners: I)
+    where
+        I: IntoIterator<Item = Runner<T>>,
+    {
+        runners.into_iter().for_each(|runner| {
+            self.insert(runner);
+        })
+    }
+}
+
+impl<T> iter::FromIterator<Runner<T>> for RunnerPool<T> {
+    fn from_iter<I>(runners: I) -> Self
+    where
+        I: IntoIterator<Item = Runner<T>>,
+    {
+        let mut pool = Self::new();
+        pool.extend(runners);
+        pool
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::data::{self, ExpressionContext, Value};
+    use crate::run::testing::TestManager;
+    use crate::run::{Pooled, PooledBulletManager, Runner, RunnerPool, RunnerStatus};
+
+    /// A bullet within a pool.
+    #[derive(Debug, Clone, Copy, Default, PartialEq)]
+    struct Body {
+        direction: f32,
+        speed: f32,
+        alive: bool,
+    }
+
+    /// A manager for a bullet in a pool shared by all managers.
+    #[derive(Debug)]
+    struct PoolManager {
+        pool: Rc<RefCell<Vec<Body>>>,
+        slot: usize,
+    }
+
+    impl PoolManager {
+        fn body(&self) -> Body {
+            self.pool.borrow()[self.slot]
+        }
+
+        fn spawn(&mut self, direction: f32, speed: f32) -> usize {
+            let mut pool = self.pool.borrow_mut();
+            pool.push(Body {
+                direction,
+                speed,
+                alive: true,
+            });
+            pool.len() - 1
+        }
+    }
+
+    impl ExpressionContext for PoolManager {
+        fn get(&self, _: &str) -> Option<Value> {
+            None
+        }
+
+        fn get_param(&self, _: usize) -> Option<Value> {
+            None
+        }
+
+        fn rand(&self) -> Value {
+            0.5
+        }
+
+        fn rank(&self) -> Value {
+            0.
+        }
+    }
+
+    impl PooledBulletManager for PoolManager {
+        type Handle = usize;
+
+        fn new_simple(&mut self, direction: f32, speed: f32) -> usize {
+            self.spawn(direction, speed)
+        }
+
+        fn new_bullet(&mut self, direction: f32, speed: f32) -> usize {
+            self.spawn(direction, speed)
+        }
+
+        fn turn(&self) -> u32 {
+            0
+        }
+
+        fn direction(&self) -> f32 {
+            self.body().direction
+        }
+
+        fn aim_direction(&self) -> f32 {
+            0.
+        }
+
+        fn speed(&self) -> f32 {
+            self.body().speed
+        }
+
+        fn speed_x(&self) -> f32 {
+            0.
+        }
+
+        fn speed_y(&self) -> f32 {
+            0.
+        }
+
+        fn default_speed(&self) -> f32 {
+            1.
+        }
+
+        fn vanish(&mut self) {
+            self.pool.borrow_mut()[self.slot].alive = false;
+        }
+
+        fn change_direction(&mut self, degrees: f32) {
+            self.pool.borrow_mut()[self.slot].direction = degrees;
+        }
+
+        fn change_speed(&mut self, speed: f32) {
+            self.pool.borrow_mut()[self.slot].speed = speed;
+        }
+
+        fn accel_x(&mut self, _: f32) {}
+
+        fn accel_y(&mut self, _: f32) {}
+    }
+
+    #[test]
+    fn test_pooled() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <fire>
+                    <direction type="absolute">90</direction>
+                    <bullet/>
+                </fire>
+                <fire>
+                    <direction type="absolute">180</direction>
+                    <bullet>
+                        <action>
+                            <vanish/>
+                        </action>
+                    </bullet>
+                </fire>
+            </action>
+        </bulletml>"#;
+        let bulletml = serde_xml_rs::from_str(doc).unwrap();
+        let pool = Rc::new(RefCell::new(vec![Body {
+            alive: true,
+            ..Default::default()
+        }]));
+        let turret = PoolManager {
+            pool: Rc::clone(&pool),
+            slot: 0,
+        };
+        let mut runner = Runner::new(Pooled::new(turret), bulletml).unwrap();
+
+        runner.update().unwrap();
+
+        let handles = runner
+            .manager()
+            .spawned()
+            .iter()
+            .map(|spawned| (spawned.handle, spawned.script.is_some()))
+            .collect::<Vec<_>>();
+        assert_eq!(handles, [(1, false), (2, true)]);
+
+        // Each scripted bullet runs with a manager for its own slot.
+        let mut runners = runner.manager_mut().take_runners(|slot| {
+            Pooled::new(PoolManager {
+                pool: Rc::clone(&pool),
+                slot,
+            })
+        });
+        assert!(runner.manager().spawned().is_empty());
+        assert_eq!(runners.len(), 1);
+        assert_eq!(runners[0].manager().inner().slot, 2);
+
+        runners[0].update().unwrap();
+        assert_eq!(runners[0].status(), RunnerStatus::Vanished);
+        assert_eq!(
+            *pool.borrow(),
+            [
+                Body {
+                    direction: 0.,
+                    speed: 0.,
+                    alive: true,
+                },
+                Body {
+                    direction: 90.,
+                    speed: 1.,
+                    alive: true,
+                },
+                Body {
+                    direction: 180.,
+                    speed: 1.,
+                    alive: false,
+                },
+            ],
+        );
+    }
+
+    const BURST: &str = r#"<bulletml>
+        <action label="top">
+            <repeat>
+                <times>2</times>
+                <action>
+                    <repeat>
+                        <times>3</times>
+                        <action>
+                            <fire>
+                                <bullet>
+                                    <action>
+                                        <vanish/>
+                                    </action>
+                                </bullet>
+                            </fire>
+                        </action>
+                    </repeat>
+                    <wait>1</wait>
+                </action>
+            </repeat>
+        </action>
+    </bulletml>"#;
+
+    fn emitter() -> Runner<TestManager> {
+        let bulletml: data::BulletML = serde_xml_rs::from_str(BURST).unwrap();
+        Runner::new(TestManager::default(), bulletml).unwrap()
+    }
+
+    #[test]
+    fn test_runner_pool() {
+        let mut emitter = emitter();
+        let mut pool = RunnerPool::new();
+
+        let mut capacities = Vec::new();
+        for turn in 0..2 {
+            emitter.manager_mut().turn = turn;
+            emitter.update().unwrap();
+            for script in emitter.manager_mut().scripts.drain(..) {
+                pool.spawn(TestManager::default(), script);
+            }
+            assert_eq!(pool.len(), 3);
+            capacities.push(pool.stats().capacity);
+
+            pool.update().unwrap();
+            assert!(pool
+                .iter()
+                .all(|(_, runner)| runner.manager().log == ["vanish"]));
+            pool.release_done();
+            assert!(pool.is_empty());
+        }
+
+        // The second burst reuses the slots of the first.
+        let stats = pool.stats();
+        assert_eq!(stats.live, 0);
+        assert_eq!(stats.peak, 3);
+        assert_eq!(stats.spawned, 6);
+        assert_eq!(stats.reused, 3);
+        assert!(stats.capacity >= 3);
+        assert_eq!(capacities[0], capacities[1]);
+    }
+
+    #[test]
+    fn test_runner_pool_slots() {
+        let mut pool = (0..3).map(|_| emitter()).collect::<RunnerPool<_>>();
+        assert_eq!(pool.len(), 3);
+
+        pool.get_mut(1).unwrap().manager_mut().turn = 10;
+        let removed = pool.remove(1).unwrap();
+        assert_eq!(removed.manager().turn, 10);
+        assert!(pool.get(1).is_none());
+        assert!(pool.remove(1).is_none());
+        assert!(pool.remove(5).is_none());
+        assert_eq!(
+            pool.iter().map(|(index, _)| index).collect::<Vec<_>>(),
+            [0, 2],
+        );
+
+        // Released slots are used before the storage grows.
+        assert_eq!(pool.insert(emitter()), 1);
+        assert_eq!(pool.insert(emitter()), 3);
+        pool.retain(|runner| runner.manager().turn != 0);
+        assert!(pool.is_empty());
+        assert_eq!(pool.insert(emitter()), 3);
+
+        let stats = pool.stats();
+        assert_eq!(stats.peak, 4);
+        assert_eq!(stats.spawned, 6);
+        assert_eq!(stats.reused, 2);
+    }
+}
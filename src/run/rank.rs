@@ -0,0 +1,288 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use crate::data::{ExpressionContext, Value};
+use crate::run::{BulletManager, BulletScript};
+
+/// A mapping from the rank of a game to the rank used by scripts.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RankCurve {
+    /// The rank is used as is.
+    Identity,
+    /// The rank is scaled and offset.
+    Linear {
+        /// The factor to multiply the rank by.
+        scale: Value,
+        /// The amount to add to the scaled rank.
+        offset: Value,
+    },
+    /// The rank is replaced by a fixed value for each range of ranks.
+    ///
+    /// Each step is a threshold and the rank to use once the rank reaches it. The rank of the
+    /// last step whose threshold has been reached is used; ranks below every threshold use `0`.
+    Stepped(Vec<(Value, Value)>),
+    /// A separate curve for each stage of the game.
+    ///
+    /// The curve is chosen by the stage of the `RankContext`. Stages past the end use the last
+    /// curve and the rank is used as is if there are no curves.
+    PerStage(Vec<RankCurve>),
+}
+
+impl Default for RankCurve {
+    fn default() -> Self {
+        RankCurve::Identity
+    }
+}
+
+impl RankCurve {
+    /// Map a rank for a stage of the game.
+    ///
+    /// The result is clamped to the range `[0, 1]` expected by scripts.
+    pub fn apply(&self, rank: Value, stage: usize) -> Value {
+        self.map(rank, stage).max(0.).min(1.)
+    }
+
+    fn map(&self, rank: Value, stage: usize) -> Value {
+        match *self {
+            RankCurve::Identity => rank,
+            RankCurve::Linear {
+                scale,
+                offset,
+            } => rank * scale + offset,
+            RankCurve::Stepped(ref steps) => {
+                steps
+                    .iter()
+                    .filter(|&&(threshold, _)| threshold <= rank)
+                    .last()
+                    .map_or(0., |&(_, value)| value)
+            },
+            RankCurve::PerStage(ref curves) => {
+                curves
+                    .get(stage)
+                    .or_else(|| curves.last())
+                    .map_or(rank, |curve| curve.map(rank, stage))
+            },
+        }
+    }
+}
+
+/// A context which adjusts the rank of another context.
+///
+/// All other queries are forwarded to the wrapped context. When the wrapped context is a
+/// `BulletManager`, so is this context, so it may be given to a `Runner` directly. This allows
+/// games to tune difficulty without changing their managers.
+#[derive(Debug)]
+pub struct RankContext<T> {
+    inner: T,
+    curve: RankCurve,
+    stage: usize,
+}
+
+impl<T> RankContext<T> {
+    /// Adjust the rank of a context with a curve.
+    pub fn new(inner: T, curve: RankCurve) -> Self {
+        RankContext {
+            inner,
+            curve,
+            stage: 0,
+        }
+    }
+
+    /// The curve applied to the rank.
+    pub fn curve(&self) -> &RankCurve {
+        &self.curve
+    }
+
+    /// Change the curve applied to the rank.
+    pub fn set_curve(&mut self, curve: RankCurve) {
+        self.curve = curve;
+    }
+
+    /// The current stage of the game.
+    pub fn stage(&self) -> usize {
+        self.stage
+    }
+
+    /// Change the current stage of the game.
+    ///
+    /// This selects the curve used by `RankCurve::PerStage`.
+    pub fn set_stage(&mut self, stage: usize) {
+        self.stage = stage;
+    }
+
+    /// The wrapped context.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// The wrapped context.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Unwrap the context.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T> ExpressionContext for RankContext<T>
+where
+    T: ExpressionContext,
+{
+    fn get(&self, name: &str) -> Option<Value> {
+        self.inner.get(name)
+    }
+
+    fn get_index(&self, idx: usize, name: &str) -> Option<Value> {
+        self.inner.get_index(idx, name)
+    }
+
+    fn get_param(&self, idx: usize) -> Option<Value> {
+        self.inner.get_param(idx)
+    }
+
+    fn rand(&self) -> Value {
+        self.inner.rand()
+    }
+
+    fn rank(&self) -> Value {
+        self.curve.apply(self.inner.rank(), self.stage)
+    }
+}
+
+impl<T> BulletManager for RankContext<T>
+where
+    T: BulletManager,
+{
+    fn new_simple(&mut self, direction: f32, speed: f32) {
+        self.inner.new_simple(direction, speed)
+    }
+
+    fn new_bullet(&mut self, direction: f32, speed: f32) {
+        self.inner.new_bullet(direction, speed)
+    }
+
+    fn new_bullet_with_script(&mut self, direction: f32, speed: f32, script: BulletScript) {
+        self.inner.new_bullet_with_script(direction, speed, script)
+    }
+
+    fn turn(&self) -> u32 {
+        self.inner.turn()
+    }
+
+    fn direction(&self) -> f32 {
+        self.inner.direction()
+    }
+
+    fn aim_direction(&self) -> f32 {
+        self.inner.aim_direction()
+    }
+
+    fn try_aim_direction(&self) -> Option<f32> {
+        self.inner.try_aim_direction()
+    }
+
+    fn target_velocity(&self) -> Option<(f32, f32)> {
+        self.inner.target_velocity()
+    }
+
+    fn speed(&self) -> f32 {
+        self.inner.speed()
+    }
+
+    fn speed_x(&self) -> f32 {
+        self.inner.speed_x()
+    }
+
+    fn speed_y(&self) -> f32 {
+        self.inner.speed_y()
+    }
+
+    fn default_speed(&self) -> f32 {
+        self.inner.default_speed()
+    }
+
+    fn owner_velocity(&self) -> (f32, f32) {
+        self.inner.owner_velocity()
+    }
+
+    fn vanish(&mut self) {
+        self.inner.vanish()
+    }
+
+    fn change_direction(&mut self, degrees: f32) {
+        self.inner.change_direction(degrees)
+    }
+
+    fn change_speed(&mut self, speed: f32) {
+        self.inner.change_speed(speed)
+    }
+
+    fn accel_x(&mut self, amount: f32) {
+        self.inner.accel_x(amount)
+    }
+
+    fn accel_y(&mut self, amount: f32) {
+        self.inner.accel_y(amount)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::data;
+    use crate::run::testing::TestManager;
+    use crate::run::{RankContext, RankCurve, Runner};
+
+    #[test]
+    fn test_rank_curves() {
+        assert_eq!(RankCurve::Identity.apply(0.25, 0), 0.25);
+        assert_eq!(RankCurve::Identity.apply(2., 0), 1.);
+
+        let linear = RankCurve::Linear {
+            scale: 0.5,
+            offset: 0.25,
+        };
+        assert_eq!(linear.apply(0., 0), 0.25);
+        assert_eq!(linear.apply(1., 0), 0.75);
+
+        let stepped = RankCurve::Stepped(vec![(0.25, 0.5), (0.75, 1.)]);
+        assert_eq!(stepped.apply(0., 0), 0.);
+        assert_eq!(stepped.apply(0.25, 0), 0.5);
+        assert_eq!(stepped.apply(0.5, 0), 0.5);
+        assert_eq!(stepped.apply(0.75, 0), 1.);
+
+        let stages = RankCurve::PerStage(vec![RankCurve::Identity, linear]);
+        assert_eq!(stages.apply(0.5, 0), 0.5);
+        assert_eq!(stages.apply(0.5, 1), 0.5);
+        assert_eq!(stages.apply(1., 1), 0.75);
+        assert_eq!(stages.apply(1., 5), 0.75);
+        assert_eq!(RankCurve::PerStage(Vec::new()).apply(0.5, 1), 0.5);
+    }
+
+    #[test]
+    fn test_rank_context() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <fire>
+                    <direction type="absolute">$rank * 100</direction>
+                    <bullet/>
+                </fire>
+            </action>
+        </bulletml>"#;
+        let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+
+        let mut manager = TestManager::default();
+        manager.rank = 0.5;
+        let mut context = RankContext::new(manager, RankCurve::Identity);
+        context.set_curve(RankCurve::PerStage(vec![
+            RankCurve::Identity,
+            RankCurve::Stepped(vec![(0., 0.25)]),
+        ]));
+        context.set_stage(1);
+
+        let mut runner = Runner::new(context, bulletml).unwrap();
+        runner.update().unwrap();
+        assert_eq!(runner.manager().inner().log, ["new_simple(25, 1)"]);
+    }
+}
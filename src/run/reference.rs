@@ -0,0 +1,917 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+//! A reference evaluator for the runner.
+//!
+//! The reference executes scripts directly from the compiled entities. Its position is a stack
+//! of indices into actions and repeats rather than a tree of nodes, and nothing is expanded or
+//! evaluated ahead of time: every step is looked up and evaluated as it executes. It is slow, but
+//! simple enough to be obviously correct, so runners may check every update against it (see
+//! `Runner::check_against_reference`).
+//!
+//! The reference shares the pure functions of the `semantics` module with the runner; it checks
+//! how the runner schedules steps, binds parameters, and keeps state between frames.
+
+use std::cell::RefCell;
+use std::collections::hash_map::HashMap;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::data;
+pub(crate) use crate::run::command::Command;
+use crate::run::compile::{
+    Accel, Acceleration, Action, ChangeDirection, ChangeSpeed, Easing, Expression, Fire,
+    Orientation, Repeat, Step, Value, Wait,
+};
+use crate::run::interp::{self, Function};
+use crate::run::rng::RandomSource;
+use crate::run::scope::Scope;
+use crate::run::semantics::{self, Snapshot};
+use crate::run::{
+    BulletManager, NegativeSpeed, RepeatEvaluation, RunnerOptions, UnknownVariablePolicy,
+};
+
+/// The top-level actions of a runner with the parameters they are bound to, if any.
+pub(crate) type Program = Vec<(Arc<Action>, Option<Vec<Value>>)>;
+
+/// Commands for a panic message.
+struct Commands<'a>(&'a [Command]);
+
+impl fmt::Display for Commands<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "    (none)");
+        }
+
+        for (idx, command) in self.0.iter().enumerate() {
+            if idx > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "    {:?}", command)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Check that the commands given by the runner match those of the reference.
+pub(crate) fn check(
+    turn: u32,
+    actual: &[Command],
+    expected: &[Command],
+    actual_res: &Result<(), &data::ExpressionError>,
+    expected_res: &Result<(), data::ExpressionError>,
+) {
+    assert!(
+        actual == expected && actual_res.is_ok() == expected_res.is_ok(),
+        "the runner diverged from the reference evaluator on turn {}\n  \
+         runner ({}):\n{}\n  reference ({}):\n{}",
+        turn,
+        if actual_res.is_ok() { "ok" } else { "error" },
+        Commands(actual),
+        if expected_res.is_ok() { "ok" } else { "error" },
+        Commands(expected),
+    );
+}
+
+/// How an action receives its parameters.
+enum Given<'a> {
+    /// The action uses the parameters of the enclosing action.
+    Inherited,
+    /// The action is given expressions to evaluate in the enclosing action.
+    Unbound(&'a [Expression]),
+    /// The action is given values.
+    Bound(&'a [Value]),
+}
+
+impl<'a> Given<'a> {
+    fn from_ref(params: &'a Option<Arc<[Expression]>>) -> Self {
+        params
+            .as_ref()
+            .map_or(Given::Inherited, |params| Given::Unbound(params))
+    }
+}
+
+/// A position within the script.
+enum Cursor {
+    /// Within the top-level actions.
+    Root { next: usize },
+    /// Within the steps of an action.
+    Action {
+        action: Arc<Action>,
+        params: Vec<Value>,
+        next: usize,
+    },
+    /// Within a repeat.
+    Repeat {
+        repeat: Repeat,
+        params: Vec<Value>,
+        iteration: usize,
+        /// The number of iterations, if fixed when the repeat starts.
+        count: Option<usize>,
+        /// The next action within the current iteration, if the iteration has started.
+        next: Option<usize>,
+    },
+}
+
+/// The state of the reference evaluator.
+pub(crate) struct Reference<T> {
+    clone: fn(&T) -> T,
+    program: Program,
+    stack: Vec<Cursor>,
+
+    last_aim: Option<f32>,
+    prev_dir: Option<f32>,
+    prev_speed: Option<f32>,
+    change_dir: Option<Function>,
+    change_speed: Option<Function>,
+    speed_reflected: bool,
+    accel_x: Option<Function>,
+    accel_y: Option<Function>,
+
+    next: Option<u32>,
+    wait_remainder: f32,
+
+    pending_ttl: Option<u32>,
+    deadline: Option<u32>,
+    expired: bool,
+    vanished: bool,
+}
+
+/// The environment of an update.
+pub(crate) struct Env<'a> {
+    pub options: &'a RunnerOptions,
+    pub orientation: Orientation,
+    pub vars: &'a HashMap<String, Value>,
+    pub random: RandomSource,
+}
+
+/// A single update of the reference evaluator.
+struct Update<'a, T> {
+    manager: T,
+    env: Env<'a>,
+    unknown: RefCell<BTreeSet<String>>,
+    commands: Vec<Command>,
+}
+
+impl<'a, T> Update<'a, T>
+where
+    T: BulletManager,
+{
+    fn context<'b>(&'b self, params: &'b [Value]) -> Scope<'b> {
+        let scope = Scope::new(params, self.env.vars, &self.manager).with_random(&self.env.random);
+        match self.env.options.unknown_variable {
+            UnknownVariablePolicy::Error => scope,
+            UnknownVariablePolicy::Zero => scope.with_unknown(&self.unknown),
+        }
+    }
+
+    fn eval(&self, expr: &Expression, params: &[Value]) -> Result<Value, data::ExpressionError> {
+        expr.eval(&self.context(params))
+    }
+
+    fn eval_all(
+        &self,
+        exprs: &[Expression],
+        params: &[Value],
+    ) -> Result<Vec<Value>, data::ExpressionError> {
+        exprs.iter().map(|expr| self.eval(expr, params)).collect()
+    }
+
+    fn command(&mut self, command: Command) {
+        self.commands.push(command);
+        // Scripts are not created by the reference; the manager is a copy which is discarded.
+        command.apply(&mut self.manager);
+    }
+}
+
+impl<T> Reference<T>
+where
+    T: BulletManager,
+{
+    pub(crate) fn new(
+        clone: fn(&T) -> T,
+        program: Program,
+        options: &RunnerOptions,
+        deadline: Option<u32>,
+    ) -> Self {
+        Reference {
+            clone,
+            program,
+            stack: vec![Cursor::Root {
+                next: 0,
+            }],
+
+            last_aim: None,
+            prev_dir: None,
+            prev_speed: None,
+            change_dir: None,
+            change_speed: None,
+            speed_reflected: false,
+            accel_x: None,
+            accel_y: None,
+
+            next: None,
+            wait_remainder: 0.,
+
+            pending_ttl: options.default_ttl,
+            deadline,
+            expired: false,
+            vanished: false,
+        }
+    }
+
+    /// Run an update against a copy of the manager.
+    ///
+    /// Returns the commands given to the manager and whether the update succeeded.
+    pub(crate) fn update(
+        &mut self,
+        manager: &T,
+        env: Env,
+    ) -> (Vec<Command>, Result<(), data::ExpressionError>) {
+        let mut update = Update {
+            manager: (self.clone)(manager),
+            env,
+            unknown: RefCell::new(BTreeSet::new()),
+            commands: Vec::new(),
+        };
+        let res = self.frame(&mut update);
+
+        (update.commands, res)
+    }
+
+    fn frame(&mut self, update: &mut Update<T>) -> Result<(), data::ExpressionError> {
+        if self.expired || self.vanished {
+            return Ok(());
+        }
+
+        let turn = update.manager.turn();
+        if let Some(ttl) = self.pending_ttl.take() {
+            self.ttl(turn, ttl);
+        }
+        if self.deadline.map_or(false, |deadline| deadline <= turn) {
+            self.expired = true;
+            update.command(Command::Vanish);
+            return Ok(());
+        }
+
+        self.changes(update, turn);
+
+        while let Some(cursor) = self.stack.last_mut() {
+            match *cursor {
+                Cursor::Root {
+                    ref mut next,
+                } => {
+                    let idx = *next;
+                    *next += 1;
+                    if let Some((action, params)) = self.program.get(idx).cloned() {
+                        let given = params
+                            .as_ref()
+                            .map_or(Given::Inherited, |params| Given::Bound(params));
+                        self.start_action(update, &action, given, &[])?;
+                    } else {
+                        self.stack.pop();
+                    }
+                },
+                Cursor::Action {
+                    ref action,
+                    ref params,
+                    ref mut next,
+                } => {
+                    let step = match action.steps.get(*next).cloned() {
+                        Some(step) => step,
+                        None => {
+                            self.stack.pop();
+                            continue;
+                        },
+                    };
+                    let params = params.clone();
+
+                    // Waits and vanishes end the frame without moving to the next step.
+                    if !self.step(update, &step, &params)? {
+                        return Ok(());
+                    }
+                    if let Some(Cursor::Action {
+                        ref mut next, ..
+                    }) = self.stack.last_mut()
+                    {
+                        *next += 1;
+                    }
+                    // Steps which start actions or repeats push them after the step is done.
+                    self.push_started(step, params, update)?;
+                },
+                Cursor::Repeat {
+                    ref repeat,
+                    ref params,
+                    ref mut iteration,
+                    count,
+                    ref mut next,
+                } => {
+                    if let Some(idx) = *next {
+                        if let Some((action, action_params)) = repeat.actions.get(idx).cloned() {
+                            *next = Some(idx + 1);
+                            let params = params.clone();
+                            let given = Given::from_ref(&action_params);
+                            self.start_action(update, &action, given, &params)?;
+                        } else {
+                            *iteration += 1;
+                            *next = None;
+                        }
+                        continue;
+                    }
+
+                    let count = if let Some(count) = count {
+                        count
+                    } else {
+                        let times = update.eval(&repeat.times.value, params)?;
+                        semantics::repeat_count(times)
+                    };
+                    if *iteration < count {
+                        *next = Some(0);
+                    } else {
+                        self.stack.pop();
+                    }
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Start an action.
+    fn start_action(
+        &mut self,
+        update: &mut Update<T>,
+        action: &Arc<Action>,
+        given: Given,
+        enclosing: &[Value],
+    ) -> Result<(), data::ExpressionError> {
+        if !update.env.options.runs_tags(&action.tags) {
+            return Ok(());
+        }
+
+        let params = match given {
+            Given::Inherited => enclosing.into(),
+            Given::Unbound(exprs) => update.eval_all(exprs, enclosing)?,
+            Given::Bound(values) => values.into(),
+        };
+        if let Some(ttl) = action.ttl {
+            let turn = update.manager.turn();
+            self.ttl(turn, ttl);
+        }
+
+        self.stack.push(Cursor::Action {
+            action: Arc::clone(action),
+            params,
+            next: 0,
+        });
+
+        Ok(())
+    }
+
+    /// Start the action or repeat of a step, if any.
+    fn push_started(
+        &mut self,
+        step: Step,
+        params: Vec<Value>,
+        update: &mut Update<T>,
+    ) -> Result<(), data::ExpressionError> {
+        match step {
+            Step::Action(action, action_params) => {
+                let given = Given::from_ref(&action_params);
+                self.start_action(update, &action, given, &params)
+            },
+            Step::Repeat(repeat) => {
+                let count = if let RepeatEvaluation::Once = update.env.options.repeat_evaluation {
+                    let times = update.eval(&repeat.times.value, &params)?;
+                    Some(semantics::repeat_count(times))
+                } else {
+                    None
+                };
+                self.stack.push(Cursor::Repeat {
+                    repeat,
+                    params,
+                    iteration: 0,
+                    count,
+                    next: None,
+                });
+                Ok(())
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Execute a step.
+    ///
+    /// Returns whether execution continues within the frame.
+    fn step(
+        &mut self,
+        update: &mut Update<T>,
+        step: &Step,
+        params: &[Value],
+    ) -> Result<bool, data::ExpressionError> {
+        match *step {
+            Step::Fire(ref fire, ref fire_params) => {
+                self.fire(update, fire, fire_params.as_deref(), params)?
+            },
+            Step::ChangeSpeed(ref cs) => self.change_speed(update, cs, params)?,
+            Step::ChangeDirection(ref cd) => self.change_direction(update, cd, params)?,
+            Step::Accel(ref accel) => self.accel(update, accel, params)?,
+            Step::Wait(ref wait) => return self.wait(update, wait, params),
+            Step::Vanish(_) => {
+                update.command(Command::Vanish);
+                // Nothing else happens to a vanished bullet.
+                self.vanished = true;
+                self.change_dir = None;
+                self.change_speed = None;
+                self.accel_x = None;
+                self.accel_y = None;
+                return Ok(false);
+            },
+            // Actions and repeats start once the step is done. Custom step executors are not run.
+            Step::Action(..) | Step::Repeat(_) | Step::Custom(_) => (),
+        }
+
+        Ok(true)
+    }
+
+    fn ttl(&mut self, turn: u32, ttl: u32) {
+        let deadline = turn.saturating_add(ttl);
+        self.deadline = Some(self.deadline.map_or(deadline, |current| current.min(deadline)));
+    }
+
+    fn changes(&mut self, update: &mut Update<T>, turn: u32) {
+        let options = update.env.options;
+
+        if let Some(func) = self.change_dir {
+            let (cont, v) = func.update(turn);
+            update.command(Command::ChangeDirection(options.quantize_direction(v)));
+            if !cont {
+                self.change_dir = None;
+            }
+        }
+        if let Some(func) = self.change_speed {
+            let (cont, v) = func.update(turn);
+            let v = match options.negative_speed {
+                NegativeSpeed::Allow => v,
+                NegativeSpeed::ClampZero => v.max(0.),
+                NegativeSpeed::ReflectDirection => {
+                    if (v < 0.) != self.speed_reflected {
+                        self.speed_reflected = v < 0.;
+                        let dir = semantics::reflect(update.manager.direction());
+                        update.command(Command::ChangeDirection(options.quantize_direction(dir)));
+                    }
+                    v.abs()
+                },
+            };
+            update.command(Command::ChangeSpeed(options.quantize_speed(v)));
+            if !cont {
+                self.change_speed = None;
+            }
+        }
+        if let Some(func) = self.accel_x {
+            let (cont, v) = func.update(turn);
+            update.command(Command::AccelX(options.quantize_speed(v)));
+            if !cont {
+                self.accel_x = None;
+            }
+        }
+        if let Some(func) = self.accel_y {
+            let (cont, v) = func.update(turn);
+            update.command(Command::AccelY(options.quantize_speed(v)));
+            if !cont {
+                self.accel_y = None;
+            }
+        }
+    }
+
+    fn snapshot(&mut self, update: &Update<T>) -> Snapshot {
+        let target = update.manager.try_aim_direction();
+        if target.is_some() {
+            self.last_aim = target;
+        }
+
+        let mut snapshot = Snapshot::new(&update.manager);
+        snapshot.aim_direction = semantics::aim_direction(
+            target,
+            self.last_aim,
+            update.env.options.no_target,
+            update.env.orientation,
+            snapshot.aim_direction,
+        );
+        snapshot
+    }
+
+    fn fire(
+        &mut self,
+        update: &mut Update<T>,
+        fire: &Fire,
+        fire_params: Option<&[Expression]>,
+        params: &[Value],
+    ) -> Result<(), data::ExpressionError> {
+        let mut params = params.to_vec();
+        if let Some(exprs) = fire_params {
+            params = update.eval_all(exprs, &params)?;
+        }
+
+        let snapshot = self.snapshot(update);
+        let orientation = update.env.orientation;
+        let mut fire_dir = None;
+        if let Some(direction) = fire.direction.as_ref() {
+            let degrees = update.eval(&direction.degrees, &params)?;
+            fire_dir = Some(semantics::fire_direction(
+                &snapshot,
+                orientation,
+                self.prev_dir,
+                direction.kind,
+                degrees,
+            ));
+        }
+        let mut fire_speed = None;
+        if let Some(speed) = fire.speed.as_ref() {
+            let change = update.eval(&speed.change, &params)?;
+            fire_speed = Some(semantics::fire_speed(
+                &snapshot,
+                self.prev_speed,
+                speed.kind,
+                change,
+            ));
+        }
+
+        let bullet = &fire.bullet;
+        if let Some(exprs) = fire.bullet_params.as_ref() {
+            params = update.eval_all(exprs, &params)?;
+        }
+
+        let mut bullet_dir = None;
+        if let Some(direction) = bullet.direction.as_ref() {
+            let degrees = update.eval(&direction.degrees, &params)?;
+            bullet_dir = Some(semantics::fire_direction(
+                &snapshot,
+                orientation,
+                self.prev_dir,
+                direction.kind,
+                degrees,
+            ));
+        }
+        let mut bullet_speed = None;
+        if let Some(speed) = bullet.speed.as_ref() {
+            let change = update.eval(&speed.change, &params)?;
+            bullet_speed = Some(semantics::fire_speed(
+                &snapshot,
+                self.prev_speed,
+                speed.kind,
+                change,
+            ));
+        }
+
+        let (dir, speed) = semantics::fire(
+            &snapshot,
+            bullet_dir.or(fire_dir),
+            bullet_speed.or(fire_speed),
+        );
+        let dir = match bullet.direction.as_ref().or_else(|| fire.direction.as_ref()) {
+            Some(direction) => semantics::lead_direction(&snapshot, direction, dir, speed),
+            None => dir,
+        };
+        self.prev_dir = Some(dir);
+        self.prev_speed = Some(speed);
+
+        let options = update.env.options;
+        let (dir, speed) = semantics::negative_speed(options.negative_speed, dir, speed);
+        let (dir, speed) = if options.inherit_velocity {
+            semantics::inherit_velocity(dir, speed, update.manager.owner_velocity())
+        } else {
+            (dir, speed)
+        };
+        let dir = options.quantize_direction(dir);
+        let speed = options.quantize_speed(speed);
+
+        if bullet.actions.is_empty() {
+            update.command(Command::NewSimple(dir, speed));
+        } else {
+            // The parameters of the actions of the bullet are evaluated as it is fired. The
+            // generator for its runner is split from this one without taking values from it.
+            for (_, action_params) in &bullet.actions {
+                if let Some(exprs) = action_params {
+                    update.eval_all(exprs, &params)?;
+                }
+            }
+            update.command(Command::NewBullet(dir, speed));
+        }
+
+        Ok(())
+    }
+
+    fn change_speed(
+        &mut self,
+        update: &mut Update<T>,
+        cs: &ChangeSpeed,
+        params: &[Value],
+    ) -> Result<(), data::ExpressionError> {
+        let duration = update.eval(&cs.value.value, params)?.max(0.);
+        let change = update.eval(&cs.speed.change, params)?;
+        let snapshot = self.snapshot(update);
+
+        let easing = cs.easing.unwrap_or(update.env.options.easing);
+
+        self.speed_reflected = false;
+        self.change_speed = Some(
+            semantics::change_speed(&snapshot, cs.speed.kind, change, duration).with_easing(easing),
+        );
+
+        Ok(())
+    }
+
+    fn change_direction(
+        &mut self,
+        update: &mut Update<T>,
+        cd: &ChangeDirection,
+        params: &[Value],
+    ) -> Result<(), data::ExpressionError> {
+        let duration = update.eval(&cd.value.value, params)?.max(0.);
+        let degrees = update.eval(&cd.direction.degrees, params)?;
+        let snapshot = self.snapshot(update);
+        let degrees = semantics::lead_direction(&snapshot, &cd.direction, degrees, snapshot.speed);
+
+        let easing = cd.easing.unwrap_or(update.env.options.easing);
+
+        self.change_dir = Some(
+            semantics::change_direction(
+                &snapshot,
+                update.env.orientation,
+                cd.direction.kind,
+                degrees,
+                duration,
+            )
+            .with_easing(easing),
+        );
+
+        Ok(())
+    }
+
+    fn accel(
+        &mut self,
+        update: &mut Update<T>,
+        accel: &Accel,
+        params: &[Value],
+    ) -> Result<(), data::ExpressionError> {
+        fn func<A>(
+            update: &Update<impl BulletManager>,
+            accel: Option<&A>,
+            params: &[Value],
+            start: f32,
+            turn: u32,
+            duration: f32,
+            easing: Easing,
+        ) -> Result<Option<Function>, data::ExpressionError>
+        where
+            A: Acceleration,
+        {
+            if let Some(accel) = accel {
+                let amount = accel.amount(&update.context(params))?;
+                let end = accel.modify(amount, start, duration);
+                Ok(Some(
+                    interp::interpolate(turn, duration, start, end).with_easing(easing),
+                ))
+            } else {
+                Ok(None)
+            }
+        }
+
+        let duration = update.eval(&accel.duration.value, params)?.max(0.);
+        let easing = accel.easing.unwrap_or(update.env.options.easing);
+        let snapshot = self.snapshot(update);
+        let turn = snapshot.turn;
+
+        // Horizontal games move along the `y` axis.
+        if let Orientation::Horizontal = update.env.orientation {
+            let x = accel.vertical.as_ref();
+            let y = accel.horizontal.as_ref();
+            self.accel_x = func(update, x, params, snapshot.speed_x, turn, duration, easing)?;
+            self.accel_y = func(update, y, params, snapshot.speed_y, turn, duration, easing)?;
+        } else {
+            let x = accel.horizontal.as_ref();
+            let y = accel.vertical.as_ref();
+            self.accel_x = func(update, x, params, snapshot.speed_x, turn, duration, easing)?;
+            self.accel_y = func(update, y, params, snapshot.speed_y, turn, duration, easing)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether the wait is over.
+    fn wait(
+        &mut self,
+        update: &mut Update<T>,
+        wait: &Wait,
+        params: &[Value],
+    ) -> Result<bool, data::ExpressionError> {
+        let turn = update.manager.turn();
+        let next = if let Some(next) = self.next {
+            next
+        } else {
+            let frames = update.eval(&wait.frames, params)?;
+            let (next, remainder) = semantics::wait(
+                turn,
+                frames,
+                self.wait_remainder,
+                update.env.options.accumulate_wait,
+            );
+            self.wait_remainder = remainder;
+            next
+        };
+
+        if turn < next {
+            self.next = Some(next);
+            Ok(false)
+        } else {
+            self.next = None;
+            Ok(true)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::data;
+    use crate::run::reference::{self, Command};
+    use crate::run::testing::TestManager;
+    use crate::run::{
+        CompiledBulletML, NegativeSpeed, RepeatEvaluation, Rng, Runner, RunnerOptions,
+        UnknownVariablePolicy,
+    };
+
+    fn check(doc: &str, options: RunnerOptions, frames: u32) -> Vec<String> {
+        let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+        let compiled = CompiledBulletML::new(bulletml).unwrap();
+        let mut runner = Runner::from_compiled(TestManager::default(), &compiled, options);
+        assert!(runner.check_against_reference());
+        runner.set_rng(Rng::new(1));
+
+        let mut children = Vec::new();
+        for turn in 0..frames {
+            runner.manager_mut().turn = turn;
+            runner.manager_mut().aim = (turn * 7) as f32;
+            runner.update().unwrap();
+
+            for script in runner.manager_mut().scripts.drain(..) {
+                let mut child = script.runner(TestManager::default());
+                assert!(child.check_against_reference());
+                children.push(child);
+            }
+            for child in &mut children {
+                child.manager_mut().turn = turn;
+                child.update().unwrap();
+            }
+        }
+
+        let log = runner.manager().log.clone();
+        children.into_iter().fold(log, |mut log, child| {
+            log.extend(child.manager().log.iter().cloned());
+            log
+        })
+    }
+
+    const DOC: &str = r#"<bulletml type="vertical">
+        <action label="top">
+            <repeat>
+                <times>2 + $rand * 2</times>
+                <actionRef label="volley">
+                    <param>$rand * 30</param>
+                </actionRef>
+            </repeat>
+            <changeDirection>
+                <direction type="sequence">$rank + 3</direction>
+                <term>4</term>
+            </changeDirection>
+            <accel>
+                <horizontal type="relative">1</horizontal>
+                <term>3</term>
+            </accel>
+            <wait>2.5</wait>
+            <fireRef label="seeker">
+                <param>2</param>
+            </fireRef>
+            <vanish/>
+        </action>
+        <action label="volley">
+            <fire>
+                <direction type="aim">$1</direction>
+                <speed type="sequence">0.5</speed>
+                <bullet/>
+            </fire>
+            <action ttl="10">
+                <changeSpeed>
+                    <speed>-$1 / 10</speed>
+                    <term>3</term>
+                </changeSpeed>
+                <wait>1.5</wait>
+            </action>
+        </action>
+        <fire label="seeker">
+            <direction type="relative">$1 * 10</direction>
+            <bulletRef label="homing">
+                <param>$1 + 1</param>
+            </bulletRef>
+        </fire>
+        <bullet label="homing">
+            <speed>$1</speed>
+            <action>
+                <repeat>
+                    <times>$1</times>
+                    <action>
+                        <fire>
+                            <direction type="sequence">$rand * 5</direction>
+                            <bullet/>
+                        </fire>
+                        <wait>1</wait>
+                    </action>
+                </repeat>
+            </action>
+        </bullet>
+    </bulletml>"#;
+
+    #[test]
+    fn test_reference_matches() {
+        let log = check(DOC, RunnerOptions::default(), 40);
+        assert!(log.iter().any(|cmd| cmd.starts_with("new_bullet")));
+        assert!(log.iter().any(|cmd| cmd.starts_with("accel_x")));
+    }
+
+    #[test]
+    fn test_reference_matches_options() {
+        let options = RunnerOptions {
+            accumulate_wait: true,
+            inherit_velocity: true,
+            default_ttl: Some(12),
+            direction_steps: Some(32),
+            speed_fraction_bits: Some(4),
+            negative_speed: NegativeSpeed::ReflectDirection,
+            repeat_evaluation: RepeatEvaluation::EachIteration,
+            unknown_variable: UnknownVariablePolicy::Zero,
+            tags: Some(vec!["hard".into()]),
+            ..Default::default()
+        };
+        let doc = r#"<bulletml type="horizontal">
+            <action label="top">
+                <action tags="easy">
+                    <fire><bullet/></fire>
+                </action>
+                <action tags="hard">
+                    <fire><speed>$missing - 1</speed><bullet/></fire>
+                </action>
+                <changeSpeed>
+                    <speed>-2</speed>
+                    <term>3</term>
+                </changeSpeed>
+                <accel>
+                    <vertical type="sequence">0.25</vertical>
+                    <term>4</term>
+                </accel>
+                <wait>1.5</wait>
+                <repeat>
+                    <times>3</times>
+                    <action>
+                        <fire><direction type="absolute">45</direction><bullet/></fire>
+                        <wait>0.75</wait>
+                    </action>
+                </repeat>
+            </action>
+        </bulletml>"#;
+
+        let log = check(doc, options, 16);
+        assert!(log.contains(&"vanish".into()));
+    }
+
+    #[test]
+    #[should_panic(expected = "diverged from the reference evaluator on turn 3")]
+    fn test_reference_divergence() {
+        let actual = [Command::NewSimple(0., 1.), Command::Vanish];
+        let expected = [Command::NewSimple(0., 1.)];
+
+        reference::check(3, &actual, &expected, &Ok(()), &Ok(()));
+    }
+
+    #[test]
+    fn test_reference_nan() {
+        let commands = [Command::ChangeSpeed(f32::NAN)];
+
+        reference::check(0, &commands, &commands, &Ok(()), &Ok(()));
+    }
+
+    #[test]
+    fn test_reference_started() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <wait>1</wait>
+            </action>
+        </bulletml>"#;
+        let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+        let mut runner = Runner::new(TestManager::default(), bulletml).unwrap();
+        runner.update().unwrap();
+
+        assert!(!runner.check_against_reference());
+    }
+}
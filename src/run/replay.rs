@@ -0,0 +1,287 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use std::cell::{Cell, RefCell};
+
+use serde::{Deserialize, Serialize};
+
+use crate::data::{ExpressionContext, Value};
+use crate::run::{BulletManager, BulletScript};
+
+/// The random values consumed by a run, in order.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Recording {
+    /// The values returned by `rand`.
+    pub values: Vec<Value>,
+}
+
+#[derive(Debug)]
+enum Mode {
+    Record(RefCell<Vec<Value>>),
+    Replay {
+        values: Vec<Value>,
+        next: Cell<usize>,
+    },
+}
+
+/// A context which records the random values it provides or replays a recording of them.
+///
+/// All other queries are forwarded to the wrapped context. When the wrapped context is a
+/// `BulletManager`, so is the recorder, so it may be given to a `Runner` directly.
+#[derive(Debug)]
+pub struct Recorder<T> {
+    inner: T,
+    mode: Mode,
+}
+
+impl<T> Recorder<T> {
+    /// Record the random values provided by a context.
+    pub fn record(inner: T) -> Self {
+        Recorder {
+            inner,
+            mode: Mode::Record(RefCell::new(Vec::new())),
+        }
+    }
+
+    /// Replay random values from a recording.
+    ///
+    /// Once the recording is exhausted, random values are provided by the wrapped context.
+    pub fn replay(inner: T, recording: Recording) -> Self {
+        Recorder {
+            inner,
+            mode: Mode::Replay {
+                values: recording.values,
+                next: Cell::new(0),
+            },
+        }
+    }
+
+    /// The recording.
+    ///
+    /// When replaying, this is the recording being replayed.
+    pub fn recording(&self) -> Recording {
+        let values = match self.mode {
+            Mode::Record(ref values) => values.borrow().clone(),
+            Mode::Replay {
+                ref values, ..
+            } => values.clone(),
+        };
+
+        Recording {
+            values,
+        }
+    }
+
+    /// Whether every value of a replayed recording has been consumed.
+    ///
+    /// Recorders which are recording are never exhausted.
+    pub fn is_exhausted(&self) -> bool {
+        match self.mode {
+            Mode::Record(_) => false,
+            Mode::Replay {
+                ref values,
+                ref next,
+            } => next.get() >= values.len(),
+        }
+    }
+
+    /// The wrapped context.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// The wrapped context.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Unwrap the context.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T> ExpressionContext for Recorder<T>
+where
+    T: ExpressionContext,
+{
+    fn get(&self, name: &str) -> Option<Value> {
+        self.inner.get(name)
+    }
+
+    fn get_index(&self, idx: usize, name: &str) -> Option<Value> {
+        self.inner.get_index(idx, name)
+    }
+
+    fn get_param(&self, idx: usize) -> Option<Value> {
+        self.inner.get_param(idx)
+    }
+
+    fn rand(&self) -> Value {
+        match self.mode {
+            Mode::Record(ref values) => {
+                let value = self.inner.rand();
+                values.borrow_mut().push(value);
+                value
+            },
+            Mode::Replay {
+                ref values,
+                ref next,
+            } => {
+                let idx = next.get();
+                next.set(idx + 1);
+                values
+                    .get(idx)
+                    .copied()
+                    .unwrap_or_else(|| self.inner.rand())
+            },
+        }
+    }
+
+    fn rank(&self) -> Value {
+        self.inner.rank()
+    }
+}
+
+impl<T> BulletManager for Recorder<T>
+where
+    T: BulletManager,
+{
+    fn new_simple(&mut self, direction: f32, speed: f32) {
+        self.inner.new_simple(direction, speed)
+    }
+
+    fn new_bullet(&mut self, direction: f32, speed: f32) {
+        self.inner.new_bullet(direction, speed)
+    }
+
+    fn new_bullet_with_script(&mut self, direction: f32, speed: f32, script: BulletScript) {
+        self.inner.new_bullet_with_script(direction, speed, script)
+    }
+
+    fn turn(&self) -> u32 {
+        self.inner.turn()
+    }
+
+    fn direction(&self) -> f32 {
+        self.inner.direction()
+    }
+
+    fn aim_direction(&self) -> f32 {
+        self.inner.aim_direction()
+    }
+
+    fn try_aim_direction(&self) -> Option<f32> {
+        self.inner.try_aim_direction()
+    }
+
+    fn target_velocity(&self) -> Option<(f32, f32)> {
+        self.inner.target_velocity()
+    }
+
+    fn speed(&self) -> f32 {
+        self.inner.speed()
+    }
+
+    fn speed_x(&self) -> f32 {
+        self.inner.speed_x()
+    }
+
+    fn speed_y(&self) -> f32 {
+        self.inner.speed_y()
+    }
+
+    fn default_speed(&self) -> f32 {
+        self.inner.default_speed()
+    }
+
+    fn owner_velocity(&self) -> (f32, f32) {
+        self.inner.owner_velocity()
+    }
+
+    fn vanish(&mut self) {
+        self.inner.vanish()
+    }
+
+    fn change_direction(&mut self, degrees: f32) {
+        self.inner.change_direction(degrees)
+    }
+
+    fn change_speed(&mut self, speed: f32) {
+        self.inner.change_speed(speed)
+    }
+
+    fn accel_x(&mut self, amount: f32) {
+        self.inner.accel_x(amount)
+    }
+
+    fn accel_y(&mut self, amount: f32) {
+        self.inner.accel_y(amount)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::data::{self, ExpressionContext, Value};
+    use crate::run::testing::TestManager;
+    use crate::run::{Recorder, Recording, Rng, Runner};
+
+    struct Seeded(Rng);
+
+    impl ExpressionContext for Seeded {
+        fn get(&self, _: &str) -> Option<Value> {
+            None
+        }
+
+        fn get_param(&self, _: usize) -> Option<Value> {
+            None
+        }
+
+        fn rand(&self) -> Value {
+            self.0.next_value()
+        }
+
+        fn rank(&self) -> Value {
+            0.
+        }
+    }
+
+    #[test]
+    fn test_record_replay() {
+        let recorder = Recorder::record(Seeded(Rng::new(1)));
+        let recorded = (0..8).map(|_| recorder.rand()).collect::<Vec<_>>();
+        let recording = recorder.recording();
+        assert_eq!(recording.values, recorded);
+
+        let json = serde_json::to_string(&recording).unwrap();
+        let recording: Recording = serde_json::from_str(&json).unwrap();
+
+        let replayer = Recorder::replay(Seeded(Rng::new(2)), recording);
+        let replayed = (0..8).map(|_| replayer.rand()).collect::<Vec<_>>();
+        assert_eq!(replayed, recorded);
+        assert!(replayer.is_exhausted());
+
+        // Values past the end of the recording come from the context.
+        let fallback = Rng::new(2);
+        assert_eq!(replayer.rand(), fallback.next_value());
+    }
+
+    #[test]
+    fn test_record_runner() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <fire>
+                    <direction type="absolute">$rand * 360</direction>
+                    <bullet/>
+                </fire>
+            </action>
+        </bulletml>"#;
+        let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+        let mut runner = Runner::new(Recorder::record(TestManager::default()), bulletml).unwrap();
+        runner.update().unwrap();
+
+        let manager = runner.manager();
+        assert_eq!(manager.recording().values, [0.5]);
+        assert_eq!(manager.inner().log, ["new_simple(180, 1)"]);
+    }
+}
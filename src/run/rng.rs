@@ -0,0 +1,265 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use std::cell::{Cell, RefCell};
+#[cfg(feature = "os-rng")]
+use std::collections::hash_map::RandomState;
+#[cfg(feature = "os-rng")]
+use std::hash::{BuildHasher, Hasher};
+
+use crate::data::{ExpressionContext, Value};
+use crate::run::BulletId;
+
+/// The `splitmix64` mixing function.
+fn mix(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// A small, deterministic, pseudo-random number generator.
+///
+/// This is an `xorshift64*` generator seeded through `splitmix64` so that nearby seeds produce
+/// unrelated sequences. The state uses interior mutability since expression contexts only provide
+/// shared access when producing random values.
+///
+/// The generator is only ever seeded by the caller, so the same seed always produces the same
+/// sequence on every platform. Seeding from the operating system is available with the `os-rng`
+/// feature.
+///
+/// Independent streams may be split from a generator with `split`. When many runners share one
+/// generator, the values each runner sees depend on the order in which all of them run. Giving
+/// each runner its own stream, e.g., split from the seed of the level by the index of the enemy
+/// or by `CompiledBulletML::content_hash` of its pattern, keeps them reproducible as the game
+/// changes.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    seed: u64,
+    state: Cell<u64>,
+}
+
+impl Rng {
+    /// Create a generator from a seed.
+    pub fn new(seed: u64) -> Self {
+        let z = mix(seed);
+
+        Rng {
+            seed,
+            // The generator is stuck at zero, so avoid it.
+            state: Cell::new(if z == 0 { 0x9e37_79b9_7f4a_7c15 } else { z }),
+        }
+    }
+
+    /// Create a generator for an independent stream.
+    ///
+    /// The stream depends only on the seed of this generator and the stream identifier; values
+    /// already taken from this generator do not affect it. Streams may be split further.
+    pub fn split(&self, stream: u64) -> Self {
+        Self::new(mix(self.seed ^ mix(stream)))
+    }
+
+    /// Create a generator seeded from randomness provided by the operating system.
+    ///
+    /// This uses the randomness `std` uses to seed its hash maps, so no additional dependencies
+    /// are required.
+    #[cfg(feature = "os-rng")]
+    pub fn from_os() -> Self {
+        Self::new(RandomState::new().build_hasher().finish())
+    }
+
+    /// The next random integer.
+    pub fn next_u64(&self) -> u64 {
+        let mut x = self.state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state.set(x);
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// The next random value within `[0, 1)`.
+    pub fn next_value(&self) -> Value {
+        // Use the top 24 bits so that every value is exactly representable.
+        (self.next_u64() >> 40) as Value / (1u64 << 24) as Value
+    }
+}
+
+/// The source of random values for a runner.
+///
+/// Values come from a replayed recording, then a generator, and finally the outer context. Every
+/// value provided may also be recorded.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RandomSource {
+    rng: Option<Rng>,
+    replay: Vec<Value>,
+    next: Cell<usize>,
+    recording: Option<RefCell<Vec<Value>>>,
+}
+
+impl RandomSource {
+    pub(crate) fn set_rng(&mut self, rng: Rng) {
+        self.rng = Some(rng);
+    }
+
+    pub(crate) fn replay(&mut self, values: Vec<Value>) {
+        self.replay = values;
+        self.next.set(0);
+    }
+
+    pub(crate) fn record(&mut self) {
+        self.recording = Some(RefCell::new(Vec::new()));
+    }
+
+    pub(crate) fn recording(&self) -> Option<Vec<Value>> {
+        self.recording
+            .as_ref()
+            .map(|recording| recording.borrow().clone())
+    }
+
+    /// A generator for the runner of a fired bullet.
+    ///
+    /// It is split from this generator by the identifier of the bullet so that children are
+    /// reproducible as well. Children do not take values from this generator, so the values seen
+    /// by this runner do not depend on how many bullets with actions it fires.
+    pub(crate) fn fork(&self, id: BulletId) -> Option<Rng> {
+        self.rng.as_ref().map(|rng| rng.split(id.get()))
+    }
+
+    pub(crate) fn rand(&self, outer: &dyn ExpressionContext) -> Value {
+        let next = self.next.get();
+        let value = if let Some(&value) = self.replay.get(next) {
+            self.next.set(next + 1);
+            value
+        } else if let Some(rng) = self.rng.as_ref() {
+            rng.next_value()
+        } else {
+            outer.rand()
+        };
+
+        if let Some(recording) = self.recording.as_ref() {
+            recording.borrow_mut().push(value);
+        }
+
+        value
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::data::{ExpressionContext, Value};
+    use crate::run::rng::{RandomSource, Rng};
+    use crate::run::BulletId;
+
+    struct Outer;
+
+    impl ExpressionContext for Outer {
+        fn get(&self, _: &str) -> Option<Value> {
+            None
+        }
+
+        fn get_param(&self, _: usize) -> Option<Value> {
+            None
+        }
+
+        fn rand(&self) -> Value {
+            0.5
+        }
+
+        fn rank(&self) -> Value {
+            0.
+        }
+    }
+
+    #[test]
+    fn test_rng_deterministic() {
+        let lhs = Rng::new(42);
+        let rhs = Rng::new(42);
+        let other = Rng::new(43);
+
+        let lhs = (0..16).map(|_| lhs.next_u64()).collect::<Vec<_>>();
+        let rhs = (0..16).map(|_| rhs.next_u64()).collect::<Vec<_>>();
+        let other = (0..16).map(|_| other.next_u64()).collect::<Vec<_>>();
+
+        assert_eq!(lhs, rhs);
+        assert_ne!(lhs, other);
+    }
+
+    #[test]
+    fn test_rng_split() {
+        let values = |rng: &Rng| (0..16).map(|_| rng.next_u64()).collect::<Vec<_>>();
+
+        let rng = Rng::new(42);
+        let stream = values(&rng.split(1));
+        assert_eq!(values(&Rng::new(42).split(1)), stream);
+
+        // Streams do not depend on the values taken from the generator.
+        values(&rng);
+        assert_eq!(values(&rng.split(1)), stream);
+
+        assert_ne!(values(&rng.split(2)), stream);
+        assert_ne!(values(&Rng::new(43).split(1)), stream);
+        assert_ne!(values(&Rng::new(42)), stream);
+
+        // Nested streams depend on the order of splitting.
+        assert_ne!(
+            values(&rng.split(1).split(2)),
+            values(&rng.split(2).split(1)),
+        );
+    }
+
+    #[test]
+    fn test_random_source_fork() {
+        let mut random = RandomSource::default();
+        assert!(random.fork(BulletId::new(0)).is_none());
+
+        random.set_rng(Rng::new(7));
+        let first = random.fork(BulletId::new(0)).unwrap().next_u64();
+        random.rand(&Outer);
+        assert_eq!(random.fork(BulletId::new(0)).unwrap().next_u64(), first);
+        assert_ne!(random.fork(BulletId::new(1)).unwrap().next_u64(), first);
+
+        // Forking does not take values from the generator.
+        let mut expected = RandomSource::default();
+        expected.set_rng(Rng::new(7));
+        expected.rand(&Outer);
+        assert_eq!(random.rand(&Outer), expected.rand(&Outer));
+    }
+
+    #[test]
+    fn test_rng_value_range() {
+        let rng = Rng::new(0);
+
+        for _ in 0..1000 {
+            let value = rng.next_value();
+            assert!((0. ..1.).contains(&value));
+        }
+    }
+
+    #[test]
+    fn test_random_source() {
+        let mut random = RandomSource::default();
+        random.record();
+        assert_eq!(random.rand(&Outer), 0.5);
+
+        random.set_rng(Rng::new(7));
+        let expected = Rng::new(7).next_value();
+        assert_eq!(random.rand(&Outer), expected);
+
+        random.replay(vec![0.25]);
+        assert_eq!(random.rand(&Outer), 0.25);
+        assert_ne!(random.rand(&Outer), 0.25);
+
+        let recording = random.recording().unwrap();
+        assert_eq!(recording.len(), 4);
+        assert_eq!(recording[..3], [0.5, expected, 0.25]);
+    }
+
+    #[cfg(feature = "os-rng")]
+    #[test]
+    fn test_rng_from_os() {
+        let rng = Rng::from_os();
+
+        assert!((0. ..1.).contains(&rng.next_value()));
+    }
+}
@@ -1,44 +1,38 @@
 // Distributed under the OSI-approved BSD 2-Clause License.
 // See accompanying LICENSE file for details.
 
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::hash_map::HashMap;
+use std::collections::BTreeSet;
+#[cfg(feature = "debug")]
+use std::collections::VecDeque;
+use std::fmt::{self, Write};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe, UnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use thiserror::Error;
+
 use crate::data;
+#[cfg(any(feature = "debug", feature = "reference-check"))]
+use crate::run::command::Command;
 use crate::run::compile::*;
+use crate::run::interp::{self, Function};
+#[cfg(feature = "reference-check")]
+use crate::run::reference::{self, Program, Reference};
+use crate::run::rng::RandomSource;
+use crate::run::scope::Scope;
+use crate::run::semantics::{self, Snapshot};
+use crate::run::sequence::{Sequence, SequenceHandle};
+use crate::run::spec::Rule;
 use crate::run::BulletManager;
-use crate::run::Node;
-
-#[derive(Debug, Clone, Copy)]
-struct Function {
-    min: u32,
-    max: u32,
-
-    start: f32,
-    end: f32,
-    step: f32,
-}
-
-impl Function {
-    fn new(min: u32, max: u32, start: f32, end: f32) -> Self {
-        Function {
-            min,
-            max,
-            start,
-            end,
-            step: (end - start) / ((max - min) as f32),
-        }
-    }
-
-    fn call(&self, x: u32) -> f32 {
-        self.start + self.step * ((x - self.min) as f32)
-    }
-
-    fn is_in_domain(&self, x: u32) -> bool {
-        self.min <= x && x < self.max
-    }
-
-    fn last(&self) -> f32 {
-        self.end
-    }
-}
+use crate::run::{Node, ZipperIter};
+#[cfg(feature = "debug")]
+use crate::run::History;
+use crate::run::{BulletId, Coverage, Event, Observer, Recording, Rng};
+use crate::run::{NegativeSpeed, RepeatEvaluation, RunnerOptions, UnknownVariablePolicy};
 
 enum Status {
     /// The action has completed.
@@ -47,31 +41,102 @@ enum Status {
     Continue,
     /// New actions should be performed.
     NewSteps(Vec<Node<NodeStep>>),
+    /// The step and everything within it should be skipped.
+    Skip,
+}
+
+/// The number of steps kept for diagnostics.
+#[cfg(feature = "debug")]
+const HISTORY_SIZE: usize = 10;
+
+/// The number of frames per second for runners stepped by elapsed time by default.
+const DEFAULT_FRAMES_PER_SECOND: f32 = 60.;
+
+/// Slack for rounding errors when converting elapsed time into frames.
+const FRAME_EPSILON: f64 = 1e-4;
+
+/// The clock of a runner stepped by elapsed time.
+#[derive(Debug, Clone, Copy)]
+struct Clock {
+    /// The turn of the next frame.
+    turn: u32,
+    /// The number of frames which have elapsed but not run.
+    pending: f64,
 }
 
+/// An executor for a custom step.
+type CustomExecutor<T> = Box<dyn FnMut(&mut T, &dyn CustomStep) + Send>;
+
 struct State<T> {
     manager: T,
     orientation: Orientation,
+    options: RunnerOptions,
+
+    custom_steps: HashMap<String, CustomExecutor<T>>,
+
+    params: Vec<Value>,
+    vars: HashMap<String, Value>,
+    unknown_vars: RefCell<BTreeSet<String>>,
+    random: RandomSource,
+
+    source: Option<BulletId>,
+    next_id: u64,
+    fire_index: usize,
+    /// Whether the step budget split the last frame, so the next update continues it.
+    frame_split: bool,
+    observer: Option<Box<dyn Observer + Send>>,
+
+    last_aim: Option<f32>,
 
     prev_dir: Option<f32>,
     change_dir: Option<Function>,
 
     prev_speed: Option<f32>,
     change_speed: Option<Function>,
+    speed_reflected: bool,
+
+    /// Sequence memory shared with other runners, if any.
+    shared_sequence: Option<SequenceHandle>,
 
     accel_x: Option<Function>,
     accel_y: Option<Function>,
 
     next: Option<u32>,
+    wait_remainder: f32,
+    clock: Option<Clock>,
+
+    pending_ttl: Option<u32>,
+    deadline: Option<u32>,
+    expired: bool,
+    vanished: bool,
+    poisoned: bool,
+
+    /// Set once the bullet vanishes; shared with the scripts of bullets it fires.
+    vanish_signal: Arc<AtomicBool>,
+    /// The signal of the runner which fired the bullet, if its vanishing is followed.
+    parent_vanished: Option<Arc<AtomicBool>>,
+
+    coverage: Option<Coverage>,
+
+    /// Commands given to the manager during a checked update.
+    #[cfg(feature = "reference-check")]
+    commands: Option<Vec<Command>>,
+
+    #[cfg(feature = "debug")]
+    history: VecDeque<(u32, String)>,
+    #[cfg(feature = "debug")]
+    shadowed: Vec<String>,
+    /// Commands given to the manager since recording started.
+    #[cfg(feature = "debug")]
+    command_history: Option<History>,
+    /// The manager before the commands in the history.
+    #[cfg(feature = "debug")]
+    history_base: Option<T>,
 }
 
 macro_rules! run_function {
     ( $opt_func:expr, $turn:expr, $cb:expr ) => {
-        if $opt_func.is_some() {
-            let (cont, v) = {
-                let func = $opt_func.as_ref().expect("checked above");
-                Self::update_function(func, $turn)
-            };
+        if let Some((cont, v)) = $opt_func.as_ref().map(|func| func.update($turn)) {
             $cb(v);
             if !cont {
                 $opt_func = None;
@@ -84,29 +149,87 @@ macro_rules! run_function {
 }
 
 impl<T> State<T> {
-    fn new(manager: T, orientation: Orientation) -> Self {
+    fn new(manager: T, orientation: Orientation, options: RunnerOptions) -> Self {
         Self {
             manager,
             orientation,
 
+            custom_steps: HashMap::new(),
+
+            params: Vec::new(),
+            vars: HashMap::new(),
+            unknown_vars: RefCell::new(BTreeSet::new()),
+            random: RandomSource::default(),
+
+            source: None,
+            next_id: 0,
+            fire_index: 0,
+            frame_split: false,
+            observer: None,
+
+            last_aim: None,
+
             prev_dir: None,
             change_dir: None,
 
             prev_speed: None,
             change_speed: None,
+            speed_reflected: false,
+
+            shared_sequence: None,
 
             accel_x: None,
             accel_y: None,
 
             next: None,
+            wait_remainder: 0.,
+            clock: None,
+
+            pending_ttl: options.default_ttl,
+            deadline: None,
+            expired: false,
+            vanished: false,
+            poisoned: false,
+
+            vanish_signal: Arc::new(AtomicBool::new(false)),
+            parent_vanished: None,
+
+            coverage: None,
+
+            #[cfg(feature = "reference-check")]
+            commands: None,
+
+            #[cfg(feature = "debug")]
+            history: VecDeque::with_capacity(HISTORY_SIZE),
+            #[cfg(feature = "debug")]
+            shadowed: Vec::new(),
+            #[cfg(feature = "debug")]
+            command_history: None,
+            #[cfg(feature = "debug")]
+            history_base: None,
+
+            options,
         }
     }
 
-    fn update_function(f: &Function, turn: u32) -> (bool, f32) {
-        if f.is_in_domain(turn) {
-            (true, f.call(turn))
-        } else {
-            (false, f.last())
+    fn allocate_id(&mut self) -> BulletId {
+        let id = BulletId::new(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    fn notify(&mut self, event: Event) {
+        if let Some(observer) = self.observer.as_mut() {
+            observer.notify(&event);
+        }
+    }
+
+    fn store_sequence(&self) {
+        if let Some(shared) = self.shared_sequence.as_ref() {
+            shared.set(Sequence {
+                direction: self.prev_dir,
+                speed: self.prev_speed,
+            });
         }
     }
 }
@@ -115,243 +238,521 @@ impl<T> State<T>
 where
     T: BulletManager,
 {
+    /// The current turn.
+    ///
+    /// Runners stepped by elapsed time count their own turns rather than using the manager's.
+    fn turn(&self) -> u32 {
+        self.clock
+            .map_or_else(|| self.manager.turn(), |clock| clock.turn)
+    }
+
+    fn context(&self) -> Scope<'_> {
+        let scope = Scope::new(&self.params, &self.vars, &self.manager).with_random(&self.random);
+        match self.options.unknown_variable {
+            UnknownVariablePolicy::Error => scope,
+            UnknownVariablePolicy::Zero => scope.with_unknown(&self.unknown_vars),
+        }
+    }
+
+    fn eval_params(&self, params: &[Expression]) -> Result<Vec<Value>, data::ExpressionError> {
+        let ctx = self.context();
+        params.iter().map(|param| param.eval(&ctx)).collect()
+    }
+
+    /// Evaluate the parameters of an action as it starts.
+    ///
+    /// Parameters are evaluated in the frame of the enclosing action.
+    fn bind_params(&self, frame: &mut Frame) -> Result<(), data::ExpressionError> {
+        let values = if let Frame::Unbound(ref params) = *frame {
+            self.eval_params(params)?
+        } else {
+            return Ok(());
+        };
+        *frame = Frame::Bound(values);
+
+        Ok(())
+    }
+
+    /// Note a command given to the manager during a checked update or for the history.
+    #[cfg(any(feature = "debug", feature = "reference-check"))]
+    fn note(&mut self, command: Command) {
+        #[cfg(feature = "reference-check")]
+        {
+            if let Some(commands) = self.commands.as_mut() {
+                commands.push(command);
+            }
+        }
+
+        #[cfg(feature = "debug")]
+        {
+            let turn = self.turn();
+            if let Some(history) = self.command_history.as_mut() {
+                // Dropped commands are applied to the base so that it stays just before the
+                // remaining commands.
+                if let Some(dropped) = history.push(turn, command) {
+                    if let Some(base) = self.history_base.as_mut() {
+                        dropped.apply(base);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The sequence memory of the runner.
+    fn sequence(&self) -> Sequence {
+        self.shared_sequence.as_ref().map_or_else(
+            || {
+                Sequence {
+                    direction: self.prev_dir,
+                    speed: self.prev_speed,
+                }
+            },
+            SequenceHandle::get,
+        )
+    }
+
+    /// Pick up bullets fired by other runners sharing the sequence memory.
+    fn load_sequence(&mut self) {
+        let sequence = self.sequence();
+        self.prev_dir = sequence.direction;
+        self.prev_speed = sequence.speed;
+    }
+
+    fn run_ttl(&mut self, ttl: u32) -> Status {
+        let deadline = self.turn().saturating_add(ttl);
+        self.deadline = Some(self.deadline.map_or(deadline, |current| current.min(deadline)));
+
+        Status::Continue
+    }
+
+    fn check_deadline(&mut self) -> bool {
+        if let Some(ttl) = self.pending_ttl.take() {
+            self.run_ttl(ttl);
+        }
+
+        let expired = self
+            .deadline
+            .map_or(false, |deadline| deadline <= self.turn());
+        if expired {
+            self.expired = true;
+            self.run_vanish();
+        }
+
+        expired
+    }
+
+    fn check_parent(&mut self) -> bool {
+        let vanished = self
+            .parent_vanished
+            .as_ref()
+            .map_or(false, |signal| signal.load(Ordering::Acquire));
+        if vanished {
+            self.run_vanish();
+        }
+
+        vanished
+    }
+
     fn update_functions(&mut self) -> bool {
-        let turn = self.manager.turn();
+        let turn = self.turn();
 
         let dir_updated = run_function!(self.change_dir, turn, |v| {
+            let v = self.options.quantize_direction(v);
+            #[cfg(any(feature = "debug", feature = "reference-check"))]
+            self.note(Command::ChangeDirection(v));
             self.manager.change_direction(v)
         });
         let speed_updated = run_function!(self.change_speed, turn, |v| {
+            let v = self.changed_speed(v);
+            let v = self.options.quantize_speed(v);
+            #[cfg(any(feature = "debug", feature = "reference-check"))]
+            self.note(Command::ChangeSpeed(v));
             self.manager.change_speed(v)
         });
-        let accel_x_updated = run_function!(self.accel_x, turn, |v| self.manager.accel_x(v));
-        let accel_y_updated = run_function!(self.accel_y, turn, |v| self.manager.accel_y(v));
+        let accel_x_updated = run_function!(self.accel_x, turn, |v| {
+            let v = self.options.quantize_speed(v);
+            #[cfg(any(feature = "debug", feature = "reference-check"))]
+            self.note(Command::AccelX(v));
+            self.manager.accel_x(v)
+        });
+        let accel_y_updated = run_function!(self.accel_y, turn, |v| {
+            let v = self.options.quantize_speed(v);
+            #[cfg(any(feature = "debug", feature = "reference-check"))]
+            self.note(Command::AccelY(v));
+            self.manager.accel_y(v)
+        });
 
         dir_updated || speed_updated || accel_x_updated || accel_y_updated
     }
 
+    /// Apply the negative speed policy to a speed from a `<changeSpeed>`.
+    fn changed_speed(&mut self, speed: f32) -> f32 {
+        match self.options.negative_speed {
+            NegativeSpeed::Allow => speed,
+            NegativeSpeed::ClampZero => speed.max(0.),
+            NegativeSpeed::ReflectDirection => {
+                // Reflect the direction whenever the speed changes sign.
+                let reflected = speed < 0.;
+                if reflected != self.speed_reflected {
+                    self.speed_reflected = reflected;
+                    let dir = semantics::reflect(self.manager.direction());
+                    let dir = self.options.quantize_direction(dir);
+                    #[cfg(any(feature = "debug", feature = "reference-check"))]
+                    self.note(Command::ChangeDirection(dir));
+                    self.manager.change_direction(dir);
+                }
+                speed.abs()
+            },
+        }
+    }
+
+    fn snapshot(&mut self) -> Snapshot {
+        let target = self.manager.try_aim_direction();
+        if target.is_some() {
+            self.last_aim = target;
+        }
+
+        let mut snapshot = Snapshot::new(&self.manager);
+        snapshot.turn = self.turn();
+        snapshot.aim_direction = semantics::aim_direction(
+            target,
+            self.last_aim,
+            self.options.no_target,
+            self.orientation,
+            snapshot.aim_direction,
+        );
+        snapshot
+    }
+
     fn speed_func<A>(
         &self,
         accel: Option<&A>,
         init_speed: f32,
         turn: u32,
         duration: f32,
+        easing: Easing,
     ) -> Result<Option<Function>, data::ExpressionError>
     where
         A: Acceleration,
     {
         accel
             .map(|accel| {
-                let change = accel.amount(&self.manager)?;
+                let change = accel.amount(&self.context())?;
                 let final_speed = accel.modify(change, init_speed, duration);
-                Ok(Function::new(
-                    turn,
-                    turn + (duration.ceil() as u32),
-                    init_speed,
-                    final_speed,
-                ))
+                let func = interp::interpolate(turn, duration, init_speed, final_speed);
+                Ok(func.with_easing(easing))
             })
             .transpose()
     }
 
     fn run_accel(&mut self, accel: &Accel) -> Result<Status, data::ExpressionError> {
-        let duration = accel.duration.eval(&self.manager)?.max(0.);
-        let turn = self.manager.turn();
-
-        if let Orientation::Horizontal = self.orientation {
-            self.accel_x = self.speed_func(
-                accel.vertical.as_ref(),
-                self.manager.speed_x(),
-                turn,
-                duration,
-            )?;
-            self.accel_y = self.speed_func(
-                accel.horizontal.as_ref(),
-                self.manager.speed_y(),
-                turn,
-                duration,
-            )?;
+        let duration = accel.duration.eval(&self.context())?.max(0.);
+        let easing = accel.easing.unwrap_or(self.options.easing);
+        let snapshot = self.snapshot();
+        let turn = snapshot.turn;
+
+        let horizontal_orientation = matches!(self.orientation, Orientation::Horizontal);
+        let (init_horizontal, init_vertical) = if horizontal_orientation {
+            (snapshot.speed_y, snapshot.speed_x)
         } else {
-            self.accel_x = self.speed_func(
-                accel.horizontal.as_ref(),
-                self.manager.speed_x(),
-                turn,
-                duration,
-            )?;
-            self.accel_y = self.speed_func(
-                accel.vertical.as_ref(),
-                self.manager.speed_y(),
-                turn,
-                duration,
-            )?;
+            (snapshot.speed_x, snapshot.speed_y)
         };
-
-        Ok(Status::Continue)
-    }
-
-    fn target_direction(&self, kind: DirectionKind, degrees: f32) -> f32 {
-        let dir = match kind {
-            DirectionKind::Aim => {
-                // Aim at the player.
-                degrees + self.manager.aim_direction()
-            },
-            DirectionKind::Absolute => {
-                // Orient according to the setup.
-                self.orientation.up(degrees)
-            },
-            DirectionKind::Relative => {
-                // Modify relative to the current direction.
-                degrees + self.manager.direction()
-            },
-            DirectionKind::Sequence => {
-                if let Some(prev_dir) = self.prev_dir {
-                    // Change relative to the previous direction.
-                    degrees + prev_dir
-                } else {
-                    // Default towards the target.
-                    self.manager.aim_direction()
-                }
-            },
+        let horizontal = self.speed_func(
+            accel.horizontal.as_ref(),
+            init_horizontal,
+            turn,
+            duration,
+            easing,
+        )?;
+        let vertical =
+            self.speed_func(accel.vertical.as_ref(), init_vertical, turn, duration, easing)?;
+        let (along_x, along_y) = if horizontal_orientation {
+            (vertical, horizontal)
+        } else {
+            (horizontal, vertical)
         };
+        self.accel_x = along_x;
+        self.accel_y = along_y;
 
-        dir % 360.
+        Ok(Status::Continue)
     }
 
-    fn target_direction_data(&self, direction: &Direction) -> Result<f32, data::ExpressionError> {
-        direction
-            .degrees
-            .eval(&self.manager)
-            .map(|degrees| self.target_direction(direction.kind, degrees))
+    fn target_direction_data(
+        &self,
+        snapshot: &Snapshot,
+        direction: &Direction,
+    ) -> Result<f32, data::ExpressionError> {
+        direction.degrees.eval(&self.context()).map(|degrees| {
+            semantics::fire_direction(
+                snapshot,
+                self.orientation,
+                self.prev_dir,
+                direction.kind,
+                degrees,
+            )
+        })
     }
 
     fn run_change_direction(
         &mut self,
         cd: &ChangeDirection,
     ) -> Result<Status, data::ExpressionError> {
-        let duration = cd.value.eval(&self.manager)?.max(0.);
+        let duration = cd.value.eval(&self.context())?.max(0.);
         let direction = &cd.direction;
-        let cur_dir = self.manager.direction();
-        let degrees = direction.degrees.eval(&self.manager)?;
-
-        let final_dir = if let DirectionKind::Sequence = direction.kind {
-            duration * degrees + cur_dir
-        } else {
-            self.target_direction(direction.kind, degrees)
-        };
-
-        let turn = self.manager.turn();
-        self.change_dir = Some(Function::new(
-            turn,
-            turn + (duration.ceil() as u32),
-            cur_dir,
-            final_dir,
-        ));
+        let degrees = direction.degrees.eval(&self.context())?;
+        let snapshot = self.snapshot();
+        let degrees = semantics::lead_direction(&snapshot, direction, degrees, snapshot.speed);
+
+        let func = semantics::change_direction(
+            &snapshot,
+            self.orientation,
+            direction.kind,
+            degrees,
+            duration,
+        );
+        self.change_dir = Some(func.with_easing(cd.easing.unwrap_or(self.options.easing)));
 
         Ok(Status::Continue)
     }
 
-    fn target_speed(&self, kind: Change, value: f32) -> f32 {
-        match kind {
-            Change::Absolute => value,
-            Change::Relative => value + self.manager.speed(),
-            Change::Sequence => {
-                if let Some(prev_speed) = self.prev_speed {
-                    value + prev_speed
-                } else {
-                    1.
-                }
-            },
-        }
-    }
-
-    fn target_speed_data(&self, speed: &Speed) -> Result<f32, data::ExpressionError> {
+    fn target_speed_data(
+        &self,
+        snapshot: &Snapshot,
+        speed: &Speed,
+    ) -> Result<f32, data::ExpressionError> {
         speed
             .change
-            .eval(&self.manager)
-            .map(|change| self.target_speed(speed.kind, change))
+            .eval(&self.context())
+            .map(|change| semantics::fire_speed(snapshot, self.prev_speed, speed.kind, change))
     }
 
     fn run_change_speed(&mut self, cs: &ChangeSpeed) -> Result<Status, data::ExpressionError> {
-        let duration = cs.value.eval(&self.manager)?.max(0.);
+        let duration = cs.value.eval(&self.context())?.max(0.);
         let speed = &cs.speed;
-        let cur_speed = self.manager.speed();
-        let change = speed.change.eval(&self.manager)?;
-
-        let final_speed = if let Change::Sequence = speed.kind {
-            duration * change + cur_speed
-        } else {
-            self.target_speed(speed.kind, change)
-        };
+        let change = speed.change.eval(&self.context())?;
+        let snapshot = self.snapshot();
 
-        let turn = self.manager.turn();
-        self.change_speed = Some(Function::new(
-            turn,
-            turn + (duration.ceil() as u32),
-            cur_speed,
-            final_speed,
-        ));
+        // The new change starts from the speed the manager reports.
+        self.speed_reflected = false;
+        let func = semantics::change_speed(&snapshot, speed.kind, change, duration);
+        self.change_speed = Some(func.with_easing(cs.easing.unwrap_or(self.options.easing)));
 
         Ok(Status::Continue)
     }
 
-    fn run_fire(&mut self, fire: &Fire) -> Result<Status, data::ExpressionError> {
+    fn run_fire(
+        &mut self,
+        fire: &Fire,
+        params: Option<&[Expression]>,
+    ) -> Result<Status, data::ExpressionError> {
+        // Referenced entities are evaluated in their own frames. The frame of the enclosing
+        // action is restored before the next step.
+        if let Some(params) = params {
+            self.params = self.eval_params(params)?;
+        }
+
+        self.load_sequence();
+        let snapshot = self.snapshot();
         let fire_dir = fire
             .direction
             .as_ref()
-            .map(|direction| self.target_direction_data(direction))
+            .map(|direction| self.target_direction_data(&snapshot, direction))
             .transpose()?;
         let fire_speed = fire
             .speed
             .as_ref()
-            .map(|speed| self.target_speed_data(speed))
+            .map(|speed| self.target_speed_data(&snapshot, speed))
             .transpose()?;
 
         let bullet = fire.bullet.as_ref();
+        if let Some(params) = fire.bullet_params.as_ref() {
+            self.params = self.eval_params(params)?;
+        }
 
-        let dir = bullet
+        let bullet_dir = bullet
             .direction
             .as_ref()
-            .map(|direction| self.target_direction_data(direction))
-            .transpose()?
-            .or(fire_dir)
-            .unwrap_or_else(|| self.manager.aim_direction());
-        let speed = bullet
+            .map(|direction| self.target_direction_data(&snapshot, direction))
+            .transpose()?;
+        let bullet_speed = bullet
             .speed
             .as_ref()
-            .map(|speed| self.target_speed_data(speed))
-            .transpose()?
-            .or(fire_speed)
-            .unwrap_or_else(|| self.manager.default_speed());
+            .map(|speed| self.target_speed_data(&snapshot, speed))
+            .transpose()?;
+        let (dir, speed) = semantics::fire(
+            &snapshot,
+            bullet_dir.or(fire_dir),
+            bullet_speed.or(fire_speed),
+        );
+        // Leading the target depends on the speed of the bullet.
+        let dir = bullet
+            .direction
+            .as_ref()
+            .or_else(|| fire.direction.as_ref())
+            .map_or(dir, |direction| {
+                semantics::lead_direction(&snapshot, direction, dir, speed)
+            });
 
         self.prev_dir = Some(dir);
         self.prev_speed = Some(speed);
+        self.store_sequence();
+
+        if let Some(coverage) = self.coverage.as_mut() {
+            if let Some(label) = fire.label.as_ref() {
+                Coverage::record(&mut coverage.fires, label);
+            }
+            if let Some(label) = bullet.label.as_ref() {
+                Coverage::record(&mut coverage.bullets, label);
+            }
+        }
 
-        if bullet.actions.is_empty() {
+        let (dir, speed) = semantics::negative_speed(self.options.negative_speed, dir, speed);
+        let (dir, speed) = if self.options.inherit_velocity {
+            semantics::inherit_velocity(dir, speed, self.manager.owner_velocity())
+        } else {
+            (dir, speed)
+        };
+        let dir = self.options.quantize_direction(dir);
+        let speed = self.options.quantize_speed(speed);
+        let id = self.allocate_id();
+        let index = self.fire_index;
+        self.fire_index += 1;
+        let simple = bullet.actions.is_empty();
+        if simple {
+            #[cfg(any(feature = "debug", feature = "reference-check"))]
+            self.note(Command::NewSimple(dir, speed));
             self.manager.new_simple(dir, speed);
         } else {
-            // TODO(#4): The actions need to be handled here.
-            self.manager.new_bullet(dir, speed);
+            let script = self.bullet_script(bullet, id)?;
+            #[cfg(any(feature = "debug", feature = "reference-check"))]
+            self.note(Command::NewBullet(dir, speed));
+            self.manager.new_bullet_with_script(dir, speed, script);
         }
 
+        self.notify(Event::Fired {
+            source: self.source,
+            id,
+            index,
+            direction: dir,
+            speed,
+            simple,
+            ttl: bullet.ttl,
+        });
+
         Ok(Status::Continue)
     }
 
+    /// The script for the actions of a fired bullet.
+    ///
+    /// The parameters of the actions are evaluated in the frame of the bullet as it is fired.
+    fn bullet_script(
+        &self,
+        bullet: &Bullet,
+        id: BulletId,
+    ) -> Result<BulletScript, data::ExpressionError> {
+        let mut root = Node::new(NodeStep::Root);
+        #[cfg(feature = "reference-check")]
+        let mut program = Program::new();
+        for (action, params) in &bullet.actions {
+            let values = if let Some(params) = params {
+                self.eval_params(params)?
+            } else {
+                self.params.clone()
+            };
+            #[cfg(feature = "reference-check")]
+            program.push((Arc::clone(action), Some(values.clone())));
+            root.add_child(action.node_in(Frame::Bound(values)));
+        }
+
+        Ok(BulletScript {
+            steps: root.zipper().iter(),
+            #[cfg(feature = "reference-check")]
+            program,
+            orientation: self.orientation,
+            options: self.options.clone(),
+            vars: self.vars.clone(),
+            rng: self.random.fork(id),
+            source: id,
+            parent_vanished: if self.options.vanish_children {
+                Some(Arc::clone(&self.vanish_signal))
+            } else {
+                None
+            },
+            deadline: bullet.ttl.map(|ttl| self.turn().saturating_add(ttl)),
+        })
+    }
+
     fn run_repeat(&mut self, repeat: &Repeat) -> Result<Status, data::ExpressionError> {
-        let times = repeat.times.value.eval(&self.manager)?;
+        let count = if let RepeatEvaluation::EachIteration = self.options.repeat_evaluation {
+            None
+        } else {
+            Some(self.repeat_count(repeat)?)
+        };
 
-        // Other implementations use C++'s static_cast which truncates, so compare with `1`
-        // rather than letting rounding occur.
-        let count = if times.is_nan() || times < 1. {
-            0
+        self.run_repeat_iteration(repeat, 0, count)
+    }
+
+    fn run_repeat_iteration(
+        &mut self,
+        repeat: &Repeat,
+        index: usize,
+        count: Option<usize>,
+    ) -> Result<Status, data::ExpressionError> {
+        let count = if let Some(count) = count {
+            count
         } else {
-            times as usize
+            self.repeat_count(repeat)?
         };
 
-        Ok(Status::NewSteps(repeat.new_steps(count)))
+        if index < count {
+            Ok(Status::NewSteps(repeat.iteration_steps(index, Some(count))))
+        } else {
+            Ok(Status::Continue)
+        }
+    }
+
+    fn repeat_count(&self, repeat: &Repeat) -> Result<usize, data::ExpressionError> {
+        let times = repeat.times.value.eval(&self.context())?;
+        Ok(semantics::repeat_count(times))
+    }
+
+    fn run_action(&mut self, label: Option<&str>, tags: &[String]) -> Status {
+        if !self.options.runs_tags(tags) {
+            return Status::Skip;
+        }
+
+        if let (Some(coverage), Some(label)) = (self.coverage.as_mut(), label) {
+            Coverage::record(&mut coverage.actions, label);
+        }
+
+        Status::Continue
+    }
+
+    fn run_custom(&mut self, step: &dyn CustomStep) -> Status {
+        if let Some(executor) = self.custom_steps.get_mut(step.name()) {
+            executor(&mut self.manager, step);
+        }
+
+        Status::Continue
     }
 
     fn run_vanish(&mut self) -> Status {
+        #[cfg(any(feature = "debug", feature = "reference-check"))]
+        self.note(Command::Vanish);
         self.manager.vanish();
+
+        // Nothing happens to the bullet once it is gone.
+        self.vanished = true;
+        self.change_dir = None;
+        self.change_speed = None;
+        self.accel_x = None;
+        self.accel_y = None;
+        self.next = None;
+        self.vanish_signal.store(true, Ordering::Release);
+
+        self.notify(Event::Vanished {
+            source: self.source,
+        });
         Status::End
     }
 
@@ -359,11 +760,18 @@ where
         let next = if let Some(next) = self.next {
             next
         } else {
-            let frames = wait.frames.eval(&self.manager)?;
-            self.manager.turn() + (frames.ceil() as u32)
+            let frames = wait.frames.eval(&self.context())?;
+            let (next, remainder) = semantics::wait(
+                self.turn(),
+                frames,
+                self.wait_remainder,
+                self.options.accumulate_wait,
+            );
+            self.wait_remainder = remainder;
+            next
         };
 
-        Ok(if next < self.manager.turn() {
+        Ok(if self.turn() < next {
             self.next = Some(next);
             Status::End
         } else {
@@ -373,68 +781,2482 @@ where
     }
 }
 
+/// The result of updating a runner.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UpdateReport {
+    /// Whether any state was updated.
+    pub updated: bool,
+    /// The number of steps executed.
+    pub steps: usize,
+    /// Whether execution stopped because the step budget was exhausted.
+    ///
+    /// The remaining steps are executed by the next update.
+    pub budget_exhausted: bool,
+    /// The status of the runner after the update.
+    pub status: RunnerStatus,
+}
+
+/// The status of a runner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunnerStatus {
+    /// Steps or changes over time remain.
+    Running,
+    /// All steps and changes over time have completed.
+    Done,
+    /// The bullet vanished because its `ttl` expired.
+    Expired,
+    /// The bullet vanished because of a `<vanish>` or because the bullet which fired it did.
+    Vanished,
+    /// A panic during an update stopped the runner.
+    Poisoned,
+}
+
+impl Default for RunnerStatus {
+    fn default() -> Self {
+        RunnerStatus::Running
+    }
+}
+
+/// An error when updating a runner with panics isolated.
+#[derive(Debug, Error)]
+pub enum UpdateError {
+    /// An expression failed to evaluate.
+    #[error("expression error")]
+    Expression {
+        /// The source of the error.
+        #[from]
+        source: data::ExpressionError,
+    },
+    /// A callback panicked during the update.
+    #[error("panic during update: {}", message)]
+    Panic {
+        /// The message of the panic.
+        message: String,
+    },
+    /// The runner panicked during an earlier update.
+    #[error("runner is poisoned by an earlier panic")]
+    Poisoned,
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).into()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic payload".into()
+    }
+}
+
+/// The result of executing a single step.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MicroStep {
+    /// The name of the element for the executed step.
+    pub step: String,
+    /// Whether the step ended the frame.
+    pub end_of_frame: bool,
+}
+
 /// Run a script with a given bullet manager.
+///
+/// # Changes over time
+///
+/// `<changeDirection>`, `<changeSpeed>`, and `<accel>` interpolate linearly from the value the
+/// manager reports when the step executes to the target value over the given term. The target
+/// value is set exactly on the first update after the term ends. Changes to the direction turn
+/// through the smaller angle unless the `sequence` type is used, in which case the amount is
+/// applied for every frame of the term. Steps with an `easing` attribute, or runners with
+/// `RunnerOptions::easing`, follow a curve between the same endpoints instead of a line.
+///
+/// Starting a change while another change of the same kind is in progress cancels the previous
+/// change; its target value is never reached. The new change starts from the value the manager
+/// reports at that time, which includes the progress made by the cancelled change in the current
+/// frame.
 pub struct Runner<T> {
     state: State<T>,
-    bulletml: BulletML,
+    steps: ZipperIter<NodeStep>,
+    in_frame: bool,
+    queued: Option<Queued>,
+
+    /// The top-level actions for the reference evaluator.
+    #[cfg(feature = "reference-check")]
+    program: Program,
+    #[cfg(feature = "reference-check")]
+    reference: Option<Box<Reference<T>>>,
+}
+
+/// The actions of a fired bullet.
+///
+/// Bullets with actions are given to `BulletManager::new_bullet_with_script` along with their
+/// script. Creating a runner from the script with a manager for the new bullet runs the actions
+/// on that bullet. The runner uses the options and variables of the runner which fired the
+/// bullet; custom step executors need to be registered again. If the firing runner has a random
+/// number generator, the runner for the bullet has one split from it. If the bullet has a `ttl`,
+/// the runner vanishes the bullet once that many frames have passed since it was fired.
+#[derive(Debug)]
+pub struct BulletScript {
+    steps: ZipperIter<NodeStep>,
+    #[cfg(feature = "reference-check")]
+    program: Program,
+    orientation: Orientation,
+    options: RunnerOptions,
+    vars: HashMap<String, Value>,
+    rng: Option<Rng>,
+    source: BulletId,
+    parent_vanished: Option<Arc<AtomicBool>>,
+    /// The turn at which the `ttl` of the bullet expires.
+    deadline: Option<u32>,
+}
+
+impl BulletScript {
+    /// The identifier of the bullet given by the runner which fired it.
+    ///
+    /// Events from the runner for the bullet use this as their `source`.
+    pub fn id(&self) -> BulletId {
+        self.source
+    }
+
+    /// Create a runner for the bullet.
+    pub fn runner<T>(self, manager: T) -> Runner<T> {
+        Runner::from_script(manager, self)
+    }
+}
+
+/// A script to run once the current one completes.
+struct Queued {
+    steps: ZipperIter<NodeStep>,
+    orientation: Orientation,
+    keep_sequence: bool,
 }
 
 impl<T> Runner<T> {
     /// Create a new runner for a manager and BulletML script.
     pub fn new(manager: T, bulletml: data::BulletML) -> Result<Self, BulletMLError> {
-        Ok(Runner {
-            state: State::new(manager, bulletml.orientation),
-            bulletml: BulletML::new(bulletml)?,
-        })
+        Self::with_options(manager, bulletml, RunnerOptions::default())
     }
-}
 
-impl<T> Runner<T>
-where
-    T: BulletManager,
-{
-    /// Update the state.
-    pub fn update(&mut self) -> Result<bool, data::ExpressionError> {
-        let mut updated = self.state.update_functions();
+    /// Create a new runner for a manager and BulletML script with options.
+    pub fn with_options(
+        manager: T,
+        bulletml: data::BulletML,
+        options: RunnerOptions,
+    ) -> Result<Self, BulletMLError> {
+        let bulletml = BulletML::new(bulletml)?;
+        Ok(Self::from_compiled(manager, &bulletml, options))
+    }
 
-        loop {
-            let status = {
-                let node = if let Some(node) = self.bulletml.steps.current_mut() {
-                    updated = true;
-                    node
-                } else {
-                    break;
-                };
-
-                let status = match node.as_ref() {
-                    NodeStep::Root => Status::Continue,
-                    NodeStep::Repeat(ref r) => self.state.run_repeat(r)?,
-                    NodeStep::Fire(ref f) => self.state.run_fire(f)?,
-                    NodeStep::ChangeSpeed(ref cs) => self.state.run_change_speed(cs)?,
-                    NodeStep::ChangeDirection(ref cd) => self.state.run_change_direction(cd)?,
-                    NodeStep::Accel(ref a) => self.state.run_accel(a)?,
-                    NodeStep::Wait(ref w) => self.state.run_wait(w)?,
-                    NodeStep::Vanish(_) => self.state.run_vanish(),
-                };
-
-                if let Status::NewSteps(steps) = status {
-                    steps.into_iter().for_each(|step| node.add_child(step));
-                    Status::Continue
-                } else {
-                    status
-                }
-            };
+    /// Create a new runner for a manager and BulletML script in a given orientation.
+    ///
+    /// The orientation overrides the `type` of the script. This allows, e.g., patterns written
+    /// for vertical games to be used in horizontal games.
+    pub fn new_with_orientation(
+        manager: T,
+        bulletml: data::BulletML,
+        orientation: Orientation,
+    ) -> Result<Self, BulletMLError> {
+        let mut bulletml = BulletML::new(bulletml)?;
+        bulletml.orientation = orientation;
+        Ok(Self::from_compiled(manager, &bulletml, RunnerOptions::default()))
+    }
 
-            match status {
-                Status::End => break,
-                Status::Continue => {
-                    self.bulletml.steps.next();
-                },
-                Status::NewSteps(_) => unreachable!(),
+    /// Create a new runner for a manager from a compiled BulletML script.
+    pub fn from_compiled(manager: T, bulletml: &BulletML, options: RunnerOptions) -> Self {
+        #[allow(unused_mut)]
+        let mut runner = Self::from_steps(manager, bulletml.orientation, bulletml.steps(), options);
+        #[cfg(feature = "reference-check")]
+        {
+            runner.program = bulletml
+                .actions()
+                .iter()
+                .map(|action| (Arc::clone(action), None))
+                .collect();
+        }
+        runner
+    }
+
+    /// Create a new runner for the actions of a fired bullet.
+    pub fn from_script(manager: T, script: BulletScript) -> Self {
+        let mut runner =
+            Self::from_steps(manager, script.orientation, script.steps, script.options);
+        runner.state.vars = script.vars;
+        if let Some(rng) = script.rng {
+            runner.state.random.set_rng(rng);
+        }
+        runner.state.source = Some(script.source);
+        runner.state.parent_vanished = script.parent_vanished;
+        runner.state.deadline = script.deadline;
+        #[cfg(feature = "reference-check")]
+        {
+            runner.program = script.program;
+        }
+        runner
+    }
+
+    /// Create a new runner for a single top-level action of a compiled BulletML script.
+    ///
+    /// Reference implementations run each top-level action (`top`, `top1`, `top2`, etc.) in its
+    /// own runner so that they fire in parallel. Returns `None` if there is no top-level action
+    /// with the label.
+    pub fn from_top_action(
+        manager: T,
+        bulletml: &BulletML,
+        label: &str,
+        options: RunnerOptions,
+    ) -> Option<Self> {
+        #[allow(unused_mut)]
+        let mut runner = bulletml
+            .top_action_steps(label)
+            .map(|steps| Self::from_steps(manager, bulletml.orientation, steps, options))?;
+        #[cfg(feature = "reference-check")]
+        {
+            runner.program = bulletml
+                .top_action(label)
+                .map(|action| (Arc::clone(action), None))
+                .into_iter()
+                .collect();
+        }
+        Some(runner)
+    }
+
+    /// Create a runner for each top-level action of a compiled BulletML script.
+    ///
+    /// The manager for each runner is created from the label of its action.
+    pub fn for_top_actions<F>(
+        bulletml: &BulletML,
+        options: RunnerOptions,
+        mut manager: F,
+    ) -> Vec<Self>
+    where
+        F: FnMut(&str) -> T,
+    {
+        bulletml
+            .top_actions()
+            .filter_map(|label| {
+                Self::from_top_action(manager(label), bulletml, label, options.clone())
+            })
+            .collect()
+    }
+
+    fn from_steps(
+        manager: T,
+        orientation: Orientation,
+        mut steps: ZipperIter<NodeStep>,
+        options: RunnerOptions,
+    ) -> Self {
+        // Start the iteration so that the root is only executed once.
+        steps.next();
+
+        Runner {
+            state: State::new(manager, orientation, options),
+            steps,
+            in_frame: false,
+            queued: None,
+
+            #[cfg(feature = "reference-check")]
+            program: Program::new(),
+            #[cfg(feature = "reference-check")]
+            reference: None,
+        }
+    }
+
+    /// Continue with a top-level action of another script once the current script completes.
+    ///
+    /// The action starts within the update in which the current script runs out of steps, so
+    /// there is no frame between the two scripts. This allows, e.g., the phases of a boss to be
+    /// chained. Changes over time which are in progress continue. The sequence direction and
+    /// speed continue from the current script if `keep_sequence` is set and otherwise start
+    /// over as for a new runner.
+    ///
+    /// Queueing replaces any script which was queued before. Returns `false` if there is no
+    /// top-level action with the label.
+    pub fn queue_next(
+        &mut self,
+        bulletml: &BulletML,
+        entry_label: &str,
+        keep_sequence: bool,
+    ) -> bool {
+        let steps = if let Some(steps) = bulletml.top_action_steps(entry_label) {
+            steps
+        } else {
+            return false;
+        };
+
+        self.queued = Some(Queued {
+            steps,
+            orientation: bulletml.orientation,
+            keep_sequence,
+        });
+        // The reference evaluator does not chain scripts.
+        #[cfg(feature = "reference-check")]
+        {
+            self.reference = None;
+        }
+        true
+    }
+
+    /// Share sequence memory with other runners.
+    ///
+    /// `sequence` directions and speeds continue from the last bullet fired by any runner
+    /// sharing the handle, including bullets fired before this runner joined. Scripts queued
+    /// without `keep_sequence` start the shared sequence over when they start. Runners for the
+    /// bullets fired by this runner keep their own memory.
+    ///
+    /// The reference evaluator does not share memory, so this stops reference checking.
+    pub fn share_sequence_state(&mut self, handle: SequenceHandle) {
+        self.state.shared_sequence = Some(handle);
+
+        #[cfg(feature = "reference-check")]
+        {
+            self.reference = None;
+        }
+    }
+
+    /// The sequence memory shared by the runner, if any.
+    pub fn sequence_state(&self) -> Option<&SequenceHandle> {
+        self.state.shared_sequence.as_ref()
+    }
+
+    /// Whether a script is queued to run once the current script completes.
+    pub fn has_queued(&self) -> bool {
+        self.queued.is_some()
+    }
+
+    /// Start the queued script if the current script has completed.
+    fn start_queued(&mut self) {
+        if self.steps.current().is_some() {
+            return;
+        }
+
+        if let Some(queued) = self.queued.take() {
+            let mut steps = queued.steps;
+            // Start the iteration so that the root is only executed once.
+            steps.next();
+            self.steps = steps;
+
+            self.state.orientation = queued.orientation;
+            if !queued.keep_sequence {
+                self.state.prev_dir = None;
+                self.state.prev_speed = None;
+                self.state.store_sequence();
             }
         }
+    }
 
-        Ok(updated)
+    /// The manager for the runner.
+    pub fn manager(&self) -> &T {
+        &self.state.manager
+    }
+
+    /// The manager for the runner.
+    pub fn manager_mut(&mut self) -> &mut T {
+        &mut self.state.manager
+    }
+
+    /// Set the observer to notify of events.
+    ///
+    /// Observers must be `Send` so that the runner may be moved to another thread.
+    pub fn set_observer<O>(&mut self, observer: O)
+    where
+        O: Observer + Send + 'static,
+    {
+        self.state.observer = Some(Box::new(observer));
+    }
+
+    /// Start counting how often labeled entities execute.
+    ///
+    /// Any previous counts are discarded.
+    pub fn track_coverage(&mut self) {
+        self.state.coverage = Some(Coverage::default());
+    }
+
+    /// How often labeled entities have executed since coverage tracking started.
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.state.coverage.as_ref()
+    }
+
+    /// Provide `$rand` values from a random number generator rather than the manager.
+    ///
+    /// Bullets fired by the runner are given generators split from this one (see `Rng::split`)
+    /// by their identifiers so that an entire pattern may be reproduced from a single seed. Their
+    /// values do not depend on the values used by the runner.
+    pub fn set_rng(&mut self, rng: Rng) {
+        self.state.random.set_rng(rng);
+    }
+
+    /// Start recording the `$rand` values used by the runner.
+    ///
+    /// Any previous recording is discarded.
+    pub fn record_rand(&mut self) {
+        self.state.random.record();
+    }
+
+    /// The `$rand` values used since recording started.
+    pub fn rand_recording(&self) -> Option<Recording> {
+        self.state.random.recording().map(|values| {
+            Recording {
+                values,
+            }
+        })
+    }
+
+    /// Replay `$rand` values from a recording.
+    ///
+    /// Once the recording is exhausted, values come from the random number generator, if set, or
+    /// the manager.
+    pub fn replay_rand(&mut self, recording: Recording) {
+        self.state.random.replay(recording.values);
+    }
+
+    /// Start recording the commands given to the manager.
+    ///
+    /// Each command is stamped with the turn it was given on. With a limit, only the most recent
+    /// commands are kept. A copy of the manager is taken so that its state at any recorded turn
+    /// may be rebuilt with `rebuild_at`. Any previous history is discarded.
+    #[cfg(feature = "debug")]
+    pub fn record_history(&mut self, limit: Option<usize>)
+    where
+        T: Clone,
+    {
+        self.state.command_history = Some(History::new(limit));
+        self.state.history_base = Some(self.state.manager.clone());
+    }
+
+    /// The commands given to the manager since recording started.
+    #[cfg(feature = "debug")]
+    pub fn command_history(&self) -> Option<&History> {
+        self.state.command_history.as_ref()
+    }
+
+    /// Rebuild the manager as it was at the end of a turn.
+    ///
+    /// The recorded commands up to the turn are replayed in order on the copy of the manager
+    /// taken when recording started. This is intended for editors which scrub through the
+    /// history of a pattern. Only commands are replayed: changes the game makes to the manager
+    /// itself, such as advancing the turn or moving the bullet, are not. Fired bullets are given
+    /// to `new_bullet` since their scripts are not kept.
+    ///
+    /// Returns `None` if no history is being recorded or if the commands for the turn have been
+    /// dropped from a limited history.
+    #[cfg(feature = "debug")]
+    pub fn rebuild_at(&self, turn: u32) -> Option<T>
+    where
+        T: BulletManager + Clone,
+    {
+        let history = self.state.command_history.as_ref()?;
+        if !history.covers(turn) {
+            return None;
+        }
+
+        let mut manager = self.state.history_base.clone()?;
+        history
+            .until(turn)
+            .for_each(|&(_, command)| command.apply(&mut manager));
+        Some(manager)
+    }
+
+    /// Set a variable for expressions run by the runner.
+    ///
+    /// Runner variables shadow variables of the same name provided by the manager. Parameters of
+    /// the running action are looked up before either. With the `debug` feature, names which
+    /// shadow a manager variable are listed in the diagnostic dump.
+    pub fn set_var<N>(&mut self, name: N, value: Value)
+    where
+        N: Into<String>,
+        T: BulletManager,
+    {
+        self.state.vars.insert(name.into(), value);
+
+        #[cfg(feature = "debug")]
+        {
+            let shadowed = self.state.context().shadowed();
+            self.state.shadowed = shadowed;
+        }
+    }
+
+    /// The value of a runner variable.
+    pub fn var(&self, name: &str) -> Option<Value> {
+        self.state.vars.get(name).copied()
+    }
+
+    /// Undefined variables which have evaluated to `0`.
+    ///
+    /// This is only populated with `UnknownVariablePolicy::Zero`.
+    pub fn unknown_variables(&self) -> Vec<String> {
+        self.state.unknown_vars.borrow().iter().cloned().collect()
+    }
+
+    /// Register an executor for custom steps with the given name.
+    ///
+    /// Custom steps without a registered executor are skipped. Executors must be `Send` so that
+    /// the runner may be moved to another thread.
+    pub fn register_custom_step<N, F>(&mut self, name: N, executor: F)
+    where
+        N: Into<String>,
+        F: FnMut(&mut T, &dyn CustomStep) + Send + 'static,
+    {
+        self.state
+            .custom_steps
+            .insert(name.into(), Box::new(executor));
+    }
+}
+
+impl<T> Runner<T>
+where
+    T: BulletManager,
+{
+    /// Update the state.
+    ///
+    /// Steps are executed in document order. Bullets fired during the update are reported to the
+    /// manager (and observer) in the order their `<fire>` steps execute.
+    ///
+    /// If the options specify a step budget, execution stops once the budget is exhausted and
+    /// continues with the next update.
+    pub fn update(&mut self) -> Result<UpdateReport, data::ExpressionError> {
+        #[cfg(feature = "reference-check")]
+        {
+            if let Some(reference) = self.reference.take() {
+                return self.update_checked(reference);
+            }
+        }
+
+        self.update_frame()
+    }
+
+    /// Update the state by an amount of elapsed time.
+    ///
+    /// This is intended for games which do not run at a fixed frame rate. Elapsed time is
+    /// converted into frames at `RunnerOptions::frames_per_second` and a frame is run for every
+    /// whole frame which has elapsed, so waits, changes over time, and accelerations take the
+    /// same amount of time at any frame rate. Time which does not make up a whole frame carries
+    /// over to the next call.
+    ///
+    /// Once a runner has been stepped by elapsed time, it counts turns itself starting from the
+    /// turn the manager reported at the first call rather than using `BulletManager::turn`, so
+    /// it should not be mixed with `update`. The reference evaluator does not check frames run
+    /// this way.
+    ///
+    /// The report combines those of the frames which were run.
+    pub fn update_dt(&mut self, dt: f32) -> Result<UpdateReport, data::ExpressionError> {
+        let fps = self
+            .state
+            .options
+            .frames_per_second
+            .unwrap_or(DEFAULT_FRAMES_PER_SECOND);
+        let turn = self.state.manager.turn();
+        let clock = self.state.clock.get_or_insert(Clock {
+            turn,
+            pending: 0.,
+        });
+        clock.pending += f64::from(dt.max(0.)) * f64::from(fps);
+
+        let mut report = UpdateReport {
+            status: self.status(),
+            ..Default::default()
+        };
+        while self
+            .state
+            .clock
+            .map_or(false, |clock| clock.pending >= 1. - FRAME_EPSILON)
+        {
+            let frame = self.update_frame()?;
+            report.updated |= frame.updated;
+            report.steps += frame.steps;
+            report.budget_exhausted |= frame.budget_exhausted;
+            report.status = frame.status;
+
+            if let Some(clock) = self.state.clock.as_mut() {
+                clock.turn = clock.turn.saturating_add(1);
+                clock.pending -= 1.;
+            }
+        }
+
+        Ok(report)
+    }
+
+    fn update_frame(&mut self) -> Result<UpdateReport, data::ExpressionError> {
+        let mut report = UpdateReport::default();
+        self.in_frame = false;
+
+        let (updated, runnable) = self.begin_frame();
+        report.updated = updated;
+        if !runnable {
+            report.status = self.status();
+            return Ok(report);
+        }
+
+        while let Some(continues) = self.execute_step()? {
+            report.updated = true;
+            report.steps += 1;
+
+            if !continues {
+                break;
+            }
+
+            let budget_exhausted = self
+                .state
+                .options
+                .step_budget
+                .map_or(false, |budget| report.steps >= budget);
+            if budget_exhausted && self.steps.current().is_some() {
+                report.budget_exhausted = true;
+                break;
+            }
+        }
+
+        self.state.frame_split = report.budget_exhausted;
+        report.status = self.status();
+        Ok(report)
+    }
+
+    /// Check every update against the reference evaluator.
+    ///
+    /// The reference evaluator is a slow, straightforward implementation of the step semantics.
+    /// With checking enabled, each update also runs the reference against a copy of the manager
+    /// taken before the update and panics if the commands given to the manager differ. This is
+    /// intended to guard changes to the runner; it should not be enabled in release builds.
+    ///
+    /// The reference does not run custom step executors, so they may not give commands to the
+    /// manager, and observers and coverage are not checked. Values of `$rand` must be the same
+    /// for the copy of the manager, so a generator should be set with `set_rng`. Runners for
+    /// fired bullets need checking enabled separately.
+    ///
+    /// Checking stops after an update fails, after `micro_step`, or once a script is queued with
+    /// `queue_next`. Returns `false` if the runner has already executed steps, has a step budget,
+    /// has a queued script, follows the vanishing of the bullet which fired it, or shares its
+    /// sequence memory.
+    #[cfg(feature = "reference-check")]
+    pub fn check_against_reference(&mut self) -> bool
+    where
+        T: Clone,
+    {
+        let started = !matches!(self.steps.current(), Some(NodeStep::Root))
+            || self.state.expired
+            || self.state.vanished
+            || self.state.poisoned;
+        let unchecked = self.state.options.step_budget.is_some()
+            || self.queued.is_some()
+            || self.state.parent_vanished.is_some()
+            || self.state.shared_sequence.is_some();
+        if started || unchecked {
+            return false;
+        }
+
+        let program = self.program.clone();
+        self.reference = Some(Box::new(Reference::new(
+            T::clone,
+            program,
+            &self.state.options,
+            self.state.deadline,
+        )));
+        true
+    }
+
+    /// Run an update and the reference evaluator and compare the commands they give.
+    #[cfg(feature = "reference-check")]
+    fn update_checked(
+        &mut self,
+        mut reference: Box<Reference<T>>,
+    ) -> Result<UpdateReport, data::ExpressionError> {
+        let turn = self.state.manager.turn();
+        let env = reference::Env {
+            options: &self.state.options,
+            orientation: self.state.orientation,
+            vars: &self.state.vars,
+            random: self.state.random.clone(),
+        };
+        let (expected, expected_res) = reference.update(&self.state.manager, env);
+
+        self.state.commands = Some(Vec::new());
+        let res = self.update_frame();
+        let actual = self.state.commands.take().unwrap_or_default();
+
+        reference::check(
+            turn,
+            &actual,
+            &expected,
+            &res.as_ref().map(|_| ()),
+            &expected_res,
+        );
+        if res.is_ok() {
+            self.reference = Some(reference);
+        }
+
+        res
+    }
+
+    /// Update the state, catching panics.
+    ///
+    /// This is intended for hosts, such as editors, which run callbacks they do not control. A
+    /// panic from the manager, an observer, or a custom step executor is returned as an error
+    /// rather than unwinding through the caller. The panic is still reported by the panic hook.
+    ///
+    /// The runner may be left in an inconsistent state by a panic, so it is poisoned: further
+    /// updates do nothing and this method returns `UpdateError::Poisoned`.
+    pub fn update_isolated(&mut self) -> Result<UpdateReport, UpdateError>
+    where
+        T: UnwindSafe,
+    {
+        if self.state.poisoned {
+            return Err(UpdateError::Poisoned);
+        }
+
+        // Observers and custom step executors are not required to be unwind safe, but the runner
+        // does not use them again once it is poisoned.
+        match panic::catch_unwind(AssertUnwindSafe(|| self.update())) {
+            Ok(result) => Ok(result?),
+            Err(payload) => {
+                self.state.poisoned = true;
+                Err(UpdateError::Panic {
+                    message: panic_message(payload.as_ref()),
+                })
+            },
+        }
+    }
+
+    /// Execute exactly one step.
+    ///
+    /// This is intended for debuggers which single-step through a script. The first step of a
+    /// frame also performs the per-frame updates of `update` (e.g., changes over time). A frame
+    /// ends once a step yields until a later turn (e.g., `<wait>`); the turn of the manager
+    /// should be advanced before stepping further.
+    ///
+    /// Returns `None` if no steps remain or the bullet has vanished.
+    pub fn micro_step(&mut self) -> Result<Option<MicroStep>, data::ExpressionError> {
+        // The reference evaluator only runs whole updates.
+        #[cfg(feature = "reference-check")]
+        {
+            self.reference = None;
+        }
+
+        if !self.in_frame {
+            let (_, runnable) = self.begin_frame();
+            if !runnable {
+                return Ok(None);
+            }
+            self.in_frame = true;
+        }
+
+        self.start_queued();
+        let step = if let Some(step) = self.steps.current() {
+            step.name().into()
+        } else {
+            self.in_frame = false;
+            return Ok(None);
+        };
+        let continues = self.execute_step()?.unwrap_or(false);
+        self.in_frame = continues;
+
+        Ok(Some(MicroStep {
+            step,
+            end_of_frame: !continues,
+        }))
+    }
+
+    /// Prepare for a new frame.
+    ///
+    /// Returns whether any state was updated and whether steps may be executed.
+    fn begin_frame(&mut self) -> (bool, bool) {
+        // Bullets fired after a split continue the indices of the frame.
+        if !mem::take(&mut self.state.frame_split) {
+            self.state.fire_index = 0;
+        }
+
+        if self.state.expired || self.state.vanished || self.state.poisoned {
+            return (false, false);
+        }
+        if self.state.check_deadline() {
+            return (true, false);
+        }
+        if self.state.check_parent() {
+            return (true, false);
+        }
+
+        (self.state.update_functions(), true)
+    }
+
+    /// Execute the current step.
+    ///
+    /// Returns whether execution continues within the current frame or `None` if there are no
+    /// steps left.
+    fn execute_step(&mut self) -> Result<Option<bool>, data::ExpressionError> {
+        self.start_queued();
+
+        // Use the parameters of the innermost action which was given any.
+        self.state.params = self
+            .steps
+            .ancestors()
+            .find_map(|step| {
+                match *step {
+                    NodeStep::Action(_, _, Frame::Bound(ref params)) => Some(params.clone()),
+                    _ => None,
+                }
+            })
+            .unwrap_or_default();
+
+        let continues = {
+            let node = if let Some(node) = self.steps.current_mut() {
+                node
+            } else {
+                return Ok(None);
+            };
+
+            #[cfg(feature = "debug")]
+            {
+                if self.state.history.len() == HISTORY_SIZE {
+                    self.state.history.pop_front();
+                }
+                let turn = self.state.turn();
+                let name = node.as_ref().name().into();
+                self.state.history.push_back((turn, name));
+            }
+
+            if let NodeStep::Action(_, ref tags, ref mut frame) = *node.as_mut() {
+                // Skipped actions do not evaluate their parameters.
+                if self.state.options.runs_tags(tags) {
+                    self.state.bind_params(frame)?;
+                }
+            }
+
+            let status = match node.as_ref() {
+                NodeStep::Root => Status::Continue,
+                NodeStep::Action(ref label, ref tags, _) => {
+                    self.state.run_action(label.as_deref(), tags)
+                },
+                NodeStep::Repeat(ref r) => self.state.run_repeat(r)?,
+                NodeStep::RepeatIteration(ref r, index, count) => {
+                    self.state.run_repeat_iteration(r, *index, *count)?
+                },
+                NodeStep::Fire(ref f, ref params) => self.state.run_fire(f, params.as_deref())?,
+                NodeStep::ChangeSpeed(ref cs) => self.state.run_change_speed(cs)?,
+                NodeStep::ChangeDirection(ref cd) => self.state.run_change_direction(cd)?,
+                NodeStep::Accel(ref a) => self.state.run_accel(a)?,
+                NodeStep::Wait(ref w) => self.state.run_wait(w)?,
+                NodeStep::Vanish(_) => self.state.run_vanish(),
+                NodeStep::Custom(ref c) => self.state.run_custom(c.as_ref()),
+                NodeStep::Ttl(ttl) => self.state.run_ttl(*ttl),
+            };
+
+            match status {
+                Status::End => false,
+                Status::Continue => true,
+                Status::NewSteps(steps) => {
+                    steps.into_iter().for_each(|step| node.add_child(step));
+                    true
+                },
+                Status::Skip => {
+                    node.clear();
+                    true
+                },
+            }
+        };
+
+        if continues {
+            self.steps.next();
+        }
+
+        Ok(Some(continues))
+    }
+
+    /// Whether a panic has stopped the runner.
+    ///
+    /// See `update_isolated`.
+    pub fn is_poisoned(&self) -> bool {
+        self.state.poisoned
+    }
+
+    /// The status of the runner.
+    pub fn status(&self) -> RunnerStatus {
+        let state = &self.state;
+
+        if state.poisoned {
+            RunnerStatus::Poisoned
+        } else if state.expired {
+            RunnerStatus::Expired
+        } else if state.vanished {
+            RunnerStatus::Vanished
+        } else if self.steps.current().is_some()
+            || self.queued.is_some()
+            || state.change_dir.is_some()
+            || state.change_speed.is_some()
+            || state.accel_x.is_some()
+            || state.accel_y.is_some()
+        {
+            RunnerStatus::Running
+        } else {
+            RunnerStatus::Done
+        }
+    }
+
+    /// Whether the runner has nothing left to do.
+    ///
+    /// This is the case once all steps, queued scripts, and changes over time have completed or
+    /// the runner has stopped. Game loops may use this to despawn the entity controlled by the
+    /// runner.
+    pub fn is_done(&self) -> bool {
+        self.status() != RunnerStatus::Running
+    }
+
+    /// A readable dump of the state of the runner.
+    ///
+    /// This is intended to be attached to bug reports, e.g., when `update` returns an error. It
+    /// includes the path to the current step, active changes, and the sequence memory. With the
+    /// `debug` feature, the most recently executed steps are included as well. Sections are
+    /// tagged with the identifiers of the semantic rules which govern them.
+    pub fn diagnostic_dump(&self) -> String {
+        let mut dump = String::new();
+        self.write_dump(&mut dump)
+            .expect("writing to a string should not fail");
+        dump
+    }
+
+    fn write_dump(&self, out: &mut String) -> fmt::Result {
+        fn optional<D>(value: Option<D>) -> String
+        where
+            D: fmt::Display,
+        {
+            value.map_or_else(|| "none".into(), |value| value.to_string())
+        }
+
+        let state = &self.state;
+
+        writeln!(out, "turn: {}", state.turn())?;
+        let path = self.steps.path();
+        if path.is_empty() {
+            writeln!(out, "path: (done)")?;
+        } else {
+            let names = path.iter().map(|step| step.name()).collect::<Vec<_>>();
+            writeln!(out, "path: {}", names.join(" > "))?;
+        }
+        writeln!(out, "changes {}:", Rule::Change)?;
+        writeln!(out, "  direction: {}", optional(state.change_dir.as_ref()))?;
+        writeln!(out, "  speed: {}", optional(state.change_speed.as_ref()))?;
+        writeln!(out, "  accel_x: {}", optional(state.accel_x.as_ref()))?;
+        writeln!(out, "  accel_y: {}", optional(state.accel_y.as_ref()))?;
+        let sequence = state.sequence();
+        let shared = if state.shared_sequence.is_some() {
+            " (shared)"
+        } else {
+            ""
+        };
+        writeln!(out, "sequence {}{}:", Rule::Sequence, shared)?;
+        writeln!(out, "  direction: {}", optional(sequence.direction))?;
+        writeln!(out, "  speed: {}", optional(sequence.speed))?;
+        writeln!(out, "wait until {}: {}", Rule::Wait, optional(state.next))?;
+        writeln!(out, "deadline {}: {}", Rule::Ttl, optional(state.deadline))?;
+        writeln!(out, "expired: {}", state.expired)?;
+        writeln!(out, "vanished {}: {}", Rule::Vanish, state.vanished)?;
+        writeln!(out, "poisoned: {}", state.poisoned)?;
+        let unknown = state.unknown_vars.borrow();
+        if !unknown.is_empty() {
+            let names = unknown.iter().map(String::as_str).collect::<Vec<_>>();
+            writeln!(out, "unknown variables: {}", names.join(", "))?;
+        }
+
+        #[cfg(feature = "debug")]
+        {
+            if !state.shadowed.is_empty() {
+                writeln!(out, "shadowed {}: {}", Rule::Scope, state.shadowed.join(", "))?;
+            }
+            writeln!(out, "recent steps:")?;
+            for &(turn, ref name) in &state.history {
+                writeln!(out, "  {}: {}", turn, name)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use crate::data::{self, Easing, ErrorCode};
+    use crate::run::testing::{self, TestManager};
+    #[cfg(feature = "debug")]
+    use crate::run::Command;
+    use crate::run::{
+        BulletScript, CompiledBulletML, Event, NegativeSpeed, Rng, Runner, RunnerOptions,
+        RunnerStatus, SequenceHandle, UnknownVariablePolicy,
+    };
+
+    fn runner(doc: &str) -> Runner<TestManager> {
+        runner_with_options(doc, RunnerOptions::default())
+    }
+
+    fn runner_with_options(doc: &str, options: RunnerOptions) -> Runner<TestManager> {
+        let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+        Runner::with_options(TestManager::default(), bulletml, options).unwrap()
+    }
+
+    const FIRE_ORDER: &str = r#"<bulletml>
+        <action label="top">
+            <repeat>
+                <times>3</times>
+                <action>
+                    <fire>
+                        <direction type="sequence">10</direction>
+                        <bullet/>
+                    </fire>
+                </action>
+            </repeat>
+            <fire>
+                <direction type="absolute">180</direction>
+                <bullet/>
+            </fire>
+        </action>
+    </bulletml>"#;
+
+    #[test]
+    fn test_fire_order() {
+        let mut runner = runner(FIRE_ORDER);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let observed = Arc::clone(&events);
+        runner.set_observer(move |event: &Event| observed.lock().unwrap().push(*event));
+
+        runner.update().unwrap();
+
+        let fires = events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|event| {
+                if let Event::Fired {
+                    index,
+                    direction,
+                    ..
+                } = *event
+                {
+                    Some((index, direction))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(fires, [(0, 0.), (1, 10.), (2, 20.), (3, 180.)]);
+        assert_eq!(
+            runner.manager().log,
+            [
+                "new_simple(0, 1)",
+                "new_simple(10, 1)",
+                "new_simple(20, 1)",
+                "new_simple(180, 1)",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_quantize() {
+        let options = RunnerOptions {
+            direction_steps: Some(8),
+            speed_fraction_bits: Some(2),
+            ..Default::default()
+        };
+        let mut runner = runner_with_options(FIRE_ORDER, options);
+
+        runner.update().unwrap();
+
+        assert_eq!(
+            runner.manager().log,
+            [
+                "new_simple(0, 1)",
+                "new_simple(0, 1)",
+                "new_simple(0, 1)",
+                "new_simple(180, 1)",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_inherit_velocity() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <fire>
+                    <direction type="absolute">0</direction>
+                    <speed>1</speed>
+                    <bullet/>
+                </fire>
+                <fire>
+                    <direction type="sequence">0</direction>
+                    <speed type="sequence">1</speed>
+                    <bullet/>
+                </fire>
+            </action>
+        </bulletml>"#;
+        let options = RunnerOptions {
+            inherit_velocity: true,
+            ..Default::default()
+        };
+        let mut runner = runner_with_options(doc, options);
+        runner.manager_mut().speed_y = -1.;
+
+        runner.update().unwrap();
+
+        // The sequence continues from the speed given by the script.
+        assert_eq!(
+            runner.manager().log,
+            ["new_simple(0, 2)", "new_simple(0, 3)"],
+        );
+    }
+
+    #[test]
+    fn test_lead_target() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <fire>
+                    <direction lead="true">0</direction>
+                    <speed>2</speed>
+                    <bullet/>
+                </fire>
+                <fire>
+                    <direction type="sequence" lead="true">0</direction>
+                    <speed>2</speed>
+                    <bullet/>
+                </fire>
+                <fire>
+                    <direction>0</direction>
+                    <speed>2</speed>
+                    <bullet/>
+                </fire>
+            </action>
+        </bulletml>"#;
+        let options = RunnerOptions {
+            direction_steps: Some(360),
+            ..Default::default()
+        };
+        let mut runner = runner_with_options(doc, options);
+        runner.manager_mut().aim = 90.;
+        runner.manager_mut().target_velocity = Some((0., -1.));
+
+        runner.update().unwrap();
+
+        // Only aimed directions lead the target, but sequences continue from led bullets.
+        assert_eq!(
+            runner.manager().log,
+            ["new_simple(60, 2)", "new_simple(60, 2)", "new_simple(90, 2)"],
+        );
+    }
+
+    #[test]
+    fn test_variable_indices() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <fire>
+                    <direction type="absolute">$angle</direction>
+                    <speed>$speed * $angle</speed>
+                    <bullet/>
+                </fire>
+            </action>
+        </bulletml>"#;
+        let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+        let compiled = CompiledBulletML::new(bulletml).unwrap();
+        assert_eq!(compiled.variables(), ["angle", "speed"]);
+
+        let manager = TestManager {
+            variables: vec![("angle", 90.), ("speed", 0.5)],
+            ..Default::default()
+        };
+        let mut runner = Runner::from_compiled(manager, &compiled, RunnerOptions::default());
+        runner.update().unwrap();
+
+        assert_eq!(runner.manager().log, ["new_simple(90, 45)"]);
+    }
+
+    #[test]
+    fn test_unknown_variable() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <fire>
+                    <direction type="absolute">90 + $engine_angle</direction>
+                    <bullet/>
+                </fire>
+            </action>
+        </bulletml>"#;
+
+        let err = runner(doc).update().unwrap_err();
+        assert_eq!(err.to_string(), "BML3001: undefined variable `engine_angle`");
+
+        let options = RunnerOptions {
+            unknown_variable: UnknownVariablePolicy::Zero,
+            ..Default::default()
+        };
+        let mut runner = runner_with_options(doc, options);
+        runner.update().unwrap();
+
+        assert_eq!(runner.manager().log, ["new_simple(90, 1)"]);
+        assert_eq!(runner.unknown_variables(), ["engine_angle"]);
+        assert!(runner
+            .diagnostic_dump()
+            .contains("unknown variables: engine_angle\n"));
+    }
+
+    #[test]
+    fn test_params() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <actionRef label="spread">
+                    <param>90</param>
+                    <param>2</param>
+                </actionRef>
+            </action>
+            <action label="spread">
+                <fireRef label="shot">
+                    <param>$1</param>
+                    <param>$2 * 2</param>
+                </fireRef>
+                <action>
+                    <fire>
+                        <direction type="absolute">$1 + 10</direction>
+                        <bulletRef label="slow">
+                            <param>$2</param>
+                        </bulletRef>
+                    </fire>
+                </action>
+            </action>
+            <fire label="shot">
+                <direction type="absolute">$1</direction>
+                <bullet>
+                    <speed>$2</speed>
+                </bullet>
+            </fire>
+            <bullet label="slow">
+                <speed>$1</speed>
+            </bullet>
+        </bulletml>"#;
+        let mut runner = runner(doc);
+
+        runner.update().unwrap();
+
+        assert_eq!(
+            runner.manager().log,
+            ["new_simple(90, 4)", "new_simple(100, 2)"],
+        );
+    }
+
+    #[test]
+    fn test_missing_param() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <actionRef label="aimed"/>
+            </action>
+            <action label="aimed">
+                <fire>
+                    <direction>$1</direction>
+                    <bullet/>
+                </fire>
+            </action>
+        </bulletml>"#;
+
+        let err = runner(doc).update().unwrap_err();
+        assert_eq!(err.to_string(), "BML3002: missing parameter `1`");
+    }
+
+    #[test]
+    fn test_update_isolated() {
+        let mut runner = runner(FIRE_ORDER);
+        runner.manager_mut().panic_on_fire = true;
+
+        let err = runner.update_isolated().unwrap_err();
+        assert_eq!(err.to_string(), "panic during update: new_simple(0, 1)");
+        assert!(runner.is_poisoned());
+        assert!(runner.diagnostic_dump().contains("poisoned: true\n"));
+
+        // The runner no longer executes steps.
+        runner.manager_mut().panic_on_fire = false;
+        let err = runner.update_isolated().unwrap_err();
+        assert_eq!(err.to_string(), "runner is poisoned by an earlier panic");
+        assert!(!runner.update().unwrap().updated);
+        assert!(runner.manager().log.is_empty());
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let compile = |doc| {
+            let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+            CompiledBulletML::new(bulletml).unwrap_err()
+        };
+
+        let err = compile(
+            r#"<bulletml>
+                <action label="top"><actionRef label="missing"/></action>
+            </bulletml>"#,
+        );
+        assert_eq!(err.code(), ErrorCode::UnknownReference);
+
+        let err = compile(
+            r#"<bulletml>
+                <bullet label="dup"/>
+                <bullet label="dup"/>
+            </bulletml>"#,
+        );
+        assert_eq!(err.code(), ErrorCode::DuplicateLabel);
+    }
+
+    #[test]
+    fn test_step_budget() {
+        let options = RunnerOptions {
+            step_budget: Some(4),
+            ..Default::default()
+        };
+        let mut budgeted = runner_with_options(FIRE_ORDER, options);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let observed = Arc::clone(&events);
+        budgeted.set_observer(move |event: &Event| observed.lock().unwrap().push(*event));
+
+        let report = budgeted.update().unwrap();
+        assert!(report.updated);
+        assert_eq!(report.steps, 4);
+        assert!(report.budget_exhausted);
+        assert!(budgeted.manager().log.len() < 4);
+
+        let mut steps = report.steps;
+        loop {
+            let report = budgeted.update().unwrap();
+            steps += report.steps;
+            if !report.budget_exhausted {
+                break;
+            }
+        }
+
+        let unbudgeted = runner(FIRE_ORDER).update().unwrap();
+        assert!(!unbudgeted.budget_exhausted);
+        assert_eq!(steps, unbudgeted.steps);
+        assert_eq!(
+            budgeted.manager().log,
+            [
+                "new_simple(0, 1)",
+                "new_simple(10, 1)",
+                "new_simple(20, 1)",
+                "new_simple(180, 1)",
+            ],
+        );
+
+        // Indices continue across the updates of a split frame.
+        let indices = events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|event| {
+                if let Event::Fired {
+                    index, ..
+                } = *event
+                {
+                    Some(index)
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(indices, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_huge_repeat() {
+        let mut runner = runner(
+            r#"<bulletml>
+                <action label="top">
+                    <repeat>
+                        <times>1000000000000000000</times>
+                        <action>
+                            <fire><bullet/></fire>
+                            <wait>1</wait>
+                        </action>
+                    </repeat>
+                </action>
+            </bulletml>"#,
+        );
+
+        // Iterations are expanded lazily rather than allocated all at once.
+        for turn in 0..3 {
+            runner.manager_mut().turn = turn;
+            runner.update().unwrap();
+        }
+        assert_eq!(runner.manager().log.len(), 3);
+    }
+
+    fn trace(doc: &str, frames: u32) -> Vec<String> {
+        trace_with_options(doc, frames, RunnerOptions::default())
+    }
+
+    fn trace_with_options(doc: &str, frames: u32, options: RunnerOptions) -> Vec<String> {
+        trace_runner(runner_with_options(doc, options), frames)
+    }
+
+    fn trace_runner(mut runner: Runner<TestManager>, frames: u32) -> Vec<String> {
+        let mut trace = Vec::new();
+
+        for turn in 0..frames {
+            runner.manager_mut().turn = turn;
+            runner.update().unwrap();
+            trace.extend(
+                runner
+                    .manager_mut()
+                    .log
+                    .drain(..)
+                    .map(|cmd| format!("{}: {}", turn, cmd)),
+            );
+        }
+
+        trace
+    }
+
+    const TOP_ACTIONS: &str = r#"<bulletml>
+        <action label="top1">
+            <fire>
+                <direction type="absolute">0</direction>
+                <bullet/>
+            </fire>
+            <wait>2</wait>
+            <fire>
+                <direction type="absolute">0</direction>
+                <bullet/>
+            </fire>
+        </action>
+        <action label="top2">
+            <fire>
+                <direction type="absolute">90</direction>
+                <bullet/>
+            </fire>
+        </action>
+    </bulletml>"#;
+
+    #[test]
+    fn test_top_actions() {
+        let bulletml: data::BulletML = serde_xml_rs::from_str(TOP_ACTIONS).unwrap();
+        let compiled = CompiledBulletML::new(bulletml).unwrap();
+        assert_eq!(compiled.top_actions().collect::<Vec<_>>(), ["top1", "top2"]);
+
+        // A single runner runs the top actions one after another.
+        assert_eq!(
+            trace(TOP_ACTIONS, 4),
+            [
+                "0: new_simple(0, 1)",
+                "2: new_simple(0, 1)",
+                "2: new_simple(90, 1)",
+            ],
+        );
+
+        // Runners for each top action run them in parallel.
+        let runners = Runner::for_top_actions(&compiled, RunnerOptions::default(), |_| {
+            TestManager::default()
+        });
+        let traces = runners
+            .into_iter()
+            .map(|runner| trace_runner(runner, 4))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            traces,
+            [
+                vec!["0: new_simple(0, 1)", "2: new_simple(0, 1)"],
+                vec!["0: new_simple(90, 1)"],
+            ],
+        );
+
+        assert!(Runner::from_top_action(
+            TestManager::default(),
+            &compiled,
+            "top3",
+            RunnerOptions::default(),
+        )
+        .is_none());
+    }
+
+    fn queue_doc(direction: &str) -> String {
+        format!(
+            r#"<bulletml>
+                <action label="top">
+                    <fire>
+                        <direction type="{}">90</direction>
+                        <bullet/>
+                    </fire>
+                    <wait>2</wait>
+                </action>
+            </bulletml>"#,
+            direction,
+        )
+    }
+
+    #[test]
+    fn test_queue_next() {
+        let compile = |doc: &str| {
+            let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+            CompiledBulletML::new(bulletml).unwrap()
+        };
+        let first = compile(&queue_doc("absolute"));
+        let second = compile(&queue_doc("sequence"));
+
+        let mut runner =
+            Runner::from_compiled(TestManager::default(), &first, RunnerOptions::default());
+        assert!(!runner.queue_next(&second, "missing", true));
+        assert!(!runner.has_queued());
+        assert!(runner.queue_next(&second, "top", true));
+        assert!(runner.has_queued());
+        assert_eq!(
+            trace_runner(runner, 6),
+            ["0: new_simple(90, 1)", "2: new_simple(180, 1)"],
+        );
+
+        // Without the sequence memory, the first bullet aims at the target.
+        let mut runner =
+            Runner::from_compiled(TestManager::default(), &first, RunnerOptions::default());
+        runner.queue_next(&second, "top", false);
+        assert_eq!(
+            trace_runner(runner, 6),
+            ["0: new_simple(90, 1)", "2: new_simple(0, 1)"],
+        );
+    }
+
+    #[test]
+    fn test_share_sequence_state() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <repeat>
+                    <times>2</times>
+                    <action>
+                        <fire>
+                            <direction type="sequence">10</direction>
+                            <speed type="sequence">1</speed>
+                            <bullet/>
+                        </fire>
+                        <wait>1</wait>
+                    </action>
+                </repeat>
+            </action>
+        </bulletml>"#;
+        let handle = SequenceHandle::new();
+        let mut turrets = (0..2)
+            .map(|_| {
+                let mut turret = runner(doc);
+                turret.share_sequence_state(handle.clone());
+                turret
+            })
+            .collect::<Vec<_>>();
+
+        for turn in 0..2 {
+            for turret in &mut turrets {
+                turret.manager_mut().turn = turn;
+                turret.update().unwrap();
+            }
+        }
+
+        // Each turret continues from the bullet fired by the other.
+        assert_eq!(
+            turrets[0].manager().log,
+            ["new_simple(0, 1)", "new_simple(20, 3)"],
+        );
+        assert_eq!(
+            turrets[1].manager().log,
+            ["new_simple(10, 2)", "new_simple(30, 4)"],
+        );
+        assert_eq!(handle.direction(), Some(30.));
+        assert_eq!(handle.speed(), Some(4.));
+        assert!(turrets[0].sequence_state().unwrap().same_as(&handle));
+        assert!(turrets[0]
+            .diagnostic_dump()
+            .contains("sequence [SEQ-1] (shared):\n  direction: 30\n"));
+
+        handle.reset();
+        assert_eq!(handle.direction(), None);
+        assert!(!SequenceHandle::new().same_as(&handle));
+    }
+
+    fn orientation_doc(orientation: &str) -> String {
+        format!(
+            r#"<bulletml type="{}">
+                <action label="top">
+                    <fire>
+                        <direction type="absolute">90</direction>
+                        <speed>2</speed>
+                        <bullet/>
+                    </fire>
+                    <changeDirection>
+                        <direction type="absolute">180</direction>
+                        <term>2</term>
+                    </changeDirection>
+                    <accel>
+                        <horizontal>2</horizontal>
+                        <vertical>1</vertical>
+                        <term>2</term>
+                    </accel>
+                </action>
+            </bulletml>"#,
+            orientation,
+        )
+    }
+
+    #[test]
+    fn test_golden_horizontal() {
+        testing::assert_trace_eq(
+            &trace(&orientation_doc("horizontal"), 4),
+            &[
+                "0: new_simple(0, 2)",
+                "1: change_direction(45)",
+                "1: accel_x(0.5)",
+                "1: accel_y(1)",
+                "2: change_direction(90)",
+                "2: accel_x(1)",
+                "2: accel_y(2)",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_orientation_override() {
+        let bulletml: data::BulletML =
+            serde_xml_rs::from_str(&orientation_doc("vertical")).unwrap();
+        let runner = Runner::new_with_orientation(
+            TestManager::default(),
+            bulletml,
+            data::Orientation::Horizontal,
+        )
+        .unwrap();
+
+        assert_eq!(
+            trace_runner(runner, 4),
+            trace(&orientation_doc("horizontal"), 4),
+        );
+    }
+
+    #[test]
+    fn test_golden_vertical() {
+        testing::assert_trace_eq(
+            &trace(&orientation_doc("vertical"), 4),
+            &[
+                "0: new_simple(90, 2)",
+                "1: change_direction(90)",
+                "1: accel_x(1)",
+                "1: accel_y(0.5)",
+                "2: change_direction(180)",
+                "2: accel_x(2)",
+                "2: accel_y(1)",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_change_speed_cancel() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <changeSpeed>
+                    <speed type="absolute">4</speed>
+                    <term>4</term>
+                </changeSpeed>
+                <wait>2</wait>
+                <changeSpeed>
+                    <speed type="absolute">0</speed>
+                    <term>2</term>
+                </changeSpeed>
+            </action>
+        </bulletml>"#;
+
+        assert_eq!(
+            trace(doc, 6),
+            [
+                "1: change_speed(1)",
+                "2: change_speed(2)",
+                "3: change_speed(1)",
+                "4: change_speed(0)",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_negative_speed() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <fire>
+                    <direction type="absolute">90</direction>
+                    <speed>-1</speed>
+                    <bullet/>
+                </fire>
+                <changeSpeed>
+                    <speed type="absolute">-2</speed>
+                    <term>2</term>
+                </changeSpeed>
+            </action>
+        </bulletml>"#;
+        let trace_policy = |policy| {
+            let options = RunnerOptions {
+                negative_speed: policy,
+                ..Default::default()
+            };
+            trace_with_options(doc, 4, options)
+        };
+
+        assert_eq!(
+            trace_policy(NegativeSpeed::Allow),
+            [
+                "0: new_simple(90, -1)",
+                "1: change_speed(-1)",
+                "2: change_speed(-2)",
+            ],
+        );
+        assert_eq!(
+            trace_policy(NegativeSpeed::ReflectDirection),
+            [
+                "0: new_simple(270, 1)",
+                "1: change_direction(180)",
+                "1: change_speed(1)",
+                "2: change_speed(2)",
+            ],
+        );
+        assert_eq!(
+            trace_policy(NegativeSpeed::ClampZero),
+            [
+                "0: new_simple(90, 0)",
+                "1: change_speed(0)",
+                "2: change_speed(0)",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_bullet_script() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <fire>
+                    <direction type="absolute">90</direction>
+                    <bulletRef label="split">
+                        <param>45</param>
+                    </bulletRef>
+                </fire>
+            </action>
+            <bullet label="split">
+                <action>
+                    <wait>1</wait>
+                    <fire>
+                        <direction type="relative">$1</direction>
+                        <bullet/>
+                    </fire>
+                    <vanish/>
+                </action>
+            </bullet>
+        </bulletml>"#;
+        let mut runner = runner(doc);
+        runner.update().unwrap();
+        assert_eq!(runner.manager().log, ["new_bullet(90, 1)"]);
+
+        let script = runner.manager_mut().scripts.pop().unwrap();
+        let id = script.id();
+        assert_eq!(id.get(), 0);
+        let mut child = script.runner(TestManager {
+            direction: 90.,
+            ..Default::default()
+        });
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let observed = Arc::clone(&events);
+        child.set_observer(move |event: &Event| observed.lock().unwrap().push(*event));
+
+        assert_eq!(
+            trace_runner(child, 2),
+            ["1: new_simple(135, 1)", "1: vanish"],
+        );
+        // Events from the child runner come from the fired bullet.
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|event| {
+            match *event {
+                Event::Fired {
+                    source, ..
+                }
+                | Event::Vanished {
+                    source,
+                } => source == Some(id),
+            }
+        }));
+    }
+
+    #[test]
+    fn test_rng() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <repeat>
+                    <times>4</times>
+                    <action>
+                        <fire>
+                            <direction type="absolute">$rand * 360</direction>
+                            <bullet>
+                                <action>
+                                    <fire>
+                                        <direction type="absolute">$rand * 360</direction>
+                                        <bullet/>
+                                    </fire>
+                                </action>
+                            </bullet>
+                        </fire>
+                    </action>
+                </repeat>
+            </action>
+        </bulletml>"#;
+        let seeded = |seed| {
+            let mut runner = runner(doc);
+            runner.set_rng(Rng::new(seed));
+            runner.record_rand();
+            runner.update().unwrap();
+            runner
+        };
+
+        let mut lhs = seeded(1);
+        let mut rhs = seeded(1);
+        assert_eq!(lhs.manager().log, rhs.manager().log);
+        assert_ne!(lhs.manager().log, seeded(2).manager().log);
+
+        // The manager is not asked for random values.
+        let mut unseeded = runner(doc);
+        unseeded.update().unwrap();
+        assert_ne!(lhs.manager().log, unseeded.manager().log);
+
+        // Bullets are given generators split from the runner which fired them.
+        let lhs_script = lhs.manager_mut().scripts.pop().unwrap();
+        let rhs_script = rhs.manager_mut().scripts.pop().unwrap();
+        assert_eq!(
+            trace_runner(lhs_script.runner(TestManager::default()), 1),
+            trace_runner(rhs_script.runner(TestManager::default()), 1),
+        );
+
+        let recording = lhs.rand_recording().unwrap();
+        assert_eq!(recording.values.len(), 4);
+        let mut replayed = runner(doc);
+        replayed.replay_rand(recording);
+        replayed.update().unwrap();
+        assert_eq!(lhs.manager().log, replayed.manager().log);
+    }
+
+    #[test]
+    fn test_rng_isolated() {
+        const SCRIPTED: &str = r#"<bullet>
+            <action>
+                <fire>
+                    <direction type="absolute">$rand * 360</direction>
+                    <bullet/>
+                </fire>
+            </action>
+        </bullet>"#;
+        let seeded = |direction: &str, bullet: &str| {
+            let doc = format!(
+                r#"<bulletml>
+                    <action label="top">
+                        <fire>
+                            <direction type="absolute">{}</direction>
+                            {}
+                        </fire>
+                        <fire>
+                            <direction type="absolute">$rand * 360</direction>
+                            <bullet/>
+                        </fire>
+                    </action>
+                </bulletml>"#,
+                direction, bullet,
+            );
+            let mut runner = runner(&doc);
+            runner.set_rng(Rng::new(1));
+            runner.update().unwrap();
+            runner
+        };
+
+        // Using random values before firing does not change the values of the bullet.
+        let mut constant = seeded("90", SCRIPTED);
+        let mut random = seeded("$rand * 360", SCRIPTED);
+        let constant_script = constant.manager_mut().scripts.pop().unwrap();
+        let random_script = random.manager_mut().scripts.pop().unwrap();
+        assert_eq!(
+            trace_runner(constant_script.runner(TestManager::default()), 1),
+            trace_runner(random_script.runner(TestManager::default()), 1),
+        );
+
+        // Firing a bullet with actions does not change the values of the runner.
+        let simple = seeded("$rand * 360", "<bullet/>");
+        assert_eq!(simple.manager().log[1], random.manager().log[1]);
+    }
+
+    fn assert_send<T>()
+    where
+        T: Send,
+    {
+    }
+
+    #[test]
+    fn test_send() {
+        // Runners may be moved to worker threads along with their managers.
+        fn assert_runner_send<T>()
+        where
+            T: Send,
+        {
+            assert_send::<Runner<T>>();
+        }
+
+        assert_runner_send::<TestManager>();
+        assert_send::<BulletScript>();
+    }
+
+    #[test]
+    fn test_tags() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <action tags="easy">
+                    <fire>
+                        <direction type="absolute">0</direction>
+                        <bullet/>
+                    </fire>
+                </action>
+                <actionRef label="hard"/>
+                <fire>
+                    <direction type="absolute">180</direction>
+                    <bullet/>
+                </fire>
+            </action>
+            <action label="hard" tags="hard, boss">
+                <fire>
+                    <direction type="absolute">90</direction>
+                    <bullet/>
+                </fire>
+            </action>
+        </bulletml>"#;
+        let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+        let compiled = CompiledBulletML::new(bulletml).unwrap();
+        assert_eq!(compiled.tags(), ["boss", "easy", "hard"]);
+
+        let trace_tags = |tags: Option<&[&str]>| {
+            let options = RunnerOptions {
+                tags: tags.map(|tags| tags.iter().map(|&tag| tag.into()).collect()),
+                ..Default::default()
+            };
+            trace_with_options(doc, 1, options)
+        };
+
+        assert_eq!(
+            trace_tags(None),
+            [
+                "0: new_simple(0, 1)",
+                "0: new_simple(90, 1)",
+                "0: new_simple(180, 1)",
+            ],
+        );
+        assert_eq!(
+            trace_tags(Some(&["easy"])),
+            ["0: new_simple(0, 1)", "0: new_simple(180, 1)"],
+        );
+        assert_eq!(
+            trace_tags(Some(&["boss"])),
+            ["0: new_simple(90, 1)", "0: new_simple(180, 1)"],
+        );
+        assert_eq!(trace_tags(Some(&[])), ["0: new_simple(180, 1)"]);
+    }
+
+    #[test]
+    fn test_change_direction_cancel() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <changeDirection>
+                    <direction type="absolute">270</direction>
+                    <term>3</term>
+                </changeDirection>
+                <wait>2</wait>
+                <changeDirection>
+                    <direction type="relative">90</direction>
+                    <term>2</term>
+                </changeDirection>
+            </action>
+        </bulletml>"#;
+
+        assert_eq!(
+            trace(doc, 6),
+            [
+                "1: change_direction(-30)",
+                "2: change_direction(-60)",
+                "3: change_direction(-15)",
+                "4: change_direction(30)",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_diagnostic_dump() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <changeSpeed>
+                    <speed type="absolute">4</speed>
+                    <term>4</term>
+                </changeSpeed>
+                <fire>
+                    <direction type="absolute">$missing</direction>
+                    <bullet/>
+                </fire>
+            </action>
+        </bulletml>"#;
+        let mut runner = runner(doc);
+
+        runner.update().unwrap_err();
+
+        let dump = runner.diagnostic_dump();
+        assert!(dump.contains("turn: 0\n"), "{}", dump);
+        assert!(dump.contains("path: bulletml > action > fire\n"), "{}", dump);
+        assert!(
+            dump.contains("  speed: 0 -> 4 over turns 0..4\n"),
+            "{}",
+            dump,
+        );
+        assert!(dump.contains("  direction: none\n"), "{}", dump);
+        assert!(dump.contains("changes [CHANGE-1]:\n"), "{}", dump);
+        #[cfg(feature = "debug")]
+        assert!(
+            dump.contains("recent steps:\n  0: bulletml\n  0: action\n  0: changeSpeed\n  0: fire\n"),
+            "{}",
+            dump,
+        );
+    }
+
+    #[cfg(feature = "debug")]
+    #[test]
+    fn test_rebuild_at() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <repeat>
+                    <times>3</times>
+                    <action>
+                        <fire>
+                            <direction type="absolute">10</direction>
+                            <bullet/>
+                        </fire>
+                        <wait>1</wait>
+                    </action>
+                </repeat>
+            </action>
+        </bulletml>"#;
+        let mut runner = runner(doc);
+        assert_eq!(runner.rebuild_at(0).map(|manager| manager.log), None);
+        runner.record_history(None);
+
+        for turn in 0..3 {
+            runner.manager_mut().turn = turn;
+            runner.update().unwrap();
+        }
+
+        let history = runner.command_history().unwrap();
+        assert_eq!(
+            history.commands().collect::<Vec<_>>(),
+            [
+                &(0, Command::NewSimple(10., 1.)),
+                &(1, Command::NewSimple(10., 1.)),
+                &(2, Command::NewSimple(10., 1.)),
+            ],
+        );
+        assert_eq!(
+            runner.rebuild_at(1).unwrap().log,
+            ["new_simple(10, 1)", "new_simple(10, 1)"],
+        );
+        assert_eq!(runner.rebuild_at(5).unwrap().log.len(), 3);
+
+        // Limited histories keep the base just before the remaining commands.
+        let mut limited = self::runner(doc);
+        limited.record_history(Some(1));
+        for turn in 0..3 {
+            limited.manager_mut().turn = turn;
+            limited.update().unwrap();
+        }
+
+        assert_eq!(limited.command_history().unwrap().dropped(), 2);
+        assert!(limited.rebuild_at(0).is_none());
+        assert_eq!(limited.rebuild_at(1).unwrap().log.len(), 2);
+        assert_eq!(limited.rebuild_at(2).unwrap().log.len(), 3);
+    }
+
+    #[test]
+    fn test_update_dt() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <wait>60</wait>
+                <fire>
+                    <bullet/>
+                </fire>
+            </action>
+        </bulletml>"#;
+        let mut runner = runner(doc);
+
+        // Partial frames carry over to the next update.
+        assert_eq!(runner.update_dt(0.5 / 60.).unwrap().steps, 0);
+        for _ in 0..30 {
+            runner.update_dt(1. / 30.).unwrap();
+        }
+        assert!(runner.manager().log.is_empty());
+        runner.update_dt(1. / 60.).unwrap();
+        assert_eq!(runner.manager().log, ["new_simple(0, 1)"]);
+        assert_eq!(runner.manager().turn, 0);
+
+        let options = RunnerOptions {
+            frames_per_second: Some(120.),
+            ..Default::default()
+        };
+        let mut runner = runner_with_options(doc, options);
+        let report = runner.update_dt(0.5).unwrap();
+        assert!(report.updated);
+        assert!(runner.manager().log.is_empty());
+        runner.update_dt(1. / 120.).unwrap();
+        assert_eq!(runner.manager().log, ["new_simple(0, 1)"]);
+    }
+
+    #[test]
+    fn test_status() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <changeSpeed>
+                    <speed>2</speed>
+                    <term>3</term>
+                </changeSpeed>
+                <wait>1</wait>
+            </action>
+        </bulletml>"#;
+        let mut runner = runner(doc);
+        assert_eq!(runner.status(), RunnerStatus::Running);
+
+        let statuses = (0..4)
+            .map(|turn| {
+                runner.manager_mut().turn = turn;
+                runner.update().unwrap().status
+            })
+            .collect::<Vec<_>>();
+        // The change of speed continues after the last step.
+        assert_eq!(
+            statuses,
+            [
+                RunnerStatus::Running,
+                RunnerStatus::Running,
+                RunnerStatus::Running,
+                RunnerStatus::Done,
+            ],
+        );
+        assert!(runner.is_done());
+
+        let options = RunnerOptions {
+            default_ttl: Some(1),
+            ..Default::default()
+        };
+        let mut runner = runner_with_options(doc, options);
+        runner.update().unwrap();
+        runner.manager_mut().turn = 1;
+        assert_eq!(runner.update().unwrap().status, RunnerStatus::Expired);
+        assert!(runner.is_done());
+    }
+
+    #[test]
+    fn test_ttl() {
+        let doc = r#"<bulletml>
+            <action label="top" ttl="3">
+                <fire>
+                    <bullet ttl="2">
+                        <action>
+                            <wait>10</wait>
+                        </action>
+                    </bullet>
+                </fire>
+                <wait>10</wait>
+            </action>
+        </bulletml>"#;
+        let mut runner = runner(doc);
+        runner.update().unwrap();
+
+        // The bullet expires relative to the turn it was fired.
+        let mut child = runner.manager_mut().scripts.remove(0).runner(TestManager {
+            turn: 1,
+            ..Default::default()
+        });
+        assert_eq!(child.update().unwrap().status, RunnerStatus::Running);
+        child.manager_mut().turn = 2;
+        assert_eq!(child.update().unwrap().status, RunnerStatus::Expired);
+        assert_eq!(child.manager().log, ["vanish"]);
+
+        let statuses = (1..4)
+            .map(|turn| {
+                runner.manager_mut().turn = turn;
+                runner.update().unwrap().status
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            [
+                RunnerStatus::Running,
+                RunnerStatus::Running,
+                RunnerStatus::Expired,
+            ],
+        );
+
+        // The default applies to the runners of fired bullets from their first update, but the
+        // `ttl` of a bullet takes effect if it expires sooner.
+        let options = RunnerOptions {
+            default_ttl: Some(5),
+            ..Default::default()
+        };
+        let mut runner = runner_with_options(doc, options.clone());
+        runner.update().unwrap();
+        let script = runner.manager_mut().scripts.remove(0);
+        let mut child = script.runner(TestManager::default());
+        child.update().unwrap();
+        child.manager_mut().turn = 2;
+        assert_eq!(child.update().unwrap().status, RunnerStatus::Expired);
+
+        let doc = doc.replace(" ttl=\"2\"", "");
+        let mut runner = runner_with_options(&doc, options);
+        runner.update().unwrap();
+        let script = runner.manager_mut().scripts.remove(0);
+        let mut child = script.runner(TestManager {
+            turn: 1,
+            ..Default::default()
+        });
+        child.update().unwrap();
+        child.manager_mut().turn = 5;
+        assert_eq!(child.update().unwrap().status, RunnerStatus::Running);
+        child.manager_mut().turn = 6;
+        assert_eq!(child.update().unwrap().status, RunnerStatus::Expired);
+    }
+
+    #[test]
+    fn test_vanish() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <changeDirection>
+                    <direction type="absolute">90</direction>
+                    <term>4</term>
+                </changeDirection>
+                <wait>1</wait>
+                <vanish/>
+                <fire>
+                    <bullet/>
+                </fire>
+            </action>
+        </bulletml>"#;
+        let mut runner = runner(doc);
+
+        let statuses = (0..4)
+            .map(|turn| {
+                runner.manager_mut().turn = turn;
+                runner.update().unwrap().status
+            })
+            .collect::<Vec<_>>();
+        // The change of direction is cancelled and later steps do not run.
+        assert_eq!(
+            statuses,
+            [
+                RunnerStatus::Running,
+                RunnerStatus::Vanished,
+                RunnerStatus::Vanished,
+                RunnerStatus::Vanished,
+            ],
+        );
+        assert_eq!(runner.manager().log, ["change_direction(22.5)", "vanish"]);
+        assert!(runner.is_done());
+        assert!(runner
+            .diagnostic_dump()
+            .contains("vanished [VANISH-1]: true\n"));
+    }
+
+    #[test]
+    fn test_vanish_children() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <fire>
+                    <bullet>
+                        <action>
+                            <wait>10</wait>
+                        </action>
+                    </bullet>
+                </fire>
+                <wait>1</wait>
+                <vanish/>
+            </action>
+        </bulletml>"#;
+
+        for &vanish_children in &[false, true] {
+            let options = RunnerOptions {
+                vanish_children,
+                ..Default::default()
+            };
+            let mut parent = runner_with_options(doc, options);
+            parent.update().unwrap();
+            let script = parent.manager_mut().scripts.pop().unwrap();
+            let mut child = script.runner(TestManager::default());
+
+            child.update().unwrap();
+            parent.manager_mut().turn = 1;
+            parent.update().unwrap();
+            assert_eq!(parent.status(), RunnerStatus::Vanished);
+
+            child.manager_mut().turn = 1;
+            child.update().unwrap();
+            if vanish_children {
+                assert_eq!(child.manager().log, ["vanish"]);
+                assert_eq!(child.status(), RunnerStatus::Vanished);
+            } else {
+                assert!(child.manager().log.is_empty());
+                assert_eq!(child.status(), RunnerStatus::Running);
+            }
+        }
+    }
+
+    #[test]
+    fn test_micro_step() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <fire>
+                    <bullet/>
+                </fire>
+                <wait>1</wait>
+                <vanish/>
+            </action>
+        </bulletml>"#;
+        let mut runner = runner(doc);
+
+        let mut steps = Vec::new();
+        for turn in 0..2 {
+            runner.manager_mut().turn = turn;
+            while let Some(step) = runner.micro_step().unwrap() {
+                let end_of_frame = step.end_of_frame;
+                steps.push(format!("{}: {}", turn, step.step));
+                if end_of_frame {
+                    break;
+                }
+            }
+        }
+
+        assert_eq!(
+            steps,
+            [
+                "0: bulletml",
+                "0: action",
+                "0: fire",
+                "0: wait",
+                "1: wait",
+                "1: vanish",
+            ],
+        );
+        assert_eq!(runner.manager().log, ["new_simple(0, 1)", "vanish"]);
+    }
+
+    #[test]
+    fn test_change_speed_large_turn() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <changeSpeed>
+                    <speed type="absolute">3</speed>
+                    <term>3</term>
+                </changeSpeed>
+            </action>
+        </bulletml>"#;
+        let mut runner = runner(doc);
+        let base = (1 << 24) * 6 + 7;
+
+        for turn in base..base + 5 {
+            runner.manager_mut().turn = turn;
+            runner.update().unwrap();
+        }
+
+        assert_eq!(
+            runner.manager().log,
+            ["change_speed(1)", "change_speed(2)", "change_speed(3)"],
+        );
+    }
+
+    #[test]
+    fn test_easing() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <changeSpeed easing="easeIn">
+                    <speed type="absolute">16</speed>
+                    <term>4</term>
+                </changeSpeed>
+                <changeDirection>
+                    <direction type="absolute">40</direction>
+                    <term>4</term>
+                </changeDirection>
+            </action>
+        </bulletml>"#;
+        let options = RunnerOptions {
+            easing: Easing::EaseOut,
+            ..Default::default()
+        };
+        let mut runner = runner_with_options(doc, options);
+
+        for turn in 0..5 {
+            runner.manager_mut().turn = turn;
+            runner.update().unwrap();
+        }
+
+        // The change of speed gives its own easing; the change of direction uses the default.
+        assert_eq!(
+            runner.manager().log,
+            [
+                "change_direction(17.5)",
+                "change_speed(1)",
+                "change_direction(30)",
+                "change_speed(4)",
+                "change_direction(37.5)",
+                "change_speed(9)",
+                "change_direction(40)",
+                "change_speed(16)",
+            ],
+        );
+    }
+
+    fn wait_doc(wait: &str) -> String {
+        format!(
+            r#"<bulletml>
+                <action label="top">
+                    <repeat>
+                        <times>4</times>
+                        <action>
+                            <fire>
+                                <direction type="absolute">0</direction>
+                                <bullet/>
+                            </fire>
+                            <wait>{}</wait>
+                        </action>
+                    </repeat>
+                </action>
+            </bulletml>"#,
+            wait,
+        )
+    }
+
+    #[test]
+    fn test_wait() {
+        assert_eq!(
+            trace(&wait_doc("2"), 8),
+            [
+                "0: new_simple(0, 1)",
+                "2: new_simple(0, 1)",
+                "4: new_simple(0, 1)",
+                "6: new_simple(0, 1)",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_wait_fractional() {
+        assert_eq!(
+            trace(&wait_doc("0.5"), 4),
+            [
+                "0: new_simple(0, 1)",
+                "1: new_simple(0, 1)",
+                "2: new_simple(0, 1)",
+                "3: new_simple(0, 1)",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_wait_fractional_accumulate() {
+        let options = RunnerOptions {
+            accumulate_wait: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            trace_with_options(&wait_doc("0.5"), 4, options),
+            [
+                "0: new_simple(0, 1)",
+                "0: new_simple(0, 1)",
+                "1: new_simple(0, 1)",
+                "1: new_simple(0, 1)",
+            ],
+        );
     }
 }
 
@@ -0,0 +1,184 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use crate::data::{ExpressionError, Value};
+use crate::run::{CompiledBulletML, Timeline};
+
+/// Statistics gathered by running a script with many random seeds.
+///
+/// These help to verify that uses of `$rand` produce the intended variety.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SampleStats {
+    /// The number of runs sampled.
+    pub runs: u64,
+    /// The fewest bullets fired in a run.
+    pub min_fired: usize,
+    /// The most bullets fired in a run.
+    pub max_fired: usize,
+    /// The smallest direction of the first bullet fired in a run, normalized to `[0, 360)`.
+    ///
+    /// `None` if no run fired a bullet.
+    pub min_first_direction: Option<f32>,
+    /// The largest direction of the first bullet fired in a run, normalized to `[0, 360)`.
+    ///
+    /// `None` if no run fired a bullet.
+    pub max_first_direction: Option<f32>,
+    /// The mean duration of the runs.
+    ///
+    /// The duration of a run is the number of frames until its last bullet is fired.
+    pub mean_duration: f32,
+    /// The variance of the duration of the runs.
+    pub duration_variance: f32,
+}
+
+impl SampleStats {
+    /// The spread of the directions of the first bullet fired across runs.
+    pub fn first_direction_spread(&self) -> Option<f32> {
+        self.min_first_direction
+            .and_then(|min| self.max_first_direction.map(|max| max - min))
+    }
+}
+
+fn first_direction(timeline: &Timeline) -> Option<f32> {
+    let first = timeline.spawns.first()?;
+    timeline
+        .keyframes
+        .iter()
+        .find(|keyframe| keyframe.bullet == Some(first.bullet))
+        .map(|keyframe| keyframe.direction.rem_euclid(360.))
+}
+
+fn duration(timeline: &Timeline) -> u32 {
+    timeline
+        .spawns
+        .last()
+        .map_or(0, |spawn| spawn.frame + 1)
+}
+
+impl CompiledBulletML {
+    /// Run the script once for each seed in `0..runs` and gather statistics.
+    ///
+    /// Each run is simulated as described by `to_keyframes` for `frames` frames.
+    pub fn sample(
+        &self,
+        rank: Value,
+        runs: u64,
+        frames: u32,
+    ) -> Result<SampleStats, ExpressionError> {
+        let mut stats = SampleStats {
+            runs,
+            min_fired: usize::MAX,
+            ..SampleStats::default()
+        };
+        let mut durations = Vec::new();
+
+        for seed in 0..runs {
+            let timeline = self.to_keyframes(rank, seed, frames)?;
+
+            let fired = timeline.spawns.len();
+            stats.min_fired = stats.min_fired.min(fired);
+            stats.max_fired = stats.max_fired.max(fired);
+
+            if let Some(direction) = first_direction(&timeline) {
+                stats.min_first_direction = Some(
+                    stats
+                        .min_first_direction
+                        .map_or(direction, |min| min.min(direction)),
+                );
+                stats.max_first_direction = Some(
+                    stats
+                        .max_first_direction
+                        .map_or(direction, |max| max.max(direction)),
+                );
+            }
+
+            durations.push(duration(&timeline) as f32);
+        }
+
+        if durations.is_empty() {
+            stats.min_fired = 0;
+        } else {
+            let count = durations.len() as f32;
+            let mean = durations.iter().sum::<f32>() / count;
+            stats.mean_duration = mean;
+            stats.duration_variance = durations
+                .iter()
+                .map(|duration| (duration - mean) * (duration - mean))
+                .sum::<f32>()
+                / count;
+        }
+
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::data;
+    use crate::run::CompiledBulletML;
+
+    fn compile(doc: &str) -> CompiledBulletML {
+        let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+        CompiledBulletML::new(bulletml).unwrap()
+    }
+
+    #[test]
+    fn test_sample_variety() {
+        let compiled = compile(
+            r#"<bulletml>
+                <action label="top">
+                    <repeat>
+                        <times>1 + 3 * $rand</times>
+                        <action>
+                            <fire>
+                                <direction type="absolute">360 * $rand</direction>
+                                <bullet/>
+                            </fire>
+                        </action>
+                    </repeat>
+                </action>
+            </bulletml>"#,
+        );
+
+        let stats = compiled.sample(0., 32, 1).unwrap();
+
+        assert_eq!(stats.runs, 32);
+        assert!(stats.min_fired >= 1);
+        assert!(stats.max_fired <= 4);
+        assert!(stats.min_fired < stats.max_fired);
+        assert!(stats.first_direction_spread().unwrap() > 0.);
+        assert_eq!(stats.mean_duration, 1.);
+        assert_eq!(stats.duration_variance, 0.);
+    }
+
+    #[test]
+    fn test_sample_degenerate() {
+        let compiled = compile(
+            r#"<bulletml>
+                <action label="top">
+                    <fire>
+                        <direction type="absolute">-90</direction>
+                        <bullet/>
+                    </fire>
+                </action>
+            </bulletml>"#,
+        );
+
+        let stats = compiled.sample(0., 8, 1).unwrap();
+
+        assert_eq!(stats.min_fired, 1);
+        assert_eq!(stats.max_fired, 1);
+        assert_eq!(stats.min_first_direction, Some(270.));
+        assert_eq!(stats.first_direction_spread(), Some(0.));
+    }
+
+    #[test]
+    fn test_sample_empty() {
+        let compiled = CompiledBulletML::new(data::BulletML::default()).unwrap();
+
+        let stats = compiled.sample(0., 0, 1).unwrap();
+
+        assert_eq!(stats.min_fired, 0);
+        assert_eq!(stats.first_direction_spread(), None);
+    }
+}
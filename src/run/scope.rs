@@ -0,0 +1,211 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use std::cell::RefCell;
+use std::collections::hash_map::HashMap;
+use std::collections::BTreeSet;
+
+use crate::data::{ExpressionContext, Value};
+use crate::run::rng::RandomSource;
+
+/// The context in which a runner evaluates expressions.
+///
+/// Values are looked up in the parameter frame of the running action, then the variables of the
+/// runner, and finally the outer context (the manager). Inner scopes shadow outer scopes.
+///
+/// If a set of unknown variables is given, undefined variables evaluate to `0` and are recorded
+/// in the set rather than causing an error.
+///
+/// If a random source is given, random values come from it rather than the outer context.
+pub(crate) struct Scope<'a> {
+    params: &'a [Value],
+    vars: &'a HashMap<String, Value>,
+    outer: &'a dyn ExpressionContext,
+    unknown: Option<&'a RefCell<BTreeSet<String>>>,
+    random: Option<&'a RandomSource>,
+}
+
+impl<'a> Scope<'a> {
+    pub(crate) fn new(
+        params: &'a [Value],
+        vars: &'a HashMap<String, Value>,
+        outer: &'a dyn ExpressionContext,
+    ) -> Self {
+        Scope {
+            params,
+            vars,
+            outer,
+            unknown: None,
+            random: None,
+        }
+    }
+
+    /// Evaluate undefined variables as `0`, recording their names in `unknown`.
+    pub(crate) fn with_unknown(mut self, unknown: &'a RefCell<BTreeSet<String>>) -> Self {
+        self.unknown = Some(unknown);
+        self
+    }
+
+    /// Take random values from a random source.
+    pub(crate) fn with_random(mut self, random: &'a RandomSource) -> Self {
+        self.random = Some(random);
+        self
+    }
+
+    fn fallback(&self, name: &str) -> Option<Value> {
+        self.unknown.map(|unknown| {
+            if !unknown.borrow().contains(name) {
+                unknown.borrow_mut().insert(name.into());
+            }
+            0.
+        })
+    }
+
+    fn param(&self, idx: usize) -> Option<Value> {
+        // Parameters are numbered from `$1`.
+        idx.checked_sub(1)
+            .and_then(|idx| self.params.get(idx))
+            .copied()
+    }
+
+    /// The names which are shadowing a value from the outer context.
+    pub(crate) fn shadowed(&self) -> Vec<String> {
+        let params = (1..=self.params.len())
+            .filter(|&idx| self.outer.get_param(idx).is_some())
+            .map(|idx| format!("${}", idx));
+        let mut vars = self
+            .vars
+            .keys()
+            .filter(|name| self.outer.get(name).is_some())
+            .map(|name| format!("${}", name))
+            .collect::<Vec<_>>();
+        vars.sort();
+
+        params.chain(vars).collect()
+    }
+}
+
+impl<'a> ExpressionContext for Scope<'a> {
+    fn get(&self, name: &str) -> Option<Value> {
+        self.vars
+            .get(name)
+            .copied()
+            .or_else(|| self.outer.get(name))
+            .or_else(|| self.fallback(name))
+    }
+
+    fn get_index(&self, idx: usize, name: &str) -> Option<Value> {
+        self.vars
+            .get(name)
+            .copied()
+            .or_else(|| self.outer.get_index(idx, name))
+            .or_else(|| self.fallback(name))
+    }
+
+    fn get_param(&self, idx: usize) -> Option<Value> {
+        self.param(idx).or_else(|| self.outer.get_param(idx))
+    }
+
+    fn rand(&self) -> Value {
+        if let Some(random) = self.random {
+            random.rand(self.outer)
+        } else {
+            self.outer.rand()
+        }
+    }
+
+    fn rank(&self) -> Value {
+        self.outer.rank()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::collections::hash_map::HashMap;
+    use std::collections::BTreeSet;
+
+    use crate::data::{ExpressionContext, Value};
+    use crate::run::scope::Scope;
+
+    struct Outer;
+
+    impl ExpressionContext for Outer {
+        fn get(&self, name: &str) -> Option<Value> {
+            match name {
+                "shared" => Some(1.),
+                "outer" => Some(2.),
+                _ => None,
+            }
+        }
+
+        fn get_param(&self, idx: usize) -> Option<Value> {
+            if idx == 1 {
+                Some(10.)
+            } else {
+                None
+            }
+        }
+
+        fn rand(&self) -> Value {
+            0.5
+        }
+
+        fn rank(&self) -> Value {
+            0.25
+        }
+    }
+
+    #[test]
+    fn test_scope_shadowing() {
+        let mut vars = HashMap::new();
+        vars.insert("shared".into(), 3.);
+        vars.insert("inner".into(), 4.);
+        let params = [20., 30.];
+        let scope = Scope::new(&params, &vars, &Outer);
+
+        assert_eq!(scope.get("shared"), Some(3.));
+        assert_eq!(scope.get_index(0, "shared"), Some(3.));
+        assert_eq!(scope.get("inner"), Some(4.));
+        assert_eq!(scope.get("outer"), Some(2.));
+        assert_eq!(scope.get("missing"), None);
+
+        assert_eq!(scope.get_param(0), None);
+        assert_eq!(scope.get_param(1), Some(20.));
+        assert_eq!(scope.get_param(2), Some(30.));
+        assert_eq!(scope.get_param(3), None);
+
+        assert_eq!(scope.rand(), 0.5);
+        assert_eq!(scope.rank(), 0.25);
+
+        assert_eq!(scope.shadowed(), ["$1", "$shared"]);
+    }
+
+    #[test]
+    fn test_scope_empty() {
+        let vars = HashMap::new();
+        let scope = Scope::new(&[], &vars, &Outer);
+
+        assert_eq!(scope.get("shared"), Some(1.));
+        assert_eq!(scope.get_param(1), Some(10.));
+        assert!(scope.shadowed().is_empty());
+    }
+
+    #[test]
+    fn test_scope_unknown() {
+        let vars = HashMap::new();
+        let unknown = RefCell::new(BTreeSet::new());
+        let scope = Scope::new(&[], &vars, &Outer).with_unknown(&unknown);
+
+        assert_eq!(scope.get("outer"), Some(2.));
+        assert_eq!(scope.get("missing"), Some(0.));
+        assert_eq!(scope.get_index(3, "other"), Some(0.));
+        assert_eq!(scope.get("missing"), Some(0.));
+        assert_eq!(scope.get_param(2), None);
+
+        assert_eq!(
+            unknown.into_inner().into_iter().collect::<Vec<_>>(),
+            ["missing", "other"],
+        );
+    }
+}
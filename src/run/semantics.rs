@@ -0,0 +1,584 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+//! The semantics of steps as pure functions.
+//!
+//! The runner evaluates expressions and reads the state of the bullet into a `Snapshot`, calls
+//! these functions, and then applies their results to the manager.
+
+use crate::data::Direction;
+use crate::run::compile::{Change, DirectionKind, Orientation};
+use crate::run::interp::{interpolate, Function};
+use crate::run::{BulletManager, NegativeSpeed, NoTargetPolicy};
+
+/// The state of a bullet as observed by a step.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct Snapshot {
+    pub turn: u32,
+    pub direction: f32,
+    pub aim_direction: f32,
+    pub speed: f32,
+    pub speed_x: f32,
+    pub speed_y: f32,
+    pub default_speed: f32,
+    pub target_velocity: Option<(f32, f32)>,
+}
+
+impl Snapshot {
+    pub(crate) fn new<T>(manager: &T) -> Self
+    where
+        T: BulletManager,
+    {
+        Snapshot {
+            turn: manager.turn(),
+            direction: manager.direction(),
+            aim_direction: manager.aim_direction(),
+            speed: manager.speed(),
+            speed_x: manager.speed_x(),
+            speed_y: manager.speed_y(),
+            default_speed: manager.default_speed(),
+            target_velocity: manager.target_velocity(),
+        }
+    }
+}
+
+/// The direction to aim at given the target reported by the manager.
+///
+/// `last` is the most recent target and `fallback` is the manager's aim direction.
+pub(crate) fn aim_direction(
+    target: Option<f32>,
+    last: Option<f32>,
+    policy: NoTargetPolicy,
+    orientation: Orientation,
+    fallback: f32,
+) -> f32 {
+    target.unwrap_or_else(|| match policy {
+        NoTargetPolicy::KeepLast => last.unwrap_or(fallback),
+        NoTargetPolicy::Up => orientation.up(0.),
+        NoTargetPolicy::Down => 180.,
+    })
+}
+
+/// The direction indicated by a `<direction>` element.
+///
+/// `sequence` directions are only meaningful with respect to a history; here they are relative
+/// to the current direction. See `fire_direction` and `change_direction` for their handling.
+pub(crate) fn target_direction(
+    snapshot: &Snapshot,
+    orientation: Orientation,
+    kind: DirectionKind,
+    degrees: f32,
+) -> f32 {
+    let dir = match kind {
+        DirectionKind::Aim => {
+            // Aim at the player.
+            degrees + snapshot.aim_direction
+        },
+        DirectionKind::Absolute => {
+            // Orient according to the setup.
+            orientation.up(degrees)
+        },
+        DirectionKind::Relative | DirectionKind::Sequence => {
+            // Modify relative to the current direction.
+            degrees + snapshot.direction
+        },
+    };
+
+    dir % 360.
+}
+
+/// The speed indicated by a `<speed>` element.
+///
+/// `sequence` speeds are only meaningful with respect to a history; here they are relative to
+/// the current speed. See `fire_speed` and `change_speed` for their handling.
+pub(crate) fn target_speed(snapshot: &Snapshot, kind: Change, value: f32) -> f32 {
+    match kind {
+        Change::Absolute => value,
+        Change::Relative | Change::Sequence => value + snapshot.speed,
+    }
+}
+
+/// The direction indicated by a `<direction>` element of a `<fire>` or its `<bullet>`.
+///
+/// `sequence` directions are relative to the previous bullet fired by the runner (`prev_dir`).
+/// The first bullet of a sequence aims at the target.
+pub(crate) fn fire_direction(
+    snapshot: &Snapshot,
+    orientation: Orientation,
+    prev_dir: Option<f32>,
+    kind: DirectionKind,
+    degrees: f32,
+) -> f32 {
+    if let DirectionKind::Sequence = kind {
+        prev_dir.map_or(snapshot.aim_direction, |prev_dir| degrees + prev_dir) % 360.
+    } else {
+        target_direction(snapshot, orientation, kind, degrees)
+    }
+}
+
+/// The offset from an aim direction which leads a target moving at `velocity`.
+///
+/// A bullet moving at `speed` in the direction `aim + offset` intercepts the target if it keeps
+/// its velocity. There is no offset if the bullet cannot catch the target.
+pub(crate) fn lead_offset(aim: f32, speed: f32, velocity: (f32, f32)) -> f32 {
+    let radians = aim.to_radians();
+    // The velocity of the target along and across the line of sight.
+    let along = velocity.0 * radians.sin() - velocity.1 * radians.cos();
+    let across = velocity.0 * radians.cos() + velocity.1 * radians.sin();
+
+    // The bullet must match the target across the line of sight and still close the distance
+    // along it.
+    let closing = speed * speed - across * across;
+    if speed <= 0. || closing <= 0. || closing.sqrt() <= along {
+        return 0.;
+    }
+
+    across.atan2(closing.sqrt()).to_degrees()
+}
+
+/// A direction after leading the target, if the `<direction>` element asks for it.
+///
+/// `dir` is the direction indicated by the element and `speed` is the speed of the bullet.
+pub(crate) fn lead_direction(
+    snapshot: &Snapshot,
+    direction: &Direction,
+    dir: f32,
+    speed: f32,
+) -> f32 {
+    match (direction.kind, direction.lead, snapshot.target_velocity) {
+        (DirectionKind::Aim, true, Some(velocity)) => {
+            (dir + lead_offset(snapshot.aim_direction, speed, velocity)) % 360.
+        },
+        _ => dir,
+    }
+}
+
+/// The speed indicated by a `<speed>` element of a `<fire>` or its `<bullet>`.
+///
+/// `sequence` speeds are relative to the previous bullet fired by the runner (`prev_speed`). The
+/// first bullet of a sequence has a speed of `1`.
+pub(crate) fn fire_speed(
+    snapshot: &Snapshot,
+    prev_speed: Option<f32>,
+    kind: Change,
+    value: f32,
+) -> f32 {
+    if let Change::Sequence = kind {
+        prev_speed.map_or(1., |prev_speed| value + prev_speed)
+    } else {
+        target_speed(snapshot, kind, value)
+    }
+}
+
+/// The function for a `<changeDirection>` step.
+///
+/// `sequence` directions are amounts per frame.
+pub(crate) fn change_direction(
+    snapshot: &Snapshot,
+    orientation: Orientation,
+    kind: DirectionKind,
+    degrees: f32,
+    duration: f32,
+) -> Function {
+    let cur_dir = snapshot.direction;
+    let final_dir = if let DirectionKind::Sequence = kind {
+        duration * degrees + cur_dir
+    } else {
+        // Turn through the smaller angle. Managers may report directions outside of a single
+        // turn, so the difference is normalized first. Half turns are positive.
+        let target = target_direction(snapshot, orientation, kind, degrees);
+        let space = (target - cur_dir).rem_euclid(360.);
+        if space > 180. {
+            cur_dir + space - 360.
+        } else {
+            cur_dir + space
+        }
+    };
+
+    interpolate(snapshot.turn, duration, cur_dir, final_dir)
+}
+
+/// The function for a `<changeSpeed>` step.
+///
+/// `sequence` speeds are amounts per frame.
+pub(crate) fn change_speed(
+    snapshot: &Snapshot,
+    kind: Change,
+    change: f32,
+    duration: f32,
+) -> Function {
+    let cur_speed = snapshot.speed;
+    let final_speed = if let Change::Sequence = kind {
+        duration * change + cur_speed
+    } else {
+        target_speed(snapshot, kind, change)
+    };
+
+    interpolate(snapshot.turn, duration, cur_speed, final_speed)
+}
+
+/// The direction and speed of a fired bullet.
+///
+/// Values given by the bullet take precedence over those given by the `<fire>` element.
+pub(crate) fn fire(
+    snapshot: &Snapshot,
+    direction: Option<f32>,
+    speed: Option<f32>,
+) -> (f32, f32) {
+    (
+        direction.unwrap_or(snapshot.aim_direction),
+        speed.unwrap_or(snapshot.default_speed),
+    )
+}
+
+/// The direction and speed of a fired bullet after handling a negative speed.
+pub(crate) fn negative_speed(policy: NegativeSpeed, direction: f32, speed: f32) -> (f32, f32) {
+    if speed >= 0. {
+        return (direction, speed);
+    }
+
+    match policy {
+        NegativeSpeed::Allow => (direction, speed),
+        NegativeSpeed::ReflectDirection => (reflect(direction), -speed),
+        NegativeSpeed::ClampZero => (direction, 0.),
+    }
+}
+
+/// The direction opposite to a direction.
+pub(crate) fn reflect(direction: f32) -> f32 {
+    (direction + 180.) % 360.
+}
+
+/// The direction and speed of a fired bullet after adding the velocity of its owner.
+///
+/// The direction is kept if the bullet ends up at rest.
+pub(crate) fn inherit_velocity(direction: f32, speed: f32, owner: (f32, f32)) -> (f32, f32) {
+    let radians = direction.to_radians();
+    let speed_x = speed * radians.sin() + owner.0;
+    let speed_y = -speed * radians.cos() + owner.1;
+
+    let speed = speed_x.hypot(speed_y);
+    if speed > 0. {
+        (speed_x.atan2(-speed_y).to_degrees(), speed)
+    } else {
+        (direction, speed)
+    }
+}
+
+/// The turn at which a `<wait>` ends and the fractional frames carried to the next wait.
+pub(crate) fn wait(turn: u32, frames: f32, remainder: f32, accumulate: bool) -> (u32, f32) {
+    if accumulate {
+        let total = (frames + remainder).max(0.);
+        let whole = total.floor();
+        (turn.saturating_add(whole as u32), total - whole)
+    } else {
+        (turn.saturating_add(frames.ceil() as u32), remainder)
+    }
+}
+
+/// The number of iterations for a `<repeat>` step.
+pub(crate) fn repeat_count(times: f32) -> usize {
+    // Other implementations use C++'s static_cast which truncates, so compare with `1`
+    // rather than letting rounding occur.
+    if times.is_nan() || times < 1. {
+        0
+    } else {
+        times as usize
+    }
+}
+
+/// Round a direction to the nearest of `steps` evenly spaced directions.
+pub(crate) fn quantize_direction(degrees: f32, steps: u32) -> f32 {
+    if steps == 0 {
+        return degrees;
+    }
+
+    let step = 360. / (steps as f32);
+    (degrees / step).round() * step
+}
+
+/// Round a speed to a fixed-point value with `bits` fractional bits.
+pub(crate) fn quantize_speed(speed: f32, bits: u32) -> f32 {
+    let scale = 2_f32.powi(bits as i32);
+    (speed * scale).round() / scale
+}
+
+#[cfg(test)]
+mod test {
+    use crate::data::Direction;
+    use crate::run::compile::{Change, DirectionKind, Orientation};
+    use crate::run::semantics::{self, Snapshot};
+    use crate::run::{NegativeSpeed, NoTargetPolicy};
+
+    fn angles() -> impl Iterator<Item = f32> + Clone {
+        (-48..48).map(|step| (step as f32) * 15.)
+    }
+
+    #[test]
+    fn test_wait_large_turn() {
+        let base = (1 << 24) * 6 + 7;
+
+        assert_eq!(semantics::wait(base, 1.5, 0., false), (base + 2, 0.));
+        assert_eq!(semantics::wait(base, 1.5, 0., true), (base + 1, 0.5));
+        assert_eq!(semantics::wait(u32::MAX - 1, 4., 0., false).0, u32::MAX);
+    }
+
+    #[test]
+    fn test_change_direction_shortest() {
+        for direction in angles() {
+            for degrees in angles() {
+                let snapshot = Snapshot {
+                    direction,
+                    ..Default::default()
+                };
+                let func = semantics::change_direction(
+                    &snapshot,
+                    Orientation::None,
+                    DirectionKind::Absolute,
+                    degrees,
+                    1.,
+                );
+                let (_, end) = func.update(1);
+
+                assert!((end - direction).abs() <= 180.);
+                assert_eq!((end - degrees).rem_euclid(360.), 0.);
+            }
+        }
+    }
+
+    #[test]
+    fn test_change_speed_sequence() {
+        for duration in 0..8 {
+            let snapshot = Snapshot {
+                speed: 2.,
+                ..Default::default()
+            };
+            let func = semantics::change_speed(&snapshot, Change::Sequence, 0.5, duration as f32);
+
+            assert_eq!(func.update(duration).1, 2. + 0.5 * (duration as f32));
+        }
+    }
+
+    #[test]
+    fn test_fire_sequence() {
+        let snapshot = Snapshot {
+            direction: 90.,
+            speed: 4.,
+            aim_direction: 45.,
+            ..Default::default()
+        };
+        let seq_dir = |prev_dir| {
+            semantics::fire_direction(
+                &snapshot,
+                Orientation::None,
+                prev_dir,
+                DirectionKind::Sequence,
+                10.,
+            )
+        };
+        let seq_speed =
+            |prev_speed| semantics::fire_speed(&snapshot, prev_speed, Change::Sequence, 1.);
+
+        // Relative to the previous bullet, not the runner.
+        assert_eq!(seq_dir(Some(355.)), 5.);
+        assert_eq!(seq_speed(Some(2.)), 3.);
+        // The first bullet aims at the target at a speed of `1`.
+        assert_eq!(seq_dir(None), 45.);
+        assert_eq!(seq_speed(None), 1.);
+
+        // Other kinds ignore the previous bullet.
+        assert_eq!(
+            semantics::fire_direction(
+                &snapshot,
+                Orientation::None,
+                Some(0.),
+                DirectionKind::Relative,
+                10.,
+            ),
+            100.,
+        );
+        assert_eq!(
+            semantics::fire_speed(&snapshot, Some(0.), Change::Relative, 1.),
+            5.,
+        );
+    }
+
+    #[test]
+    fn test_lead_offset() {
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-4;
+
+        // A stationary target is aimed at directly.
+        assert_eq!(semantics::lead_offset(30., 2., (0., 0.)), 0.);
+        // Movement along the line of sight does not change the direction.
+        assert!(close(semantics::lead_offset(0., 2., (0., 1.)), 0.));
+        // A target crossing the line of sight is led.
+        assert!(close(semantics::lead_offset(0., 2., (1., 0.)), 30.));
+        assert!(close(semantics::lead_offset(90., 2., (0., -1.)), -30.));
+        // Targets which cannot be caught are aimed at directly.
+        assert_eq!(semantics::lead_offset(0., 1., (2., 0.)), 0.);
+        assert_eq!(semantics::lead_offset(180., 1., (0., 2.)), 0.);
+        assert_eq!(semantics::lead_offset(0., 0., (1., 0.)), 0.);
+
+        for aim in angles() {
+            for &(speed, velocity) in &[(3., (1., 2.)), (2., (-1.5, 0.5)), (5., (4., -2.))] {
+                let offset = semantics::lead_offset(aim, speed, velocity);
+                if offset == 0. {
+                    continue;
+                }
+
+                // The bullet matches the target across the line of sight.
+                let dir = (aim + offset).to_radians();
+                let aim = aim.to_radians();
+                let bullet = (speed * dir.sin(), -speed * dir.cos());
+                let across = |(x, y): (f32, f32)| x * aim.cos() + y * aim.sin();
+                assert!(close(across(bullet), across(velocity)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_lead_direction() {
+        let snapshot = Snapshot {
+            aim_direction: 0.,
+            target_velocity: Some((1., 0.)),
+            ..Default::default()
+        };
+        let lead = |snapshot: &Snapshot, direction: &Direction| {
+            semantics::lead_direction(snapshot, direction, 10., 2.)
+        };
+
+        assert_eq!(lead(&snapshot, &Direction::aim(10.)), 10.);
+        assert!((lead(&snapshot, &Direction::aim(10.).leading()) - 40.).abs() < 1e-4);
+        assert_eq!(lead(&snapshot, &Direction::absolute(10.).leading()), 10.);
+
+        let snapshot = Snapshot {
+            target_velocity: None,
+            ..snapshot
+        };
+        assert_eq!(lead(&snapshot, &Direction::aim(10.).leading()), 10.);
+    }
+
+    #[test]
+    fn test_fire_defaults() {
+        let snapshot = Snapshot {
+            aim_direction: 45.,
+            default_speed: 3.,
+            ..Default::default()
+        };
+
+        assert_eq!(semantics::fire(&snapshot, None, None), (45., 3.));
+        assert_eq!(semantics::fire(&snapshot, Some(90.), None), (90., 3.));
+        assert_eq!(semantics::fire(&snapshot, None, Some(1.)), (45., 1.));
+    }
+
+    #[test]
+    fn test_wait_accumulate_total() {
+        for &frames in &[0., 0.25, 0.5, 0.75, 1., 1.5, 2.25] {
+            let mut turn = 0;
+            let mut remainder = 0.;
+            for _ in 0..8 {
+                let (next, rest) = semantics::wait(turn, frames, remainder, true);
+                assert!(next >= turn);
+                assert!((0. ..1.).contains(&rest));
+                turn = next;
+                remainder = rest;
+            }
+
+            assert_eq!(turn, (8. * frames) as u32);
+        }
+    }
+
+    #[test]
+    fn test_repeat_count() {
+        assert_eq!(semantics::repeat_count(f32::NAN), 0);
+        assert_eq!(semantics::repeat_count(-1.), 0);
+        assert_eq!(semantics::repeat_count(0.5), 0);
+        assert_eq!(semantics::repeat_count(1.), 1);
+        assert_eq!(semantics::repeat_count(2.9), 2);
+    }
+
+    #[test]
+    fn test_quantize_direction() {
+        assert_eq!(semantics::quantize_direction(10., 0), 10.);
+        assert_eq!(semantics::quantize_direction(10., 4), 0.);
+        assert_eq!(semantics::quantize_direction(50., 4), 90.);
+        assert_eq!(semantics::quantize_direction(-50., 4), -90.);
+        assert_eq!(semantics::quantize_direction(1.5, 256), 1.40625);
+
+        for degrees in angles() {
+            let quantized = semantics::quantize_direction(degrees, 256);
+            assert!((quantized - degrees).abs() <= 360. / 512.);
+        }
+    }
+
+    #[test]
+    fn test_quantize_speed() {
+        assert_eq!(semantics::quantize_speed(1.3, 0), 1.);
+        assert_eq!(semantics::quantize_speed(1.3, 2), 1.25);
+        assert_eq!(semantics::quantize_speed(1.3, 8), 333. / 256.);
+        assert_eq!(semantics::quantize_speed(-0.7, 1), -0.5);
+    }
+
+    #[test]
+    fn test_aim_direction() {
+        let aim = |target, last, policy, orientation| {
+            semantics::aim_direction(target, last, policy, orientation, 45.)
+        };
+
+        for &policy in &[
+            NoTargetPolicy::KeepLast,
+            NoTargetPolicy::Up,
+            NoTargetPolicy::Down,
+        ] {
+            assert_eq!(aim(Some(10.), Some(20.), policy, Orientation::None), 10.);
+        }
+
+        let policy = NoTargetPolicy::KeepLast;
+        assert_eq!(aim(None, Some(20.), policy, Orientation::None), 20.);
+        assert_eq!(aim(None, None, policy, Orientation::None), 45.);
+
+        let policy = NoTargetPolicy::Up;
+        assert_eq!(aim(None, Some(20.), policy, Orientation::Vertical), 0.);
+        assert_eq!(aim(None, Some(20.), policy, Orientation::Horizontal), -90.);
+
+        let policy = NoTargetPolicy::Down;
+        assert_eq!(aim(None, Some(20.), policy, Orientation::Horizontal), 180.);
+    }
+
+    #[test]
+    fn test_inherit_velocity() {
+        assert_eq!(semantics::inherit_velocity(0., 1., (0., 0.)), (0., 1.));
+        assert_eq!(semantics::inherit_velocity(0., 1., (0., -1.)), (0., 2.));
+        assert_eq!(semantics::inherit_velocity(0., 1., (0., 1.)), (0., 0.));
+
+        let (direction, speed) = semantics::inherit_velocity(90., 1., (0., -1.));
+        assert!((direction - 45.).abs() < 1e-4);
+        assert!((speed - 2_f32.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_negative_speed() {
+        let policies = [
+            NegativeSpeed::Allow,
+            NegativeSpeed::ReflectDirection,
+            NegativeSpeed::ClampZero,
+        ];
+        policies.iter().for_each(|&policy| {
+            assert_eq!(semantics::negative_speed(policy, 90., 2.), (90., 2.));
+            assert_eq!(semantics::negative_speed(policy, 90., 0.), (90., 0.));
+        });
+
+        assert_eq!(
+            semantics::negative_speed(NegativeSpeed::Allow, 90., -2.),
+            (90., -2.),
+        );
+        assert_eq!(
+            semantics::negative_speed(NegativeSpeed::ReflectDirection, 270., -2.),
+            (90., 2.),
+        );
+        assert_eq!(
+            semantics::negative_speed(NegativeSpeed::ClampZero, 90., -2.),
+            (90., 0.),
+        );
+    }
+}
@@ -0,0 +1,61 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// The direction and speed of the last bullet fired.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct Sequence {
+    pub direction: Option<f32>,
+    pub speed: Option<f32>,
+}
+
+/// Sequence memory which may be shared by several runners.
+///
+/// `sequence` directions and speeds are relative to the previous bullet fired by the runner.
+/// Runners given the same handle with `Runner::share_sequence_state` treat bullets fired by any
+/// of them as the previous bullet, so that, e.g., linked turrets running the same spiral
+/// continue each other's pattern rather than each starting it over. Handles are cheap to clone;
+/// clones refer to the same memory.
+#[derive(Debug, Clone, Default)]
+pub struct SequenceHandle {
+    sequence: Arc<Mutex<Sequence>>,
+}
+
+impl SequenceHandle {
+    /// Create a handle to new sequence memory.
+    ///
+    /// The first bullet fired by a runner sharing it starts the sequence as usual.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The direction of the last bullet fired by a runner sharing the memory.
+    pub fn direction(&self) -> Option<f32> {
+        self.get().direction
+    }
+
+    /// The speed of the last bullet fired by a runner sharing the memory.
+    pub fn speed(&self) -> Option<f32> {
+        self.get().speed
+    }
+
+    /// Forget the last bullet so that the sequence starts over.
+    pub fn reset(&self) {
+        self.set(Sequence::default());
+    }
+
+    /// Whether two handles refer to the same memory.
+    pub fn same_as(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.sequence, &other.sequence)
+    }
+
+    pub(crate) fn get(&self) -> Sequence {
+        // The memory is always in a valid state, so poisoning is ignored.
+        *self.sequence.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn set(&self, sequence: Sequence) {
+        *self.sequence.lock().unwrap_or_else(PoisonError::into_inner) = sequence;
+    }
+}
@@ -0,0 +1,574 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+//! Running patterns without a game engine.
+//!
+//! `SimulatedManager` is a reference `BulletManager` which moves a bullet in a straight line at
+//! its speed every frame. `Simulation` drives an emitter and every bullet it fires with these
+//! managers, so patterns can be run to completion and inspected in unit tests.
+//!
+//! Positions are in the same units as speeds and `y` increases downwards, so a direction of `0`
+//! moves up the screen.
+
+use crate::data::{ExpressionContext, ExpressionError, Value};
+use crate::run::{BulletManager, BulletScript, CompiledBulletML, Rng, Runner, RunnerOptions};
+
+/// The position and motion of a simulated bullet.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Body {
+    /// The horizontal position.
+    pub x: f32,
+    /// The vertical position (increasing downwards).
+    pub y: f32,
+    /// The direction of motion in degrees.
+    pub direction: f32,
+    /// The speed of motion in units per frame.
+    pub speed: f32,
+}
+
+impl Body {
+    /// Create a new body.
+    pub fn new(x: f32, y: f32, direction: f32, speed: f32) -> Self {
+        Body {
+            x,
+            y,
+            direction,
+            speed,
+        }
+    }
+
+    /// The speed along the `x` axis.
+    pub fn speed_x(&self) -> f32 {
+        self.speed * self.direction.to_radians().sin()
+    }
+
+    /// The speed along the `y` axis.
+    pub fn speed_y(&self) -> f32 {
+        -self.speed * self.direction.to_radians().cos()
+    }
+
+    /// Set the motion of the body from its speed along each axis.
+    ///
+    /// The direction is kept if the body comes to rest.
+    pub fn set_velocity(&mut self, speed_x: f32, speed_y: f32) {
+        self.speed = speed_x.hypot(speed_y);
+        if self.speed > 0. {
+            self.direction = speed_x.atan2(-speed_y).to_degrees();
+        }
+    }
+
+    /// Move the body by a frame of its motion.
+    pub fn advance(&mut self) {
+        self.x += self.speed_x();
+        self.y += self.speed_y();
+    }
+
+    /// The direction from the body to a point.
+    pub fn direction_to(&self, x: f32, y: f32) -> f32 {
+        (x - self.x).atan2(self.y - y).to_degrees()
+    }
+}
+
+/// A bullet fired by a runner which has not been added to the simulation yet.
+#[derive(Debug)]
+enum Spawned {
+    Simple(Body),
+    Scripted(Body, BulletScript),
+}
+
+/// The speed of bullets fired without a `<speed>`.
+const DEFAULT_SPEED: f32 = 1.;
+
+/// A reference manager for a single bullet.
+///
+/// The manager aims at a target point and records the bullets it fires until `Simulation` picks
+/// them up. Runners driving it should be given a random number generator with `Runner::set_rng`;
+/// otherwise `$rand` is always `0.5`.
+#[derive(Debug)]
+pub struct SimulatedManager {
+    /// The bullet controlled by the manager.
+    pub body: Body,
+    /// The position of the target.
+    pub target: (f32, f32),
+    /// The velocity of the target, if it is known.
+    pub target_velocity: Option<(f32, f32)>,
+    /// The value of `$rank`.
+    pub rank: Value,
+    /// The turn of the simulation.
+    pub turn: u32,
+    /// Whether the bullet has vanished.
+    pub vanished: bool,
+    spawned: Vec<Spawned>,
+}
+
+impl SimulatedManager {
+    /// Create a manager for a bullet aiming at a target.
+    pub fn new(body: Body, target: (f32, f32)) -> Self {
+        SimulatedManager {
+            body,
+            target,
+            target_velocity: None,
+            rank: 0.,
+            turn: 0,
+            vanished: false,
+            spawned: Vec::new(),
+        }
+    }
+}
+
+impl ExpressionContext for SimulatedManager {
+    fn get(&self, _: &str) -> Option<Value> {
+        None
+    }
+
+    fn get_param(&self, _: usize) -> Option<Value> {
+        None
+    }
+
+    fn rand(&self) -> Value {
+        0.5
+    }
+
+    fn rank(&self) -> Value {
+        self.rank
+    }
+}
+
+impl BulletManager for SimulatedManager {
+    fn new_simple(&mut self, direction: f32, speed: f32) {
+        let body = Body::new(self.body.x, self.body.y, direction, speed);
+        self.spawned.push(Spawned::Simple(body));
+    }
+
+    fn new_bullet(&mut self, direction: f32, speed: f32) {
+        self.new_simple(direction, speed)
+    }
+
+    fn new_bullet_with_script(&mut self, direction: f32, speed: f32, script: BulletScript) {
+        let body = Body::new(self.body.x, self.body.y, direction, speed);
+        self.spawned.push(Spawned::Scripted(body, script));
+    }
+
+    fn turn(&self) -> u32 {
+        self.turn
+    }
+
+    fn direction(&self) -> f32 {
+        self.body.direction
+    }
+
+    fn aim_direction(&self) -> f32 {
+        self.body.direction_to(self.target.0, self.target.1)
+    }
+
+    fn target_velocity(&self) -> Option<(f32, f32)> {
+        self.target_velocity
+    }
+
+    fn speed(&self) -> f32 {
+        self.body.speed
+    }
+
+    fn speed_x(&self) -> f32 {
+        self.body.speed_x()
+    }
+
+    fn speed_y(&self) -> f32 {
+        self.body.speed_y()
+    }
+
+    fn default_speed(&self) -> f32 {
+        DEFAULT_SPEED
+    }
+
+    fn vanish(&mut self) {
+        self.vanished = true;
+    }
+
+    fn change_direction(&mut self, degrees: f32) {
+        self.body.direction = degrees;
+    }
+
+    fn change_speed(&mut self, speed: f32) {
+        self.body.speed = speed;
+    }
+
+    fn accel_x(&mut self, amount: f32) {
+        let speed_y = self.body.speed_y();
+        self.body.set_velocity(amount, speed_y);
+    }
+
+    fn accel_y(&mut self, amount: f32) {
+        let speed_x = self.body.speed_x();
+        self.body.set_velocity(speed_x, amount);
+    }
+}
+
+/// The setup of a simulation.
+#[derive(Debug, Clone)]
+pub struct SimulationOptions {
+    /// The initial state of the emitter.
+    pub emitter: Body,
+    /// The initial position of the target.
+    pub target: (f32, f32),
+    /// The velocity of the target.
+    ///
+    /// The target moves by this amount every frame and its velocity is reported to runners.
+    pub target_velocity: (f32, f32),
+    /// The value of `$rank`.
+    pub rank: Value,
+    /// The seed for the random number generator of the emitter.
+    pub seed: u64,
+    /// Options for the runners.
+    pub runner: RunnerOptions,
+}
+
+impl Default for SimulationOptions {
+    fn default() -> Self {
+        SimulationOptions {
+            emitter: Body::default(),
+            // Straight below the emitter.
+            target: (0., 100.),
+            target_velocity: (0., 0.),
+            rank: 0.,
+            seed: 0,
+            runner: RunnerOptions::default(),
+        }
+    }
+}
+
+/// A simulation of a pattern and every bullet it fires.
+///
+/// Each frame, the emitter and then bullets with actions are updated in the order they were
+/// fired. Every body then moves by its velocity, including bullets fired during the frame, and
+/// the target moves by its velocity. Bullets fired by a runner start at the position of its body
+/// when it fired them; runners for bullets with actions are first updated on the next frame.
+///
+/// Bullets which vanish are removed. Nothing else removes bullets, so simple bullets keep moving
+/// forever.
+pub struct Simulation {
+    emitter: Runner<SimulatedManager>,
+    runners: Vec<Runner<SimulatedManager>>,
+    bullets: Vec<Body>,
+    target: (f32, f32),
+    target_velocity: (f32, f32),
+    turn: u32,
+    fired: usize,
+}
+
+impl Simulation {
+    /// Create a simulation of a compiled script.
+    pub fn new(bulletml: &CompiledBulletML, options: SimulationOptions) -> Self {
+        let mut manager = SimulatedManager::new(options.emitter, options.target);
+        manager.target_velocity = Some(options.target_velocity);
+        manager.rank = options.rank;
+
+        let mut emitter = Runner::from_compiled(manager, bulletml, options.runner);
+        emitter.set_rng(Rng::new(options.seed));
+
+        Simulation {
+            emitter,
+            runners: Vec::new(),
+            bullets: Vec::new(),
+            target: options.target,
+            target_velocity: options.target_velocity,
+            turn: 0,
+            fired: 0,
+        }
+    }
+
+    /// The runner for the emitter.
+    ///
+    /// This may be used to set variables or observers before the simulation starts. Runners for
+    /// fired bullets inherit the variables of the emitter.
+    pub fn emitter(&self) -> &Runner<SimulatedManager> {
+        &self.emitter
+    }
+
+    /// The runner for the emitter.
+    pub fn emitter_mut(&mut self) -> &mut Runner<SimulatedManager> {
+        &mut self.emitter
+    }
+
+    /// The runners for fired bullets with actions which have not vanished.
+    pub fn runners(&self) -> &[Runner<SimulatedManager>] {
+        &self.runners
+    }
+
+    /// Every fired bullet which has not vanished.
+    ///
+    /// Simple bullets come first in the order they were fired, followed by bullets with actions.
+    pub fn bullets(&self) -> impl Iterator<Item = &Body> {
+        self.bullets
+            .iter()
+            .chain(self.runners.iter().map(|runner| &runner.manager().body))
+    }
+
+    /// The number of bullets fired so far.
+    pub fn fired(&self) -> usize {
+        self.fired
+    }
+
+    /// The position of the target.
+    pub fn target(&self) -> (f32, f32) {
+        self.target
+    }
+
+    /// Move the target.
+    pub fn set_target(&mut self, x: f32, y: f32) {
+        self.target = (x, y);
+    }
+
+    /// The number of frames which have been simulated.
+    pub fn turn(&self) -> u32 {
+        self.turn
+    }
+
+    /// Whether every runner has nothing left to do.
+    ///
+    /// The emitter counts as done once it vanishes.
+    pub fn is_done(&self) -> bool {
+        let done =
+            |runner: &Runner<SimulatedManager>| runner.manager().vanished || runner.is_done();
+        done(&self.emitter) && self.runners.iter().all(done)
+    }
+
+    /// Simulate a single frame.
+    pub fn step(&mut self) -> Result<(), ExpressionError> {
+        let (turn, target) = (self.turn, self.target);
+        let mut spawned = Vec::new();
+        if !self.emitter.manager().vanished {
+            Self::update(&mut self.emitter, turn, target, &mut spawned)?;
+        }
+        let result = self
+            .runners
+            .iter_mut()
+            .try_for_each(|runner| Self::update(runner, turn, target, &mut spawned));
+        self.runners.retain(|runner| !runner.manager().vanished);
+        result?;
+
+        self.bullets.iter_mut().for_each(Body::advance);
+        for spawn in spawned {
+            self.fired += 1;
+            match spawn {
+                Spawned::Simple(mut body) => {
+                    body.advance();
+                    self.bullets.push(body);
+                },
+                Spawned::Scripted(mut body, script) => {
+                    body.advance();
+                    let mut manager = SimulatedManager::new(body, self.target);
+                    manager.target_velocity = Some(self.target_velocity);
+                    manager.rank = self.emitter.manager().rank;
+                    self.runners.push(script.runner(manager));
+                },
+            }
+        }
+
+        self.target.0 += self.target_velocity.0;
+        self.target.1 += self.target_velocity.1;
+        self.turn += 1;
+
+        Ok(())
+    }
+
+    /// Simulate a number of frames.
+    pub fn run(&mut self, frames: u32) -> Result<(), ExpressionError> {
+        (0..frames).try_for_each(|_| self.step())
+    }
+
+    /// Simulate frames until every runner is done or `max_frames` frames have been simulated.
+    ///
+    /// Returns whether every runner is done.
+    pub fn run_to_completion(&mut self, max_frames: u32) -> Result<bool, ExpressionError> {
+        for _ in 0..max_frames {
+            if self.is_done() {
+                break;
+            }
+            self.step()?;
+        }
+
+        Ok(self.is_done())
+    }
+
+    /// Update a runner and collect the bullets it fired.
+    fn update(
+        runner: &mut Runner<SimulatedManager>,
+        turn: u32,
+        target: (f32, f32),
+        spawned: &mut Vec<Spawned>,
+    ) -> Result<(), ExpressionError> {
+        let manager = runner.manager_mut();
+        manager.turn = turn;
+        manager.target = target;
+
+        runner.update()?;
+
+        let manager = runner.manager_mut();
+        spawned.append(&mut manager.spawned);
+        if !manager.vanished {
+            manager.body.advance();
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::data;
+    use crate::run::simulate::{Body, Simulation, SimulationOptions};
+    use crate::run::CompiledBulletML;
+
+    fn simulation(doc: &str, options: SimulationOptions) -> Simulation {
+        let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+        let compiled = CompiledBulletML::new(bulletml).unwrap();
+        Simulation::new(&compiled, options)
+    }
+
+    fn close(body: &Body, x: f32, y: f32) -> bool {
+        (body.x - x).hypot(body.y - y) < 1e-3
+    }
+
+    #[test]
+    fn test_simulate_aimed() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <fire>
+                    <speed>2</speed>
+                    <bullet/>
+                </fire>
+                <wait>5</wait>
+                <fire>
+                    <direction type="absolute">90</direction>
+                    <bullet/>
+                </fire>
+            </action>
+        </bulletml>"#;
+        let mut sim = simulation(doc, SimulationOptions::default());
+
+        assert!(sim.run_to_completion(100).unwrap());
+        assert_eq!(sim.turn(), 6);
+        assert_eq!(sim.fired(), 2);
+
+        let bullets = sim.bullets().collect::<Vec<_>>();
+        // The target is straight down and bullets move on the frame they are fired.
+        assert!(close(bullets[0], 0., 12.));
+        assert!(close(bullets[1], 1., 0.));
+    }
+
+    #[test]
+    fn test_simulate_scripted() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <fire>
+                    <direction type="absolute">90</direction>
+                    <bullet>
+                        <action>
+                            <wait>2</wait>
+                            <changeDirection>
+                                <direction type="absolute">180</direction>
+                                <term>1</term>
+                            </changeDirection>
+                            <wait>2</wait>
+                            <fire>
+                                <direction type="absolute">0</direction>
+                                <bullet/>
+                            </fire>
+                            <vanish/>
+                        </action>
+                    </bullet>
+                </fire>
+            </action>
+        </bulletml>"#;
+        let mut sim = simulation(doc, SimulationOptions::default());
+
+        sim.run(3).unwrap();
+        assert_eq!(sim.runners().len(), 1);
+        assert!(!sim.is_done());
+
+        assert!(sim.run_to_completion(100).unwrap());
+        assert_eq!(sim.fired(), 2);
+        // The scripted bullet vanished after firing from where it had moved to.
+        let bullets = sim.bullets().collect::<Vec<_>>();
+        assert_eq!(bullets.len(), 1);
+        assert!(sim.runners().is_empty());
+        assert_eq!(bullets[0].direction, 0.);
+        assert!(bullets[0].x > 1.);
+    }
+
+    #[test]
+    fn test_simulate_lead() {
+        let doc = |lead| {
+            format!(
+                r#"<bulletml>
+                    <action label="top">
+                        <fire>
+                            <direction lead="{}">0</direction>
+                            <speed>2</speed>
+                            <bullet/>
+                        </fire>
+                    </action>
+                </bulletml>"#,
+                lead,
+            )
+        };
+        let options = SimulationOptions {
+            target: (0., -100.),
+            target_velocity: (1., 0.),
+            ..Default::default()
+        };
+        let closest = |lead| {
+            let mut sim = simulation(&doc(lead), options.clone());
+            (0..100)
+                .map(|_| {
+                    sim.step().unwrap();
+                    let (x, y) = sim.target();
+                    let bullet = sim.bullets().next().unwrap();
+                    (bullet.x - x).hypot(bullet.y - y)
+                })
+                .fold(f32::INFINITY, f32::min)
+        };
+
+        assert!(closest(true) < 1.);
+        assert!(closest(false) > 10.);
+    }
+
+    #[test]
+    fn test_simulate_incomplete() {
+        let doc = r#"<bulletml>
+            <action label="top">
+                <repeat>
+                    <times>100</times>
+                    <action>
+                        <fire>
+                            <direction type="absolute">360 * $rand</direction>
+                            <bullet/>
+                        </fire>
+                        <wait>1</wait>
+                    </action>
+                </repeat>
+            </action>
+        </bulletml>"#;
+        let mut sim = simulation(doc, SimulationOptions::default());
+
+        assert!(!sim.run_to_completion(10).unwrap());
+        assert_eq!(sim.turn(), 10);
+        assert_eq!(sim.fired(), 10);
+
+        // The seed determines the values of `$rand`.
+        let directions = |seed| {
+            let options = SimulationOptions {
+                seed,
+                ..Default::default()
+            };
+            let mut sim = simulation(doc, options);
+            sim.run(4).unwrap();
+            sim.bullets()
+                .map(|bullet| bullet.direction)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(directions(1), directions(1));
+        assert_ne!(directions(1), directions(2));
+    }
+}
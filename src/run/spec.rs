@@ -0,0 +1,366 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+//! The semantics of BulletML as implemented by the runner.
+//!
+//! Each rule has an identifier which is referenced by diagnostics and has an executable test
+//! below named after it. Ports of the interpreter should be able to follow these rules alone.
+//!
+//! - `WAIT-1`: `<wait>` resumes on the turn `turn + ceil(frames)`. Waits of zero or fewer frames
+//!   do not pause.
+//! - `WAIT-2`: when accumulating waits, fractional frames carry over to the next `<wait>`.
+//! - `SEQ-1`: `sequence` directions and speeds of a `<fire>` are relative to the previous bullet
+//!   fired by the runner. Without a previous bullet, the direction aims at the player and the
+//!   speed is `1`.
+//! - `SEQ-2`: `sequence` changes in `<changeDirection>`, `<changeSpeed>`, and `<accel>` are
+//!   amounts per frame.
+//! - `AIM-1`: `aim` directions are computed when the `<fire>` executes.
+//! - `AIM-2`: without a target, `aim` directions follow `RunnerOptions::no_target`. By default,
+//!   the last known target is used.
+//! - `ORIENT-1`: `absolute` directions in `horizontal` documents are rotated by `-90` degrees so
+//!   that `0` points along the direction of play.
+//! - `REPEAT-1`: `<times>` is truncated towards zero; fewer than one repetition runs nothing.
+//! - `REPEAT-2`: with `RepeatEvaluation::EachIteration`, `<times>` is evaluated before each
+//!   iteration and the repeat ends once that many iterations have run.
+//! - `CHANGE-1`: starting a change cancels any in-progress change of the same kind. The new
+//!   change starts from the current value.
+//! - `TTL-1`: the bullet vanishes at the earliest deadline given by a `ttl`.
+//! - `VANISH-1`: once the bullet vanishes, no further steps run and in-progress changes are
+//!   cancelled. With `RunnerOptions::vanish_children`, runners for bullets it fired vanish on
+//!   their next update.
+//! - `SCOPE-1`: variables are looked up in the parameters of the running action, then the
+//!   variables of the runner, and then the manager. Inner scopes shadow outer ones.
+
+use std::fmt;
+
+/// A rule of the semantics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Rule {
+    /// Wait timing.
+    Wait,
+    /// Sequence memory for fired bullets.
+    Sequence,
+    /// Expiration deadlines.
+    Ttl,
+    /// Cancellation of changes.
+    Change,
+    /// Termination by vanishing.
+    Vanish,
+    /// Variable scoping.
+    #[cfg_attr(not(feature = "debug"), allow(dead_code))]
+    Scope,
+}
+
+impl Rule {
+    /// The identifier of the rule.
+    pub(crate) fn id(self) -> &'static str {
+        match self {
+            Rule::Wait => "WAIT-1",
+            Rule::Sequence => "SEQ-1",
+            Rule::Ttl => "TTL-1",
+            Rule::Change => "CHANGE-1",
+            Rule::Vanish => "VANISH-1",
+            Rule::Scope => "SCOPE-1",
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}]", self.id())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::data;
+    use crate::run::testing::TestManager;
+    use crate::run::{CompiledBulletML, NoTargetPolicy, RepeatEvaluation, Runner, RunnerOptions};
+
+    fn runner(doc: &str, options: RunnerOptions) -> Runner<TestManager> {
+        let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+        Runner::with_options(TestManager::default(), bulletml, options).unwrap()
+    }
+
+    fn run<F>(runner: &mut Runner<TestManager>, frames: u32, mut setup: F) -> Vec<String>
+    where
+        F: FnMut(u32, &mut TestManager),
+    {
+        let mut trace = Vec::new();
+
+        for turn in 0..frames {
+            runner.manager_mut().turn = turn;
+            setup(turn, runner.manager_mut());
+            runner.update().unwrap();
+            trace.extend(
+                runner
+                    .manager_mut()
+                    .log
+                    .drain(..)
+                    .map(|cmd| format!("{}: {}", turn, cmd)),
+            );
+        }
+
+        trace
+    }
+
+    fn trace(doc: &str, frames: u32) -> Vec<String> {
+        run(
+            &mut runner(doc, RunnerOptions::default()),
+            frames,
+            |_, _| (),
+        )
+    }
+
+    fn top(steps: &str) -> String {
+        format!(
+            r#"<bulletml><action label="top">{}</action></bulletml>"#,
+            steps,
+        )
+    }
+
+    const FIRE: &str = r#"<fire><direction type="absolute">0</direction><bullet/></fire>"#;
+
+    #[test]
+    fn rule_wait_1() {
+        let doc = top(&format!("{}<wait>2.5</wait>{}", FIRE, FIRE));
+        assert_eq!(
+            trace(&doc, 4),
+            ["0: new_simple(0, 1)", "3: new_simple(0, 1)"],
+        );
+
+        let doc = top(&format!("{}<wait>0</wait>{}", FIRE, FIRE));
+        assert_eq!(
+            trace(&doc, 1),
+            ["0: new_simple(0, 1)", "0: new_simple(0, 1)"],
+        );
+    }
+
+    #[test]
+    fn rule_wait_2() {
+        let doc = top(&format!(
+            "{}<wait>1.5</wait>{}<wait>1.5</wait>{}",
+            FIRE, FIRE, FIRE,
+        ));
+        let options = RunnerOptions {
+            accumulate_wait: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            run(&mut runner(&doc, options), 4, |_, _| ()),
+            [
+                "0: new_simple(0, 1)",
+                "1: new_simple(0, 1)",
+                "3: new_simple(0, 1)",
+            ],
+        );
+    }
+
+    #[test]
+    fn rule_seq_1() {
+        let doc = top(r#"
+            <fire>
+                <direction type="sequence">10</direction>
+                <speed type="sequence">2</speed>
+                <bullet/>
+            </fire>
+            <fire>
+                <direction type="sequence">10</direction>
+                <speed type="sequence">2</speed>
+                <bullet/>
+            </fire>"#);
+        let mut runner = runner(&doc, RunnerOptions::default());
+
+        // The direction and speed of the runner itself do not matter.
+        assert_eq!(
+            run(&mut runner, 1, |_, manager| {
+                manager.aim = 30.;
+                manager.direction = 90.;
+                manager.speed = 5.;
+            }),
+            ["0: new_simple(30, 1)", "0: new_simple(40, 3)"],
+        );
+    }
+
+    #[test]
+    fn rule_seq_2() {
+        let doc = top(r#"
+            <changeSpeed>
+                <speed type="sequence">1</speed>
+                <term>2</term>
+            </changeSpeed>"#);
+
+        assert_eq!(trace(&doc, 3), ["1: change_speed(1)", "2: change_speed(2)"]);
+    }
+
+    #[test]
+    fn rule_aim_1() {
+        let fire = r#"<fire><direction type="aim">0</direction><bullet/></fire>"#;
+        let doc = top(&format!("{}<wait>1</wait>{}", fire, fire));
+        let mut runner = runner(&doc, RunnerOptions::default());
+
+        assert_eq!(
+            run(&mut runner, 2, |turn, manager| {
+                manager.aim = 30. * ((turn + 1) as f32)
+            }),
+            ["0: new_simple(30, 1)", "1: new_simple(60, 1)"],
+        );
+    }
+
+    #[test]
+    fn rule_aim_2() {
+        let fire = r#"<fire><direction type="aim">0</direction><bullet/></fire>"#;
+        let doc = top(&format!("{}<wait>1</wait>{}", fire, fire));
+        let respawn = |turn, manager: &mut TestManager| {
+            manager.aim = 30. * ((turn + 1) as f32);
+            manager.no_target = turn > 0;
+        };
+
+        assert_eq!(
+            run(&mut runner(&doc, RunnerOptions::default()), 2, respawn),
+            ["0: new_simple(30, 1)", "1: new_simple(30, 1)"],
+        );
+
+        let options = RunnerOptions {
+            no_target: NoTargetPolicy::Down,
+            ..Default::default()
+        };
+        assert_eq!(
+            run(&mut runner(&doc, options), 2, respawn),
+            ["0: new_simple(30, 1)", "1: new_simple(180, 1)"],
+        );
+    }
+
+    #[test]
+    fn rule_orient_1() {
+        let doc = |orientation| {
+            format!(
+                r#"<bulletml type="{}"><action label="top">
+                    <fire><direction type="absolute">90</direction><bullet/></fire>
+                </action></bulletml>"#,
+                orientation,
+            )
+        };
+
+        assert_eq!(trace(&doc("none"), 1), ["0: new_simple(90, 1)"]);
+        assert_eq!(trace(&doc("vertical"), 1), ["0: new_simple(90, 1)"]);
+        assert_eq!(trace(&doc("horizontal"), 1), ["0: new_simple(0, 1)"]);
+    }
+
+    #[test]
+    fn rule_repeat_1() {
+        let doc = |times| {
+            top(&format!(
+                "<repeat><times>{}</times><action>{}</action></repeat>",
+                times, FIRE,
+            ))
+        };
+
+        assert_eq!(trace(&doc("2.9"), 1).len(), 2);
+        assert!(trace(&doc("0.9"), 1).is_empty());
+        assert!(trace(&doc("-1"), 1).is_empty());
+    }
+
+    #[test]
+    fn rule_repeat_2() {
+        let doc = top(&format!(
+            "<repeat><times>$rank * 4</times><action>{}<wait>1</wait></action></repeat>",
+            FIRE,
+        ));
+        let ramp = |turn, manager: &mut TestManager| {
+            manager.rank = if turn == 0 { 1. } else { 0.5 };
+        };
+
+        assert_eq!(
+            run(&mut runner(&doc, RunnerOptions::default()), 5, ramp).len(),
+            4,
+        );
+
+        let options = RunnerOptions {
+            repeat_evaluation: RepeatEvaluation::EachIteration,
+            ..Default::default()
+        };
+        assert_eq!(
+            run(&mut runner(&doc, options), 5, ramp),
+            ["0: new_simple(0, 1)", "1: new_simple(0, 1)"],
+        );
+    }
+
+    #[test]
+    fn rule_change_1() {
+        let doc = top(r#"
+            <changeSpeed>
+                <speed type="absolute">4</speed>
+                <term>4</term>
+            </changeSpeed>
+            <wait>2</wait>
+            <changeSpeed>
+                <speed type="absolute">0</speed>
+                <term>2</term>
+            </changeSpeed>"#);
+
+        assert_eq!(
+            trace(&doc, 6),
+            [
+                "1: change_speed(1)",
+                "2: change_speed(2)",
+                "3: change_speed(1)",
+                "4: change_speed(0)",
+            ],
+        );
+    }
+
+    #[test]
+    fn rule_scope_1() {
+        let doc = top(
+            r#"<fire><direction type="absolute">$angle</direction><bullet/></fire>"#,
+        );
+        let bulletml: data::BulletML = serde_xml_rs::from_str(&doc).unwrap();
+        let compiled = CompiledBulletML::new(bulletml).unwrap();
+        let manager = TestManager {
+            variables: vec![("angle", 90.)],
+            ..Default::default()
+        };
+        let mut runner = Runner::from_compiled(manager, &compiled, RunnerOptions::default());
+        runner.set_var("angle", 45.);
+
+        assert_eq!(run(&mut runner, 1, |_, _| ()), ["0: new_simple(45, 1)"]);
+        #[cfg(feature = "debug")]
+        assert!(runner.diagnostic_dump().contains("shadowed [SCOPE-1]: $angle\n"));
+    }
+
+    #[test]
+    fn rule_ttl_1() {
+        let doc = top(&format!("{}<wait>10</wait>", FIRE));
+        let options = RunnerOptions {
+            default_ttl: Some(3),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            run(&mut runner(&doc, options), 5, |_, _| ()),
+            ["0: new_simple(0, 1)", "3: vanish"],
+        );
+    }
+
+    #[test]
+    fn rule_vanish_1() {
+        let doc = top(&format!(
+            r#"
+            <changeSpeed>
+                <speed type="absolute">4</speed>
+                <term>4</term>
+            </changeSpeed>
+            <wait>2</wait>
+            <vanish/>
+            {}"#,
+            FIRE,
+        ));
+
+        assert_eq!(
+            trace(&doc, 6),
+            ["1: change_speed(1)", "2: change_speed(2)", "2: vanish"],
+        );
+    }
+}
@@ -0,0 +1,343 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use std::panic::UnwindSafe;
+
+use crate::data::{ExpressionContext, Value};
+use crate::run::{BulletManager, BulletScript};
+
+/// The scale of the rounding of values written to traces.
+///
+/// Traces only keep four decimal places so that they do not depend on the last bits of floating
+/// point results, which may differ between platforms.
+const TRACE_SCALE: f64 = 1e4;
+/// The largest difference between values of traces which are considered the same.
+///
+/// This is one unit of the rounding, with slack for the rounding itself.
+const TRACE_TOLERANCE: f64 = 1.5 / TRACE_SCALE;
+
+/// Write a value to a trace.
+pub fn canonical(value: f32) -> String {
+    let rounded = (f64::from(value) * TRACE_SCALE).round() / TRACE_SCALE;
+    // Negative zero is written as zero.
+    if rounded == 0. {
+        "0".into()
+    } else {
+        rounded.to_string()
+    }
+}
+
+/// A piece of a line of a trace.
+#[derive(Debug, Clone, Copy)]
+enum TraceToken<'a> {
+    Text(&'a str),
+    Number(f64),
+}
+
+impl<'a> TraceToken<'a> {
+    fn matches(self, other: Self) -> bool {
+        match (self, other) {
+            (TraceToken::Text(lhs), TraceToken::Text(rhs)) => lhs == rhs,
+            (TraceToken::Number(lhs), TraceToken::Number(rhs)) => {
+                (lhs - rhs).abs() <= TRACE_TOLERANCE
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Split a line of a trace into numbers and the text between them.
+fn trace_tokens(line: &str) -> Vec<TraceToken> {
+    let bytes = line.as_bytes();
+    let is_number_start = |idx: usize| {
+        bytes[idx].is_ascii_digit()
+            || (bytes[idx] == b'-' && bytes.get(idx + 1).map_or(false, u8::is_ascii_digit))
+    };
+
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if !is_number_start(idx) {
+            idx += 1;
+            continue;
+        }
+
+        if start < idx {
+            tokens.push(TraceToken::Text(&line[start..idx]));
+        }
+        start = idx;
+        idx += 1;
+        while idx < bytes.len() && (bytes[idx].is_ascii_digit() || bytes[idx] == b'.') {
+            idx += 1;
+        }
+        match line[start..idx].parse() {
+            Ok(value) => tokens.push(TraceToken::Number(value)),
+            Err(_) => tokens.push(TraceToken::Text(&line[start..idx])),
+        }
+        start = idx;
+    }
+    if start < bytes.len() {
+        tokens.push(TraceToken::Text(&line[start..]));
+    }
+
+    tokens
+}
+
+/// Whether two lines of traces are the same up to the tolerance of their values.
+fn trace_line_matches(actual: &str, expected: &str) -> bool {
+    let actual = trace_tokens(actual);
+    let expected = trace_tokens(expected);
+
+    actual.len() == expected.len()
+        && actual
+            .iter()
+            .zip(expected.iter())
+            .all(|(&lhs, &rhs)| lhs.matches(rhs))
+}
+
+/// Assert that two traces are the same up to the tolerance of their values.
+///
+/// Golden traces should be compared with this rather than `assert_eq!` so that they do not fail
+/// on platforms whose floating point results differ in their last bits.
+pub fn assert_trace_eq<A, E>(actual: &[A], expected: &[E])
+where
+    A: AsRef<str>,
+    E: AsRef<str>,
+{
+    let actual = actual.iter().map(AsRef::as_ref).collect::<Vec<_>>();
+    let expected = expected.iter().map(AsRef::as_ref).collect::<Vec<_>>();
+
+    let mismatch = actual
+        .iter()
+        .zip(expected.iter())
+        .position(|(lhs, rhs)| !trace_line_matches(lhs, rhs));
+    if let Some(idx) = mismatch {
+        panic!(
+            "traces differ at line {}: `{}` != `{}`\n actual: {:?}\n expected: {:?}",
+            idx, actual[idx], expected[idx], actual, expected,
+        );
+    }
+    if actual.len() != expected.len() {
+        panic!(
+            "traces differ in length: {} != {}\n actual: {:?}\n expected: {:?}",
+            actual.len(),
+            expected.len(),
+            actual,
+            expected,
+        );
+    }
+}
+
+/// A bullet manager which records the commands it receives.
+#[derive(Debug, Default)]
+pub struct TestManager {
+    pub turn: u32,
+    pub direction: f32,
+    pub speed: f32,
+    pub speed_x: f32,
+    pub speed_y: f32,
+    pub aim: f32,
+    pub no_target: bool,
+    pub target_velocity: Option<(f32, f32)>,
+    pub rank: f32,
+    pub variables: Vec<(&'static str, Value)>,
+    pub log: Vec<String>,
+    pub scripts: Vec<BulletScript>,
+    pub panic_on_fire: bool,
+}
+
+// Scripts of fired bullets hold steps which are not unwind safe, but they are only collected for
+// tests to inspect.
+impl UnwindSafe for TestManager {}
+
+// Copies for the reference evaluator and command histories do not need the scripts of fired
+// bullets.
+#[cfg(any(feature = "debug", feature = "reference-check"))]
+impl Clone for TestManager {
+    fn clone(&self) -> Self {
+        TestManager {
+            turn: self.turn,
+            direction: self.direction,
+            speed: self.speed,
+            speed_x: self.speed_x,
+            speed_y: self.speed_y,
+            aim: self.aim,
+            no_target: self.no_target,
+            target_velocity: self.target_velocity,
+            rank: self.rank,
+            variables: self.variables.clone(),
+            log: self.log.clone(),
+            scripts: Vec::new(),
+            panic_on_fire: self.panic_on_fire,
+        }
+    }
+}
+
+impl ExpressionContext for TestManager {
+    fn get(&self, name: &str) -> Option<Value> {
+        self.variables
+            .iter()
+            .find(|&&(var, _)| var == name)
+            .map(|&(_, value)| value)
+    }
+
+    fn get_index(&self, idx: usize, _: &str) -> Option<Value> {
+        self.variables.get(idx).map(|&(_, value)| value)
+    }
+
+    fn get_param(&self, _: usize) -> Option<Value> {
+        None
+    }
+
+    fn rand(&self) -> Value {
+        0.5
+    }
+
+    fn rank(&self) -> Value {
+        self.rank
+    }
+}
+
+impl BulletManager for TestManager {
+    fn new_simple(&mut self, direction: f32, speed: f32) {
+        if self.panic_on_fire {
+            panic!("new_simple({}, {})", canonical(direction), canonical(speed));
+        }
+        self.log.push(format!(
+            "new_simple({}, {})",
+            canonical(direction),
+            canonical(speed),
+        ));
+    }
+
+    fn new_bullet(&mut self, direction: f32, speed: f32) {
+        self.log.push(format!(
+            "new_bullet({}, {})",
+            canonical(direction),
+            canonical(speed),
+        ));
+    }
+
+    fn new_bullet_with_script(&mut self, direction: f32, speed: f32, script: BulletScript) {
+        self.new_bullet(direction, speed);
+        self.scripts.push(script);
+    }
+
+    fn turn(&self) -> u32 {
+        self.turn
+    }
+
+    fn direction(&self) -> f32 {
+        self.direction
+    }
+
+    fn aim_direction(&self) -> f32 {
+        self.aim
+    }
+
+    fn try_aim_direction(&self) -> Option<f32> {
+        if self.no_target {
+            None
+        } else {
+            Some(self.aim)
+        }
+    }
+
+    fn target_velocity(&self) -> Option<(f32, f32)> {
+        self.target_velocity
+    }
+
+    fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    fn speed_x(&self) -> f32 {
+        self.speed_x
+    }
+
+    fn speed_y(&self) -> f32 {
+        self.speed_y
+    }
+
+    fn default_speed(&self) -> f32 {
+        1.
+    }
+
+    fn vanish(&mut self) {
+        self.log.push("vanish".into());
+    }
+
+    fn change_direction(&mut self, degrees: f32) {
+        self.direction = degrees;
+        self.log
+            .push(format!("change_direction({})", canonical(degrees)));
+    }
+
+    fn change_speed(&mut self, speed: f32) {
+        self.speed = speed;
+        self.log.push(format!("change_speed({})", canonical(speed)));
+    }
+
+    fn accel_x(&mut self, amount: f32) {
+        self.speed_x = amount;
+        self.log.push(format!("accel_x({})", canonical(amount)));
+    }
+
+    fn accel_y(&mut self, amount: f32) {
+        self.speed_y = amount;
+        self.log.push(format!("accel_y({})", canonical(amount)));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::run::testing::{self, TestManager};
+    use crate::run::BulletManager;
+
+    #[test]
+    fn test_canonical() {
+        assert_eq!(testing::canonical(45.), "45");
+        assert_eq!(testing::canonical(0.5), "0.5");
+        assert_eq!(testing::canonical(1. / 3.), "0.3333");
+        assert_eq!(testing::canonical(-2. / 3.), "-0.6667");
+        assert_eq!(testing::canonical(-0.), "0");
+        assert_eq!(testing::canonical(-0.00001), "0");
+        assert_eq!(testing::canonical(0.1 + 0.2), "0.3");
+
+        let mut manager = TestManager::default();
+        manager.new_simple(10. / 3., 1.);
+        manager.change_direction(-1. / 7.);
+        assert_eq!(
+            manager.log,
+            ["new_simple(3.3333, 1)", "change_direction(-0.1429)"],
+        );
+    }
+
+    #[test]
+    fn test_assert_trace_eq() {
+        testing::assert_trace_eq(
+            &["0: new_simple(90.0001, 2)", "3: accel_x(-0.5)", "4: vanish"],
+            &["0: new_simple(90, 2)", "3: accel_x(-0.5001)", "4: vanish"],
+        );
+        testing::assert_trace_eq::<&str, &str>(&[], &[]);
+    }
+
+    #[test]
+    #[should_panic(expected = "traces differ at line 0")]
+    fn test_assert_trace_eq_value() {
+        testing::assert_trace_eq(&["0: new_simple(90.001, 2)"], &["0: new_simple(90, 2)"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "traces differ at line 0")]
+    fn test_assert_trace_eq_text() {
+        testing::assert_trace_eq(&["0: new_bullet(90, 2)"], &["0: new_simple(90, 2)"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "traces differ in length")]
+    fn test_assert_trace_eq_length() {
+        testing::assert_trace_eq(&["0: vanish", "1: vanish"], &["0: vanish"]);
+    }
+}
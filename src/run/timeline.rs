@@ -0,0 +1,345 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use std::sync::mpsc;
+
+use serde::Serialize;
+
+use crate::data::{ExpressionContext, ExpressionError, Value};
+use crate::run::simulate::Body;
+use crate::run::Rng;
+use crate::run::{BulletId, BulletManager, CompiledBulletML, Event, Runner, RunnerOptions};
+
+/// A bullet spawned within a timeline.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Spawn {
+    /// The frame on which the bullet was spawned.
+    pub frame: u32,
+    /// The identifier of the bullet.
+    pub bullet: BulletId,
+    /// Whether the bullet is simple (i.e., has no actions).
+    pub simple: bool,
+}
+
+/// The state of motion of a bullet at a frame.
+///
+/// Motion between consecutive keyframes of the same bullet is linear.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Keyframe {
+    /// The frame of the keyframe.
+    pub frame: u32,
+    /// The bullet (`None` for the emitter running the script).
+    pub bullet: Option<BulletId>,
+    /// The horizontal position.
+    pub x: f32,
+    /// The vertical position (increasing downwards).
+    pub y: f32,
+    /// The direction of motion in degrees.
+    pub direction: f32,
+    /// The speed of motion in units per frame.
+    pub speed: f32,
+}
+
+/// A timeline of a pattern suitable for export to external tools.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Timeline {
+    /// The number of frames covered by the timeline.
+    pub frames: u32,
+    /// Bullets spawned during the timeline in the order they were fired.
+    pub spawns: Vec<Spawn>,
+    /// Keyframes for the emitter and spawned bullets ordered by frame.
+    pub keyframes: Vec<Keyframe>,
+}
+
+/// The keyframe for the state of a body.
+fn keyframe(body: &Body, frame: u32, bullet: Option<BulletId>) -> Keyframe {
+    Keyframe {
+        frame,
+        bullet,
+        x: body.x,
+        y: body.y,
+        direction: body.direction,
+        speed: body.speed,
+    }
+}
+
+/// The emitter is at the origin and aims straight down.
+const AIM_DIRECTION: f32 = 180.;
+const DEFAULT_SPEED: f32 = 1.;
+
+/// A manager which simulates the motion of the emitter.
+#[derive(Debug)]
+pub(crate) struct TimelineManager {
+    rng: Rng,
+    rank: Value,
+    pub(crate) turn: u32,
+    emitter: Body,
+}
+
+impl TimelineManager {
+    pub(crate) fn new(rank: Value, seed: u64) -> Self {
+        TimelineManager {
+            rng: Rng::new(seed),
+            rank,
+            turn: 0,
+            emitter: Body::new(0., 0., 0., 0.),
+        }
+    }
+}
+
+impl ExpressionContext for TimelineManager {
+    fn get(&self, _: &str) -> Option<Value> {
+        None
+    }
+
+    fn get_param(&self, _: usize) -> Option<Value> {
+        None
+    }
+
+    fn rand(&self) -> Value {
+        self.rng.next_value()
+    }
+
+    fn rank(&self) -> Value {
+        self.rank
+    }
+}
+
+impl BulletManager for TimelineManager {
+    fn new_simple(&mut self, _: f32, _: f32) {
+        // Spawns are tracked through runner events.
+    }
+
+    fn new_bullet(&mut self, _: f32, _: f32) {
+        // Spawns are tracked through runner events.
+    }
+
+    fn turn(&self) -> u32 {
+        self.turn
+    }
+
+    fn direction(&self) -> f32 {
+        self.emitter.direction
+    }
+
+    fn aim_direction(&self) -> f32 {
+        AIM_DIRECTION
+    }
+
+    fn speed(&self) -> f32 {
+        self.emitter.speed
+    }
+
+    fn speed_x(&self) -> f32 {
+        self.emitter.speed_x()
+    }
+
+    fn speed_y(&self) -> f32 {
+        self.emitter.speed_y()
+    }
+
+    fn default_speed(&self) -> f32 {
+        DEFAULT_SPEED
+    }
+
+    fn vanish(&mut self) {}
+
+    fn change_direction(&mut self, degrees: f32) {
+        self.emitter.direction = degrees;
+    }
+
+    fn change_speed(&mut self, speed: f32) {
+        self.emitter.speed = speed;
+    }
+
+    fn accel_x(&mut self, amount: f32) {
+        let speed_y = self.emitter.speed_y();
+        self.emitter.set_velocity(amount, speed_y);
+    }
+
+    fn accel_y(&mut self, amount: f32) {
+        let speed_x = self.emitter.speed_x();
+        self.emitter.set_velocity(speed_x, amount);
+    }
+}
+
+impl CompiledBulletML {
+    /// Simulate the script and export its motion as a timeline of keyframes.
+    ///
+    /// The emitter starts at the origin at rest and its aim is straight down (`180` degrees).
+    /// Random values are drawn from a generator initialized with `seed`, so the same inputs
+    /// always produce the same timeline. Spawned bullets move in straight lines; their own
+    /// actions are not run.
+    ///
+    /// The emitter has a keyframe whenever its motion changes and every spawned bullet has one
+    /// when spawned. All bodies have a final keyframe at `frames`.
+    pub fn to_keyframes(
+        &self,
+        rank: Value,
+        seed: u64,
+        frames: u32,
+    ) -> Result<Timeline, ExpressionError> {
+        let manager = TimelineManager::new(rank, seed);
+        let mut runner = Runner::from_compiled(manager, self, RunnerOptions::default());
+        let (sender, fired) = mpsc::channel();
+        runner.set_observer(move |event: &Event| {
+            if let Event::Fired {
+                id,
+                direction,
+                speed,
+                simple,
+                ..
+            } = *event
+            {
+                // The receiver is alive while the runner updates.
+                let _ = sender.send((id, direction, speed, simple));
+            }
+        });
+
+        let mut timeline = Timeline {
+            frames,
+            ..Timeline::default()
+        };
+        let mut bullets: Vec<(BulletId, Body)> = Vec::new();
+        let mut last_emitter: Option<Body> = None;
+
+        for frame in 0..frames {
+            runner.manager_mut().turn = frame;
+            runner.update()?;
+
+            let emitter = runner.manager().emitter;
+            let changed = last_emitter.map_or(true, |last| {
+                last.direction != emitter.direction || last.speed != emitter.speed
+            });
+            if changed {
+                timeline.keyframes.push(keyframe(&emitter, frame, None));
+            }
+
+            for (id, direction, speed, simple) in fired.try_iter() {
+                let body = Body::new(emitter.x, emitter.y, direction, speed);
+                timeline.spawns.push(Spawn {
+                    frame,
+                    bullet: id,
+                    simple,
+                });
+                timeline.keyframes.push(keyframe(&body, frame, Some(id)));
+                bullets.push((id, body));
+            }
+
+            runner.manager_mut().emitter.advance();
+            bullets.iter_mut().for_each(|(_, body)| body.advance());
+            last_emitter = Some(runner.manager().emitter);
+        }
+
+        if frames > 0 {
+            let emitter = runner.manager().emitter;
+            timeline.keyframes.push(keyframe(&emitter, frames, None));
+            timeline.keyframes.extend(
+                bullets
+                    .iter()
+                    .map(|&(id, ref body)| keyframe(body, frames, Some(id))),
+            );
+        }
+
+        Ok(timeline)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::data;
+    use crate::run::{CompiledBulletML, Keyframe, Spawn};
+
+    fn compile(doc: &str) -> CompiledBulletML {
+        let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+        CompiledBulletML::new(bulletml).unwrap()
+    }
+
+    #[test]
+    fn test_keyframes_spawns() {
+        let compiled = compile(
+            r#"<bulletml>
+                <action label="top">
+                    <fire>
+                        <direction type="absolute">90</direction>
+                        <speed>2</speed>
+                        <bullet/>
+                    </fire>
+                    <fire>
+                        <direction type="aim">0</direction>
+                        <speed>$rank</speed>
+                        <bullet/>
+                    </fire>
+                </action>
+            </bulletml>"#,
+        );
+
+        let timeline = compiled.to_keyframes(3., 0, 4).unwrap();
+
+        assert_eq!(timeline.frames, 4);
+        assert_eq!(
+            timeline
+                .spawns
+                .iter()
+                .map(|spawn| (spawn.frame, spawn.bullet.get(), spawn.simple))
+                .collect::<Vec<_>>(),
+            vec![(0, 0, true), (0, 1, true)],
+        );
+
+        let last = |id| {
+            timeline
+                .keyframes
+                .iter()
+                .rev()
+                .find(|keyframe| keyframe.bullet.map(|b| b.get()) == Some(id))
+                .copied()
+                .unwrap()
+        };
+        let first = last(0);
+        assert_eq!(first.frame, 4);
+        assert!((first.x - 8.).abs() < 1e-4);
+        assert!(first.y.abs() < 1e-4);
+        let second = last(1);
+        assert_eq!(second.frame, 4);
+        assert!(second.x.abs() < 1e-4);
+        assert!((second.y - 12.).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_keyframes_deterministic() {
+        let compiled = compile(
+            r#"<bulletml>
+                <action label="top">
+                    <repeat>
+                        <times>4</times>
+                        <action>
+                            <fire>
+                                <direction type="absolute">360 * $rand</direction>
+                                <bullet/>
+                            </fire>
+                        </action>
+                    </repeat>
+                </action>
+            </bulletml>"#,
+        );
+
+        let lhs = compiled.to_keyframes(0., 7, 2).unwrap();
+        let rhs = compiled.to_keyframes(0., 7, 2).unwrap();
+        let other = compiled.to_keyframes(0., 8, 2).unwrap();
+
+        assert_eq!(lhs, rhs);
+        assert_ne!(lhs, other);
+        assert_eq!(lhs.spawns.len(), 4);
+        assert!(lhs.spawns.iter().all(|spawn| *spawn == Spawn {
+            frame: 0,
+            bullet: spawn.bullet,
+            simple: true,
+        }));
+        // One keyframe for the emitter and each bullet at the start and the end.
+        assert_eq!(lhs.keyframes.len(), 10);
+        assert!(lhs
+            .keyframes
+            .iter()
+            .all(|keyframe: &Keyframe| keyframe.frame == 0 || keyframe.frame == 2));
+    }
+}
@@ -5,9 +5,11 @@ use std::collections::hash_map::{Entry, HashMap};
 
 use thiserror::Error;
 
+use crate::data::ErrorCode;
+
 #[derive(Debug, Error)]
 pub enum EntityError {
-    #[error("duplicate {} entity `{}`", kind, name)]
+    #[error("{}: duplicate {} entity `{}`", ErrorCode::DuplicateLabel, kind, name)]
     Duplicate { name: String, kind: &'static str },
 }
 
@@ -21,6 +23,14 @@ impl EntityError {
             name: name.into(),
         }
     }
+
+    pub fn code(&self) -> ErrorCode {
+        match *self {
+            EntityError::Duplicate {
+                ..
+            } => ErrorCode::DuplicateLabel,
+        }
+    }
 }
 
 pub fn try_insert<N, V, F>(
@@ -0,0 +1,301 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+use std::slice;
+use std::sync::Arc;
+
+use crate::run::compile::{
+    Accel, Action, ChangeDirection, ChangeSpeed, CustomStep, Expression, Fire, Repeat, Step,
+    Vanish, Wait,
+};
+use crate::run::CompiledBulletML;
+
+/// A step of a compiled script.
+#[derive(Debug, Clone, Copy)]
+pub enum StepKind<'a> {
+    /// The start of an action.
+    ///
+    /// This is either a top-level action, an action referenced from another action, an action
+    /// repeated by a `<repeat>`, or an action of a fired bullet.
+    Action(&'a Action),
+    /// Cause a set of actions to be repeated a number of times.
+    Repeat(&'a Repeat),
+    /// Cause a bullet to be fired.
+    Fire(&'a Fire),
+    /// A change of speed.
+    ChangeSpeed(&'a ChangeSpeed),
+    /// A change of direction.
+    ChangeDirection(&'a ChangeDirection),
+    /// An acceleration.
+    Accel(&'a Accel),
+    /// Pause for a number of frames.
+    Wait(&'a Wait),
+    /// Destroy the bullet.
+    Vanish(&'a Vanish),
+    /// A user-defined step.
+    Custom(&'a dyn CustomStep),
+}
+
+impl<'a> StepKind<'a> {
+    /// The name of the element for the step.
+    pub fn name(&self) -> &'a str {
+        match *self {
+            StepKind::Action(_) => "action",
+            StepKind::Repeat(_) => "repeat",
+            StepKind::Fire(_) => "fire",
+            StepKind::ChangeSpeed(_) => "changeSpeed",
+            StepKind::ChangeDirection(_) => "changeDirection",
+            StepKind::Accel(_) => "accel",
+            StepKind::Wait(_) => "wait",
+            StepKind::Vanish(_) => "vanish",
+            StepKind::Custom(custom) => custom.name(),
+        }
+    }
+
+    fn from_step(step: &'a Step) -> Self {
+        match *step {
+            Step::Repeat(ref repeat) => StepKind::Repeat(repeat),
+            Step::Fire(ref fire, _) => StepKind::Fire(fire),
+            Step::ChangeSpeed(ref cs) => StepKind::ChangeSpeed(cs),
+            Step::ChangeDirection(ref cd) => StepKind::ChangeDirection(cd),
+            Step::Accel(ref accel) => StepKind::Accel(accel),
+            Step::Wait(ref wait) => StepKind::Wait(wait),
+            Step::Vanish(ref vanish) => StepKind::Vanish(vanish),
+            Step::Custom(ref custom) => StepKind::Custom(custom.as_ref()),
+            Step::Action(ref action, _) => StepKind::Action(action),
+        }
+    }
+}
+
+/// A step reached while walking a compiled script.
+#[derive(Debug, Clone, Copy)]
+pub struct StepInfo<'a> {
+    /// The step.
+    pub kind: StepKind<'a>,
+    /// The number of actions and repeats enclosing the step.
+    ///
+    /// Top-level actions have a depth of zero. The actions of a fired bullet are nested within
+    /// the `<fire>` which fires it.
+    pub depth: usize,
+    /// The number of `<fire>` elements enclosing the step.
+    ///
+    /// Steps with a bullet depth of zero are run by the runner itself; others are run by the
+    /// scripts of fired bullets.
+    pub bullet_depth: usize,
+}
+
+/// The children of a step which remain to be walked.
+#[derive(Debug)]
+enum Pending<'a> {
+    Actions(slice::Iter<'a, Arc<Action>>),
+    RefActions(slice::Iter<'a, (Arc<Action>, Option<Arc<[Expression]>>)>),
+    Steps(slice::Iter<'a, Step>),
+}
+
+impl<'a> Pending<'a> {
+    fn next(&mut self) -> Option<StepKind<'a>> {
+        match *self {
+            Pending::Actions(ref mut actions) => {
+                actions.next().map(|action| StepKind::Action(action))
+            },
+            Pending::RefActions(ref mut actions) => {
+                actions.next().map(|(action, _)| StepKind::Action(action))
+            },
+            Pending::Steps(ref mut steps) => steps.next().map(StepKind::from_step),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Level<'a> {
+    pending: Pending<'a>,
+    depth: usize,
+    bullet_depth: usize,
+}
+
+/// An iterator over the steps of a compiled script without running it.
+///
+/// Steps are visited in document order with each step before the steps within it. Entities
+/// which are referenced from multiple places are visited once per reference and `<repeat>`
+/// bodies are visited once regardless of their count, so the walk describes the structure of
+/// the script rather than its execution. Expressions are not evaluated.
+///
+/// This is intended for tools such as visualizers and linters which analyze patterns without
+/// driving a `BulletManager`.
+#[derive(Debug)]
+pub struct StepIter<'a> {
+    stack: Vec<Level<'a>>,
+}
+
+impl<'a> StepIter<'a> {
+    fn new(pending: Pending<'a>) -> Self {
+        StepIter {
+            stack: vec![Level {
+                pending,
+                depth: 0,
+                bullet_depth: 0,
+            }],
+        }
+    }
+
+    fn enter(&mut self, info: &StepInfo<'a>) {
+        let (pending, bullet_depth) = match info.kind {
+            StepKind::Action(action) => (Pending::Steps(action.steps.iter()), info.bullet_depth),
+            StepKind::Repeat(repeat) => {
+                (
+                    Pending::RefActions(repeat.actions.iter()),
+                    info.bullet_depth,
+                )
+            },
+            StepKind::Fire(fire) => {
+                (
+                    Pending::RefActions(fire.bullet.actions.iter()),
+                    info.bullet_depth + 1,
+                )
+            },
+            _ => return,
+        };
+
+        self.stack.push(Level {
+            pending,
+            depth: info.depth + 1,
+            bullet_depth,
+        });
+    }
+}
+
+impl<'a> Iterator for StepIter<'a> {
+    type Item = StepInfo<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let level = self.stack.last_mut()?;
+            if let Some(kind) = level.pending.next() {
+                let info = StepInfo {
+                    kind,
+                    depth: level.depth,
+                    bullet_depth: level.bullet_depth,
+                };
+                self.enter(&info);
+                return Some(info);
+            }
+            self.stack.pop();
+        }
+    }
+}
+
+impl CompiledBulletML {
+    /// Walk the steps of the script starting from its top-level actions.
+    ///
+    /// See `StepIter` for the order of the steps.
+    pub fn walk(&self) -> StepIter {
+        StepIter::new(Pending::Actions(self.actions().iter()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::data;
+    use crate::run::{CompiledBulletML, StepKind};
+
+    const DOC: &str = r#"<bulletml>
+        <bullet label="splitter">
+            <action>
+                <wait>10</wait>
+                <fire><bullet/></fire>
+                <vanish/>
+            </action>
+        </bullet>
+        <action label="volley">
+            <repeat>
+                <times>3</times>
+                <action>
+                    <fire><bulletRef label="splitter"/></fire>
+                    <wait>5</wait>
+                </action>
+            </repeat>
+        </action>
+        <action label="top">
+            <changeSpeed>
+                <speed>2</speed>
+                <term>4</term>
+            </changeSpeed>
+            <actionRef label="volley"/>
+            <wait>20</wait>
+        </action>
+    </bulletml>"#;
+
+    fn compile(doc: &str) -> CompiledBulletML {
+        let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+        CompiledBulletML::new(bulletml).unwrap()
+    }
+
+    #[test]
+    fn test_walk() {
+        let compiled = compile(DOC);
+
+        let steps = compiled
+            .walk()
+            .map(|info| (info.kind.name(), info.depth, info.bullet_depth))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            steps,
+            [
+                ("action", 0, 0),
+                ("changeSpeed", 1, 0),
+                ("action", 1, 0),
+                ("repeat", 2, 0),
+                ("action", 3, 0),
+                ("fire", 4, 0),
+                ("action", 5, 1),
+                ("wait", 6, 1),
+                ("fire", 6, 1),
+                ("vanish", 6, 1),
+                ("wait", 4, 0),
+                ("wait", 1, 0),
+            ],
+        );
+
+        let action = compiled.walk().next().unwrap();
+        if let StepKind::Action(action) = action.kind {
+            assert_eq!(action.label(), Some("top"));
+        } else {
+            panic!("unexpected step: {:?}", action);
+        }
+    }
+
+    #[test]
+    fn test_walk_analysis() {
+        let compiled = compile(DOC);
+
+        let max_depth = compiled.walk().map(|info| info.depth).max();
+        assert_eq!(max_depth, Some(6));
+
+        let fires = compiled
+            .walk()
+            .filter(|info| matches!(info.kind, StepKind::Fire(_)))
+            .count();
+        assert_eq!(fires, 2);
+
+        let runner_waits = compiled
+            .walk()
+            .filter(|info| info.bullet_depth == 0)
+            .filter_map(|info| {
+                if let StepKind::Wait(wait) = info.kind {
+                    wait.frames.constant_value()
+                } else {
+                    None
+                }
+            })
+            .sum::<f32>();
+        assert_eq!(runner_waits, 25.);
+    }
+
+    #[test]
+    fn test_walk_empty() {
+        let compiled = CompiledBulletML::new(data::BulletML::default()).unwrap();
+
+        assert_eq!(compiled.walk().count(), 0);
+    }
+}
@@ -1,6 +1,7 @@
 // Distributed under the OSI-approved BSD 2-Clause License.
 // See accompanying LICENSE file for details.
 
+use std::iter;
 use std::mem;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -29,6 +30,10 @@ impl<T> Node<T> {
         self.children.push(child);
     }
 
+    pub fn clear(&mut self) {
+        self.children.clear();
+    }
+
     pub fn zipper(self) -> Zipper<T> {
         Zipper {
             node: self,
@@ -43,6 +48,12 @@ impl<T> AsRef<T> for Node<T> {
     }
 }
 
+impl<T> AsMut<T> for Node<T> {
+    fn as_mut(&mut self) -> &mut T {
+        &mut self.data
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum ParentStatus {
     AtRoot,
@@ -67,6 +78,18 @@ impl<T> Zipper<T> {
         ZipperIter::new(self)
     }
 
+    /// The data of the nodes from the root to the current node.
+    pub fn path(&self) -> Vec<&T> {
+        let mut path = vec![&self.node.data];
+        let mut zipper = self;
+        while let Some((ref parent, _)) = zipper.parent {
+            path.push(&parent.node.data);
+            zipper = parent;
+        }
+        path.reverse();
+        path
+    }
+
     fn child(&mut self, idx: usize) {
         // Find the child.
         let child = self.node.children.swap_remove(idx);
@@ -89,6 +112,8 @@ impl<T> Zipper<T> {
 
         // Swap the node with the parent node.
         mem::swap(&mut self.node, &mut parent.node);
+        // Take over the parent's link to its own parent.
+        self.parent = parent.parent.take();
 
         // Push the old child node back into its position.
         self.node.children.push(parent.node);
@@ -134,6 +159,28 @@ impl<T> ZipperIter<T> {
         Some(&self.zipper.node.data)
     }
 
+    pub fn path(&self) -> Vec<&T> {
+        if self.done {
+            return Vec::new();
+        }
+
+        self.zipper.path()
+    }
+
+    /// The data of the nodes from the current node to the root.
+    pub fn ancestors(&self) -> impl Iterator<Item = &T> {
+        let current = if self.done {
+            None
+        } else {
+            Some(&self.zipper)
+        };
+
+        iter::successors(current, |zipper| {
+            zipper.parent.as_ref().map(|(parent, _)| parent.as_ref())
+        })
+        .map(|zipper| &zipper.node.data)
+    }
+
     pub fn current_mut(&mut self) -> Option<&mut Node<T>> {
         if self.done {
             return None;
@@ -218,6 +265,25 @@ mod test {
         assert!(iter.done);
     }
 
+    #[test]
+    fn test_zipper_ancestors() {
+        let mut tree = Node::new(0);
+        let mut child = Node::new(1);
+        child.add_child(Node::new(2));
+        tree.add_child(child);
+        tree.add_child(Node::new(3));
+        let zipper = tree.zipper();
+        let mut iter = zipper.iter();
+        iter.next();
+        iter.next();
+        iter.next();
+        assert_eq!(iter.ancestors().collect::<Vec<_>>(), [&2, &1, &0]);
+        iter.next();
+        assert_eq!(iter.ancestors().collect::<Vec<_>>(), [&3, &0]);
+        iter.next();
+        assert_eq!(iter.ancestors().next(), None);
+    }
+
     #[test]
     fn test_zipper_siblings() {
         let mut tree = Node::new(0);
@@ -238,4 +304,45 @@ mod test {
         assert_eq!(iter.next(), None);
         assert!(iter.done);
     }
+
+    #[test]
+    fn test_zipper_path() {
+        let mut tree = Node::new(0);
+        let mut child = Node::new(1);
+        child.add_child(Node::new(2));
+        tree.add_child(child);
+        tree.add_child(Node::new(3));
+        let zipper = tree.zipper();
+        let mut iter = zipper.iter();
+        iter.next();
+        assert_eq!(iter.path(), [&0]);
+        iter.next();
+        assert_eq!(iter.path(), [&0, &1]);
+        iter.next();
+        assert_eq!(iter.path(), [&0, &1, &2]);
+        iter.next();
+        assert_eq!(iter.path(), [&0, &3]);
+        iter.next();
+        assert!(iter.path().is_empty());
+    }
+
+    #[test]
+    fn test_zipper_nested() {
+        let mut tree = Node::new(0);
+        let mut child = Node::new(1);
+        let mut grandchild = Node::new(2);
+        grandchild.add_child(Node::new(3));
+        grandchild.add_child(Node::new(4));
+        child.add_child(grandchild);
+        child.add_child(Node::new(5));
+        tree.add_child(child);
+        tree.add_child(Node::new(6));
+        let zipper = tree.zipper();
+        let mut iter = zipper.iter();
+        let mut order = Vec::new();
+        while let Some(&data) = iter.next() {
+            order.push(data);
+        }
+        assert_eq!(order, [0, 1, 2, 3, 4, 5, 6]);
+    }
 }
@@ -0,0 +1,727 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+//! Schemas for BulletML documents.
+//!
+//! The schemas describe the documents accepted by the parser in a dialect, including the
+//! extensions supported by this crate. They are generated from the names recognized by
+//! `Dialect::element_names` and `Dialect::attribute_names` so that external validators and
+//! editors stay in sync with the parser.
+//!
+//! Schemas describe parsing with `ParseOptions::strict`; otherwise, unknown elements are ignored.
+//! Custom steps registered with `ParseOptions::custom_steps` are not included.
+
+use std::fmt::{self, Write};
+
+use crate::data::Dialect;
+
+/// How often a child element may appear.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Occurs {
+    /// The child must appear exactly once.
+    Required,
+    /// The child may appear at most once.
+    Optional,
+    /// The child may appear any number of times.
+    Many,
+}
+
+impl Occurs {
+    fn min(self) -> &'static str {
+        match self {
+            Occurs::Required => "1",
+            Occurs::Optional | Occurs::Many => "0",
+        }
+    }
+
+    fn max(self) -> &'static str {
+        match self {
+            Occurs::Required | Occurs::Optional => "1",
+            Occurs::Many => "unbounded",
+        }
+    }
+}
+
+/// The values an attribute may take.
+#[derive(Debug, Clone, Copy)]
+enum AttributeType {
+    /// The label of an entity.
+    Label,
+    /// A number of frames.
+    Frames,
+    /// A comma-separated list of tags.
+    Tags,
+    /// One of a set of keywords.
+    Choice(&'static [&'static str]),
+}
+
+/// An attribute of an element.
+#[derive(Debug)]
+struct Attribute {
+    name: &'static str,
+    kind: AttributeType,
+    required: bool,
+    default: Option<&'static str>,
+}
+
+/// The content of an element.
+#[derive(Debug)]
+enum Content {
+    /// The element has no content.
+    Empty,
+    /// The element contains an expression.
+    Expression,
+    /// The element contains other elements, in any order.
+    Children {
+        children: &'static [(&'static str, Occurs)],
+        /// Exactly one of these children must appear.
+        one_of: &'static [&'static str],
+    },
+}
+
+/// An element in a document.
+#[derive(Debug)]
+struct Element {
+    name: &'static str,
+    description: &'static str,
+    attributes: &'static [Attribute],
+    content: Content,
+}
+
+const ORIENTATIONS: &[&str] = &["none", "vertical", "horizontal"];
+const DIRECTION_KINDS: &[&str] = &["aim", "absolute", "relative", "sequence"];
+const CHANGES: &[&str] = &["absolute", "relative", "sequence"];
+const BOOLEANS: &[&str] = &["true", "false"];
+const EASINGS: &[&str] = &["linear", "easeIn", "easeOut", "easeInOut", "sine"];
+
+const LABEL: Attribute = Attribute {
+    name: "label",
+    kind: AttributeType::Label,
+    required: false,
+    default: None,
+};
+const REF_LABEL: Attribute = Attribute {
+    name: "label",
+    kind: AttributeType::Label,
+    required: true,
+    default: None,
+};
+const TTL: Attribute = Attribute {
+    name: "ttl",
+    kind: AttributeType::Frames,
+    required: false,
+    default: None,
+};
+const CHANGE: Attribute = Attribute {
+    name: "type",
+    kind: AttributeType::Choice(CHANGES),
+    required: false,
+    default: Some("absolute"),
+};
+// The default depends on the options of the runner.
+const EASING: Attribute = Attribute {
+    name: "easing",
+    kind: AttributeType::Choice(EASINGS),
+    required: false,
+    default: None,
+};
+
+const PARAMS: &[(&str, Occurs)] = &[("param", Occurs::Many)];
+
+/// Every element accepted by the parser.
+const ELEMENTS: &[Element] = &[
+    Element {
+        name: "bulletml",
+        description: "A BulletML document.",
+        attributes: &[Attribute {
+            name: "type",
+            kind: AttributeType::Choice(ORIENTATIONS),
+            required: false,
+            default: Some("none"),
+        }],
+        content: Content::Children {
+            children: &[
+                ("bullet", Occurs::Many),
+                ("action", Occurs::Many),
+                ("fire", Occurs::Many),
+            ],
+            one_of: &[],
+        },
+    },
+    Element {
+        name: "bullet",
+        description: "A bullet.",
+        attributes: &[LABEL, TTL],
+        content: Content::Children {
+            children: &[
+                ("direction", Occurs::Optional),
+                ("speed", Occurs::Optional),
+                ("action", Occurs::Many),
+                ("actionRef", Occurs::Many),
+            ],
+            one_of: &[],
+        },
+    },
+    Element {
+        name: "action",
+        description: "An action that may be performed for a bullet.",
+        attributes: &[
+            LABEL,
+            TTL,
+            Attribute {
+                name: "tags",
+                kind: AttributeType::Tags,
+                required: false,
+                default: None,
+            },
+        ],
+        content: Content::Children {
+            children: &[
+                ("repeat", Occurs::Many),
+                ("fire", Occurs::Many),
+                ("fireRef", Occurs::Many),
+                ("changeSpeed", Occurs::Many),
+                ("changeDirection", Occurs::Many),
+                ("accel", Occurs::Many),
+                ("wait", Occurs::Many),
+                ("vanish", Occurs::Many),
+                ("action", Occurs::Many),
+                ("actionRef", Occurs::Many),
+            ],
+            one_of: &[],
+        },
+    },
+    Element {
+        name: "fire",
+        description: "Create a new bullet.",
+        attributes: &[LABEL],
+        content: Content::Children {
+            children: &[
+                ("direction", Occurs::Optional),
+                ("speed", Occurs::Optional),
+                ("bullet", Occurs::Optional),
+                ("bulletRef", Occurs::Optional),
+            ],
+            one_of: &["bullet", "bulletRef"],
+        },
+    },
+    Element {
+        name: "changeDirection",
+        description: "A change in direction.",
+        attributes: &[EASING],
+        content: Content::Children {
+            children: &[("direction", Occurs::Required), ("term", Occurs::Required)],
+            one_of: &[],
+        },
+    },
+    Element {
+        name: "changeSpeed",
+        description: "A change in speed.",
+        attributes: &[EASING],
+        content: Content::Children {
+            children: &[("speed", Occurs::Required), ("term", Occurs::Required)],
+            one_of: &[],
+        },
+    },
+    Element {
+        name: "accel",
+        description: "An acceleration.",
+        attributes: &[EASING],
+        content: Content::Children {
+            children: &[
+                ("horizontal", Occurs::Optional),
+                ("vertical", Occurs::Optional),
+                ("term", Occurs::Required),
+            ],
+            one_of: &[],
+        },
+    },
+    Element {
+        name: "wait",
+        description: "Pause for a number of frames.",
+        attributes: &[],
+        content: Content::Expression,
+    },
+    Element {
+        name: "vanish",
+        description: "Destroy the bullet.",
+        attributes: &[],
+        content: Content::Empty,
+    },
+    Element {
+        name: "repeat",
+        description: "Repeat a set of actions a number of times.",
+        attributes: &[],
+        content: Content::Children {
+            children: &[
+                ("times", Occurs::Required),
+                ("action", Occurs::Many),
+                ("actionRef", Occurs::Many),
+            ],
+            one_of: &[],
+        },
+    },
+    Element {
+        name: "direction",
+        description: "A direction, in degrees.",
+        attributes: &[
+            Attribute {
+                name: "type",
+                kind: AttributeType::Choice(DIRECTION_KINDS),
+                required: false,
+                default: Some("aim"),
+            },
+            Attribute {
+                name: "lead",
+                kind: AttributeType::Choice(BOOLEANS),
+                required: false,
+                default: Some("false"),
+            },
+        ],
+        content: Content::Expression,
+    },
+    Element {
+        name: "speed",
+        description: "A speed.",
+        attributes: &[CHANGE],
+        content: Content::Expression,
+    },
+    Element {
+        name: "horizontal",
+        description: "A horizontal acceleration.",
+        attributes: &[CHANGE],
+        content: Content::Expression,
+    },
+    Element {
+        name: "vertical",
+        description: "A vertical acceleration.",
+        attributes: &[CHANGE],
+        content: Content::Expression,
+    },
+    Element {
+        name: "term",
+        description: "A duration, in frames.",
+        attributes: &[],
+        content: Content::Expression,
+    },
+    Element {
+        name: "times",
+        description: "A number of repetitions.",
+        attributes: &[],
+        content: Content::Expression,
+    },
+    Element {
+        name: "bulletRef",
+        description: "A reference to a labeled bullet.",
+        attributes: &[REF_LABEL],
+        content: Content::Children {
+            children: PARAMS,
+            one_of: &[],
+        },
+    },
+    Element {
+        name: "actionRef",
+        description: "A reference to a labeled action.",
+        attributes: &[REF_LABEL],
+        content: Content::Children {
+            children: PARAMS,
+            one_of: &[],
+        },
+    },
+    Element {
+        name: "fireRef",
+        description: "A reference to a labeled fire.",
+        attributes: &[REF_LABEL],
+        content: Content::Children {
+            children: PARAMS,
+            one_of: &[],
+        },
+    },
+    Element {
+        name: "param",
+        description: "A parameter to a referenced entity, available as `$1`, `$2`, and so on.",
+        attributes: &[],
+        content: Content::Expression,
+    },
+];
+
+impl Element {
+    /// The attributes of the element recognized in a dialect.
+    fn attributes(&self, dialect: Dialect) -> impl Iterator<Item = &Attribute> {
+        let name = self.name;
+        self.attributes
+            .iter()
+            .filter(move |attr| dialect.is_attribute(name, attr.name))
+    }
+}
+
+/// The elements recognized in a dialect.
+fn elements(dialect: Dialect) -> impl Iterator<Item = &'static Element> {
+    ELEMENTS
+        .iter()
+        .filter(move |element| dialect.is_element(element.name))
+}
+
+/// Escape text for use within XML content or attribute values.
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Escape text for use within a JSON string.
+fn json_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn write_xml_attribute(out: &mut String, attr: &Attribute, indent: &str) -> fmt::Result {
+    write!(out, "{}<xs:attribute name=\"{}\"", indent, attr.name)?;
+    if attr.required {
+        write!(out, " use=\"required\"")?;
+    }
+    if let Some(default) = attr.default {
+        write!(out, " default=\"{}\"", default)?;
+    }
+
+    match attr.kind {
+        AttributeType::Label | AttributeType::Tags => writeln!(out, " type=\"xs:string\"/>"),
+        AttributeType::Frames => writeln!(out, " type=\"xs:unsignedInt\"/>"),
+        AttributeType::Choice(values) => {
+            writeln!(out, ">")?;
+            writeln!(out, "{}  <xs:simpleType>", indent)?;
+            writeln!(out, "{}    <xs:restriction base=\"xs:string\">", indent)?;
+            for value in values {
+                writeln!(out, "{}      <xs:enumeration value=\"{}\"/>", indent, value)?;
+            }
+            writeln!(out, "{}    </xs:restriction>", indent)?;
+            writeln!(out, "{}  </xs:simpleType>", indent)?;
+            writeln!(out, "{}</xs:attribute>", indent)
+        },
+    }
+}
+
+fn write_xml_schema(out: &mut String, dialect: Dialect) -> fmt::Result {
+    writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(
+        out,
+        "<xs:schema xmlns:xs=\"http://www.w3.org/2001/XMLSchema\" \
+         xmlns:vc=\"http://www.w3.org/2007/XMLSchema-versioning\" vc:minVersion=\"1.1\">",
+    )?;
+    writeln!(out, "  <xs:element name=\"bulletml\" type=\"bulletml\"/>")?;
+    writeln!(out, "  <xs:simpleType name=\"expression\">")?;
+    writeln!(out, "    <xs:restriction base=\"xs:string\"/>")?;
+    writeln!(out, "  </xs:simpleType>")?;
+
+    for element in elements(dialect) {
+        writeln!(out, "  <xs:complexType name=\"{}\">", element.name)?;
+        writeln!(out, "    <xs:annotation>")?;
+        writeln!(
+            out,
+            "      <xs:documentation>{}</xs:documentation>",
+            xml_escape(element.description),
+        )?;
+        writeln!(out, "    </xs:annotation>")?;
+
+        let indent = match element.content {
+            Content::Empty => "    ",
+            Content::Expression => {
+                writeln!(out, "    <xs:simpleContent>")?;
+                writeln!(out, "      <xs:extension base=\"expression\">")?;
+                "        "
+            },
+            Content::Children {
+                children, ..
+            } => {
+                writeln!(out, "    <xs:all>")?;
+                for (name, occurs) in children {
+                    if dialect.is_element(name) {
+                        writeln!(
+                            out,
+                            "      <xs:element name=\"{}\" type=\"{}\" minOccurs=\"{}\" \
+                             maxOccurs=\"{}\"/>",
+                            name,
+                            name,
+                            occurs.min(),
+                            occurs.max(),
+                        )?;
+                    }
+                }
+                writeln!(out, "    </xs:all>")?;
+                "    "
+            },
+        };
+
+        for attr in element.attributes(dialect) {
+            write_xml_attribute(out, attr, indent)?;
+        }
+
+        match element.content {
+            Content::Empty => (),
+            Content::Expression => {
+                writeln!(out, "      </xs:extension>")?;
+                writeln!(out, "    </xs:simpleContent>")?;
+            },
+            Content::Children {
+                one_of, ..
+            } => {
+                if !one_of.is_empty() {
+                    writeln!(
+                        out,
+                        "    <xs:assert test=\"count({}) = 1\"/>",
+                        one_of.join(" | "),
+                    )?;
+                }
+            },
+        }
+
+        writeln!(out, "  </xs:complexType>")?;
+    }
+
+    writeln!(out, "</xs:schema>")
+}
+
+fn write_json_attribute(out: &mut String, attr: &Attribute) -> fmt::Result {
+    write!(out, "        \"{}\": {{ ", attr.name)?;
+    match attr.kind {
+        AttributeType::Label | AttributeType::Tags => write!(out, "\"type\": \"string\"")?,
+        AttributeType::Frames => {
+            write!(
+                out,
+                "\"type\": \"integer\", \"minimum\": 0, \"maximum\": {}",
+                u32::MAX,
+            )?
+        },
+        AttributeType::Choice(values) => {
+            let values = values
+                .iter()
+                .map(|value| format!("\"{}\"", value))
+                .collect::<Vec<_>>();
+            write!(out, "\"enum\": [{}]", values.join(", "))?
+        },
+    }
+    if let Some(default) = attr.default {
+        write!(out, ", \"default\": \"{}\"", default)?;
+    }
+    write!(out, " }}")
+}
+
+fn write_json_schema(out: &mut String, dialect: Dialect) -> fmt::Result {
+    writeln!(out, "{{")?;
+    writeln!(
+        out,
+        "  \"$schema\": \"http://json-schema.org/draft-07/schema#\",",
+    )?;
+    writeln!(out, "  \"title\": \"BulletML\",")?;
+    writeln!(out, "  \"$ref\": \"#/definitions/bulletml\",")?;
+    writeln!(out, "  \"definitions\": {{")?;
+    write!(out, "    \"expression\": {{ \"type\": \"string\" }}")?;
+
+    for element in elements(dialect) {
+        writeln!(out, ",")?;
+        writeln!(out, "    \"{}\": {{", element.name)?;
+        writeln!(out, "      \"type\": \"object\",")?;
+        writeln!(
+            out,
+            "      \"description\": \"{}\",",
+            json_escape(element.description),
+        )?;
+
+        let mut properties = Vec::new();
+        let mut required = Vec::new();
+        for attr in element.attributes(dialect) {
+            let mut property = String::new();
+            write_json_attribute(&mut property, attr)?;
+            properties.push(property);
+            if attr.required {
+                required.push(attr.name);
+            }
+        }
+
+        let mut one_of: &[&str] = &[];
+        match element.content {
+            Content::Empty => (),
+            Content::Expression => {
+                properties.push(
+                    "        \"$value\": { \"$ref\": \"#/definitions/expression\" }".into(),
+                );
+                required.push("$value");
+            },
+            Content::Children {
+                children,
+                one_of: exclusive,
+            } => {
+                for &(name, occurs) in children {
+                    if dialect.is_element(name) {
+                        properties.push(format!(
+                            "        \"{}\": {{ \"$ref\": \"#/definitions/{}\" }}",
+                            name, name,
+                        ));
+                        if occurs == Occurs::Required {
+                            required.push(name);
+                        }
+                    }
+                }
+                one_of = exclusive;
+            },
+        }
+
+        writeln!(out, "      \"properties\": {{")?;
+        if !properties.is_empty() {
+            writeln!(out, "{}", properties.join(",\n"))?;
+        }
+        writeln!(out, "      }},")?;
+        if !required.is_empty() {
+            let required = required
+                .iter()
+                .map(|name| format!("\"{}\"", name))
+                .collect::<Vec<_>>();
+            writeln!(out, "      \"required\": [{}],", required.join(", "))?;
+        }
+        if !one_of.is_empty() {
+            let one_of = one_of
+                .iter()
+                .map(|name| format!("{{ \"required\": [\"{}\"] }}", name))
+                .collect::<Vec<_>>();
+            writeln!(out, "      \"oneOf\": [{}],", one_of.join(", "))?;
+        }
+        writeln!(out, "      \"additionalProperties\": false")?;
+        write!(out, "    }}")?;
+    }
+
+    writeln!(out)?;
+    writeln!(out, "  }}")?;
+    writeln!(out, "}}")
+}
+
+/// An XML Schema (XSD) for documents of the extended dialect.
+pub fn xml_schema() -> String {
+    xml_schema_for(Dialect::Extended)
+}
+
+/// An XML Schema (XSD) for documents of a dialect.
+///
+/// Children may appear in any order and some constraints are written as assertions, so the
+/// schema requires XSD 1.1.
+pub fn xml_schema_for(dialect: Dialect) -> String {
+    let mut out = String::new();
+    // Writing to a `String` does not fail.
+    let _ = write_xml_schema(&mut out, dialect);
+    out
+}
+
+/// A JSON Schema for documents of the extended dialect.
+pub fn json_schema() -> String {
+    json_schema_for(Dialect::Extended)
+}
+
+/// A JSON Schema for documents of a dialect.
+///
+/// Documents are described as `serde` sees them: attributes and child elements are both
+/// properties of an object and the content of an element with text is its `$value` property.
+/// Elements which may repeat are given as repeated keys; JSON Schema cannot describe this, so
+/// only the schema of each occurrence is checked.
+pub fn json_schema_for(dialect: Dialect) -> String {
+    let mut out = String::new();
+    // Writing to a `String` does not fail.
+    let _ = write_json_schema(&mut out, dialect);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use crate::data::Dialect;
+    use crate::schema::{self, Content, ELEMENTS};
+
+    #[test]
+    fn test_schema_elements_match_dialect() {
+        for &dialect in &[Dialect::Extended, Dialect::Spec] {
+            let names = ELEMENTS
+                .iter()
+                .map(|element| element.name)
+                .collect::<BTreeSet<_>>();
+            let expected = dialect.element_names().iter().cloned().collect();
+            assert_eq!(names, expected);
+        }
+
+        let attributes = ELEMENTS
+            .iter()
+            .flat_map(|element| {
+                element
+                    .attributes
+                    .iter()
+                    .map(move |attr| (element.name, attr.name))
+            })
+            .collect::<BTreeSet<_>>();
+        let expected = Dialect::Extended
+            .attribute_names()
+            .iter()
+            .cloned()
+            .collect();
+        assert_eq!(attributes, expected);
+
+        // Every child is described.
+        for element in ELEMENTS {
+            if let Content::Children {
+                children,
+                one_of,
+            } = element.content
+            {
+                for (name, _) in children {
+                    assert!(ELEMENTS.iter().any(|element| element.name == *name));
+                }
+                for name in one_of {
+                    assert!(children.iter().any(|(child, _)| child == name));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_xml_schema() {
+        let extended = schema::xml_schema();
+        assert!(extended.contains("<xs:complexType name=\"changeDirection\">"));
+        assert!(extended.contains("<xs:attribute name=\"ttl\" type=\"xs:unsignedInt\"/>"));
+        assert!(extended.contains("<xs:assert test=\"count(bullet | bulletRef) = 1\"/>"));
+        assert!(extended.contains(
+            "      <xs:extension base=\"expression\">\n        \
+             <xs:attribute name=\"type\" default=\"aim\">",
+        ));
+
+        let spec = schema::xml_schema_for(Dialect::Spec);
+        assert!(!spec.contains("ttl"));
+        assert!(!spec.contains("tags"));
+        assert!(!spec.contains("lead"));
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let extended: serde_json::Value = serde_json::from_str(&schema::json_schema()).unwrap();
+        let definitions = &extended["definitions"];
+        for element in ELEMENTS {
+            assert!(definitions[element.name].is_object());
+        }
+        assert_eq!(definitions["fire"]["oneOf"][1]["required"][0], "bulletRef");
+        assert_eq!(definitions["changeSpeed"]["required"][0], "speed");
+        assert_eq!(definitions["wait"]["required"][0], "$value");
+        assert_eq!(
+            definitions["direction"]["properties"]["type"]["default"],
+            "aim",
+        );
+        assert_eq!(
+            definitions["direction"]["properties"]["lead"]["default"],
+            "false",
+        );
+        assert_eq!(
+            definitions["action"]["properties"]["ttl"]["maximum"],
+            u32::MAX,
+        );
+
+        let spec: serde_json::Value =
+            serde_json::from_str(&schema::json_schema_for(Dialect::Spec)).unwrap();
+        assert!(spec["definitions"]["action"]["properties"]["ttl"].is_null());
+        assert!(spec["definitions"]["action"]["properties"]["label"].is_object());
+    }
+}
@@ -0,0 +1,26 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+//! The stable public API.
+//!
+//! Everything re-exported here follows semantic versioning: it only changes incompatibly along
+//! with the major version (or, before 1.0, the minor version). This covers the data model, the
+//! compiled scripts and runners which games interact with, and the `BulletManager` trait games
+//! implement. Games should prefer these items; they are also available at their usual paths.
+//!
+//! Other public items may change in any minor release. APIs for tooling which are expected to
+//! change more quickly are in the `unstable` module behind the `unstable` feature.
+
+pub use crate::data;
+pub use crate::geom;
+#[cfg(feature = "async")]
+pub use crate::run::{compile_async, CompileFuture};
+#[cfg(feature = "runtime")]
+pub use crate::run::{
+    BulletId, BulletMLError, BulletManager, BulletPrototype, BulletScript, CompileJob,
+    CompiledBulletML, Event, NegativeSpeed, NoTargetPolicy, Observer, RepeatEvaluation, Rng,
+    Runner, RunnerOptions, RunnerOptionsBuilder, RunnerStatus, UnknownVariablePolicy, UpdateError,
+    UpdateReport,
+};
+#[cfg(feature = "xml")]
+pub use crate::{Pattern, PatternArchive, PatternError, PatternMetadata, PatternValidation};
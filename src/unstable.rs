@@ -0,0 +1,22 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+//! APIs for tooling which are not covered by semantic versioning.
+//!
+//! This module is only available with the `unstable` feature. Anything in it may change or be
+//! removed in any release, so only tools which are updated along with this crate should use it.
+
+pub mod analysis;
+
+pub use crate::run::simulate;
+pub use crate::run::{
+    Coverage, Keyframe, MicroStep, SampleStats, Spawn, StepInfo, StepIter, StepKind, Timeline,
+};
+
+/// The intermediate representation of compiled scripts.
+///
+/// Compiling a script resolves the references of its entities into a tree of these types, which
+/// runners then walk.
+pub mod ir {
+    pub use crate::run::compile::{Action, Bullet, Fire, Frame, NodeStep, Repeat, Step, Tags};
+}
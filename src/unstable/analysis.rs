@@ -0,0 +1,279 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+//! Automatic analysis of patterns.
+//!
+//! Estimates are computed from the simulation performed by `CompiledBulletML::to_keyframes` and
+//! are meant for coarse comparisons such as sorting a large archive of patterns.
+
+use std::f32::consts::PI;
+
+use crate::data::{ExpressionError, Value};
+use crate::geom::Velocity;
+use crate::run::{CompiledBulletML, Keyframe};
+
+/// A simple model of how the player moves while a pattern runs.
+///
+/// The emitter is at the origin and aims straight down, so the player should be placed below it
+/// (at a positive `y`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlayerModel {
+    /// The horizontal position around which the player moves.
+    pub x: f32,
+    /// The vertical position of the player.
+    pub y: f32,
+    /// How far the player sways to either side.
+    pub sway: f32,
+    /// The number of frames for a full sway.
+    pub period: u32,
+    /// The size of a cell in the density grid.
+    pub cell_size: f32,
+    /// The number of cells on each side of the player in the density grid.
+    pub reach: usize,
+    /// The number of frames to observe.
+    pub frames: u32,
+}
+
+impl Default for PlayerModel {
+    fn default() -> Self {
+        PlayerModel {
+            x: 0.,
+            y: 160.,
+            sway: 0.,
+            period: 120,
+            cell_size: 8.,
+            reach: 8,
+            frames: 300,
+        }
+    }
+}
+
+impl PlayerModel {
+    /// The position of the player on a frame.
+    pub fn position(&self, frame: u32) -> (f32, f32) {
+        let phase = if self.period == 0 {
+            0.
+        } else {
+            2. * PI * ((frame % self.period) as f32) / (self.period as f32)
+        };
+
+        (self.x + self.sway * phase.sin(), self.y)
+    }
+
+    /// The number of cells along each side of the density grid.
+    pub fn width(&self) -> usize {
+        2 * self.reach + 1
+    }
+}
+
+/// The density of bullets around the player on a frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DensityFrame {
+    /// The frame.
+    pub frame: u32,
+    /// The number of bullets in each cell, by row (increasing downwards) and then column.
+    ///
+    /// The grid is centered on the player.
+    pub cells: Vec<u32>,
+    /// The width of the widest run of empty cells in the player's row.
+    pub safe_gap: f32,
+}
+
+/// An estimate of the difficulty of a pattern.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Difficulty {
+    /// The difficulty score.
+    ///
+    /// This is the mean over all frames of how much of the player's row is not part of the
+    /// widest safe gap. It ranges from `0` (always clear) to `1` (never any gap).
+    pub score: f32,
+    /// The number of cells along each side of the density grids.
+    pub width: usize,
+    /// The density around the player for each frame.
+    pub frames: Vec<DensityFrame>,
+}
+
+fn position(keyframe: &Keyframe, frame: u32) -> (f32, f32) {
+    let elapsed = frame.saturating_sub(keyframe.frame) as f32;
+    let velocity = Velocity::new(keyframe.direction, keyframe.speed);
+
+    (
+        keyframe.x + elapsed * velocity.x,
+        keyframe.y + elapsed * velocity.y,
+    )
+}
+
+fn cell(offset: f32, cell_size: f32, reach: usize) -> Option<usize> {
+    let index = (offset / cell_size).round();
+    let reach = reach as f32;
+    if (-reach..=reach).contains(&index) {
+        Some((index + reach) as usize)
+    } else {
+        None
+    }
+}
+
+fn safe_gap(row: &[u32]) -> usize {
+    row.split(|&count| count > 0)
+        .map(<[u32]>::len)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Estimate the difficulty of a pattern for a player following `player`.
+///
+/// The pattern is simulated at `rank` with a fixed seed. Spawned bullets move in straight lines.
+pub fn difficulty(
+    pattern: &CompiledBulletML,
+    rank: Value,
+    player: &PlayerModel,
+) -> Result<Difficulty, ExpressionError> {
+    let timeline = pattern.to_keyframes(rank, 0, player.frames)?;
+    // Each bullet has a keyframe when it is spawned and one at the end of the timeline.
+    let spawned = timeline
+        .keyframes
+        .iter()
+        .filter(|keyframe| keyframe.bullet.is_some() && keyframe.frame < timeline.frames)
+        .collect::<Vec<_>>();
+
+    let width = player.width();
+    let row_width = (width as f32) * player.cell_size;
+    let frames = (0..player.frames)
+        .map(|frame| {
+            let (px, py) = player.position(frame);
+            let mut cells = vec![0; width * width];
+
+            spawned
+                .iter()
+                .filter(|keyframe| keyframe.frame <= frame)
+                .for_each(|keyframe| {
+                    let (x, y) = position(keyframe, frame);
+                    let col = cell(x - px, player.cell_size, player.reach);
+                    let row = cell(y - py, player.cell_size, player.reach);
+                    if let (Some(col), Some(row)) = (col, row) {
+                        cells[row * width + col] += 1;
+                    }
+                });
+
+            let player_row = &cells[player.reach * width..(player.reach + 1) * width];
+            let safe_gap = (safe_gap(player_row) as f32) * player.cell_size;
+
+            DensityFrame {
+                frame,
+                cells,
+                safe_gap,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let score = if frames.is_empty() {
+        0.
+    } else {
+        frames
+            .iter()
+            .map(|frame| 1. - frame.safe_gap / row_width)
+            .sum::<f32>()
+            / (frames.len() as f32)
+    };
+
+    Ok(Difficulty {
+        score,
+        width,
+        frames,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::data;
+    use crate::run::CompiledBulletML;
+    use crate::unstable::analysis::{self, PlayerModel};
+
+    fn compile(doc: &str) -> CompiledBulletML {
+        let bulletml: data::BulletML = serde_xml_rs::from_str(doc).unwrap();
+        CompiledBulletML::new(bulletml).unwrap()
+    }
+
+    const STREAM: &str = r#"<?xml version="1.0" ?>
+<bulletml>
+<action label="top">
+<repeat>
+<times>100</times>
+<action>
+<fire><direction type="absolute">180</direction><speed>8</speed><bullet/></fire>
+<wait>1</wait>
+</action>
+</repeat>
+</action>
+</bulletml>"#;
+
+    fn player() -> PlayerModel {
+        PlayerModel {
+            y: 40.,
+            reach: 2,
+            frames: 20,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_difficulty_empty() {
+        let compiled = CompiledBulletML::new(data::BulletML::default()).unwrap();
+        let difficulty = analysis::difficulty(&compiled, 0., &player()).unwrap();
+
+        assert_eq!(difficulty.score, 0.);
+        assert_eq!(difficulty.width, 5);
+        assert_eq!(difficulty.frames.len(), 20);
+        assert!(difficulty
+            .frames
+            .iter()
+            .all(|frame| frame.safe_gap == 40. && frame.cells.iter().all(|&count| count == 0)));
+    }
+
+    #[test]
+    fn test_difficulty_stream() {
+        let compiled = compile(STREAM);
+        let difficulty = analysis::difficulty(&compiled, 0., &player()).unwrap();
+
+        // The stream reaches the player's row on frame 5 and splits it from then on.
+        assert!(difficulty.frames[..5]
+            .iter()
+            .all(|frame| frame.safe_gap == 40.));
+        assert!(difficulty.frames[5..]
+            .iter()
+            .all(|frame| frame.safe_gap == 16. && frame.cells[12] == 1));
+        assert!((difficulty.score - 0.6 * 15. / 20.).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_difficulty_sway() {
+        let compiled = compile(STREAM);
+        let still = analysis::difficulty(&compiled, 0., &player()).unwrap();
+        let sway = analysis::difficulty(
+            &compiled,
+            0.,
+            &PlayerModel {
+                sway: 100.,
+                period: 4,
+                ..player()
+            },
+        )
+        .unwrap();
+
+        assert!(sway.score < still.score);
+    }
+
+    #[test]
+    fn test_player_position() {
+        let player = PlayerModel {
+            x: 10.,
+            sway: 5.,
+            period: 4,
+            ..Default::default()
+        };
+
+        assert_eq!(player.position(0), (10., 160.));
+        assert_eq!(player.position(4), (10., 160.));
+        assert!((player.position(1).0 - 15.).abs() < 1e-4);
+    }
+}
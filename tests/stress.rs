@@ -0,0 +1,183 @@
+// Distributed under the OSI-approved BSD 2-Clause License.
+// See accompanying LICENSE file for details.
+
+//! Run many runners against a single compiled pattern.
+
+#![cfg(feature = "runtime")]
+
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use bulletml::data::{self, ExpressionContext, Value};
+use bulletml::run::{BulletManager, CompiledBulletML, Runner, RunnerOptions};
+
+const RUNNERS: usize = 10_000;
+const FRAMES: u32 = 60;
+/// The expected upper bound for updating every runner once.
+///
+/// This is informative only; shared CI machines are too noisy to fail on it.
+const FRAME_BUDGET: Duration = Duration::from_millis(50);
+
+const DENSE: &str = r#"<?xml version="1.0" ?>
+<bulletml type="vertical">
+<action label="top">
+<repeat>
+<times>999</times>
+<action>
+<repeat>
+<times>4 + $rank * 4</times>
+<action>
+<fire>
+<direction type="sequence">360 / (4 + $rank * 4) + 3</direction>
+<speed type="sequence">$rand * 0.1</speed>
+<bullet/>
+</fire>
+</action>
+</repeat>
+<changeDirection>
+<direction type="aim">0</direction>
+<term>4</term>
+</changeDirection>
+<wait>1</wait>
+</action>
+</repeat>
+</action>
+</bulletml>"#;
+
+/// Counters shared by every manager.
+#[derive(Debug, Default)]
+struct Counters {
+    live: Cell<usize>,
+    fired: Cell<usize>,
+}
+
+/// A manager which only counts what it is asked to do.
+struct CountingManager {
+    counters: Rc<Counters>,
+    turn: u32,
+    seed: u32,
+}
+
+impl CountingManager {
+    fn new(counters: &Rc<Counters>, seed: u32) -> Self {
+        counters.live.set(counters.live.get() + 1);
+
+        CountingManager {
+            counters: Rc::clone(counters),
+            turn: 0,
+            seed,
+        }
+    }
+}
+
+impl Drop for CountingManager {
+    fn drop(&mut self) {
+        self.counters.live.set(self.counters.live.get() - 1);
+    }
+}
+
+impl ExpressionContext for CountingManager {
+    fn get(&self, _: &str) -> Option<Value> {
+        None
+    }
+
+    fn get_param(&self, _: usize) -> Option<Value> {
+        None
+    }
+
+    fn rand(&self) -> Value {
+        ((self.seed.wrapping_mul(2_654_435_761) ^ self.turn) % 1000) as Value / 1000.
+    }
+
+    fn rank(&self) -> Value {
+        0.5
+    }
+}
+
+impl BulletManager for CountingManager {
+    fn new_simple(&mut self, _: f32, _: f32) {
+        self.counters.fired.set(self.counters.fired.get() + 1);
+    }
+
+    fn new_bullet(&mut self, _: f32, _: f32) {
+        self.counters.fired.set(self.counters.fired.get() + 1);
+    }
+
+    fn turn(&self) -> u32 {
+        self.turn
+    }
+
+    fn direction(&self) -> f32 {
+        0.
+    }
+
+    fn aim_direction(&self) -> f32 {
+        (self.seed % 360) as f32
+    }
+
+    fn speed(&self) -> f32 {
+        1.
+    }
+
+    fn speed_x(&self) -> f32 {
+        0.
+    }
+
+    fn speed_y(&self) -> f32 {
+        0.
+    }
+
+    fn default_speed(&self) -> f32 {
+        1.
+    }
+
+    fn vanish(&mut self) {}
+
+    fn change_direction(&mut self, _: f32) {}
+
+    fn change_speed(&mut self, _: f32) {}
+
+    fn accel_x(&mut self, _: f32) {}
+
+    fn accel_y(&mut self, _: f32) {}
+}
+
+#[test]
+fn test_stress_runners() {
+    let bulletml: data::BulletML = serde_xml_rs::from_str(DENSE).unwrap();
+    let compiled = CompiledBulletML::new(bulletml).unwrap();
+    let counters = Rc::new(Counters::default());
+
+    let mut runners = (0..RUNNERS)
+        .map(|seed| {
+            let manager = CountingManager::new(&counters, seed as u32);
+            Runner::from_compiled(manager, &compiled, RunnerOptions::default())
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(counters.live.get(), RUNNERS);
+
+    let mut slowest = Duration::default();
+    for turn in 0..FRAMES {
+        let start = Instant::now();
+        for runner in &mut runners {
+            runner.manager_mut().turn = turn;
+            runner.update().unwrap();
+        }
+        slowest = slowest.max(start.elapsed());
+    }
+
+    if slowest > FRAME_BUDGET {
+        eprintln!(
+            "warning: the slowest frame for {} runners took {:?} (expected at most {:?})",
+            RUNNERS, slowest, FRAME_BUDGET,
+        );
+    }
+
+    // Each runner fires at least four bullets per frame.
+    assert!(counters.fired.get() >= RUNNERS * (FRAMES as usize) * 4);
+
+    drop(runners);
+    assert_eq!(counters.live.get(), 0);
+    assert_eq!(Rc::strong_count(&counters), 1);
+}